@@ -0,0 +1,29 @@
+CREATE TABLE IF NOT EXISTS webhooks
+(
+  id          INTEGER PRIMARY KEY NOT NULL,
+  user_id     INTEGER NOT NULL,
+  url         VARCHAR(2048) NOT NULL,
+  secret      VARCHAR(64) NOT NULL,
+  events      VARCHAR(250) NOT NULL,
+  active      BOOLEAN NOT NULL DEFAULT 1,
+
+  FOREIGN KEY (user_id) REFERENCES users(id)
+);
+
+CREATE TABLE IF NOT EXISTS webhook_deliveries
+(
+  id              INTEGER PRIMARY KEY NOT NULL,
+  webhook_id      INTEGER NOT NULL,
+  event           VARCHAR(64) NOT NULL,
+  payload         TEXT NOT NULL,
+  status          VARCHAR(16) NOT NULL DEFAULT 'pending',
+  attempts        INTEGER NOT NULL DEFAULT 0,
+  next_attempt_at DATETIME NOT NULL,
+  response_status INTEGER,
+  last_error      TEXT,
+  created_at      DATETIME NOT NULL,
+
+  FOREIGN KEY (webhook_id) REFERENCES webhooks(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS webhook_deliveries_due ON webhook_deliveries (status, next_attempt_at);
@@ -0,0 +1,9 @@
+ALTER TABLE transactions ADD COLUMN payee VARCHAR(250);
+
+CREATE TABLE IF NOT EXISTS csv_import_mappings
+(
+  account_id  INTEGER PRIMARY KEY NOT NULL,
+  mapping     TEXT NOT NULL,
+
+  FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
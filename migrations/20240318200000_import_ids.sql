@@ -0,0 +1,3 @@
+ALTER TABLE transactions ADD COLUMN import_id VARCHAR(250);
+
+CREATE UNIQUE INDEX IF NOT EXISTS transactions_import_id ON transactions (account_id, import_id);
@@ -0,0 +1,4 @@
+ALTER TABLE transactions ADD COLUMN value_date DATE;
+
+ALTER TABLE accounts ADD COLUMN reconcile_balance INTEGER;
+ALTER TABLE accounts ADD COLUMN reconcile_date DATE;
@@ -0,0 +1,13 @@
+ALTER TABLE transactions ADD COLUMN imported BOOLEAN NOT NULL DEFAULT 0;
+
+UPDATE transactions SET imported = 1 WHERE import_id IS NOT NULL;
+
+CREATE TABLE IF NOT EXISTS dismissed_matches
+(
+  imported_id INTEGER NOT NULL,
+  manual_id   INTEGER NOT NULL,
+
+  PRIMARY KEY (imported_id, manual_id),
+  FOREIGN KEY (imported_id) REFERENCES transactions(id) ON DELETE CASCADE,
+  FOREIGN KEY (manual_id) REFERENCES transactions(id) ON DELETE CASCADE
+);
@@ -0,0 +1,50 @@
+CREATE TABLE IF NOT EXISTS category_groups
+(
+  id      INTEGER PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL,
+  name    VARCHAR(250) NOT NULL,
+
+  UNIQUE (user_id, name),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS categories
+(
+  id       INTEGER PRIMARY KEY NOT NULL,
+  group_id INTEGER NOT NULL,
+  name     VARCHAR(250) NOT NULL,
+
+  UNIQUE (group_id, name),
+  FOREIGN KEY (group_id) REFERENCES category_groups(id) ON DELETE CASCADE
+);
+
+-- The amount assigned to a category for a month, keyed by the first day of the month.
+CREATE TABLE IF NOT EXISTS category_budgets
+(
+  category_id INTEGER NOT NULL,
+  month       DATE NOT NULL,
+  assigned    INTEGER NOT NULL DEFAULT 0,
+
+  PRIMARY KEY (category_id, month),
+  FOREIGN KEY (category_id) REFERENCES categories(id) ON DELETE CASCADE
+);
+
+ALTER TABLE transactions ADD COLUMN category_id INTEGER REFERENCES categories(id) ON DELETE SET NULL;
+-- The transaction on the other side of a transfer between two accounts.
+ALTER TABLE transactions ADD COLUMN transfer_id INTEGER REFERENCES transactions(id) ON DELETE SET NULL;
+
+-- The parts of a transaction that is split across several categories. The parts add up to the
+-- transaction's inflow and outflow.
+CREATE TABLE IF NOT EXISTS transaction_splits
+(
+  id             INTEGER PRIMARY KEY NOT NULL,
+  transaction_id INTEGER NOT NULL,
+  category_id    INTEGER,
+  payee          VARCHAR(250),
+  memo           TEXT NOT NULL DEFAULT '',
+  inflow         INTEGER NOT NULL DEFAULT 0,
+  outflow        INTEGER NOT NULL DEFAULT 0,
+
+  FOREIGN KEY (transaction_id) REFERENCES transactions(id) ON DELETE CASCADE,
+  FOREIGN KEY (category_id) REFERENCES categories(id) ON DELETE SET NULL
+);
@@ -0,0 +1,2 @@
+-- The ISO 4217 code of the currency the account is held in.
+ALTER TABLE accounts ADD COLUMN currency VARCHAR(3) NOT NULL DEFAULT 'GBP';
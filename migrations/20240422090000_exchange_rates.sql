@@ -0,0 +1,16 @@
+-- The ISO 4217 code of the currency the budget's totals are shown in.
+ALTER TABLE users ADD COLUMN currency VARCHAR(3) NOT NULL DEFAULT 'GBP';
+
+-- One unit of `base` is worth `rate` units of `quote` on `date`.
+CREATE TABLE IF NOT EXISTS exchange_rates
+(
+  id      INTEGER PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL,
+  date    DATE NOT NULL,
+  base    VARCHAR(3) NOT NULL,
+  quote   VARCHAR(3) NOT NULL,
+  rate    REAL NOT NULL,
+
+  UNIQUE (user_id, date, base, quote),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
@@ -0,0 +1,2 @@
+-- How the user writes amounts of money, as a BCP 47 tag such as `en-GB`.
+ALTER TABLE users ADD COLUMN locale VARCHAR(35) NOT NULL DEFAULT 'en-GB';
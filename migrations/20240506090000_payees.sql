@@ -0,0 +1,36 @@
+CREATE TABLE IF NOT EXISTS payees
+(
+  id                  INTEGER PRIMARY KEY NOT NULL,
+  user_id             INTEGER NOT NULL,
+  name                VARCHAR(250) NOT NULL,
+  -- Given to new transactions with this payee that have no category.
+  default_category_id INTEGER,
+
+  UNIQUE (user_id, name),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE,
+  FOREIGN KEY (default_category_id) REFERENCES categories(id) ON DELETE SET NULL
+);
+
+-- Names a payee had before it was renamed, or that were merged into it, so transactions entered
+-- or imported with those names get the payee.
+CREATE TABLE IF NOT EXISTS payee_aliases
+(
+  payee_id INTEGER NOT NULL,
+  name     VARCHAR(250) NOT NULL,
+
+  PRIMARY KEY (payee_id, name),
+  FOREIGN KEY (payee_id) REFERENCES payees(id) ON DELETE CASCADE
+);
+
+-- `transactions.payee` keeps the text the payee was entered or imported with.
+ALTER TABLE transactions ADD COLUMN payee_id INTEGER REFERENCES payees(id) ON DELETE SET NULL;
+
+INSERT OR IGNORE INTO payees (user_id, name)
+SELECT DISTINCT a.user_id, trim(t.payee)
+FROM transactions t JOIN accounts a ON a.id = t.account_id
+WHERE trim(coalesce(t.payee, '')) <> '';
+
+UPDATE transactions SET payee_id = (
+  SELECT p.id FROM payees p JOIN accounts a ON a.user_id = p.user_id
+  WHERE a.id = transactions.account_id AND p.name = trim(transactions.payee)
+);
@@ -0,0 +1,15 @@
+-- A coloured flag, such as `red`.
+ALTER TABLE transactions ADD COLUMN flag VARCHAR(10);
+
+-- Conditions and actions are stored as JSON, see `rules::Rule`.
+CREATE TABLE IF NOT EXISTS rules
+(
+  id         INTEGER PRIMARY KEY NOT NULL,
+  user_id    INTEGER NOT NULL,
+  name       VARCHAR(250) NOT NULL,
+  priority   INTEGER NOT NULL DEFAULT 0,
+  conditions TEXT NOT NULL,
+  actions    TEXT NOT NULL,
+
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
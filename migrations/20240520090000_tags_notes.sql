@@ -0,0 +1,22 @@
+-- Longer than the memo, for things like why a transaction is disputed.
+ALTER TABLE transactions ADD COLUMN notes TEXT;
+
+CREATE TABLE IF NOT EXISTS tags
+(
+  id      INTEGER PRIMARY KEY NOT NULL,
+  user_id INTEGER NOT NULL,
+  name    VARCHAR(50) NOT NULL,
+
+  UNIQUE (user_id, name),
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS transaction_tags
+(
+  transaction_id INTEGER NOT NULL,
+  tag_id         INTEGER NOT NULL,
+
+  PRIMARY KEY (transaction_id, tag_id),
+  FOREIGN KEY (transaction_id) REFERENCES transactions(id) ON DELETE CASCADE,
+  FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+);
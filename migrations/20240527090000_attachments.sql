@@ -0,0 +1,13 @@
+-- Receipts and other documents. The file itself is kept in attachment storage under `storage_key`.
+CREATE TABLE IF NOT EXISTS attachments
+(
+  id             INTEGER PRIMARY KEY NOT NULL,
+  transaction_id INTEGER NOT NULL,
+  filename       VARCHAR(250) NOT NULL,
+  content_type   VARCHAR(50) NOT NULL,
+  size           INTEGER NOT NULL,
+  storage_key    VARCHAR(64) NOT NULL UNIQUE,
+  created_at     DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+  FOREIGN KEY (transaction_id) REFERENCES transactions(id) ON DELETE CASCADE
+);
@@ -0,0 +1,30 @@
+-- Full-text search over a transaction's payee, memo and notes, kept up to date by the triggers
+-- below. `rowid` is the transaction's id. The payee column holds both the payee's name and the
+-- text the transaction was entered or imported with.
+CREATE VIRTUAL TABLE IF NOT EXISTS transactions_fts USING fts5(payee, memo, notes, tokenize = 'unicode61 remove_diacritics 2');
+
+INSERT INTO transactions_fts (rowid, payee, memo, notes)
+SELECT t.id, trim(COALESCE(p.name, '') || ' ' || COALESCE(t.payee, '')), COALESCE(t.memo, ''), COALESCE(t.notes, '')
+FROM transactions t LEFT JOIN payees p ON p.id = t.payee_id;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_insert AFTER INSERT ON transactions BEGIN
+  INSERT INTO transactions_fts (rowid, payee, memo, notes)
+  VALUES (new.id, trim(COALESCE((SELECT name FROM payees WHERE id = new.payee_id), '') || ' ' || COALESCE(new.payee, '')), COALESCE(new.memo, ''), COALESCE(new.notes, ''));
+END;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_update AFTER UPDATE OF payee, payee_id, memo, notes ON transactions BEGIN
+  DELETE FROM transactions_fts WHERE rowid = old.id;
+  INSERT INTO transactions_fts (rowid, payee, memo, notes)
+  VALUES (new.id, trim(COALESCE((SELECT name FROM payees WHERE id = new.payee_id), '') || ' ' || COALESCE(new.payee, '')), COALESCE(new.memo, ''), COALESCE(new.notes, ''));
+END;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_delete AFTER DELETE ON transactions BEGIN
+  DELETE FROM transactions_fts WHERE rowid = old.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_payee_rename AFTER UPDATE OF name ON payees BEGIN
+  DELETE FROM transactions_fts WHERE rowid IN (SELECT id FROM transactions WHERE payee_id = new.id);
+  INSERT INTO transactions_fts (rowid, payee, memo, notes)
+  SELECT t.id, trim(new.name || ' ' || COALESCE(t.payee, '')), COALESCE(t.memo, ''), COALESCE(t.notes, '')
+  FROM transactions t WHERE t.payee_id = new.id;
+END;
@@ -0,0 +1,3 @@
+-- The register pages through an account by date, and matching looks for imported transactions a
+-- few days either side of a manual one.
+CREATE INDEX IF NOT EXISTS transactions_account_date ON transactions (account_id, date);
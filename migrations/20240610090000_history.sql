@@ -0,0 +1,29 @@
+-- Changes to the user's data, kept so they can be undone and redone. Each change set is one thing
+-- the user did, and each change is one row before and after, as JSON. A row that didn't exist
+-- before or after has no image there. A change set is `done`, `undone`, or `discarded` when it
+-- was undone and something else was done before it was redone.
+CREATE TABLE IF NOT EXISTS change_sets
+(
+  id          INTEGER PRIMARY KEY NOT NULL,
+  user_id     INTEGER NOT NULL,
+  description TEXT NOT NULL,
+  created_at  DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  status      VARCHAR(10) NOT NULL DEFAULT 'done',
+
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS changes
+(
+  id            INTEGER PRIMARY KEY NOT NULL,
+  change_set_id INTEGER NOT NULL,
+  table_name    VARCHAR(50) NOT NULL,
+  row_id        INTEGER NOT NULL,
+  before        TEXT,
+  after         TEXT,
+
+  FOREIGN KEY (change_set_id) REFERENCES change_sets(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS change_sets_user ON change_sets (user_id, id);
+CREATE INDEX IF NOT EXISTS changes_change_set ON changes (change_set_id);
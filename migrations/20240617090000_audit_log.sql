@@ -0,0 +1,24 @@
+-- Every change made to the data: who made it, from where, and each row before and after as JSON.
+-- Rows are only ever added. `actor_id` isn't a foreign key so entries outlive the user.
+CREATE TABLE IF NOT EXISTS audit_log
+(
+  id         INTEGER PRIMARY KEY NOT NULL,
+  actor_id   INTEGER,
+  ip         VARCHAR(45),
+  entity     VARCHAR(50) NOT NULL,
+  entity_id  INTEGER NOT NULL,
+  action     VARCHAR(10) NOT NULL,
+  before     TEXT,
+  after      TEXT,
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS audit_log_actor ON audit_log (actor_id, id);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+  SELECT RAISE(ABORT, 'the audit log cannot be changed');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log BEGIN
+  SELECT RAISE(ABORT, 'the audit log cannot be changed');
+END;
@@ -0,0 +1,212 @@
+-- A budget owns accounts, categories, payees, tags, rules, exchange rates and webhooks, and is
+-- shared by its members. Each existing user gets a budget with the same id as their own, so the
+-- old `user_id` columns become `budget_id` without changing their values.
+CREATE TABLE IF NOT EXISTS budgets
+(
+  id         INTEGER PRIMARY KEY NOT NULL,
+  name       VARCHAR(250) NOT NULL,
+  -- The ISO 4217 code of the currency the budget's totals are shown in.
+  currency   VARCHAR(3) NOT NULL DEFAULT 'GBP',
+  created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- An owner can do anything, an editor can change the budget's data but not who it is shared with,
+-- and a viewer can only look.
+CREATE TABLE IF NOT EXISTS budget_members
+(
+  budget_id INTEGER NOT NULL,
+  user_id   INTEGER NOT NULL,
+  role      VARCHAR(10) NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+
+  PRIMARY KEY (budget_id, user_id),
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE,
+  FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS budget_members_user ON budget_members (user_id);
+
+-- An invitation to join a budget, sent to an email address. Whoever follows the link with `token`
+-- while logged in as that address becomes a member with `role`.
+CREATE TABLE IF NOT EXISTS budget_invitations
+(
+  id          INTEGER PRIMARY KEY NOT NULL,
+  budget_id   INTEGER NOT NULL,
+  email       VARCHAR(250) NOT NULL,
+  role        VARCHAR(10) NOT NULL CHECK (role IN ('owner', 'editor', 'viewer')),
+  token       VARCHAR(64) NOT NULL UNIQUE,
+  invited_by  INTEGER,
+  created_at  DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  accepted_at DATETIME,
+
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE,
+  FOREIGN KEY (invited_by) REFERENCES users(id) ON DELETE SET NULL
+);
+
+INSERT INTO budgets (id, name, currency)
+SELECT id, name || '''s budget', currency FROM users;
+
+INSERT INTO budget_members (budget_id, user_id, role)
+SELECT id, id, 'owner' FROM users;
+
+ALTER TABLE users DROP COLUMN currency;
+
+-- The tables below are rebuilt to point at budgets instead of users, following SQLite's steps for
+-- changing a table: copy it to `new_X` with the new definition, drop it and rename the copy. With
+-- foreign keys on, dropping a table deletes the rows that point at it, and the pragma has no
+-- effect inside the transaction a migration runs in, so the server migrates on a connection with
+-- them off. This refuses to run with them on once there is data to lose.
+PRAGMA foreign_keys = OFF;
+CREATE TEMP TABLE budgets_migration_check (passed BOOLEAN NOT NULL CHECK (passed));
+INSERT INTO budgets_migration_check SELECT NOT foreign_keys OR NOT EXISTS (SELECT 1 FROM users) FROM pragma_foreign_keys;
+
+-- The search triggers read `payees`, so they are recreated once it has been rebuilt.
+DROP TRIGGER IF EXISTS transactions_fts_insert;
+DROP TRIGGER IF EXISTS transactions_fts_update;
+
+CREATE TABLE new_accounts
+(
+  id                INTEGER PRIMARY KEY NOT NULL,
+  budget_id         INTEGER,
+  name              VARCHAR(250) NOT NULL,
+  currency          VARCHAR(3) NOT NULL DEFAULT 'GBP',
+  reconcile_balance INTEGER,
+  reconcile_date    DATE,
+
+  FOREIGN KEY (budget_id) REFERENCES budgets(id)
+);
+INSERT INTO new_accounts (id, budget_id, name, currency, reconcile_balance, reconcile_date)
+SELECT id, user_id, name, currency, reconcile_balance, reconcile_date FROM accounts;
+DROP TABLE accounts;
+ALTER TABLE new_accounts RENAME TO accounts;
+
+CREATE TABLE new_webhooks
+(
+  id          INTEGER PRIMARY KEY NOT NULL,
+  budget_id   INTEGER NOT NULL,
+  url         VARCHAR(2048) NOT NULL,
+  secret      VARCHAR(64) NOT NULL,
+  events      VARCHAR(250) NOT NULL,
+  active      BOOLEAN NOT NULL DEFAULT 1,
+
+  FOREIGN KEY (budget_id) REFERENCES budgets(id)
+);
+INSERT INTO new_webhooks (id, budget_id, url, secret, events, active)
+SELECT id, user_id, url, secret, events, active FROM webhooks;
+DROP TABLE webhooks;
+ALTER TABLE new_webhooks RENAME TO webhooks;
+
+CREATE TABLE new_category_groups
+(
+  id        INTEGER PRIMARY KEY NOT NULL,
+  budget_id INTEGER NOT NULL,
+  name      VARCHAR(250) NOT NULL,
+
+  UNIQUE (budget_id, name),
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE
+);
+INSERT INTO new_category_groups (id, budget_id, name)
+SELECT id, user_id, name FROM category_groups;
+DROP TABLE category_groups;
+ALTER TABLE new_category_groups RENAME TO category_groups;
+
+CREATE TABLE new_exchange_rates
+(
+  id        INTEGER PRIMARY KEY NOT NULL,
+  budget_id INTEGER NOT NULL,
+  date      DATE NOT NULL,
+  base      VARCHAR(3) NOT NULL,
+  quote     VARCHAR(3) NOT NULL,
+  rate      REAL NOT NULL,
+
+  UNIQUE (budget_id, date, base, quote),
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE
+);
+INSERT INTO new_exchange_rates (id, budget_id, date, base, quote, rate)
+SELECT id, user_id, date, base, quote, rate FROM exchange_rates;
+DROP TABLE exchange_rates;
+ALTER TABLE new_exchange_rates RENAME TO exchange_rates;
+
+CREATE TABLE new_payees
+(
+  id                  INTEGER PRIMARY KEY NOT NULL,
+  budget_id           INTEGER NOT NULL,
+  name                VARCHAR(250) NOT NULL,
+  -- Given to new transactions with this payee that have no category.
+  default_category_id INTEGER,
+
+  UNIQUE (budget_id, name),
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE,
+  FOREIGN KEY (default_category_id) REFERENCES categories(id) ON DELETE SET NULL
+);
+INSERT INTO new_payees (id, budget_id, name, default_category_id)
+SELECT id, user_id, name, default_category_id FROM payees;
+DROP TABLE payees;
+ALTER TABLE new_payees RENAME TO payees;
+
+CREATE TABLE new_rules
+(
+  id         INTEGER PRIMARY KEY NOT NULL,
+  budget_id  INTEGER NOT NULL,
+  name       VARCHAR(250) NOT NULL,
+  priority   INTEGER NOT NULL DEFAULT 0,
+  conditions TEXT NOT NULL,
+  actions    TEXT NOT NULL,
+
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE
+);
+INSERT INTO new_rules (id, budget_id, name, priority, conditions, actions)
+SELECT id, user_id, name, priority, conditions, actions FROM rules;
+DROP TABLE rules;
+ALTER TABLE new_rules RENAME TO rules;
+
+CREATE TABLE new_tags
+(
+  id        INTEGER PRIMARY KEY NOT NULL,
+  budget_id INTEGER NOT NULL,
+  name      VARCHAR(50) NOT NULL,
+
+  UNIQUE (budget_id, name),
+  FOREIGN KEY (budget_id) REFERENCES budgets(id) ON DELETE CASCADE
+);
+INSERT INTO new_tags (id, budget_id, name)
+SELECT id, user_id, name FROM tags;
+DROP TABLE tags;
+ALTER TABLE new_tags RENAME TO tags;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_insert AFTER INSERT ON transactions BEGIN
+  INSERT INTO transactions_fts (rowid, payee, memo, notes)
+  VALUES (new.id, trim(COALESCE((SELECT name FROM payees WHERE id = new.payee_id), '') || ' ' || COALESCE(new.payee, '')), COALESCE(new.memo, ''), COALESCE(new.notes, ''));
+END;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_update AFTER UPDATE OF payee, payee_id, memo, notes ON transactions BEGIN
+  DELETE FROM transactions_fts WHERE rowid = old.id;
+  INSERT INTO transactions_fts (rowid, payee, memo, notes)
+  VALUES (new.id, trim(COALESCE((SELECT name FROM payees WHERE id = new.payee_id), '') || ' ' || COALESCE(new.payee, '')), COALESCE(new.memo, ''), COALESCE(new.notes, ''));
+END;
+
+CREATE TRIGGER IF NOT EXISTS transactions_fts_payee_rename AFTER UPDATE OF name ON payees BEGIN
+  DELETE FROM transactions_fts WHERE rowid IN (SELECT id FROM transactions WHERE payee_id = new.id);
+  INSERT INTO transactions_fts (rowid, payee, memo, notes)
+  SELECT t.id, trim(new.name || ' ' || COALESCE(t.payee, '')), COALESCE(t.memo, ''), COALESCE(t.notes, '')
+  FROM transactions t WHERE t.payee_id = new.id;
+END;
+
+-- Every copied row still has to point at something that exists.
+INSERT INTO budgets_migration_check SELECT NOT EXISTS (SELECT 1 FROM pragma_foreign_key_check);
+DROP TABLE budgets_migration_check;
+
+-- Which budget a change was made to, so every member can see the budget's log. Entries from
+-- before budgets were made in the actor's own, and are filled in with the log briefly unlocked.
+ALTER TABLE audit_log ADD COLUMN budget_id INTEGER;
+DROP TRIGGER audit_log_no_update;
+UPDATE audit_log SET budget_id = actor_id;
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log BEGIN
+  SELECT RAISE(ABORT, 'the audit log cannot be changed');
+END;
+CREATE INDEX IF NOT EXISTS audit_log_budget ON audit_log (budget_id, id);
+
+-- Undo only reaches the changes a user made to the budget they are working in.
+ALTER TABLE change_sets ADD COLUMN budget_id INTEGER REFERENCES budgets(id) ON DELETE CASCADE;
+UPDATE change_sets SET budget_id = user_id;
+DROP INDEX IF EXISTS change_sets_user;
+CREATE INDEX IF NOT EXISTS change_sets_user ON change_sets (user_id, budget_id, id);
@@ -0,0 +1,2 @@
+-- An archived budget is kept with everything in it, but is left out of the budget picker.
+ALTER TABLE budgets ADD COLUMN archived_at DATETIME;
@@ -0,0 +1,3 @@
+-- Tracking accounts, such as a pension or a house, count towards net worth but are kept out of the
+-- budget.
+ALTER TABLE accounts ADD COLUMN on_budget BOOLEAN NOT NULL DEFAULT 1;
@@ -1,27 +1,93 @@
 use poem::{get, middleware::AddData, post, session::{CookieConfig, CookieSession}, EndpointExt, IntoEndpoint, Route
 };
+use std::sync::Arc;
+
 use sqlx::{Pool, Sqlite};
-use crate::handlers::{create_account, get_accounts, get_transactions, home, login, login_page, logout, sign_up, sign_up_page};
+use crate::{attachments::Storage, audit};
+use crate::handlers::{income_expense_page, export_income_expense, cash_flow_page, export_cash_flow, reports_page, report_lines, net_worth_page, export_net_worth, budgets_page, create_budget, switch_budget, rename_budget, archive_budget, members_page, invite_member, update_member, cancel_invitation, invitation_page, accept_invitation, audit_page, history_page, undo, redo, add_exchange_rate, categories_page, assign_category, move_money, delete_payee, merge_payee, payees_page, update_payee, create_rule, delete_rule, preview_rule, rules_page, tags_page, all_accounts, get_all_register_page, create_transaction_in_any_account, bulk_edit, transaction_details, upload_attachment, get_attachment, delete_attachment, update_transaction_details, approve_match, backup_page, currencies_page, import_exchange_rates, save_currency_settings, save_locale, export_archive, export_journal, restore_archive, confirm_import, confirm_statement_import, create_account, create_transaction, create_webhook, delete_webhook, dismiss_match, get_accounts, get_register_page, get_transactions, get_webhook_deliveries, get_webhooks, home, import_page, login, login_page, logout, preview_import, preview_statement_import, sign_up, sign_up_page, upload_import, upload_ynab_import, confirm_ynab_import, ynab_import_page};
 
-pub fn app(pool: Pool<Sqlite>) -> impl IntoEndpoint {
+pub fn app(pool: Pool<Sqlite>, storage: Arc<dyn Storage>) -> impl IntoEndpoint {
     Route::new()
         .at("/", get(home))
         .at("/login", get(login_page).post(login))
         .at("/signup", get(sign_up_page).post(sign_up))
         .at("/logout", get(logout))
         .at("/accounts/:id", get(get_transactions))
+        .at("/accounts/:id/transactions", get(get_register_page).post(create_transaction))
+        .at("/accounts/:id/matches/approve", post(approve_match))
+        .at("/accounts/:id/matches/dismiss", post(dismiss_match))
+        .at("/accounts/:id/import", get(import_page).post(upload_import))
+        .at("/accounts/:id/import/preview", post(preview_import))
+        .at("/accounts/:id/import/confirm", post(confirm_import))
+        .at("/accounts/:id/import/statement/preview", post(preview_statement_import))
+        .at("/accounts/:id/import/statement/confirm", post(confirm_statement_import))
+        .at("/import/ynab", get(ynab_import_page).post(upload_ynab_import))
+        .at("/import/ynab/confirm", post(confirm_ynab_import))
+        .at("/backup", get(backup_page))
+        .at("/backup/export", get(export_archive))
+        .at("/backup/export/:format", get(export_journal))
+        .at("/backup/restore", post(restore_archive))
+        .at("/currencies", get(currencies_page).post(save_currency_settings))
+        .at("/currencies/rates", post(add_exchange_rate))
+        .at("/currencies/rates/import", post(import_exchange_rates))
+        .at("/locale", post(save_locale))
+        .at("/categories", get(categories_page))
+        .at("/categories/move", post(move_money))
+        .at("/categories/:id/assign", post(assign_category))
+        .at("/payees", get(payees_page))
+        .at("/payees/:id", post(update_payee))
+        .at("/payees/:id/merge", post(merge_payee))
+        .at("/payees/:id/delete", post(delete_payee))
+        .at("/transactions/:id", get(transaction_details).post(update_transaction_details))
+        .at("/transactions/:id/attachments", post(upload_attachment))
+        .at("/attachments/:id", get(get_attachment))
+        .at("/attachments/:id/delete", post(delete_attachment))
+        .at("/tags", get(tags_page))
+        .at("/history", get(history_page))
+        .at("/audit", get(audit_page))
+        .at("/reports", get(reports_page))
+        .at("/reports/lines", get(report_lines))
+        .at("/reports/net-worth", get(net_worth_page))
+        .at("/reports/net-worth.csv", get(export_net_worth))
+        .at("/reports/income-and-spending", get(income_expense_page))
+        .at("/reports/income-and-spending/:format", get(export_income_expense))
+        .at("/reports/cash-flow", get(cash_flow_page))
+        .at("/reports/cash-flow/:format", get(export_cash_flow))
+        .at("/budgets", get(budgets_page).post(create_budget))
+        .at("/budgets/switch", post(switch_budget))
+        .at("/budgets/:id", post(rename_budget))
+        .at("/budgets/:id/archive", post(archive_budget))
+        .at("/budget/members", get(members_page).post(invite_member))
+        .at("/budget/members/:user_id", post(update_member))
+        .at("/budget/invitations/:id/cancel", post(cancel_invitation))
+        .at("/invitations/:token", get(invitation_page))
+        .at("/invitations/:token/accept", post(accept_invitation))
+        .at("/undo", post(undo))
+        .at("/redo", post(redo))
+        .at("/accounts", get(all_accounts))
+        .at("/search", get(all_accounts))
+        .at("/transactions/bulk", post(bulk_edit))
+        .at("/transactions", get(get_all_register_page).post(create_transaction_in_any_account))
+        .at("/rules", get(rules_page).post(create_rule))
+        .at("/rules/preview", post(preview_rule))
+        .at("/rules/:id/delete", post(delete_rule))
         .at("/api/accounts", get(get_accounts))
         .at("/account/create", post(create_account))
+        .at("/webhooks", get(get_webhooks).post(create_webhook))
+        .at("/webhooks/deliveries", get(get_webhook_deliveries))
+        .at("/webhooks/:id/delete", post(delete_webhook))
         .with(AddData::new(pool))
+        .with(AddData::new(storage))
+        .around(audit::track)
         .with(CookieSession::new(CookieConfig::default().secure(false)))
 }
 
 #[cfg(test)] 
 mod tests {
-    use poem::test::TestClient;
+    use poem::{http::{header, StatusCode}, test::{TestClient, TestForm, TestFormField}, Endpoint};
     use serde::{Serialize, Deserialize};
 
-    use crate::db::{get_user, User};
+    use crate::{attachments::MemoryStorage, budgets::{self, Role}, db::{self, get_user, User}, search};
 
     use super::*;
 
@@ -38,6 +104,63 @@ mod tests {
         password: &'a str,
     }
 
+    /// Creates test@example.com and returns a client that sends its session cookie.
+    async fn logged_in_client(pool: &Pool<Sqlite>) -> TestClient<impl Endpoint> {
+        logged_in_client_with_storage(pool, Arc::new(MemoryStorage::default())).await
+    }
+
+    /// The id of test@example.com's budget.
+    async fn test_budget(pool: &Pool<Sqlite>) -> i32 {
+        let user_id = get_user(pool, "test@example.com".to_string()).await.unwrap().id.unwrap();
+        budgets::get_for_user(pool, user_id).await[0].id
+    }
+
+    /// Every transaction in the account in the order they were added, loaded through the register.
+    async fn account_transactions(pool: &Pool<Sqlite>, account_id: i64) -> Vec<db::Transaction> {
+        let budget_id: i32 = sqlx::query_scalar("SELECT budget_id FROM accounts WHERE id = ?").bind(account_id).fetch_one(pool).await.unwrap();
+        let filter = search::Filter { account_id: Some(account_id), ..Default::default() };
+        let order = search::Order { sort: search::Sort::Date, descending: false };
+        let mut transactions: Vec<db::Transaction> = db::get_register(pool, budget_id, &filter, order, None).await.into_iter().map(|row| row.transaction).collect();
+        transactions.sort_by_key(|t| t.id);
+        transactions
+    }
+
+    async fn logged_in_client_with_storage(pool: &Pool<Sqlite>, storage: Arc<MemoryStorage>) -> TestClient<impl Endpoint> {
+        logged_in_as(pool, "test@example.com", storage).await
+    }
+
+    /// Creates a user with their own budget, named after the start of their email address, and
+    /// returns a client that sends their session cookie.
+    async fn logged_in_as(pool: &Pool<Sqlite>, email: &str, storage: Arc<MemoryStorage>) -> TestClient<impl Endpoint> {
+        let name = email.split('@').next().unwrap();
+        let password = User::hash_password("password".to_string()).expect("Could not hash password");
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES (?, ?, ?, 1)")
+            .bind(name)
+            .bind(email)
+            .bind(password)
+            .execute(pool)
+            .await
+            .unwrap()
+            .last_insert_rowid();
+        budgets::insert(&mut pool.acquire().await.unwrap(), user_id, &format!("{}'s budget", name), "GBP").await.unwrap();
+
+        let cli = TestClient::new(app(pool.clone(), storage));
+        let resp = cli
+            .post("/login")
+            .form(&Login {
+                email,
+                password: "password"
+            })
+            .send()
+            .await;
+        cli.default_header(header::COOKIE, session_cookie(&resp))
+    }
+
+    /// The session cookie a response sets, for a client to send from then on.
+    fn session_cookie(response: &poem::test::TestResponse) -> String {
+        response.0.headers().get(header::SET_COOKIE).unwrap().to_str().unwrap().split(';').next().unwrap().to_string()
+    }
+
     #[sqlx::test]
     async fn test_login(pool: Pool<Sqlite>) -> sqlx::Result<()> {
         // Setup
@@ -48,7 +171,7 @@ mod tests {
             .await?;
 
         // Act
-        let cli = TestClient::new(app(pool));
+        let cli = TestClient::new(app(pool, Arc::new(MemoryStorage::default())));
         let resp = cli
             .post("/login")
             .form(&Login {
@@ -75,7 +198,7 @@ mod tests {
             .await?;
 
         // Act
-        let cli = TestClient::new(app(pool));
+        let cli = TestClient::new(app(pool, Arc::new(MemoryStorage::default())));
         let invalid_password_response = cli
             .post("/login")
             .form(&Login {
@@ -106,16 +229,16 @@ mod tests {
             .await;
         let both_body = invalid_both_response.0.into_body();
 
-        assert_eq!(username_body.into_string().await.unwrap().contains("User not found"), true, "Incorrect response when username is invalid");
-        assert_eq!(password_body.into_string().await.unwrap().contains("User not found"), true, "Incorrect response when password is invalid");
-        assert_eq!(both_body.into_string().await.unwrap().contains("User not found"), true, "Incorrect response when both username and password are invalid");
+        assert!(username_body.into_string().await.unwrap().contains("User not found"), "Incorrect response when username is invalid");
+        assert!(password_body.into_string().await.unwrap().contains("User not found"), "Incorrect response when password is invalid");
+        assert!(both_body.into_string().await.unwrap().contains("User not found"), "Incorrect response when both username and password are invalid");
 
         Ok(())
     }
 
     #[sqlx::test]
     async fn test_signup(pool: Pool<Sqlite>) -> sqlx::Result<()> {
-        let cli = TestClient::new(app(pool.clone()));
+        let cli = TestClient::new(app(pool.clone(), Arc::new(MemoryStorage::default())));
         let response = cli
             .post("/signup")
             .form(&Signup {
@@ -132,9 +255,977 @@ mod tests {
 
         assert_eq!(user.email, "test@example.com");
         assert_eq!(user.name, "Test");
-        assert_eq!(user.active, true);
+        assert!(user.active);
 
         Ok(())
     }
-}
 
+    #[sqlx::test]
+    async fn test_csv_import(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let content = "Date,Description,Amount\n2024-02-01,Tesco,-12.50\n2024-02-03,Employer,2000.00\n";
+
+        // Act
+        let upload = cli
+            .post(format!("/accounts/{}/import", account_id))
+            .multipart(TestForm::new().field(TestFormField::text(content).name("file").filename("export.csv")))
+            .send()
+            .await;
+        let confirm = cli
+            .post(format!("/accounts/{}/import/confirm", account_id))
+            .form(&[
+                ("content", content),
+                ("delimiter", ","),
+                ("has_header", "on"),
+                ("date_column", "0"),
+                ("date_format", "%Y-%m-%d"),
+                ("payee_column", "1"),
+                ("memo_column", ""),
+                ("amount_column", "2"),
+                ("inflow_column", ""),
+                ("outflow_column", ""),
+                ("decimal_separator", "."),
+            ])
+            .send()
+            .await;
+
+        // Assert
+        upload.assert_status_is_ok();
+        assert!(upload.0.into_body().into_string().await.unwrap().contains("Import 2 transactions"));
+        confirm.assert_header("HX-Trigger", "accountsUpdated");
+
+        let transactions = account_transactions(&pool, account_id).await;
+        assert_eq!(transactions.len(), 3, "Starting balance plus two imported transactions");
+        assert_eq!(transactions[1].payee.as_deref(), Some("Tesco"));
+        assert_eq!(transactions[1].outflow, 1250);
+        assert_eq!(transactions[2].inflow, 200000);
+
+        let mapping = db::get_csv_mapping(&pool, account_id).await.expect("Mapping should be remembered");
+        assert_eq!(mapping.amount_column, Some(2));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_ofx_import_skips_already_imported_transactions(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let content = "<OFX><STMTTRN><DTPOSTED>20240105<TRNAMT>-12.50<FITID>1<NAME>Tesco</STMTTRN>\
+            <STMTTRN><DTPOSTED>20240106<TRNAMT>-3.00<FITID>2<NAME>Aldi</STMTTRN></OFX>";
+
+        // Act
+        let upload = cli
+            .post(format!("/accounts/{}/import", account_id))
+            .multipart(TestForm::new().field(TestFormField::text(content).name("file").filename("statement.ofx")))
+            .send()
+            .await;
+        let first = cli
+            .post(format!("/accounts/{}/import/statement/confirm", account_id))
+            .form(&[("content", content), ("format", "ofx")])
+            .send()
+            .await;
+        let second = cli
+            .post(format!("/accounts/{}/import/statement/confirm", account_id))
+            .form(&[("content", content), ("format", "ofx")])
+            .send()
+            .await;
+
+        // Assert
+        assert!(upload.0.into_body().into_string().await.unwrap().contains("Import 2 transactions"));
+        assert!(first.0.into_body().into_string().await.unwrap().contains("Imported 2 transactions"));
+        assert!(second.0.into_body().into_string().await.unwrap().contains("Skipped 2 transactions"));
+
+        let transactions = account_transactions(&pool, account_id).await;
+        assert_eq!(transactions.len(), 3, "Starting balance plus two imported transactions");
+        assert_eq!(transactions[1].import_id.as_deref(), Some("1"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_statement_import_uses_account_currency_minor_units(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_empty_account(&pool, budget_id, "Tokyo", "JPY").await.unwrap();
+        let content = "<OFX><STMTTRN><DTPOSTED>20240105<TRNAMT>-1500<FITID>1<NAME>Lawson</STMTTRN></OFX>";
+
+        // Act
+        cli.post(format!("/accounts/{}/import/statement/confirm", account_id))
+            .form(&[("content", content), ("format", "ofx")])
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Assert
+        let transactions = account_transactions(&pool, account_id).await;
+        assert_eq!(transactions[0].outflow, 1500, "Yen have no minor units");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_failed_statement_import_sets_no_reconcile_target(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_empty_account(&pool, budget_id, "Girokonto", "EUR").await.unwrap();
+        sqlx::query("CREATE TRIGGER refuse_transactions BEFORE INSERT ON transactions BEGIN SELECT RAISE(ABORT, 'no transactions'); END").execute(&pool).await?;
+        let content = ":20:STARTUMSE\n:25:10020030/1234567\n:60F:C240104EUR0,\n:61:2401050105D12,50NTRFNONREF\n:86:Tesco\n:62F:D240105EUR12,50\n";
+
+        // Act
+        let resp = cli.post(format!("/accounts/{}/import/statement/confirm", account_id))
+            .form(&[("content", content), ("format", "mt940")])
+            .send()
+            .await;
+
+        // Assert
+        resp.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        let account = db::get_account(&pool, budget_id, account_id).await.unwrap();
+        assert_eq!((account.reconcile_balance, account.reconcile_date), (None, None), "The closing balance is only kept when its transactions are");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_matching_imported_transaction_keeps_manual_details(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        cli.post(format!("/accounts/{}/transactions", account_id))
+            .form(&[("date", "2024-01-03"), ("payee", "Tesco"), ("memo", "Birthday cake"), ("inflow", ""), ("outflow", "12.50")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let content = "<OFX><STMTTRN><DTPOSTED>20240105<TRNAMT>-12.50<FITID>1<NAME>TESCO STORES 2231</STMTTRN></OFX>";
+        cli.post(format!("/accounts/{}/import/statement/confirm", account_id))
+            .form(&[("content", content), ("format", "ofx")])
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        let transactions = account_transactions(&pool, account_id).await;
+        let (manual, imported) = (transactions[1].id.to_string(), transactions[2].id.to_string());
+        let register = cli.get(format!("/accounts/{}", account_id)).send().await;
+        assert!(register.0.into_body().into_string().await.unwrap().contains("Possible duplicates"));
+
+        // Act
+        let resp = cli
+            .post(format!("/accounts/{}/matches/approve", account_id))
+            .form(&[("imported_id", imported.as_str()), ("manual_id", manual.as_str())])
+            .send()
+            .await;
+
+        // Assert
+        resp.assert_status_is_ok();
+        let transactions = account_transactions(&pool, account_id).await;
+        assert_eq!(transactions.len(), 2, "Starting balance plus the matched transaction");
+        assert_eq!(transactions[1].memo, "Birthday cake");
+        assert_eq!(transactions[1].payee.as_deref(), Some("Tesco"));
+        assert_eq!(transactions[1].import_id.as_deref(), Some("1"));
+        assert!(transactions[1].cleared);
+
+        // Importing the same file again does not bring the duplicate back.
+        let again = cli
+            .post(format!("/accounts/{}/import/statement/confirm", account_id))
+            .form(&[("content", content), ("format", "ofx")])
+            .send()
+            .await;
+        assert!(again.0.into_body().into_string().await.unwrap().contains("Skipped 1 transactions"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_budget_total_converts_to_budget_currency(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        for (name, currency, balance) in [("Current", "GBP", "100"), ("Girokonto", "EUR", "50")] {
+            cli.post("/account/create")
+                .form(&[("name", name), ("currency", currency), ("starting_balance", balance)])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let before = cli.get("/api/accounts").send().await.0.into_body().into_string().await.unwrap();
+
+        // Act
+        cli.post("/currencies/rates")
+            .form(&[("date", "2024-01-01"), ("base", "eur"), ("quote", "GBP"), ("rate", "0.8")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let after = cli.get("/api/accounts").send().await.0.into_body().into_string().await.unwrap();
+
+        // Assert
+        assert!(before.contains("£100.00 (excluding EUR)"));
+        assert!(after.contains("£140.00"));
+        assert!(after.contains("€50.00"), "Accounts show their own currency");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_amounts_follow_the_users_locale(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        cli.post("/currencies").form(&[("currency", "EUR")]).send().await.assert_status_is_ok();
+        cli.post("/locale").form(&[("locale", "de-DE")]).send().await.assert_status_is_ok();
+
+        // Act
+        let resp = cli
+            .post("/account/create")
+            .form(&[("name", "Girokonto"), ("starting_balance", "1.234,56")])
+            .send()
+            .await;
+
+        // Assert
+        resp.assert_status_is_ok();
+        let budget_id = test_budget(&pool).await;
+        let accounts = db::get_accounts_for_budget(&pool, budget_id).await.unwrap();
+        assert_eq!((accounts[0].total, accounts[0].currency.as_str()), (123456, "EUR"));
+        let sidebar = cli.get("/api/accounts").send().await.0.into_body().into_string().await.unwrap();
+        assert!(sidebar.contains("1.234,56 €"));
+        cli.post("/account/create")
+            .form(&[("name", "Bargeld"), ("starting_balance", "1,234.56")])
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_viewers_can_change_their_number_format(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        sqlx::query("UPDATE budget_members SET role = 'viewer'").execute(&pool).await?;
+
+        // Act
+        let locale = cli.post("/locale").form(&[("locale", "de-DE")]).send().await;
+        let currency = cli.post("/currencies").form(&[("currency", "EUR")]).send().await;
+
+        // Assert
+        locale.assert_status_is_ok();
+        currency.assert_status(StatusCode::FORBIDDEN);
+        let user = get_user(&pool, "test@example.com".to_string()).await.unwrap();
+        assert_eq!(user.locale, "de-DE");
+        let budget_id = test_budget(&pool).await;
+        assert_eq!(db::get_budget_currency(&pool, budget_id).await, "GBP");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_merged_payees_keep_their_transactions(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        for payee in ["Tesco", "TESCO STORES 2231"] {
+            cli.post(format!("/accounts/{}/transactions", account_id))
+                .form(&[("date", "2024-01-03"), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", "12.50")])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let group = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Everyday").await.unwrap();
+        let groceries = db::insert_category(&mut pool.acquire().await.unwrap(), group, "Groceries").await.unwrap();
+        let payees = db::get_payees_for_budget(&pool, budget_id).await;
+        let (tesco, duplicate) = (payees[0].id, payees[1].id);
+
+        // Act
+        cli.post(format!("/payees/{}/merge", duplicate))
+            .form(&[("into_id", tesco.to_string())])
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.post(format!("/payees/{}", tesco))
+            .form(&[("name", "Tesco"), ("default_category_id", &groceries.to_string())])
+            .send()
+            .await
+            .assert_status_is_ok();
+        cli.post(format!("/accounts/{}/transactions", account_id))
+            .form(&[("date", "2024-01-04"), ("payee", "TESCO STORES 2231"), ("memo", ""), ("inflow", ""), ("outflow", "3.00")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let delete = cli.post(format!("/payees/{}/delete", tesco)).send().await;
+
+        // Assert
+        let transactions = account_transactions(&pool, account_id).await;
+        assert!(transactions[1..].iter().all(|t| t.payee.as_deref() == Some("Tesco") && t.payee_id == Some(tesco)));
+        assert_eq!(transactions[1].category_id, None, "Default categories only apply to new transactions");
+        assert_eq!(transactions[3].category_id, Some(groceries));
+        let payees = db::get_payees_for_budget(&pool, budget_id).await;
+        assert_eq!(payees.len(), 1, "The merged name does not come back");
+        assert_eq!(payees[0].transactions, 3);
+        assert!(delete.0.into_body().into_string().await.unwrap().contains("Only payees with no transactions can be deleted."));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_rules_run_on_new_transactions(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let group = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Everyday").await.unwrap();
+        let groceries = db::insert_category(&mut pool.acquire().await.unwrap(), group, "Groceries").await.unwrap();
+        let payment = |payee: &'static str| [("date", "2024-01-03"), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", "12.50")];
+        cli.post(format!("/accounts/{}/transactions", account_id))
+            .form(&payment("CARD PAYMENT TO TESCO STORES 2231"))
+            .send()
+            .await
+            .assert_status_is_ok();
+        let groceries = groceries.to_string();
+        let categorise = [("name", "Groceries"), ("priority", "2"), ("payee_operator", "contains"), ("payee", "tesco"), ("max_amount", "0"), ("category_id", &groceries)];
+        let rename = [("name", "Tesco"), ("priority", "1"), ("payee_operator", "regex"), ("payee", r"^card payment to (tesco) stores \d+$"), ("rename_payee", "Tesco"), ("flag", "green")];
+
+        // Act
+        let preview = cli.post("/rules/preview").form(&rename).send().await.0.into_body().into_string().await.unwrap();
+        cli.post("/rules").form(&categorise).send().await.assert_status_is_ok();
+        cli.post("/rules").form(&rename).send().await.assert_status_is_ok();
+        cli.post(format!("/accounts/{}/transactions", account_id))
+            .form(&payment("CARD PAYMENT TO TESCO STORES 4410"))
+            .send()
+            .await
+            .assert_status_is_ok();
+        let invalid = cli.post("/rules").form(&[("name", "Broken"), ("payee_operator", "regex"), ("payee", "(tesco"), ("flag", "red")]).send().await;
+
+        // Assert
+        assert!(preview.contains("This rule would match 1 of your transactions."));
+        assert!(preview.contains("CARD PAYMENT TO TESCO STORES 2231 → Tesco"));
+        let transactions = account_transactions(&pool, account_id).await;
+        assert_eq!(transactions[1].payee.as_deref(), Some("CARD PAYMENT TO TESCO STORES 2231"), "Rules do not change existing transactions");
+        assert_eq!(transactions[2].payee.as_deref(), Some("Tesco"));
+        assert_eq!(transactions[2].category_id, Some(groceries.parse().unwrap()));
+        assert_eq!(transactions[2].flag.as_deref(), Some("green"));
+        assert!(invalid.0.into_body().into_string().await.unwrap().contains("is not a valid pattern"));
+        assert_eq!(db::get_rules_for_budget(&pool, budget_id).await.len(), 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_flags_tags_and_notes(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        for payee in ["Train tickets", "Coffee"] {
+            cli.post(format!("/accounts/{}/transactions", account_id))
+                .form(&[("date", "2024-01-03"), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", "12.50")])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let transactions = account_transactions(&pool, account_id).await;
+        let (tickets, coffee) = (transactions[1].id, transactions[2].id);
+
+        // Act
+        cli.post(format!("/transactions/{}", tickets))
+            .form(&[("flag", "orange"), ("tags", "reimbursable, tax deductible, reimbursable"), ("notes", "Client visit in Leeds")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let tagged = cli.get(format!("/accounts/{}?flag=&tag=reimbursable", account_id)).send().await.0.into_body().into_string().await.unwrap();
+        let flagged = cli.get(format!("/accounts/{}?flag=red", account_id)).send().await.0.into_body().into_string().await.unwrap();
+        let report = cli.get("/tags").send().await.0.into_body().into_string().await.unwrap();
+
+        // Assert
+        let transaction = db::get_transaction(&pool, tickets.into()).await.unwrap();
+        assert_eq!(transaction.flag.as_deref(), Some("orange"));
+        assert_eq!(transaction.tags(), vec!["reimbursable", "tax deductible"]);
+        assert_eq!(transaction.notes.as_deref(), Some("Client visit in Leeds"));
+        assert_eq!(transaction.memo, "", "Notes are kept apart from the memo");
+        assert!(tagged.contains("Train tickets</div>") && !tagged.contains("Coffee</div>"));
+        assert!(!flagged.contains("Train tickets</div>"));
+        assert!(report.contains("reimbursable") && report.contains("£12.50"));
+        cli.post(format!("/transactions/{}", coffee))
+            .form(&[("flag", "pink")])
+            .send()
+            .await
+            .assert_status(StatusCode::BAD_REQUEST);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_search_transactions(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let current = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let savings = db::create_account(&pool, budget_id, "Savings", "GBP", 0).await.unwrap();
+        for (account_id, date, payee, outflow) in [(current, "2024-01-03", "Café Nero", "3.20"), (current, "2024-02-10", "Tesco", "45.00"), (savings, "2024-02-12", "Tesco", "8.99")] {
+            cli.post(format!("/accounts/{}/transactions", account_id))
+                .form(&[("date", date), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", outflow)])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let nero = account_transactions(&pool, current).await[1].id;
+        cli.post(format!("/transactions/{}", nero))
+            .form(&[("flag", "green"), ("tags", ""), ("notes", "Meeting with the accountant")])
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Act
+        let search = |query: &str| {
+            let request = cli.get(format!("/search?{}", query)).header("HX-Request", "true");
+            async { request.send().await.0.into_body().into_string().await.unwrap() }
+        };
+        let by_notes = search("q=accountant").await;
+        let by_accent = search("q=cafe").await;
+        let by_amount = search("q=tesco&min_amount=10").await;
+        let by_account = search(&format!("account_id={}&to=2024-02-28", savings)).await;
+        let by_flag = search("flag=green").await;
+        let bad_date = search("from=yesterday").await;
+        let in_account = cli.get(format!("/accounts/{}?q=tes", current)).header("HX-Request", "true").send().await.0.into_body().into_string().await.unwrap();
+        let shared = cli.get("/search?q=tesco").send().await.0.into_body().into_string().await.unwrap();
+
+        // Assert
+        assert!(by_notes.contains("Café Nero</div>") && by_notes.contains("1 transactions found"));
+        assert!(by_accent.contains("Café Nero</div>"), "Accents are ignored");
+        assert!(by_amount.contains("£45.00") && !by_amount.contains("£8.99"));
+        assert!(by_account.contains("£8.99") && !by_account.contains("£45.00"));
+        assert!(by_flag.contains("Café Nero</div>") && !by_flag.contains("Tesco</div>"));
+        assert!(bad_date.contains("yesterday is not a date."));
+        assert!(in_account.contains("£45.00") && !in_account.contains("£8.99") && !in_account.contains("Café Nero</div>"));
+        assert!(shared.contains("<!DOCTYPE html>") && shared.contains("£45.00"), "Shared links open the whole app");
+
+        let tesco = db::get_payees_for_budget(&pool, budget_id).await.into_iter().find(|p| p.name == "Tesco").unwrap();
+        cli.post(format!("/payees/{}", tesco.id))
+            .form(&[("name", "Sainsbury's"), ("default_category_id", "")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        assert!(search("q=sainsbury").await.contains("2 transactions found"), "Renamed payees are found by their new name");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_register_is_sorted_and_paged(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        for (date, payee, outflow) in [("2024-01-03", "Tesco", "45.00"), ("2024-01-01", "Bakery", "3.20")] {
+            cli.post(format!("/accounts/{}/transactions", account_id))
+                .form(&[("date", date), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", outflow)])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let tesco = account_transactions(&pool, account_id).await[1].id;
+
+        // Act
+        let register = cli.get(format!("/accounts/{}?to=2024-12-31", account_id)).send().await.0.into_body().into_string().await.unwrap();
+        let by_payee = cli.get(format!("/accounts/{}?sort=payee&order=desc", account_id)).send().await.0.into_body().into_string().await.unwrap();
+        let next_page = cli.get(format!("/accounts/{}/transactions?to=2024-12-31&after={}", account_id, tesco)).send().await.0.into_body().into_string().await.unwrap();
+
+        // Assert
+        assert!(register.find("Tesco</div>").unwrap() < register.find("Bakery</div>").unwrap(), "Newest first");
+        assert!(register.contains("2024-01-03</a>") && register.contains("-£48.20"), "Dates are readable and balances run");
+        assert!(by_payee.find("Tesco</div>").unwrap() < by_payee.find("Bakery</div>").unwrap());
+        assert!(by_payee.contains("sort=payee&amp;order=asc"), "Clicking the column again reverses it");
+        assert!(next_page.contains("Bakery</div>") && !next_page.contains("Tesco</div>"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_all_accounts_register(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let current = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let holiday = db::create_account(&pool, budget_id, "Holiday", "EUR", 0).await.unwrap();
+        cli.post(format!("/accounts/{}/transactions", current))
+            .form(&[("date", "2024-03-01"), ("payee", "Ferry"), ("memo", ""), ("inflow", ""), ("outflow", "60.00")])
+            .send()
+            .await
+            .assert_status_is_ok();
+
+        // Act
+        let created = cli.post("/transactions")
+            .form(&[("account_id", holiday.to_string().as_str()), ("date", "2024-03-02"), ("payee", "Ferry café"), ("memo", ""), ("inflow", ""), ("outflow", "7.50")])
+            .send()
+            .await;
+        let register = cli.get("/accounts?q=ferry").send().await.0.into_body().into_string().await.unwrap();
+        let rows = cli.get(format!("/transactions?q=ferry&account_id={}", holiday)).send().await.0.into_body().into_string().await.unwrap();
+        let ferry = account_transactions(&pool, current).await[1].id;
+        let saved = cli.post(format!("/transactions/{}", ferry)).form(&[("flag", "blue"), ("tags", ""), ("notes", "")]).send().await;
+
+        // Assert
+        created.assert_status_is_ok();
+        created.assert_header("HX-Trigger", "accountsUpdated");
+        let added = account_transactions(&pool, holiday).await;
+        assert_eq!((added[1].payee.as_deref(), added[1].outflow), (Some("Ferry café"), 750));
+        assert!(register.contains("<!DOCTYPE html>") && register.contains("All Accounts"));
+        assert!(register.contains("Current</div>") && register.contains("Holiday</div>"), "Each row names its account");
+        assert!(register.contains("2 transactions found"));
+        assert!(register.contains("GBP: in £0.00 · out £60.00") && register.contains("EUR: in €0.00 · out €7.50"), "Totals are kept apart by currency");
+        assert!(rows.contains("Ferry café</div>") && !rows.contains("Ferry</div>"));
+        saved.assert_status_is_ok();
+        saved.assert_header("HX-Trigger", "transactionsUpdated");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_receipts_are_attached_to_transactions(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        cli.post(format!("/accounts/{}/transactions", account_id))
+            .form(&[("date", "2024-01-03"), ("payee", "Hotel"), ("memo", ""), ("inflow", ""), ("outflow", "120.00")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let transaction_id = account_transactions(&pool, account_id).await[1].id;
+        let receipt = b"%PDF-1.7 hotel receipt".to_vec();
+
+        // Act
+        let upload = cli
+            .post(format!("/transactions/{}/attachments", transaction_id))
+            .multipart(TestForm::new().field(TestFormField::bytes(receipt.clone()).name("file").filename("../receipt.pdf")))
+            .send()
+            .await;
+        let rejected = cli
+            .post(format!("/transactions/{}/attachments", transaction_id))
+            .multipart(TestForm::new().field(TestFormField::text("<script>alert(1)</script>").name("file").filename("receipt.pdf")))
+            .send()
+            .await;
+
+        // Assert
+        assert!(upload.0.into_body().into_string().await.unwrap().contains("receipt.pdf"));
+        assert!(rejected.0.into_body().into_string().await.unwrap().contains("Only JPEG, PNG, GIF and WebP images and PDFs can be attached."));
+        let attachments = db::get_attachments_for_transaction(&pool, transaction_id.into()).await;
+        assert_eq!(attachments.len(), 1);
+        assert_eq!((attachments[0].filename.as_str(), attachments[0].content_type.as_str()), ("receipt.pdf", "application/pdf"));
+        let register = cli.get(format!("/accounts/{}", account_id)).send().await.0.into_body().into_string().await.unwrap();
+        assert!(register.contains(&format!("/attachments/{}", attachments[0].id)));
+        let file = cli.get(format!("/attachments/{}", attachments[0].id)).send().await;
+        file.assert_status_is_ok();
+        file.assert_content_type("application/pdf");
+        assert_eq!(file.0.into_body().into_vec().await.unwrap(), receipt);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_bulk_edit(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let storage = Arc::new(MemoryStorage::default());
+        let cli = logged_in_client_with_storage(&pool, storage.clone()).await;
+        let budget_id = test_budget(&pool).await;
+        let current = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let savings = db::create_account(&pool, budget_id, "Savings", "GBP", 0).await.unwrap();
+        let holiday = db::create_account(&pool, budget_id, "Holiday", "EUR", 0).await.unwrap();
+        let group = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Everyday").await.unwrap();
+        let groceries = db::insert_category(&mut pool.acquire().await.unwrap(), group, "Groceries").await.unwrap().to_string();
+        for payee in ["CARD 1234 TESCO", "TESCO STORES", "Rent"] {
+            cli.post(format!("/accounts/{}/transactions", current))
+                .form(&[("date", "2024-04-01"), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", "10.00")])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let ids: Vec<String> = account_transactions(&pool, current).await[1..].iter().map(|t| t.id.to_string()).collect();
+        let (tesco, stores, rent) = (ids[0].as_str(), ids[1].as_str(), ids[2].as_str());
+        cli.post(format!("/transactions/{}/attachments", rent))
+            .multipart(TestForm::new().field(TestFormField::bytes(b"%PDF-1.7 lease".to_vec()).name("file").filename("lease.pdf")))
+            .send()
+            .await
+            .assert_status_is_ok();
+        let lease = db::get_attachments_for_transaction(&pool, rent.parse().unwrap()).await.remove(0);
+        let bulk = |form: Vec<(&str, &str)>| cli.post("/transactions/bulk").form(&form).send();
+
+        // Act
+        let categorised = bulk(vec![("ids", tesco), ("ids", stores), ("action", "categorise"), ("category_id", &groceries)]).await;
+        bulk(vec![("ids", tesco), ("ids", stores), ("action", "payee"), ("payee", "Tesco")]).await.assert_status_is_ok();
+        bulk(vec![("ids", tesco), ("ids", stores), ("action", "cleared")]).await.assert_status_is_ok();
+        bulk(vec![("ids", tesco), ("action", "flag"), ("flag", "purple")]).await.assert_status_is_ok();
+        bulk(vec![("ids", stores), ("action", "move"), ("account_id", &savings.to_string())]).await.assert_status_is_ok();
+        let other_currency = bulk(vec![("ids", tesco), ("action", "move"), ("account_id", &holiday.to_string())]).await;
+        let not_theirs = bulk(vec![("ids", tesco), ("ids", "999"), ("action", "uncleared")]).await;
+        let repeated = bulk(vec![("ids", rent), ("ids", rent), ("action", "flag"), ("flag", "red")]).await;
+        bulk(vec![("ids", rent), ("action", "delete")]).await.assert_status_is_ok();
+        let history = cli.get("/history").send().await.0.into_body().into_string().await.unwrap();
+
+        // Assert
+        categorised.assert_header("HX-Trigger", "transactionsUpdated");
+        assert_eq!(categorised.0.into_body().into_string().await.unwrap(), "2 transactions updated.");
+        let tesco = db::get_transaction(&pool, tesco.parse().unwrap()).await.unwrap();
+        let stores = db::get_transaction(&pool, stores.parse().unwrap()).await.unwrap();
+        assert_eq!((tesco.category.as_deref(), tesco.payee.as_deref(), tesco.cleared, tesco.flag.as_deref()), (Some("Groceries"), Some("Tesco"), true, Some("purple")));
+        assert_eq!((stores.payee.as_deref(), stores.account_id), (Some("Tesco"), savings as i32));
+        assert_eq!(tesco.account_id, current as i32);
+        assert!(other_currency.0.into_body().into_string().await.unwrap().contains("same currency"));
+        assert!(not_theirs.0.into_body().into_string().await.unwrap().contains("could not be found"), "Nothing changes if any transaction is missing");
+        assert_eq!(repeated.0.into_body().into_string().await.unwrap(), "1 transactions updated.", "A transaction selected twice is changed once");
+        assert!(history.contains("Flagged 1 transaction red"));
+        assert!(db::get_transaction(&pool, rent.parse().unwrap()).await.is_none());
+        assert!(storage.get(&lease.storage_key).is_err(), "Attachments of deleted transactions are removed");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_undo_and_redo(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let current = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        for payee in ["Tesco", "Rent"] {
+            cli.post(format!("/accounts/{}/transactions", current))
+                .form(&[("date", "2024-04-01"), ("payee", payee), ("memo", ""), ("inflow", ""), ("outflow", "10.00")])
+                .send()
+                .await
+                .assert_status_is_ok();
+        }
+        let ids: Vec<i64> = account_transactions(&pool, current).await[1..].iter().map(|t| t.id.into()).collect();
+        let (tesco, rent) = (ids[0], ids[1]);
+        cli.post(format!("/transactions/{}", tesco))
+            .form(&[("flag", "red"), ("tags", "food, weekly"), ("notes", "")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let message = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+
+        // Act
+        cli.post("/transactions/bulk").form(&[("ids", tesco.to_string()), ("action", "delete".to_string())]).send().await.assert_status_is_ok();
+        let undone = cli.post("/undo").send().await;
+        let restored = db::get_transaction(&pool, tesco).await;
+        let redone = message(cli.post("/redo").send().await).await;
+        let deleted_again = db::get_transaction(&pool, tesco).await;
+        cli.post("/undo").send().await.assert_status_is_ok();
+        cli.post("/transactions/bulk").form(&[("ids", rent.to_string()), ("action", "cleared".to_string())]).send().await.assert_status_is_ok();
+        let nothing_to_redo = message(cli.post("/redo").send().await).await;
+        sqlx::query("UPDATE transactions SET cleared = 0 WHERE id = ?").bind(rent).execute(&pool).await?;
+        let conflict = message(cli.post("/undo").send().await).await;
+        let history = message(cli.get("/history").send().await).await;
+
+        // Assert
+        undone.assert_header("HX-Trigger", "transactionsUpdated, historyUpdated");
+        assert_eq!(message(undone).await, "Undone: Deleted 1 transaction.");
+        let restored = restored.expect("Undoing a delete brings the transaction back");
+        assert_eq!((restored.payee.as_deref(), restored.flag.as_deref(), restored.tags()), (Some("Tesco"), Some("red"), vec!["food", "weekly"]));
+        assert_eq!(redone, "Redone: Deleted 1 transaction.");
+        assert!(deleted_again.is_none());
+        assert!(db::get_transaction(&pool, tesco).await.is_some());
+        assert_eq!(nothing_to_redo, "Nothing to redo.", "Doing something else means what was undone can't be redone");
+        assert!(conflict.contains("changed since"), "Nothing is undone over changes it doesn't know about");
+        assert!(db::get_transaction(&pool, rent).await.is_some_and(|t| !t.cleared));
+        assert!(history.contains("Cleared 1 transaction") && history.contains("Added a transaction to Current") && history.contains("Undone, can't be redone"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_undo_moving_money(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let group = db::insert_category_group(&mut *pool.acquire().await?, budget_id, "Bills").await?;
+        let groceries = db::insert_category(&mut *pool.acquire().await?, group, "Groceries").await?;
+        let rent = db::insert_category(&mut *pool.acquire().await?, group, "Rent").await?;
+        cli.post(format!("/categories/{}/assign", groceries))
+            .form(&[("month", "2024-04"), ("assigned", "100.00")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let april = chrono::NaiveDate::from_ymd_opt(2024, 4, 1).unwrap();
+        let assigned = |pool: Pool<Sqlite>| async move {
+            db::get_category_balances(&pool, budget_id, april).await.iter().map(|b| (b.name.clone(), b.assigned)).collect::<Vec<_>>()
+        };
+
+        // Act
+        let moved = cli.post("/categories/move")
+            .form(&[("month", "2024-04"), ("from_id", &groceries.to_string()), ("to_id", &rent.to_string()), ("amount", "30.00")])
+            .send()
+            .await;
+        let after_move = assigned(pool.clone()).await;
+        let undone = cli.post("/undo").send().await;
+        let after_undo = assigned(pool.clone()).await;
+        let page = cli.get("/categories?month=2024-04").send().await.0.into_body().into_string().await.unwrap();
+
+        // Assert
+        assert!(moved.0.into_body().into_string().await.unwrap().contains("Money moved."));
+        assert_eq!(after_move, vec![("Groceries".to_string(), 7000), ("Rent".to_string(), 3000)]);
+        assert_eq!(undone.0.into_body().into_string().await.unwrap(), "Undone: Moved money between categories.");
+        assert_eq!(after_undo, vec![("Groceries".to_string(), 10000), ("Rent".to_string(), 0)], "Undoing a move puts the money back where it was");
+        assert!(page.contains("April 2024") && page.contains("Bills: Groceries"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_audit_log(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        cli.post("/account/create")
+            .form(&[("name", "Current"), ("currency", "GBP"), ("starting_balance", "10.00")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let page = |query: &'static str| {
+            let request = cli.get(format!("/audit{}", query)).header("HX-Request", "true");
+            async move { request.send().await.0.into_body().into_string().await.unwrap() }
+        };
+
+        // Act
+        let everything = page("").await;
+        let accounts = page("?entity=accounts").await;
+        let deleted = page("?action=delete").await;
+        let bad_date = page("?from=yesterday").await;
+
+        // Assert
+        assert!(everything.contains("create accounts") && everything.contains("create transactions"));
+        assert!(everything.contains("&quot;Current&quot;"), "The new row is shown");
+        assert!(everything.contains(">test<"), "Changes are put down to whoever made them");
+        assert!(accounts.contains("create accounts") && !accounts.contains("create transactions"));
+        assert!(deleted.contains("No changes found."));
+        assert!(bad_date.contains("yesterday is not a date."));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_budgets_are_shared_by_invitation(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let owner = logged_in_client(&pool).await;
+        let friend = logged_in_as(&pool, "friend@example.com", Arc::new(MemoryStorage::default())).await;
+        owner.post("/account/create")
+            .form(&[("name", "Shared current"), ("currency", "GBP"), ("starting_balance", "10.00")])
+            .send()
+            .await
+            .assert_status_is_ok();
+        let invite = |email: &'static str| owner.post("/budget/members").form(&[("email", email), ("role", "viewer")]).send();
+        let token = |email: &'static str| {
+            let pool = pool.clone();
+            async move {
+                let (token,): (String,) = sqlx::query_as("SELECT token FROM budget_invitations WHERE email = ?").bind(email).fetch_one(&pool).await.unwrap();
+                token
+            }
+        };
+        let body = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+
+        // Act
+        let invited = body(invite("friend@example.com").await).await;
+        invite("someone@example.com").await.assert_status_is_ok();
+        let someone_elses = body(friend.post(format!("/invitations/{}/accept", token("someone@example.com").await)).send().await).await;
+        let invitation = body(friend.get(format!("/invitations/{}", token("friend@example.com").await)).send().await).await;
+        let accepted = friend.post(format!("/invitations/{}/accept", token("friend@example.com").await)).send().await;
+        let accounts = body(friend.get("/api/accounts").send().await).await;
+        let as_viewer = friend.post("/account/create").form(&[("name", "Mine"), ("currency", "GBP"), ("starting_balance", "0")]).send().await;
+        let friend_id = get_user(&pool, "friend@example.com".to_string()).await.unwrap().id.unwrap();
+        owner.post(format!("/budget/members/{}", friend_id)).form(&[("role", "editor")]).send().await.assert_status_is_ok();
+        let as_editor = friend.post("/account/create").form(&[("name", "Mine"), ("currency", "GBP"), ("starting_balance", "0")]).send().await;
+        let members = body(friend.get("/budget/members").header("HX-Request", "true").send().await).await;
+
+        // Assert
+        assert!(invited.contains("/invitations/"), "The owner gets a link to send");
+        assert!(someone_elses.contains("sent to someone else"));
+        assert!(invitation.contains("Join test's budget"));
+        accepted.assert_status_is_ok();
+        accepted.assert_header("HX-Redirect", "/");
+        assert!(accounts.contains("Shared current"), "Members see the budget's accounts");
+        as_viewer.assert_status(StatusCode::FORBIDDEN);
+        as_editor.assert_status_is_ok();
+        assert!(members.contains("friend@example.com") && members.contains("test@example.com"));
+        assert!(!members.contains("Invite"), "Only owners can invite");
+        let shared = budgets::get_for_user(&pool, friend_id).await;
+        assert_eq!(shared.iter().map(|b| (b.name.as_str(), b.role)).collect::<Vec<_>>(), vec![("test's budget", Role::Editor), ("friend's budget", Role::Owner)]);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_switching_between_budgets(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let first = test_budget(&pool).await;
+        let user_id = get_user(&pool, "test@example.com".to_string()).await.unwrap().id.unwrap();
+        let account = |name: &'static str| [("name", name), ("currency", ""), ("starting_balance", "1.00")];
+        let body = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+        // The session is kept in its cookie, so switching budget carries on with the cookie it sets.
+        let with_session = |response: poem::test::TestResponse| {
+            let cookie = session_cookie(&response);
+            TestClient::new(app(pool.clone(), Arc::new(MemoryStorage::default()))).default_header(header::COOKIE, cookie)
+        };
+        cli.post("/account/create").form(&account("Current account")).send().await.assert_status_is_ok();
+
+        // Act
+        let created = cli.post("/budgets").form(&[("name", "Holiday"), ("currency", "usd")]).send().await;
+        created.assert_header("HX-Redirect", "/");
+        let holiday = with_session(created);
+        holiday.post("/account/create").form(&account("Travel money")).send().await.assert_status_is_ok();
+        let holiday_accounts = body(holiday.get("/api/accounts").send().await).await;
+        let cli = with_session(holiday.post("/budgets/switch").form(&[("budget_id", first.to_string())]).send().await);
+        let first_accounts = body(cli.get("/api/accounts").send().await).await;
+        let holiday_id = budgets::get_for_user(&pool, user_id).await.into_iter().find(|b| b.name == "Holiday").unwrap().id;
+        cli.post(format!("/budgets/{}", holiday_id)).form(&[("name", "Summer holiday")]).send().await.assert_status_is_ok();
+        let archived = body(cli.post(format!("/budgets/{}/archive", holiday_id)).form(&[("archived", "true")]).send().await).await;
+        let picker = body(cli.get("/").send().await).await;
+        let archived_current = cli.post(format!("/budgets/{}/archive", first)).form(&[("archived", "true")]).send().await;
+        let all_archived = body(cli.get("/api/accounts").send().await).await;
+        let not_a_member = cli.post("/budgets/switch").form(&[("budget_id", "999")]).send().await;
+
+        // Assert
+        assert!(holiday_accounts.contains("Travel money") && !holiday_accounts.contains("Current account"), "Each budget has its own accounts");
+        assert!(holiday_accounts.contains("$"), "Each budget has its own currency");
+        assert!(first_accounts.contains("Current account") && !first_accounts.contains("Travel money"));
+        assert!(archived.contains("Summer holiday") && archived.contains("archived"));
+        assert!(!picker.contains("Summer holiday"), "Archived budgets are left out of the picker");
+        archived_current.assert_header("HX-Redirect", "/");
+        assert!(all_archived.contains("Current account"), "With every budget archived, the first is still used");
+        not_a_member.assert_status(StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_creating_a_budget_without_one(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let user_id = get_user(&pool, "test@example.com".to_string()).await.unwrap().id.unwrap();
+        sqlx::query("DELETE FROM budget_members WHERE budget_id = ? AND user_id = ?").bind(budget_id).bind(user_id).execute(&pool).await?;
+        let body = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+
+        // Act
+        let start = cli.get("/").send().await;
+        start.assert_status_is_ok();
+        let start = body(start).await;
+        let list = cli.get("/budgets").send().await;
+        list.assert_status_is_ok();
+        let list = body(list).await;
+        let created = cli.post("/budgets").form(&[("name", "Fresh start"), ("currency", "eur")]).send().await;
+
+        // Assert
+        assert!(start.contains("Create one to get started") && start.contains("Add budget"));
+        assert!(list.contains("Add budget") && !list.contains("Current"));
+        created.assert_header("HX-Redirect", "/");
+        let budgets = budgets::get_for_user(&pool, user_id).await;
+        assert_eq!(budgets.len(), 1);
+        assert_eq!((budgets[0].name.as_str(), budgets[0].currency.as_str()), ("Fresh start", "EUR"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_spending_report(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let budget_id = test_budget(&pool).await;
+        let account_id = db::create_empty_account(&pool, budget_id, "Current", "GBP").await.unwrap();
+        let group = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Everyday").await.unwrap();
+        let groceries = db::insert_category(&mut pool.acquire().await.unwrap(), group, "Groceries").await.unwrap();
+        db::create_transaction(&pool, &db::NewTransaction {
+            account_id,
+            date: chrono::NaiveDate::from_ymd_opt(2024, 3, 2).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            value_date: None,
+            payee: Some("Tesco".to_string()),
+            memo: "Weekly shop".to_string(),
+            inflow: 0,
+            outflow: 4250,
+            cleared: false,
+            import_id: None,
+            imported: false,
+            category_id: Some(groceries),
+        }).await.unwrap();
+        let body = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+
+        // Act
+        let report = body(cli.get("/reports?from=2024-02-01&to=2024-03-31&by=category").header("HX-Request", "true").send().await).await;
+        let lines = body(cli.get(format!("/reports/lines?from=2024-02-01&to=2024-03-31&by=category&key={}", groceries)).send().await).await;
+        let empty = body(cli.get("/reports?from=2023-01-01&to=2023-01-31").header("HX-Request", "true").send().await).await;
+        let backwards = body(cli.get("/reports?from=2024-03-01&to=2024-02-01").header("HX-Request", "true").send().await).await;
+
+        // Assert
+        assert!(report.contains("Everyday: Groceries") && report.contains("£42.50"));
+        assert!(report.contains("<svg") && report.contains("Feb 2024") && report.contains("Mar 2024"), "Charts are drawn on the server");
+        assert!(report.contains(&format!("key={}", groceries)), "Rows link to their transactions");
+        assert!(lines.contains("Weekly shop") && lines.contains("-£42.50"));
+        assert!(empty.contains("No spending in this period."));
+        assert!(backwards.contains("The report has to end after it starts."));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_net_worth_report(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let body = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+        cli.post("/account/create").form(&[("name", "Current"), ("type", "checking"), ("currency", "GBP"), ("starting_balance", "100.00")]).send().await.assert_status_is_ok();
+
+        // Act
+        let tracking = cli.post("/account/create").form(&[("name", "House"), ("type", "tracking"), ("currency", "GBP"), ("starting_balance", "250000.00")]).send().await;
+        let accounts = body(cli.get("/api/accounts").send().await).await;
+        let report = body(cli.get("/reports/net-worth").header("HX-Request", "true").send().await).await;
+        let export = cli.get("/reports/net-worth.csv").send().await;
+
+        // Assert
+        tracking.assert_status_is_ok();
+        assert!(accounts.contains("£100.00</p></div>"), "Tracking accounts are left out of the budget total");
+        assert!(accounts.contains("Tracking") && accounts.contains("House"));
+        assert!(report.contains("£250,100.00") && report.contains("(tracking)"));
+        assert!(report.contains("<svg"));
+        export.assert_status_is_ok();
+        export.assert_content_type("text/csv; charset=utf-8");
+        let csv = export.0.into_body().into_string().await.unwrap();
+        assert!(csv.starts_with("Month,Assets (GBP),Liabilities (GBP),Net worth (GBP),Current (GBP),House (GBP)\n"));
+        assert!(csv.trim_end().ends_with(",250100.00,0.00,250100.00,100.00,250000.00"));
+        assert_eq!(csv.lines().count(), 13, "A year of months after the header");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_income_expense_and_cash_flow_reports(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let cli = logged_in_client(&pool).await;
+        let body = |response: poem::test::TestResponse| async move { response.0.into_body().into_string().await.unwrap() };
+        cli.post("/account/create").form(&[("name", "Current"), ("type", "checking"), ("currency", "GBP"), ("starting_balance", "100.00")]).send().await.assert_status_is_ok();
+        let query = "from=2024-01-01&to=2024-03-31";
+
+        // Act
+        let income_expense = body(cli.get(format!("/reports/income-and-spending?{}", query)).header("HX-Request", "true").send().await).await;
+        let csv = cli.get(format!("/reports/income-and-spending/csv?{}", query)).send().await;
+        let cash_flow = body(cli.get("/reports/cash-flow").header("HX-Request", "true").send().await).await;
+        let json = cli.get("/reports/cash-flow/json").send().await;
+        let unknown = cli.get("/reports/cash-flow/xml").send().await;
+
+        // Assert
+        assert!(income_expense.contains("Income vs spending") && income_expense.contains("<svg"));
+        assert!(income_expense.contains("/reports/income-and-spending/json?from=2024-01-01&amp;to=2024-03-31"));
+        csv.assert_status_is_ok();
+        csv.assert_content_type("text/csv; charset=utf-8");
+        csv.assert_header(header::CONTENT_DISPOSITION, "attachment; filename=\"income-and-spending-2024-01-2024-03.csv\"");
+        let csv = csv.0.into_body().into_string().await.unwrap();
+        assert_eq!(csv.lines().count(), 5, "A header, three months and the total");
+        assert!(cash_flow.contains("Current") && cash_flow.contains("£100.00"));
+        json.assert_status_is_ok();
+        json.assert_content_type("application/json");
+        let json: serde_json::Value = serde_json::from_str(&json.0.into_body().into_string().await.unwrap()).unwrap();
+        assert_eq!(json["accounts"][0]["name"], "Current");
+        assert_eq!(json["accounts"][0]["closing"], 10000);
+        unknown.assert_status(StatusCode::NOT_FOUND);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,721 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use chrono::{NaiveDate, NaiveDateTime};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{attachments::{self, Storage}, audit, currency::ExchangeRate, db, helpers::{Locale, LOCALES}, import::csv::CsvMapping, rules::{Action, Condition, Rule}, webhooks};
+
+/// The archive format version. Bump it when a change means older versions of ymnab can no longer
+/// restore the archive; fields added with `#[serde(default)]` do not need a bump.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedAccount {
+    pub id: i64,
+    pub name: String,
+    pub reconcile_balance: Option<i64>,
+    pub reconcile_date: Option<NaiveDate>,
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    #[serde(default = "default_on_budget")]
+    pub on_budget: bool,
+}
+
+/// Archives from before accounts had a currency were all in pounds.
+fn default_currency() -> String {
+    "GBP".to_string()
+}
+
+/// Archives from before tracking accounts only had accounts in the budget.
+fn default_on_budget() -> bool {
+    true
+}
+
+fn default_locale() -> String {
+    LOCALES[0].code.to_string()
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedCategoryGroup {
+    pub id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedCategory {
+    pub id: i64,
+    pub group_id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedAssigned {
+    pub category_id: i64,
+    pub month: NaiveDate,
+    pub assigned: i64,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedTransaction {
+    pub id: i64,
+    pub account_id: i64,
+    pub date: NaiveDateTime,
+    pub value_date: Option<NaiveDate>,
+    pub payee: Option<String>,
+    pub memo: String,
+    pub inflow: i64,
+    pub outflow: i64,
+    pub cleared: bool,
+    pub import_id: Option<String>,
+    pub imported: bool,
+    pub category_id: Option<i64>,
+    pub transfer_id: Option<i64>,
+    #[serde(default)]
+    pub flag: Option<String>,
+    #[serde(default)]
+    pub notes: Option<String>,
+    #[serde(default)]
+    #[sqlx(skip)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedSplit {
+    pub transaction_id: i64,
+    pub category_id: Option<i64>,
+    pub payee: Option<String>,
+    pub memo: String,
+    pub inflow: i64,
+    pub outflow: i64,
+}
+
+/// Transactions refer to payees by name.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedPayee {
+    pub name: String,
+    pub default_category_id: Option<i64>,
+    pub aliases: Vec<String>,
+}
+
+/// A file attached to a transaction, with its contents in base64.
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedAttachment {
+    pub transaction_id: i64,
+    pub filename: String,
+    pub content_type: String,
+    #[serde(skip)]
+    pub storage_key: String,
+    #[sqlx(skip)]
+    pub data: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArchivedCsvMapping {
+    pub account_id: i64,
+    pub mapping: CsvMapping,
+}
+
+/// The signing secret is left out, as anyone who can see the budget can export it, and a restored
+/// webhook is given a new one.
+#[derive(Debug, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ArchivedWebhook {
+    pub url: String,
+    pub events: String,
+    pub active: bool,
+}
+
+/// Everything a user has in ymnab. Ids are the ones from the exporting database and are only used
+/// to link records within the archive; they are replaced with new ids on restore.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Archive {
+    pub version: u32,
+    pub exported_at: NaiveDateTime,
+    /// The currency budget totals are shown in.
+    #[serde(default = "default_currency")]
+    pub currency: String,
+    /// How amounts are written, such as `en-GB`.
+    #[serde(default = "default_locale")]
+    pub locale: String,
+    pub accounts: Vec<ArchivedAccount>,
+    pub category_groups: Vec<ArchivedCategoryGroup>,
+    pub categories: Vec<ArchivedCategory>,
+    pub assigned: Vec<ArchivedAssigned>,
+    #[serde(default)]
+    pub payees: Vec<ArchivedPayee>,
+    pub transactions: Vec<ArchivedTransaction>,
+    pub splits: Vec<ArchivedSplit>,
+    pub csv_mappings: Vec<ArchivedCsvMapping>,
+    pub webhooks: Vec<ArchivedWebhook>,
+    #[serde(default)]
+    pub exchange_rates: Vec<ExchangeRate>,
+    /// Account and category ids in conditions and actions are the archive's ids.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// Only filled in by `load_attachments`, as the files can be large.
+    #[serde(default)]
+    pub attachments: Vec<ArchivedAttachment>,
+}
+
+/// Reads everything in the budget into an archive, along with the locale of the user exporting it.
+pub async fn export(conn: &Pool<Sqlite>, budget_id: i32, user_id: i32) -> Result<Archive, sqlx::Error> {
+    let (currency, locale): (String, String) = sqlx::query_as("SELECT b.currency, u.locale FROM budgets b, users u WHERE b.id = ? AND u.id = ?")
+        .bind(budget_id)
+        .bind(user_id)
+        .fetch_one(conn)
+        .await?;
+    let accounts = sqlx::query_as::<_, ArchivedAccount>("SELECT id, name, reconcile_balance, reconcile_date, currency, on_budget FROM accounts WHERE budget_id = ? ORDER BY id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let category_groups = sqlx::query_as::<_, ArchivedCategoryGroup>("SELECT id, name FROM category_groups WHERE budget_id = ? ORDER BY id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let categories = sqlx::query_as::<_, ArchivedCategory>("SELECT c.id, c.group_id, c.name FROM categories c JOIN category_groups g ON g.id = c.group_id WHERE g.budget_id = ? ORDER BY c.id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let assigned = sqlx::query_as::<_, ArchivedAssigned>("SELECT b.category_id, b.month, b.assigned FROM category_budgets b JOIN categories c ON c.id = b.category_id JOIN category_groups g ON g.id = c.group_id WHERE g.budget_id = ? ORDER BY b.category_id, b.month")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let payees: Vec<(i64, String, Option<i64>)> = sqlx::query_as("SELECT id, name, default_category_id FROM payees WHERE budget_id = ? ORDER BY id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let aliases: Vec<(i64, String)> = sqlx::query_as("SELECT al.payee_id, al.name FROM payee_aliases al JOIN payees p ON p.id = al.payee_id WHERE p.budget_id = ? ORDER BY al.name")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let mut transactions = sqlx::query_as::<_, ArchivedTransaction>("SELECT t.id, t.account_id, t.date, t.value_date, COALESCE(p.name, t.payee) AS payee, COALESCE(t.memo, '') AS memo, COALESCE(t.inflow, 0) AS inflow, COALESCE(t.outflow, 0) AS outflow, t.cleared, t.import_id, t.imported, t.category_id, t.transfer_id, t.flag, t.notes FROM transactions t JOIN accounts a ON a.id = t.account_id LEFT JOIN payees p ON p.id = t.payee_id WHERE a.budget_id = ? ORDER BY t.id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let tags: Vec<(i64, String)> = sqlx::query_as("SELECT tt.transaction_id, g.name FROM transaction_tags tt JOIN tags g ON g.id = tt.tag_id WHERE g.budget_id = ? ORDER BY g.name")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    for t in transactions.iter_mut() {
+        t.tags = tags.iter().filter(|(id, _)| *id == t.id).map(|(_, name)| name.clone()).collect();
+    }
+    let attachments = sqlx::query_as::<_, ArchivedAttachment>("SELECT f.transaction_id, f.filename, f.content_type, f.storage_key FROM attachments f JOIN transactions t ON t.id = f.transaction_id JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ? ORDER BY f.id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let splits = sqlx::query_as::<_, ArchivedSplit>("SELECT s.transaction_id, s.category_id, s.payee, s.memo, s.inflow, s.outflow FROM transaction_splits s JOIN transactions t ON t.id = s.transaction_id JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ? ORDER BY s.id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let csv_mappings: Vec<(i64, String)> = sqlx::query_as("SELECT m.account_id, m.mapping FROM csv_import_mappings m JOIN accounts a ON a.id = m.account_id WHERE a.budget_id = ? ORDER BY m.account_id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let webhooks = sqlx::query_as::<_, ArchivedWebhook>("SELECT url, events, active FROM webhooks WHERE budget_id = ? ORDER BY id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+    let exchange_rates = sqlx::query_as::<_, ExchangeRate>("SELECT date, base, quote, rate FROM exchange_rates WHERE budget_id = ? ORDER BY id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(Archive {
+        version: VERSION,
+        exported_at: chrono::Utc::now().naive_utc(),
+        currency,
+        locale,
+        accounts,
+        category_groups,
+        categories,
+        assigned,
+        payees: payees
+            .into_iter()
+            .map(|(id, name, default_category_id)| ArchivedPayee {
+                name,
+                default_category_id,
+                aliases: aliases.iter().filter(|(payee_id, _)| *payee_id == id).map(|(_, alias)| alias.clone()).collect(),
+            })
+            .collect(),
+        transactions,
+        splits,
+        csv_mappings: csv_mappings
+            .into_iter()
+            .filter_map(|(account_id, mapping)| Some(ArchivedCsvMapping { account_id, mapping: serde_json::from_str(&mapping).ok()? }))
+            .collect(),
+        webhooks,
+        exchange_rates,
+        rules: db::get_rules_for_budget(conn, budget_id).await,
+        attachments,
+    })
+}
+
+/// Reads every attachment's file into the archive.
+pub fn load_attachments(archive: &mut Archive, storage: &dyn Storage) -> std::io::Result<()> {
+    for attachment in archive.attachments.iter_mut() {
+        attachment.data = STANDARD.encode(storage.get(&attachment.storage_key)?);
+    }
+    Ok(())
+}
+
+/// Looks up the new id for an id from the archive.
+fn remap(ids: &HashMap<i64, i64>, id: i64, what: &str) -> Result<i64, sqlx::Error> {
+    ids.get(&id).copied().ok_or_else(|| sqlx::Error::Protocol(format!("archive refers to unknown {} {}", what, id)))
+}
+
+/// `keys` are where each of the archive's attachments was stored, in order.
+async fn insert(tx: &mut sqlx::Transaction<'_, Sqlite>, budget_id: i32, user_id: i32, archive: &Archive, keys: &[(String, usize)]) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE budgets SET currency = ? WHERE id = ?")
+        .bind(&archive.currency)
+        .bind(budget_id)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query("UPDATE users SET locale = ? WHERE id = ?")
+        .bind(Locale::find(&archive.locale).code)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await?;
+    let mut groups = HashMap::new();
+    for group in &archive.category_groups {
+        let result = sqlx::query("INSERT INTO category_groups (budget_id, name) VALUES (?, ?)")
+            .bind(budget_id)
+            .bind(&group.name)
+            .execute(&mut **tx)
+            .await?;
+        groups.insert(group.id, result.last_insert_rowid());
+    }
+
+    let mut categories = HashMap::new();
+    for category in &archive.categories {
+        let result = sqlx::query("INSERT INTO categories (group_id, name) VALUES (?, ?)")
+            .bind(remap(&groups, category.group_id, "category group")?)
+            .bind(&category.name)
+            .execute(&mut **tx)
+            .await?;
+        categories.insert(category.id, result.last_insert_rowid());
+    }
+
+    for assigned in &archive.assigned {
+        sqlx::query("INSERT INTO category_budgets (category_id, month, assigned) VALUES (?, ?, ?)")
+            .bind(remap(&categories, assigned.category_id, "category")?)
+            .bind(assigned.month)
+            .bind(assigned.assigned)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    for payee in &archive.payees {
+        let default_category_id = payee.default_category_id.map(|id| remap(&categories, id, "category")).transpose()?;
+        let result = sqlx::query("INSERT INTO payees (budget_id, name, default_category_id) VALUES (?, ?, ?)")
+            .bind(budget_id)
+            .bind(&payee.name)
+            .bind(default_category_id)
+            .execute(&mut **tx)
+            .await?;
+        for alias in &payee.aliases {
+            sqlx::query("INSERT INTO payee_aliases (payee_id, name) VALUES (?, ?)")
+                .bind(result.last_insert_rowid())
+                .bind(alias)
+                .execute(&mut **tx)
+                .await?;
+        }
+    }
+
+    let mut accounts = HashMap::new();
+    for account in &archive.accounts {
+        let result = sqlx::query("INSERT INTO accounts (budget_id, name, reconcile_balance, reconcile_date, currency, on_budget) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(budget_id)
+            .bind(&account.name)
+            .bind(account.reconcile_balance)
+            .bind(account.reconcile_date)
+            .bind(&account.currency)
+            .bind(account.on_budget)
+            .execute(&mut **tx)
+            .await?;
+        accounts.insert(account.id, result.last_insert_rowid());
+    }
+
+    for mapping in &archive.csv_mappings {
+        sqlx::query("INSERT INTO csv_import_mappings (account_id, mapping) VALUES (?, ?)")
+            .bind(remap(&accounts, mapping.account_id, "account")?)
+            .bind(serde_json::to_string(&mapping.mapping).unwrap())
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    let mut transactions = HashMap::new();
+    for t in &archive.transactions {
+        let category_id = t.category_id.map(|id| remap(&categories, id, "category")).transpose()?;
+        // Archives from before payees were kept only have the name.
+        if let Some(payee) = &t.payee {
+            sqlx::query("INSERT OR IGNORE INTO payees (budget_id, name) VALUES (?, ?)")
+                .bind(budget_id)
+                .bind(payee)
+                .execute(&mut **tx)
+                .await?;
+        }
+        let result = sqlx::query("INSERT INTO transactions (account_id, date, value_date, payee, payee_id, memo, inflow, outflow, cleared, import_id, imported, category_id, flag, notes) VALUES (?, ?, ?, ?, (SELECT id FROM payees WHERE budget_id = ? AND name = ?), ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(remap(&accounts, t.account_id, "account")?)
+            .bind(t.date)
+            .bind(t.value_date)
+            .bind(&t.payee)
+            .bind(budget_id)
+            .bind(&t.payee)
+            .bind(&t.memo)
+            .bind(t.inflow)
+            .bind(t.outflow)
+            .bind(t.cleared)
+            .bind(&t.import_id)
+            .bind(t.imported)
+            .bind(category_id)
+            .bind(&t.flag)
+            .bind(&t.notes)
+            .execute(&mut **tx)
+            .await?;
+        for tag in &t.tags {
+            sqlx::query("INSERT OR IGNORE INTO tags (budget_id, name) VALUES (?, ?)")
+                .bind(budget_id)
+                .bind(tag)
+                .execute(&mut **tx)
+                .await?;
+            sqlx::query("INSERT INTO transaction_tags (transaction_id, tag_id) SELECT ?, id FROM tags WHERE budget_id = ? AND name = ?")
+                .bind(result.last_insert_rowid())
+                .bind(budget_id)
+                .bind(tag)
+                .execute(&mut **tx)
+                .await?;
+        }
+        transactions.insert(t.id, result.last_insert_rowid());
+    }
+
+    // Transfers point at each other, so they can only be linked once both sides exist.
+    for t in archive.transactions.iter().filter(|t| t.transfer_id.is_some()) {
+        sqlx::query("UPDATE transactions SET transfer_id = ? WHERE id = ?")
+            .bind(remap(&transactions, t.transfer_id.unwrap(), "transaction")?)
+            .bind(remap(&transactions, t.id, "transaction")?)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    for split in &archive.splits {
+        let category_id = split.category_id.map(|id| remap(&categories, id, "category")).transpose()?;
+        sqlx::query("INSERT INTO transaction_splits (transaction_id, category_id, payee, memo, inflow, outflow) VALUES (?, ?, ?, ?, ?, ?)")
+            .bind(remap(&transactions, split.transaction_id, "transaction")?)
+            .bind(category_id)
+            .bind(&split.payee)
+            .bind(&split.memo)
+            .bind(split.inflow)
+            .bind(split.outflow)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    for webhook in &archive.webhooks {
+        sqlx::query("INSERT INTO webhooks (budget_id, url, secret, events, active) VALUES (?, ?, ?, ?, ?)")
+            .bind(budget_id)
+            .bind(&webhook.url)
+            .bind(webhooks::generate_secret())
+            .bind(&webhook.events)
+            .bind(webhook.active)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    for rate in &archive.exchange_rates {
+        sqlx::query("INSERT INTO exchange_rates (budget_id, date, base, quote, rate) VALUES (?, ?, ?, ?, ?)")
+            .bind(budget_id)
+            .bind(rate.date)
+            .bind(&rate.base)
+            .bind(&rate.quote)
+            .bind(rate.rate)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    for (attachment, (key, size)) in archive.attachments.iter().zip(keys) {
+        sqlx::query("INSERT INTO attachments (transaction_id, filename, content_type, size, storage_key) VALUES (?, ?, ?, ?, ?)")
+            .bind(remap(&transactions, attachment.transaction_id, "transaction")?)
+            .bind(attachments::clean_filename(&attachment.filename))
+            .bind(&attachment.content_type)
+            .bind(*size as i64)
+            .bind(key)
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    for rule in &archive.rules {
+        let conditions = rule.conditions.iter().map(|condition| match condition {
+            Condition::Account { account_id } => Ok(Condition::Account { account_id: remap(&accounts, *account_id, "account")? }),
+            condition => Ok(condition.clone()),
+        }).collect::<Result<Vec<_>, sqlx::Error>>()?;
+        let actions = rule.actions.iter().map(|action| match action {
+            Action::SetCategory { category_id } => Ok(Action::SetCategory { category_id: remap(&categories, *category_id, "category")? }),
+            action => Ok(action.clone()),
+        }).collect::<Result<Vec<_>, sqlx::Error>>()?;
+        sqlx::query("INSERT INTO rules (budget_id, name, priority, conditions, actions) VALUES (?, ?, ?, ?, ?)")
+            .bind(budget_id)
+            .bind(&rule.name)
+            .bind(rule.priority)
+            .bind(serde_json::to_string(&conditions).unwrap())
+            .bind(serde_json::to_string(&actions).unwrap())
+            .execute(&mut **tx)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Writes the archive's attachments to storage, returning where each went and its size.
+fn store_attachments(archive: &Archive, storage: &dyn Storage) -> Result<Vec<(String, usize)>, String> {
+    let mut keys = vec![];
+    for attachment in &archive.attachments {
+        let stored = STANDARD
+            .decode(&attachment.data)
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| attachments::validate(&bytes).map(|_| bytes))
+            .and_then(|bytes| {
+                let key = attachments::generate_key();
+                storage.put(&key, &bytes).map_err(|e| e.to_string())?;
+                Ok((key, bytes.len()))
+            });
+        match stored {
+            Ok(key) => keys.push(key),
+            Err(e) => {
+                keys.iter().for_each(|(key, _)| storage.delete(key).unwrap_or_default());
+                return Err(format!("{}: {}", attachment.filename, e));
+            }
+        }
+    }
+    Ok(keys)
+}
+
+/// Restores an archive into a budget that has no accounts or categories yet, giving every record a
+/// new id. The archive's locale becomes the user's. Either everything is restored or nothing is. No
+/// webhooks are sent for restored records.
+pub async fn restore(conn: &Pool<Sqlite>, storage: &dyn Storage, budget_id: i32, user_id: i32, archive: &Archive) -> Result<(), &'static str> {
+    if archive.version > VERSION {
+        return Err("This archive was made by a newer version of ymnab.");
+    }
+
+    let has_accounts = !db::get_accounts_for_budget(conn, budget_id).await.unwrap_or_default().is_empty();
+    let has_categories: Result<(i64,), sqlx::Error> = sqlx::query_as("SELECT count(*) FROM category_groups WHERE budget_id = ?")
+        .bind(budget_id)
+        .fetch_one(conn)
+        .await;
+    if has_accounts || !matches!(has_categories, Ok((0,))) {
+        return Err("Archives can only be restored into a budget with no accounts or categories.");
+    }
+
+    let keys = match store_attachments(archive, storage) {
+        Ok(keys) => keys,
+        Err(e) => {
+            println!("{}", e);
+            return Err("The archive's attachments could not be restored.");
+        }
+    };
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        insert(&mut tx, budget_id, user_id, archive, &keys).await?;
+        // One entry for the whole archive rather than one for every row it adds.
+        let summary = serde_json::json!({
+            "accounts": archive.accounts.len(),
+            "transactions": archive.transactions.len(),
+        });
+        audit::log(&mut tx, "budgets", budget_id.into(), "restore", None, Some(&summary.to_string()), Some(user_id.into())).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            keys.iter().for_each(|(key, _)| storage.delete(key).unwrap_or_default());
+            Err("The archive could not be restored.")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::{attachments::MemoryStorage, budgets, currency, db::NewTransaction, rules::{Field, Operator}};
+
+    /// The new user's id and the id of the budget they own.
+    async fn create_user(pool: &Pool<Sqlite>, email: &str) -> (i32, i32) {
+        let result = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', ?, '', 1)")
+            .bind(email)
+            .execute(pool)
+            .await
+            .unwrap();
+        let user_id = result.last_insert_rowid() as i32;
+        let budget_id = budgets::insert(&mut pool.acquire().await.unwrap(), user_id.into(), "Budget", "GBP").await.unwrap();
+        (user_id, budget_id as i32)
+    }
+
+    fn transaction(account_id: i64, memo: &str, inflow: i64, outflow: i64, category_id: Option<i64>) -> NewTransaction {
+        NewTransaction {
+            account_id,
+            date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            value_date: NaiveDate::from_ymd_opt(2024, 1, 4),
+            payee: Some("Tesco".to_string()),
+            memo: memo.to_string(),
+            inflow,
+            outflow,
+            cleared: true,
+            import_id: Some(memo.to_string()),
+            imported: true,
+            category_id,
+        }
+    }
+
+    /// Replaces every id with its position in the archive, so archives from different databases
+    /// can be compared.
+    fn normalise(archive: &mut Archive) {
+        let positions = |ids: Vec<i64>| ids.into_iter().enumerate().map(|(i, id)| (id, i as i64)).collect::<HashMap<_, _>>();
+        let accounts = positions(archive.accounts.iter().map(|a| a.id).collect());
+        let groups = positions(archive.category_groups.iter().map(|g| g.id).collect());
+        let categories = positions(archive.categories.iter().map(|c| c.id).collect());
+        let transactions = positions(archive.transactions.iter().map(|t| t.id).collect());
+
+        archive.exported_at = NaiveDateTime::default();
+        archive.accounts.iter_mut().for_each(|a| a.id = accounts[&a.id]);
+        archive.category_groups.iter_mut().for_each(|g| g.id = groups[&g.id]);
+        archive.categories.iter_mut().for_each(|c| (c.id, c.group_id) = (categories[&c.id], groups[&c.group_id]));
+        archive.assigned.iter_mut().for_each(|a| a.category_id = categories[&a.category_id]);
+        archive.payees.iter_mut().for_each(|p| p.default_category_id = p.default_category_id.map(|id| categories[&id]));
+        for t in archive.transactions.iter_mut() {
+            t.id = transactions[&t.id];
+            t.account_id = accounts[&t.account_id];
+            t.category_id = t.category_id.map(|id| categories[&id]);
+            t.transfer_id = t.transfer_id.map(|id| transactions[&id]);
+        }
+        for s in archive.splits.iter_mut() {
+            s.transaction_id = transactions[&s.transaction_id];
+            s.category_id = s.category_id.map(|id| categories[&id]);
+        }
+        archive.csv_mappings.iter_mut().for_each(|m| m.account_id = accounts[&m.account_id]);
+        archive.attachments.iter_mut().for_each(|a| (a.transaction_id, a.storage_key) = (transactions[&a.transaction_id], String::new()));
+        for (position, rule) in archive.rules.iter_mut().enumerate() {
+            rule.id = position as i64;
+            for condition in rule.conditions.iter_mut() {
+                if let Condition::Account { account_id } = condition {
+                    *account_id = accounts[account_id];
+                }
+            }
+            for action in rule.actions.iter_mut() {
+                if let Action::SetCategory { category_id } = action {
+                    *category_id = categories[category_id];
+                }
+            }
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_round_trip(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let (user_id, budget_id) = create_user(&pool, "test@example.com").await;
+        let current = db::create_account(&pool, budget_id, "Current", "GBP", 100000).await.unwrap();
+        let savings = db::create_account(&pool, budget_id, "Savings", "GBP", 0).await.unwrap();
+        let pension = db::create_empty_account(&pool, budget_id, "Pension", "GBP").await.unwrap();
+        db::set_on_budget(&pool, pension, false).await.unwrap();
+        db::set_reconcile_target(&pool, current, NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(), 50000).await.unwrap();
+        db::save_csv_mapping(&pool, current, &CsvMapping::guess(&["Date".to_string(), "Amount".to_string()], ',')).await.unwrap();
+        let group = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Bills").await.unwrap();
+        let rent = db::insert_category(&mut pool.acquire().await.unwrap(), group, "Rent").await.unwrap();
+        let energy = db::insert_category(&mut pool.acquire().await.unwrap(), group, "Energy").await.unwrap();
+        db::assign_to_category(&mut pool.acquire().await.unwrap(), rent, NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), 65000).await.unwrap();
+        db::create_rule(&pool, budget_id, &Rule {
+            id: 0,
+            name: "Rent".to_string(),
+            priority: 0,
+            conditions: vec![
+                Condition::Account { account_id: current },
+                Condition::Text { field: Field::Memo, operator: Operator::Contains, value: "rent".to_string() },
+            ],
+            actions: vec![Action::SetCategory { category_id: rent }, Action::SetFlag { flag: "red".to_string() }],
+        }).await.unwrap();
+        db::create_transaction(&pool, &transaction(current, "rent", 0, 65000, Some(rent))).await.unwrap();
+        let split = db::create_transaction(&pool, &transaction(current, "split", 0, 3000, None)).await.unwrap();
+        db::insert_split(&mut pool.acquire().await.unwrap(), split, Some(energy), None, "gas", 0, 1000).await.unwrap();
+        db::insert_split(&mut pool.acquire().await.unwrap(), split, None, Some("Corner shop"), "", 0, 2000).await.unwrap();
+        let from = db::create_transaction(&pool, &transaction(current, "to savings", 0, 20000, None)).await.unwrap();
+        let to = db::create_transaction(&pool, &transaction(savings, "from current", 20000, 0, None)).await.unwrap();
+        db::link_transfer(&mut pool.acquire().await.unwrap(), from, to).await.unwrap();
+        db::link_transfer(&mut pool.acquire().await.unwrap(), to, from).await.unwrap();
+        db::create_webhook(&pool, budget_id, "https://example.com/hook", "secret", &["account.created"]).await.unwrap();
+        let storage = MemoryStorage::default();
+        storage.put("receipt", b"%PDF-1.7 receipt").unwrap();
+        db::create_attachment(&pool, split, "receipt.pdf", "application/pdf", 16, "receipt").await.unwrap();
+        db::update_transaction_details(&pool, user_id, budget_id, split, &db::TransactionDetails {
+            flag: Some("purple".to_string()),
+            tags: vec!["reimbursable".to_string(), "tax deductible".to_string()],
+            notes: Some("Claim from work".to_string()),
+        }).await.unwrap();
+        db::set_budget_currency(&pool, budget_id, "EUR").await.unwrap();
+        db::set_locale(&pool, user_id, "de-DE").await.unwrap();
+        let payees = db::get_payees_for_budget(&pool, user_id).await;
+        db::update_payee(&pool, budget_id, payees[0].id, "Tesco Stores", Some(rent)).await.unwrap();
+        db::save_exchange_rates(&pool, budget_id, &currency::parse_csv("2024-01-05,EUR,GBP,0.86").unwrap()).await.unwrap();
+
+        // A separate database that already has another budget's records, so restored ids differ.
+        let target = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?;
+        sqlx::migrate!("./migrations").run(&target).await?;
+        let (_, other_budget) = create_user(&target, "other@example.com").await;
+        db::create_account(&target, other_budget, "Other", "GBP", 500).await.unwrap();
+        db::insert_category_group(&mut target.acquire().await.unwrap(), other_budget, "Other").await.unwrap();
+        let (restored_user, restored_budget) = create_user(&target, "test@example.com").await;
+
+        // Act
+        let mut archive = export(&pool, budget_id, user_id).await?;
+        load_attachments(&mut archive, &storage)?;
+        let json = serde_json::to_string(&archive).unwrap();
+        let secret_exported = json.contains("\"secret\"");
+        let result = restore(&target, &storage, restored_budget, restored_user, &serde_json::from_str(&json).unwrap()).await;
+
+        // Assert
+        assert_eq!(result, Ok(()));
+        let mut expected = archive;
+        let mut restored = export(&target, restored_budget, restored_user).await?;
+        load_attachments(&mut restored, &storage)?;
+        assert_ne!(restored.accounts[0].id, expected.accounts[0].id, "Ids are remapped");
+        normalise(&mut expected);
+        normalise(&mut restored);
+        assert_eq!(restored, expected);
+        assert_eq!(expected.transactions.len(), 6);
+        assert_eq!(expected.splits.len(), 2);
+        assert!(!restored.accounts.iter().find(|a| a.name == "Pension").unwrap().on_budget, "Tracking accounts stay out of the budget");
+        assert_eq!(expected.payees[0].aliases, vec!["Tesco".to_string()]);
+        assert_eq!(expected.transactions[2].flag.as_deref(), Some("red"), "The rule flagged the rent");
+        assert_eq!(expected.rules.len(), 1);
+        assert_eq!(expected.transactions[3].tags, vec!["reimbursable".to_string(), "tax deductible".to_string()]);
+        assert_eq!(expected.transactions[3].notes.as_deref(), Some("Claim from work"));
+        assert_eq!(expected.attachments.len(), 1);
+        assert_eq!(STANDARD.decode(&expected.attachments[0].data).unwrap(), b"%PDF-1.7 receipt");
+        assert!(!secret_exported, "Webhook secrets stay out of the archive");
+        let secret: String = sqlx::query_scalar("SELECT secret FROM webhooks").fetch_one(&target).await?;
+        assert!(secret.len() == 32 && secret != "secret", "A restored webhook gets a new secret");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_restore_needs_an_empty_user(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let (user_id, budget_id) = create_user(&pool, "test@example.com").await;
+        db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let archive = export(&pool, budget_id, user_id).await?;
+
+        // Act
+        let result = restore(&pool, &MemoryStorage::default(), budget_id, user_id, &archive).await;
+
+        // Assert
+        assert!(result.is_err());
+        assert_eq!(db::get_accounts_for_budget(&pool, budget_id).await.unwrap().len(), 1);
+
+        Ok(())
+    }
+}
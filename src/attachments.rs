@@ -0,0 +1,165 @@
+use std::{fs, io, path::PathBuf};
+
+use rand::{distributions::Alphanumeric, Rng};
+
+/// Receipts larger than this are refused.
+pub const MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Where attachment files are kept. Files are looked up by a key ymnab makes up, so a storage never
+/// sees a name the user chose.
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()>;
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// Keeps every file in one directory on local disk.
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid attachment key"));
+        }
+        Ok(self.root.join(key))
+    }
+}
+
+impl Storage for FileStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        fs::write(self.path(key)?, bytes)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.path(key)?)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.path(key)?) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Keeps files in memory, for tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: std::sync::Mutex<std::collections::HashMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        self.files.lock().unwrap().insert(key.to_string(), bytes.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.files.lock().unwrap().get(key).cloned().ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.files.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+pub fn generate_key() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Works out what kind of file this is from its first bytes, rather than trusting the name or the
+/// type the browser sent. Only images and PDFs are recognised.
+pub fn content_type(bytes: &[u8]) -> Option<&'static str> {
+    if bytes.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some("image/jpeg")
+    } else if bytes.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if bytes.len() >= 12 && bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if bytes.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else {
+        None
+    }
+}
+
+/// Checks a file can be attached, returning its content type.
+pub fn validate(bytes: &[u8]) -> Result<&'static str, String> {
+    if bytes.is_empty() {
+        return Err("The file is empty.".to_string());
+    }
+    if bytes.len() > MAX_SIZE {
+        return Err(format!("Files can be at most {} MB.", MAX_SIZE / 1024 / 1024));
+    }
+    content_type(bytes).ok_or_else(|| "Only JPEG, PNG, GIF and WebP images and PDFs can be attached.".to_string())
+}
+
+/// A file name that is safe to send back in a `Content-Disposition` header.
+pub fn clean_filename(name: &str) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or_default();
+    let cleaned: String = name.chars().filter(|c| !c.is_control() && *c != '"').take(200).collect();
+    match cleaned.trim() {
+        "" => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(b"%PDF-1.7\n..."), Ok("application/pdf"));
+        assert_eq!(validate(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), Ok("image/png"));
+        assert_eq!(validate(b"RIFF\0\0\0\0WEBPVP8 "), Ok("image/webp"));
+        assert!(validate(b"<html><script>").is_err(), "Only images and PDFs are accepted");
+        assert!(validate(b"").is_err());
+        let mut large = b"%PDF-".to_vec();
+        large.resize(MAX_SIZE + 1, 0);
+        assert!(validate(&large).is_err());
+    }
+
+    #[test]
+    fn test_clean_filename() {
+        assert_eq!(clean_filename("C:\\Users\\me\\receipt \"1\".pdf"), "receipt 1.pdf");
+        assert_eq!(clean_filename("../../etc/passwd"), "passwd");
+        assert_eq!(clean_filename(""), "attachment");
+    }
+
+    #[test]
+    fn test_file_storage() {
+        // Setup
+        let root = std::env::temp_dir().join(format!("ymnab-attachments-{}", generate_key()));
+        let storage = FileStorage::new(&root).unwrap();
+        let key = generate_key();
+
+        // Act
+        storage.put(&key, b"%PDF-1.7").unwrap();
+        let read = storage.get(&key).unwrap();
+        storage.delete(&key).unwrap();
+
+        // Assert
+        assert_eq!(read, b"%PDF-1.7");
+        assert!(storage.get(&key).is_err());
+        assert!(storage.put("../escape", b"").is_err(), "Keys cannot leave the directory");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
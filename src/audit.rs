@@ -0,0 +1,283 @@
+use std::{collections::BTreeMap, future::Future, sync::Arc};
+
+use chrono::NaiveDate;
+use poem::{session::Session, Endpoint, Request};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite, SqliteConnection};
+
+use crate::{budgets, db};
+
+/// Columns that are left out of the log.
+const SECRET_COLUMNS: [&str; 3] = ["password", "secret", "token"];
+
+/// Who is making changes, to which budget, and from where.
+#[derive(Debug, Clone, Default)]
+pub struct Actor {
+    pub user_id: Option<i64>,
+    pub budget_id: Option<i64>,
+    pub ip: Option<String>,
+}
+
+tokio::task_local! {
+    static ACTOR: Actor;
+}
+
+/// Logs changes made while handling a request as made by the logged in user to the budget they are
+/// working in, from the address the request came from.
+pub async fn track<E: Endpoint>(ep: Arc<E>, req: Request) -> poem::Result<E::Output> {
+    let session = req.extensions().get::<Session>();
+    let email = session.and_then(|session| session.get::<String>("user"));
+    let (user_id, budget_id) = match (req.data::<Pool<Sqlite>>(), email) {
+        (Some(pool), Some(email)) => match db::get_user(pool, email).await.and_then(|u| u.id) {
+            Some(user_id) => {
+                let budget = budgets::current(pool, user_id, session.and_then(|session| session.get::<i32>("budget"))).await;
+                (Some(user_id.into()), budget.map(|b| b.id.into()))
+            }
+            None => (None, None),
+        },
+        _ => (None, None),
+    };
+    let ip = req.remote_addr().as_socket_addr().map(|addr| addr.ip().to_string());
+    ACTOR.scope(Actor { user_id, budget_id, ip }, ep.call(req)).await
+}
+
+/// Runs `f` with its changes logged against `budget_id` rather than the budget being worked in,
+/// such as when joining or creating a budget.
+pub async fn in_budget<F: Future>(budget_id: i64, f: F) -> F::Output {
+    let actor = ACTOR.try_with(Actor::clone).unwrap_or_default();
+    ACTOR.scope(Actor { budget_id: Some(budget_id), ..actor }, f).await
+}
+
+pub async fn columns(conn: &mut SqliteConnection, table: &str) -> sqlx::Result<Vec<String>> {
+    let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info(?) ORDER BY cid")
+        .bind(table)
+        .fetch_all(conn)
+        .await?;
+    Ok(columns.into_iter().map(|(name,)| name).collect())
+}
+
+/// The rows of `table` whose `column` is one of `ids`, by rowid, each as a JSON object of its
+/// columns. `table` and `column` are put straight into SQL, so they must not come from the user.
+pub async fn images(conn: &mut SqliteConnection, table: &str, column: &str, ids: &[i64]) -> sqlx::Result<Vec<(i64, String)>> {
+    if ids.is_empty() {
+        return Ok(vec![]);
+    }
+    let fields: Vec<String> = columns(&mut *conn, table).await?.iter().map(|c| format!("'{}', \"{}\"", c, c)).collect();
+    let mut query = sqlx::QueryBuilder::<Sqlite>::new(format!("SELECT rowid, json_object({}) FROM \"{}\" WHERE \"{}\" IN (", fields.join(", "), table, column));
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    separated.push_unseparated(")");
+    query.build_query_as().fetch_all(conn).await
+}
+
+/// One row that changed. A row that didn't exist before or after has no image there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    pub table: &'static str,
+    pub rowid: i64,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl RowChange {
+    fn action(&self) -> &'static str {
+        match (&self.before, &self.after) {
+            (None, _) => "create",
+            (_, None) => "delete",
+            _ => "update",
+        }
+    }
+}
+
+/// Collects how rows look before and after a change so it can be logged. Rows are watched by a
+/// column, such as a transaction's tags by `transaction_id`, and any row that matches afterwards
+/// is logged too, so rows that are about to be created are covered.
+#[derive(Default)]
+pub struct Audit {
+    scopes: Vec<(&'static str, &'static str, Vec<i64>)>,
+    before: BTreeMap<(&'static str, i64), String>,
+}
+
+impl Audit {
+    /// Remembers the rows of `table` whose `column` is one of `ids`, before they change.
+    pub async fn watch(&mut self, conn: &mut SqliteConnection, table: &'static str, column: &'static str, ids: &[i64]) -> sqlx::Result<()> {
+        for (rowid, image) in images(conn, table, column, ids).await? {
+            self.before.entry((table, rowid)).or_insert(image);
+        }
+        self.scopes.push((table, column, ids.to_vec()));
+        Ok(())
+    }
+
+    /// Records rows that are about to be, or were just, created, and so have nothing before.
+    pub fn created(&mut self, table: &'static str, column: &'static str, ids: &[i64]) {
+        self.scopes.push((table, column, ids.to_vec()));
+    }
+
+    /// The rows that changed since they were watched.
+    async fn changes(&self, conn: &mut SqliteConnection) -> sqlx::Result<Vec<RowChange>> {
+        let mut after = BTreeMap::new();
+        for (table, column, ids) in &self.scopes {
+            for (rowid, image) in images(&mut *conn, table, column, ids).await? {
+                after.insert((*table, rowid), image);
+            }
+        }
+        let mut keys: Vec<&(&'static str, i64)> = self.before.keys().chain(after.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        Ok(keys
+            .into_iter()
+            .map(|key| RowChange { table: key.0, rowid: key.1, before: self.before.get(key).cloned(), after: after.get(key).cloned() })
+            .filter(|change| change.before != change.after)
+            .collect())
+    }
+
+    /// Logs every watched row that changed, and returns them. The change is logged as made by
+    /// whoever is acting, or by `user_id` when no one is, such as when signing up.
+    pub async fn save(self, conn: &mut SqliteConnection, user_id: Option<i64>) -> sqlx::Result<Vec<RowChange>> {
+        let changes = self.changes(&mut *conn).await?;
+        for change in &changes {
+            log(&mut *conn, change.table, change.rowid, change.action(), change.before.as_deref(), change.after.as_deref(), user_id).await?;
+        }
+        Ok(changes)
+    }
+
+    /// Logs every watched row that changed as a change to `budget_id`, made by whoever is acting or,
+    /// when no one is, such as in a background job, by the budget's first owner.
+    pub async fn save_in_budget(self, conn: &mut SqliteConnection, budget_id: i64) -> sqlx::Result<Vec<RowChange>> {
+        let owner = budgets::owner(&mut *conn, budget_id).await?;
+        in_budget(budget_id, self.save(conn, owner)).await
+    }
+}
+
+/// Removes secrets from a row's image.
+fn redact(image: Option<&str>) -> Option<String> {
+    let image = image?;
+    match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(image) {
+        Ok(mut row) => {
+            for column in SECRET_COLUMNS {
+                row.remove(column);
+            }
+            serde_json::to_string(&row).ok()
+        }
+        Err(_) => Some(image.to_string()),
+    }
+}
+
+/// Adds one entry to the log, made by whoever is acting, or by `user_id` when no one is.
+pub async fn log(conn: &mut SqliteConnection, entity: &str, entity_id: i64, action: &str, before: Option<&str>, after: Option<&str>, user_id: Option<i64>) -> sqlx::Result<()> {
+    let actor = ACTOR.try_with(Actor::clone).unwrap_or_default();
+    sqlx::query("INSERT INTO audit_log (actor_id, budget_id, ip, entity, entity_id, action, before, after) VALUES (?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(actor.user_id.or(user_id))
+        .bind(actor.budget_id)
+        .bind(actor.ip)
+        .bind(entity)
+        .bind(entity_id)
+        .bind(action)
+        .bind(redact(before))
+        .bind(redact(after))
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Which entries to show, as they appear in the query string. Empty fields are ignored.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditParams {
+    pub entity: String,
+    pub action: String,
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Entry {
+    pub id: i64,
+    /// The name of the user who made the change, if they still exist.
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub entity: String,
+    pub entity_id: i64,
+    pub action: String,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+impl Entry {
+    /// Each column that changed, with how it was before and after. Values are shown as JSON, and
+    /// are empty where the row didn't exist.
+    pub fn changed_columns(&self) -> Vec<(String, String, String)> {
+        let row = |image: &Option<String>| {
+            image.as_deref().and_then(|image| serde_json::from_str::<BTreeMap<String, serde_json::Value>>(image).ok()).unwrap_or_default()
+        };
+        let (before, after) = (row(&self.before), row(&self.after));
+        let mut columns: Vec<&String> = before.keys().chain(after.keys()).collect();
+        columns.sort();
+        columns.dedup();
+        columns
+            .into_iter()
+            .filter(|column| before.get(*column) != after.get(*column))
+            .map(|column| {
+                let value = |row: &BTreeMap<String, serde_json::Value>| row.get(column).map(|v| v.to_string()).unwrap_or_default();
+                (column.clone(), value(&before), value(&after))
+            })
+            .collect()
+    }
+}
+
+/// The most entries shown at once.
+pub const ENTRY_LIMIT: i64 = 500;
+
+/// The changes every member made to the budget, newest first.
+pub async fn get_entries(conn: &Pool<Sqlite>, budget_id: i64, params: &AuditParams) -> Result<Vec<Entry>, String> {
+    let date = |value: &str| match value.trim() {
+        "" => Ok(None),
+        value => NaiveDate::parse_from_str(value, "%Y-%m-%d").map(Some).map_err(|_| format!("{} is not a date.", value)),
+    };
+    let (from, to) = (date(&params.from)?, date(&params.to)?);
+
+    let mut query = sqlx::QueryBuilder::<Sqlite>::new("SELECT l.id, u.name AS actor, l.ip, l.entity, l.entity_id, l.action, l.before, l.after, l.created_at FROM audit_log l LEFT JOIN users u ON u.id = l.actor_id WHERE l.budget_id = ");
+    query.push_bind(budget_id);
+    if !params.entity.is_empty() {
+        query.push(" AND l.entity = ").push_bind(params.entity.clone());
+    }
+    if !params.action.is_empty() {
+        query.push(" AND l.action = ").push_bind(params.action.clone());
+    }
+    if let Some(from) = from {
+        query.push(" AND date(l.created_at) >= ").push_bind(from);
+    }
+    if let Some(to) = to {
+        query.push(" AND date(l.created_at) <= ").push_bind(to);
+    }
+    query.push(" ORDER BY l.id DESC LIMIT ").push_bind(ENTRY_LIMIT);
+
+    query.build_query_as().fetch_all(conn).await.map_err(|e| {
+        println!("{:?}", e);
+        "Failed to read the audit log.".to_string()
+    })
+}
+
+/// The kinds of thing that have changed in the budget, for filtering by.
+pub async fn get_entities(conn: &Pool<Sqlite>, budget_id: i64) -> Vec<String> {
+    let result: Result<Vec<(String,)>, sqlx::Error> = sqlx::query_as("SELECT DISTINCT entity FROM audit_log WHERE budget_id = ? ORDER BY entity")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.map(|rows| rows.into_iter().map(|(entity,)| entity).collect()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(redact(Some(r#"{"id":1,"email":"a@b.c","password":"$2b$"}"#)).as_deref(), Some(r#"{"email":"a@b.c","id":1}"#));
+        assert_eq!(redact(None), None);
+    }
+}
@@ -0,0 +1,490 @@
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, Pool, Sqlite, SqliteConnection};
+
+use crate::{audit::{self, Audit}, db::User};
+
+/// What a member can do in a budget. Each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub enum Role {
+    /// Can look at everything but change nothing.
+    Viewer,
+    /// Can change the budget's accounts, transactions, categories and settings.
+    Editor,
+    /// Can also share the budget, restore archives into it and manage its webhooks.
+    Owner,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Owner, Role::Editor, Role::Viewer];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|r| r.as_str() == value)
+    }
+
+    /// Whether a member with this role can do what needs `needed`.
+    pub fn allows(&self, needed: Role) -> bool {
+        *self >= needed
+    }
+}
+
+/// A budget as one of its members sees it.
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Budget {
+    pub id: i32,
+    pub name: String,
+    /// The ISO 4217 code of the currency the budget's totals are shown in.
+    pub currency: String,
+    pub role: Role,
+    /// Archived budgets are left out of the budget picker.
+    pub archived: bool,
+}
+
+const BUDGET_SELECT: &str = "SELECT b.id, b.name, b.currency, m.role, b.archived_at IS NOT NULL AS archived FROM budgets b JOIN budget_members m ON m.budget_id = b.id";
+
+/// Adds a budget owned by the user, and returns its id. The caller logs it.
+pub async fn insert(conn: &mut SqliteConnection, user_id: i64, name: &str, currency: &str) -> sqlx::Result<i64> {
+    let id = sqlx::query("INSERT INTO budgets (name, currency) VALUES (?, ?)")
+        .bind(name)
+        .bind(currency)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    sqlx::query("INSERT INTO budget_members (budget_id, user_id, role) VALUES (?, ?, 'owner')")
+        .bind(id)
+        .bind(user_id)
+        .execute(conn)
+        .await?;
+    Ok(id)
+}
+
+/// Creates a budget owned by the user.
+pub async fn create(conn: &Pool<Sqlite>, user_id: i32, name: &str, currency: &str) -> Result<i32, &'static str> {
+    let result: Result<i64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = insert(&mut tx, user_id.into(), name, currency).await?;
+        let mut audit = Audit::default();
+        audit.created("budgets", "id", &[id]);
+        audit.created("budget_members", "budget_id", &[id]);
+        audit::in_budget(id, audit.save(&mut tx, Some(user_id.into()))).await?;
+        tx.commit().await?;
+        Ok(id)
+    }.await;
+
+    match result {
+        Ok(id) => Ok(id as i32),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to create the budget.")
+        }
+    }
+}
+
+/// Renames the budget for all of its members.
+pub async fn rename(conn: &Pool<Sqlite>, id: i32, name: &str) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "budgets", "id", &[id.into()]).await?;
+        sqlx::query("UPDATE budgets SET name = ? WHERE id = ?")
+            .bind(name)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, id.into()).await?;
+        tx.commit().await
+    }.await;
+
+    result.map_err(|e| {
+        println!("{:?}", e);
+        "Failed to rename the budget."
+    })
+}
+
+/// Archives the budget, or brings it back when `archived` is false. Nothing in it is changed.
+pub async fn set_archived(conn: &Pool<Sqlite>, id: i32, archived: bool) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "budgets", "id", &[id.into()]).await?;
+        sqlx::query("UPDATE budgets SET archived_at = CASE WHEN ? THEN COALESCE(archived_at, CURRENT_TIMESTAMP) END WHERE id = ?")
+            .bind(archived)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, id.into()).await?;
+        tx.commit().await
+    }.await;
+
+    result.map_err(|e| {
+        println!("{:?}", e);
+        "Failed to archive the budget."
+    })
+}
+
+/// Every budget the user is a member of, oldest first.
+pub async fn get_for_user(conn: &Pool<Sqlite>, user_id: i32) -> Vec<Budget> {
+    let result = sqlx::query_as::<_, Budget>(&format!("{} WHERE m.user_id = ? ORDER BY b.id", BUDGET_SELECT))
+        .bind(user_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+/// The budget, if the user is a member of it.
+pub async fn get(conn: &Pool<Sqlite>, user_id: i32, id: i32) -> Option<Budget> {
+    let result = sqlx::query_as::<_, Budget>(&format!("{} WHERE m.user_id = ? AND b.id = ?", BUDGET_SELECT))
+        .bind(user_id)
+        .bind(id)
+        .fetch_one(conn)
+        .await;
+
+    result.ok()
+}
+
+/// The budget the user is working in: `id` if they are still a member of it and it isn't archived,
+/// or otherwise the first they are a member of that isn't. Someone whose budgets are all archived
+/// works in the first of them.
+pub async fn current(conn: &Pool<Sqlite>, user_id: i32, id: Option<i32>) -> Option<Budget> {
+    let budgets = get_for_user(conn, user_id).await;
+    let open = |budget: &&Budget| !budget.archived;
+    let chosen = budgets.iter().filter(open).find(|budget| Some(budget.id) == id);
+    chosen.or_else(|| budgets.iter().find(open)).or(budgets.first()).cloned()
+}
+
+/// The budget's first owner.
+pub async fn owner(conn: &mut SqliteConnection, budget_id: i64) -> sqlx::Result<Option<i64>> {
+    let owner: Option<(i64,)> = sqlx::query_as("SELECT user_id FROM budget_members WHERE budget_id = ? AND role = 'owner' ORDER BY rowid LIMIT 1")
+        .bind(budget_id)
+        .fetch_optional(conn)
+        .await?;
+    Ok(owner.map(|(id,)| id))
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct Member {
+    pub user_id: i32,
+    pub name: String,
+    pub email: String,
+    pub role: Role,
+}
+
+pub async fn get_members(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Member> {
+    let result = sqlx::query_as::<_, Member>("SELECT u.id AS user_id, u.name, u.email, m.role FROM budget_members m JOIN users u ON u.id = m.user_id WHERE m.budget_id = ? ORDER BY u.name COLLATE NOCASE")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+/// Changes a member's role, or removes them from the budget when `role` is `None`. A budget always
+/// keeps at least one owner.
+pub async fn set_member_role(conn: &Pool<Sqlite>, budget_id: i32, user_id: i32, role: Option<Role>) -> Result<(), &'static str> {
+    let result: Result<Result<(), &'static str>, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "budget_members", "budget_id", &[budget_id.into()]).await?;
+        let changed = match role {
+            Some(role) => sqlx::query("UPDATE budget_members SET role = ? WHERE budget_id = ? AND user_id = ?").bind(role),
+            None => sqlx::query("DELETE FROM budget_members WHERE budget_id = ? AND user_id = ?"),
+        }
+            .bind(budget_id)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if changed == 0 {
+            return Ok(Err("That person is not a member of this budget."));
+        }
+        let (owners,): (i64,) = sqlx::query_as("SELECT count(*) FROM budget_members WHERE budget_id = ? AND role = 'owner'")
+            .bind(budget_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        if owners == 0 {
+            return Ok(Err("A budget needs at least one owner."));
+        }
+        audit.save(&mut tx, None).await?;
+        tx.commit().await?;
+        Ok(Ok(()))
+    }.await;
+
+    match result {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to change the budget's members.")
+        }
+    }
+}
+
+/// An invitation to join a budget. It is sent by giving the invited person its link.
+#[derive(Debug, Serialize, FromRow)]
+pub struct Invitation {
+    pub id: i64,
+    pub budget_id: i32,
+    pub budget_name: String,
+    pub email: String,
+    pub role: Role,
+    pub token: String,
+    /// The name of whoever sent it, if they still exist.
+    pub invited_by: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+    pub accepted_at: Option<chrono::NaiveDateTime>,
+}
+
+impl Invitation {
+    /// Where the invited person goes to accept it.
+    pub fn path(&self) -> String {
+        format!("/invitations/{}", self.token)
+    }
+}
+
+const INVITATION_SELECT: &str = "SELECT i.id, i.budget_id, b.name AS budget_name, i.email, i.role, i.token, u.name AS invited_by, i.created_at, i.accepted_at FROM budget_invitations i JOIN budgets b ON b.id = i.budget_id LEFT JOIN users u ON u.id = i.invited_by";
+
+fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Invites `email` to join the budget with `role`, and returns the invitation's token.
+pub async fn invite(conn: &Pool<Sqlite>, budget_id: i32, invited_by: i32, email: &str, role: Role) -> Result<String, &'static str> {
+    let email = email.trim();
+    if !email.contains('@') {
+        return Err("Enter the email address of the person to invite.");
+    }
+    let token = generate_token();
+    let result: Result<Result<(), &'static str>, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let member: Option<(i64,)> = sqlx::query_as("SELECT 1 FROM budget_members m JOIN users u ON u.id = m.user_id WHERE m.budget_id = ? AND u.email = ? COLLATE NOCASE")
+            .bind(budget_id)
+            .bind(email)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if member.is_some() {
+            return Ok(Err("They are already a member of this budget."));
+        }
+        let id = sqlx::query("INSERT INTO budget_invitations (budget_id, email, role, token, invited_by) VALUES (?, ?, ?, ?, ?)")
+            .bind(budget_id)
+            .bind(email)
+            .bind(role)
+            .bind(&token)
+            .bind(invited_by)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        let mut audit = Audit::default();
+        audit.created("budget_invitations", "id", &[id]);
+        audit.save(&mut tx, Some(invited_by.into())).await?;
+        tx.commit().await?;
+        Ok(Ok(()))
+    }.await;
+
+    match result {
+        Ok(result) => result.map(|_| token),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to invite them.")
+        }
+    }
+}
+
+/// The budget's invitations that haven't been accepted yet, newest first.
+pub async fn get_pending_invitations(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Invitation> {
+    let result = sqlx::query_as::<_, Invitation>(&format!("{} WHERE i.budget_id = ? AND i.accepted_at IS NULL ORDER BY i.id DESC", INVITATION_SELECT))
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+pub async fn get_invitation(conn: &Pool<Sqlite>, token: &str) -> Option<Invitation> {
+    let result = sqlx::query_as::<_, Invitation>(&format!("{} WHERE i.token = ?", INVITATION_SELECT))
+        .bind(token)
+        .fetch_one(conn)
+        .await;
+
+    result.ok()
+}
+
+pub async fn cancel_invitation(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Result<(), &'static str> {
+    let result: Result<u64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "budget_invitations", "id", &[id]).await?;
+        let deleted = sqlx::query("DELETE FROM budget_invitations WHERE id = ? AND budget_id = ? AND accepted_at IS NULL")
+            .bind(id)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        audit.save(&mut tx, None).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }.await;
+
+    match result {
+        Ok(0) => Err("That invitation has already been accepted or cancelled."),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to cancel the invitation.")
+        }
+    }
+}
+
+/// Makes the user a member of the invitation's budget, and returns the budget's id. Invitations can
+/// only be accepted once, by someone logged in with the address they were sent to. Someone who is
+/// already a member keeps their role.
+pub async fn accept_invitation(conn: &Pool<Sqlite>, token: &str, user: &User) -> Result<i32, &'static str> {
+    let user_id = user.id.unwrap_or_default();
+    let invitation = match get_invitation(conn, token).await {
+        Some(i) if i.accepted_at.is_none() => i,
+        Some(_) => return Err("This invitation has already been accepted."),
+        None => return Err("This invitation doesn't exist, or was cancelled."),
+    };
+    if !invitation.email.eq_ignore_ascii_case(&user.email) {
+        return Err("This invitation was sent to someone else. Log in as them to accept it.");
+    }
+
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "budget_invitations", "id", &[invitation.id]).await?;
+        audit.watch(&mut tx, "budget_members", "user_id", &[user_id.into()]).await?;
+        sqlx::query("INSERT INTO budget_members (budget_id, user_id, role) VALUES (?, ?, ?) ON CONFLICT (budget_id, user_id) DO NOTHING")
+            .bind(invitation.budget_id)
+            .bind(user_id)
+            .bind(invitation.role)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE budget_invitations SET accepted_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(invitation.id)
+            .execute(&mut *tx)
+            .await?;
+        audit::in_budget(invitation.budget_id.into(), audit.save(&mut tx, Some(user_id.into()))).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(invitation.budget_id),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to accept the invitation.")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_role_allows() {
+        assert!(Role::Owner.allows(Role::Editor));
+        assert!(Role::Editor.allows(Role::Editor));
+        assert!(!Role::Viewer.allows(Role::Editor));
+        assert!(!Role::Editor.allows(Role::Owner));
+        assert_eq!(Role::from_str("viewer"), Some(Role::Viewer));
+        assert_eq!(Role::from_str("admin"), None);
+    }
+
+    /// A database from before budgets with a row in every table that moves to them, on a single
+    /// connection so it stays in memory.
+    async fn database_before_budgets(foreign_keys: bool) -> sqlx::Result<Pool<Sqlite>> {
+        let options = "sqlite::memory:".parse::<sqlx::sqlite::SqliteConnectOptions>()?.foreign_keys(foreign_keys);
+        let pool = sqlx::sqlite::SqlitePoolOptions::new().max_connections(1).connect_with(options).await?;
+        let migrator = sqlx::migrate!("./migrations");
+        let before_budgets = sqlx::migrate::Migrator {
+            migrations: migrator.migrations.iter().filter(|m| m.version < 20240624090000).cloned().collect(),
+            ignore_missing: false,
+            locking: true,
+        };
+        before_budgets.run(&pool).await?;
+        for statement in [
+            "INSERT INTO users (id, name, email, password, active, currency) VALUES (1, 'Ada', 'ada@example.com', '', 1, 'EUR')",
+            "INSERT INTO accounts (id, user_id, name, currency) VALUES (1, 1, 'Current', 'EUR')",
+            "INSERT INTO csv_import_mappings (account_id, mapping) VALUES (1, '{}')",
+            "INSERT INTO category_groups (id, user_id, name) VALUES (1, 1, 'Bills')",
+            "INSERT INTO categories (id, group_id, name) VALUES (1, 1, 'Rent')",
+            "INSERT INTO category_budgets (category_id, month, assigned) VALUES (1, '2024-06-01', 50000)",
+            "INSERT INTO payees (id, user_id, name, default_category_id) VALUES (1, 1, 'Landlord', 1)",
+            "INSERT INTO payee_aliases (payee_id, name) VALUES (1, 'LANDLORD LTD')",
+            "INSERT INTO tags (id, user_id, name) VALUES (1, 1, 'home')",
+            "INSERT INTO transactions (id, account_id, date, memo, outflow, category_id, payee_id) VALUES (1, 1, '2024-06-01 12:00:00', '', 50000, 1, 1)",
+            "INSERT INTO transaction_tags (transaction_id, tag_id) VALUES (1, 1)",
+            "INSERT INTO exchange_rates (user_id, date, base, quote, rate) VALUES (1, '2024-06-01', 'GBP', 'EUR', 1.18)",
+            "INSERT INTO rules (user_id, name, conditions, actions) VALUES (1, 'Rent', '[]', '[]')",
+            "INSERT INTO webhooks (id, user_id, url, secret, events) VALUES (1, 1, 'https://example.com', 'secret', 'transaction.created')",
+            "INSERT INTO webhook_deliveries (webhook_id, event, payload, next_attempt_at, created_at) VALUES (1, 'transaction.created', '{}', '2024-06-01', '2024-06-01')",
+        ] {
+            sqlx::query(statement).execute(&pool).await?;
+        }
+        Ok(pool)
+    }
+
+    #[tokio::test]
+    async fn test_migrating_keeps_existing_data() -> sqlx::Result<()> {
+        // Setup
+        let pool = database_before_budgets(false).await?;
+
+        // Act
+        sqlx::migrate!("./migrations").run(&pool).await?;
+
+        // Assert
+        let count = |table: &str| {
+            let pool = pool.clone();
+            let query = format!("SELECT COUNT(*) FROM {}", table);
+            async move { sqlx::query_scalar::<_, i64>(&query).fetch_one(&pool).await.unwrap() }
+        };
+        for table in ["accounts", "csv_import_mappings", "category_groups", "categories", "category_budgets", "payees", "payee_aliases", "tags", "transactions", "transaction_tags", "exchange_rates", "rules", "webhooks", "webhook_deliveries"] {
+            assert_eq!(count(table).await, 1, "{} keeps its rows", table);
+        }
+        let budget = current(&pool, 1, None).await.unwrap();
+        assert_eq!((budget.id, budget.currency.as_str()), (1, "EUR"));
+        let (category_id, payee_id): (Option<i64>, Option<i64>) = sqlx::query_as("SELECT category_id, payee_id FROM transactions").fetch_one(&pool).await?;
+        assert_eq!((category_id, payee_id), (Some(1), Some(1)));
+        let parents: Vec<String> = sqlx::query_scalar("SELECT DISTINCT \"table\" FROM pragma_foreign_key_list('accounts') UNION SELECT DISTINCT \"table\" FROM pragma_foreign_key_list('tags')").fetch_all(&pool).await?;
+        assert_eq!(parents, vec!["budgets".to_string()]);
+        let broken: Vec<String> = sqlx::query_scalar("SELECT \"table\" FROM pragma_foreign_key_check").fetch_all(&pool).await?;
+        assert!(broken.is_empty(), "{:?}", broken);
+
+        let created = insert(&mut *pool.acquire().await?, 1, "Holiday", "GBP").await?;
+        sqlx::query("INSERT INTO category_groups (budget_id, name) VALUES (?, 'Travel')").bind(created).execute(&pool).await?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrating_with_foreign_keys_on_is_refused() -> sqlx::Result<()> {
+        // Setup
+        let pool = database_before_budgets(true).await?;
+
+        // Act
+        let migrated = sqlx::migrate!("./migrations").run(&pool).await;
+
+        // Assert
+        assert!(migrated.is_err(), "Dropping the old tables would have deleted the rows pointing at them");
+        let transactions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM transactions").fetch_one(&pool).await?;
+        assert_eq!(transactions, 1);
+        let user_ids: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pragma_table_info('accounts') WHERE name = 'user_id'").fetch_one(&pool).await?;
+        assert_eq!(user_ids, 1, "Nothing was migrated");
+
+        Ok(())
+    }
+}
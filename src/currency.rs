@@ -0,0 +1,264 @@
+use std::collections::BTreeSet;
+
+use chrono::NaiveDate;
+use quick_xml::{events::Event, Reader};
+use rusty_money::iso;
+use serde::{Deserialize, Serialize};
+use sqlx::prelude::FromRow;
+
+use crate::db::Account;
+
+/// The currencies offered first when choosing one. Any ISO 4217 code is accepted.
+pub const COMMON: [&str; 10] = ["GBP", "EUR", "USD", "CHF", "JPY", "CAD", "AUD", "SEK", "NOK", "DKK"];
+
+/// Normalises a currency code to upper case, or `None` if it is not an ISO 4217 code.
+pub fn code(value: &str) -> Option<String> {
+    let value = value.trim().to_uppercase();
+    iso::find(&value).map(|_| value)
+}
+
+/// How many digits the currency has after the decimal point, such as 2 for pounds and 0 for yen.
+pub fn exponent(currency: &str) -> u32 {
+    iso::find(currency).map_or(2, |c| c.exponent)
+}
+
+/// One unit of `base` is worth `rate` units of `quote` on `date`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, FromRow)]
+pub struct ExchangeRate {
+    pub date: NaiveDate,
+    pub base: String,
+    pub quote: String,
+    pub rate: f64,
+}
+
+impl ExchangeRate {
+    fn new(date: NaiveDate, base: &str, quote: &str, rate: &str) -> Result<Self, String> {
+        let base = code(base).ok_or_else(|| format!("{} is not a currency", base.trim()))?;
+        let quote = code(quote).ok_or_else(|| format!("{} is not a currency", quote.trim()))?;
+        match rate.trim().parse::<f64>() {
+            Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(Self { date, base, quote, rate }),
+            _ => Err(format!("{} is not an exchange rate", rate.trim())),
+        }
+    }
+}
+
+/// The exchange rates a user has, for converting between currencies on a given day.
+pub struct Rates {
+    /// Newest first, so the first rate found for a pair is the latest one.
+    rates: Vec<ExchangeRate>,
+}
+
+impl Rates {
+    pub fn new(mut rates: Vec<ExchangeRate>) -> Self {
+        rates.sort_by_key(|r| std::cmp::Reverse(r.date));
+        Self { rates }
+    }
+
+    /// The latest rate between the two currencies on or before `on`, in either direction.
+    fn direct(&self, from: &str, to: &str, on: NaiveDate) -> Option<f64> {
+        self.rates.iter().filter(|r| r.date <= on).find_map(|r| {
+            if r.base == from && r.quote == to {
+                Some(r.rate)
+            } else if r.base == to && r.quote == from {
+                Some(1.0 / r.rate)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// How many units of `to` one unit of `from` is worth on `on`.
+    pub fn rate(&self, from: &str, to: &str, on: NaiveDate) -> Option<f64> {
+        if from == to {
+            return Some(1.0);
+        }
+        if let Some(rate) = self.direct(from, to, on) {
+            return Some(rate);
+        }
+
+        // ECB rates are all against the euro, so pounds to dollars goes through a currency both
+        // have a rate for.
+        let currencies: BTreeSet<&str> = self.rates.iter().flat_map(|r| [r.base.as_str(), r.quote.as_str()]).collect();
+        currencies
+            .into_iter()
+            .filter(|c| *c != from && *c != to)
+            .find_map(|via| Some(self.direct(from, via, on)? * self.direct(via, to, on)?))
+    }
+
+    /// Converts an amount in `from`'s minor units into `to`'s minor units, rounding to the nearest
+    /// unit. `None` when there is no rate between the two.
+    pub fn convert(&self, amount: i64, from: &str, to: &str, on: NaiveDate) -> Option<i64> {
+        if from == to {
+            return Some(amount);
+        }
+        let rate = self.rate(from, to, on)?;
+        let scale = 10_f64.powi(exponent(to) as i32 - exponent(from) as i32);
+        Some((amount as f64 * rate * scale).round() as i64)
+    }
+
+    /// Adds up the accounts' balances in `currency`. Accounts in a currency with no rate are left
+    /// out, and their currencies are returned alongside the total.
+    pub fn total(&self, accounts: &[&Account], currency: &str, on: NaiveDate) -> (i64, BTreeSet<String>) {
+        let mut missing = BTreeSet::new();
+        let mut total = 0;
+        for account in accounts {
+            match self.convert(account.total, &account.currency, currency, on) {
+                Some(amount) => total += amount,
+                None => {
+                    missing.insert(account.currency.clone());
+                }
+            }
+        }
+        (total, missing)
+    }
+}
+
+/// Reads rates from CSV rows of `date,base,quote,rate`, such as `2024-01-05,EUR,USD,1.0921`. A
+/// header row is skipped.
+pub fn parse_csv(content: &str) -> Result<Vec<ExchangeRate>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(content.as_bytes());
+
+    let mut rates = vec![];
+    for (index, record) in reader.records().enumerate() {
+        let line = index + 1;
+        let record = record.map_err(|e| format!("Line {}: {}", line, e))?;
+        if record.iter().all(|field| field.is_empty()) {
+            continue;
+        }
+        if record.len() != 4 {
+            return Err(format!("Line {}: expected date, base, quote and rate", line));
+        }
+        let date = match NaiveDate::parse_from_str(&record[0], "%Y-%m-%d") {
+            Ok(date) => date,
+            Err(_) if index == 0 => continue,
+            Err(_) => return Err(format!("Line {}: {} is not a date", line, &record[0])),
+        };
+        rates.push(ExchangeRate::new(date, &record[1], &record[2], &record[3]).map_err(|e| format!("Line {}: {}", line, e))?);
+    }
+
+    Ok(rates)
+}
+
+/// Reads the European Central Bank's reference rates, as published in `eurofxref-daily.xml` and
+/// `eurofxref-hist.xml`. Every rate is from the euro.
+pub fn parse_ecb(content: &str) -> Result<Vec<ExchangeRate>, String> {
+    let mut reader = Reader::from_str(content);
+    reader.trim_text(true);
+
+    let mut rates = vec![];
+    let mut date = None;
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) | Ok(Event::Empty(e)) if e.local_name().as_ref() == b"Cube" => {
+                let mut currency = None;
+                let mut rate = None;
+                for attribute in e.attributes().flatten() {
+                    let value = attribute.unescape_value().map(|v| v.to_string()).unwrap_or_default();
+                    match attribute.key.local_name().as_ref() {
+                        b"time" => date = Some(NaiveDate::parse_from_str(&value, "%Y-%m-%d").map_err(|_| format!("{} is not a date", value))?),
+                        b"currency" => currency = Some(value),
+                        b"rate" => rate = Some(value),
+                        _ => {}
+                    }
+                }
+                if let (Some(date), Some(currency), Some(rate)) = (date, currency, rate) {
+                    rates.push(ExchangeRate::new(date, "EUR", &currency, &rate)?);
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => return Err(format!("Invalid XML: {}", e)),
+        }
+    }
+
+    if rates.is_empty() {
+        return Err("No exchange rates were found.".to_string());
+    }
+    Ok(rates)
+}
+
+/// Reads either an ECB XML file or a CSV file of rates.
+pub fn parse(content: &str) -> Result<Vec<ExchangeRate>, String> {
+    if content.trim_start().starts_with('<') {
+        parse_ecb(content)
+    } else {
+        parse_csv(content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2024, 1, day).unwrap()
+    }
+
+    fn rate(day: u32, base: &str, quote: &str, rate: f64) -> ExchangeRate {
+        ExchangeRate { date: date(day), base: base.to_string(), quote: quote.to_string(), rate }
+    }
+
+    #[test]
+    fn test_convert() {
+        // Setup
+        let rates = Rates::new(vec![
+            rate(2, "EUR", "USD", 1.1),
+            rate(5, "EUR", "USD", 1.2),
+            rate(5, "EUR", "GBP", 0.8),
+            rate(5, "EUR", "JPY", 160.0),
+        ]);
+
+        // Act / Assert
+        assert_eq!(rates.convert(1000, "EUR", "USD", date(3)), Some(1100), "The latest rate on or before the day is used");
+        assert_eq!(rates.convert(1000, "EUR", "USD", date(9)), Some(1200));
+        assert_eq!(rates.convert(1200, "USD", "EUR", date(9)), Some(1000), "Rates work in both directions");
+        assert_eq!(rates.convert(1200, "USD", "GBP", date(9)), Some(800), "Rates can go through the euro");
+        assert_eq!(rates.convert(1000, "EUR", "JPY", date(9)), Some(1600), "Yen have no minor unit");
+        assert_eq!(rates.convert(1000, "EUR", "GBP", date(1)), None, "There is no rate yet");
+        assert_eq!(rates.convert(1000, "CHF", "GBP", date(9)), None);
+        assert_eq!(rates.convert(1000, "CHF", "CHF", date(1)), Some(1000));
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        // Setup
+        let content = "date,base,quote,rate\n2024-01-05,eur,USD,1.0921\n\n2024-01-05, GBP , EUR , 1.16\n";
+
+        // Act
+        let rates = parse(content);
+
+        // Assert
+        assert_eq!(rates, Ok(vec![rate(5, "EUR", "USD", 1.0921), rate(5, "GBP", "EUR", 1.16)]));
+        assert!(parse("2024-01-05,EUR,XYZ,1.0").is_err());
+        assert!(parse("2024-01-05,EUR,USD,0").is_err());
+    }
+
+    #[test]
+    fn test_parse_ecb() {
+        // Setup
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gesmes:Envelope xmlns:gesmes="http://www.gesmes.org/xml/2002-08-01" xmlns="http://www.ecb.int/vocabulary/2002-08-01/eurofxref">
+    <gesmes:subject>Reference rates</gesmes:subject>
+    <Cube>
+        <Cube time="2024-01-05">
+            <Cube currency="USD" rate="1.0921"/>
+            <Cube currency="JPY" rate="158.48"/>
+        </Cube>
+        <Cube time="2024-01-04">
+            <Cube currency="USD" rate="1.0953"/>
+        </Cube>
+    </Cube>
+</gesmes:Envelope>"#;
+
+        // Act
+        let rates = parse(content);
+
+        // Assert
+        assert_eq!(rates, Ok(vec![rate(5, "EUR", "USD", 1.0921), rate(5, "EUR", "JPY", 158.48), rate(4, "EUR", "USD", 1.0953)]));
+        assert!(parse("<Cube></Cube>").is_err());
+    }
+}
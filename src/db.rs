@@ -1,8 +1,9 @@
-use bcrypt;
+use std::collections::HashSet;
+
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, Pool, Sqlite};
+use sqlx::{prelude::FromRow, sqlite::SqliteQueryResult, Pool, Sqlite, SqliteConnection, SqliteExecutor};
 
-use crate::helpers::get_total_as_formatted_string;
+use crate::{currency::ExchangeRate, helpers::{format_money, Locale, LOCALES}, audit::{self, Audit}, budgets, history::{self, Table}, import::csv::CsvMapping, matching::DATE_WINDOW_DAYS, rules::{self, Rule}, search, webhooks};
 
 #[derive(Serialize, Deserialize, FromRow)]
 pub struct User {
@@ -11,7 +12,8 @@ pub struct User {
     pub email: String,
     pub password: String,
     pub active: bool,
-
+    /// How the user writes amounts of money, such as `en-GB`.
+    pub locale: String,
 }
 
 #[derive(Debug)]
@@ -25,7 +27,8 @@ impl User {
                 name,
                 email,
                 password: v,
-                active: true
+                active: true,
+                locale: LOCALES[0].code.to_string(),
             }),
             _ => Err(HashError)
         }
@@ -38,11 +41,16 @@ impl User {
             email,
             active,
             password,
+            locale: LOCALES[0].code.to_string(),
         }
     }
 
+    pub fn locale(&self) -> &'static Locale {
+        Locale::find(&self.locale)
+    }
+
     pub fn hash_password(password: String) -> Result<String, HashError> {
-        match bcrypt::hash(&password.as_bytes(), 10) {
+        match bcrypt::hash(password.as_bytes(), 10) {
             Ok(v) => Ok(v),
             _ => Err(HashError)
         }
@@ -80,10 +88,7 @@ pub async fn get_user(conn: &Pool<Sqlite>, email: String) -> Option<User> {
         .fetch_one(conn)
         .await;
 
-    match result {
-        Ok(row) => Some(row),
-        Err(_) => None
-    }
+    result.ok()
 }
 
 #[derive(Serialize, Deserialize, FromRow)]
@@ -91,28 +96,42 @@ pub struct Account {
     pub id: i32,
     pub name: String,
     pub total: i64,
+    /// The balance the bank reported on `reconcile_date`, taken from the last imported statement.
+    pub reconcile_balance: Option<i64>,
+    pub reconcile_date: Option<chrono::NaiveDate>,
+    /// The ISO 4217 code of the account's currency, such as `GBP`.
+    pub currency: String,
+    /// Tracking accounts, such as a pension, count towards net worth but not the budget.
+    pub on_budget: bool,
 }
 
 impl Account {
-    pub fn get_total_as_formatted_string(&self) -> String {
-        get_total_as_formatted_string(self.total)
+    pub fn get_total_as_formatted_string(&self, locale: &Locale) -> String {
+        format_money(self.total, &self.currency, locale)
     }
 }
 
-pub async fn get_accounts_for_user(conn: &Pool<Sqlite>, id: i32) -> Option<Vec<Account>> {
-    let results = sqlx::query_as::<_, Account>(r#"SELECT *, (SELECT sum(inflow) - sum(outflow) FROM transactions WHERE accounts.id = transactions.account_id) as "total" FROM accounts WHERE user_id = ?"#)
+pub async fn get_accounts_for_budget(conn: &Pool<Sqlite>, id: i32) -> Option<Vec<Account>> {
+    let results = sqlx::query_as::<_, Account>(r#"SELECT *, (SELECT sum(inflow) - sum(outflow) FROM transactions WHERE accounts.id = transactions.account_id) as "total" FROM accounts WHERE budget_id = ?"#)
         .bind(id)
         .bind(id)
         .fetch_all(conn)
         .await;
 
-    match results {
-        Ok(r) => Some(r),
-        _ => None
-    }
+    results.ok()
 }
 
-#[derive(sqlx::FromRow)]
+pub async fn get_account(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Option<Account> {
+    let result = sqlx::query_as::<_, Account>(r#"SELECT *, (SELECT sum(inflow) - sum(outflow) FROM transactions WHERE accounts.id = transactions.account_id) as "total" FROM accounts WHERE budget_id = ? AND id = ?"#)
+        .bind(budget_id)
+        .bind(id)
+        .fetch_one(conn)
+        .await;
+
+    result.ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, sqlx::FromRow)]
 pub struct Transaction {
     pub id: i32,
     pub account_id: i32,
@@ -121,15 +140,57 @@ pub struct Transaction {
     pub inflow: i64,
     pub outflow: i64,
     pub cleared: bool,
+    /// The payee's name, or the text the transaction was entered with when it has no payee.
+    pub payee: Option<String>,
+    pub payee_id: Option<i64>,
+    pub import_id: Option<String>,
+    pub value_date: Option<chrono::NaiveDate>,
+    /// Whether the transaction came from a bank file rather than being entered by hand.
+    pub imported: bool,
+    pub category_id: Option<i64>,
+    /// The category's name, or "Split" when the transaction is split across categories.
+    pub category: Option<String>,
+    pub transfer_id: Option<i64>,
+    /// A colour from `rules::FLAGS`.
+    pub flag: Option<String>,
+    pub notes: Option<String>,
+    /// The transaction's tag names, separated by commas.
+    pub tags: Option<String>,
 }
-pub async fn get_transactions_for_account(conn: &Pool<Sqlite>, id: i32) -> Option<Vec<Transaction>> {
-    let result = sqlx::query_as::<_, Transaction>("SELECT * FROM transactions WHERE account_id = ?")
-        .bind(id)
+
+impl Transaction {
+    pub fn tags(&self) -> Vec<&str> {
+        let mut tags: Vec<&str> = self.tags.as_deref().unwrap_or_default().split(',').filter(|t| !t.is_empty()).collect();
+        tags.sort_unstable_by_key(|t| t.to_lowercase());
+        tags
+    }
+}
+
+/// Selects transactions along with their payee's and category's names.
+const TRANSACTION_SELECT: &str = "SELECT t.id, t.account_id, t.date, t.memo, t.inflow, t.outflow, t.cleared, COALESCE(p.name, t.payee) AS payee, t.payee_id, t.import_id, t.value_date, t.imported, t.category_id, t.transfer_id, t.flag, t.notes, (SELECT group_concat(g.name) FROM transaction_tags tt JOIN tags g ON g.id = tt.tag_id WHERE tt.transaction_id = t.id) AS tags, CASE WHEN EXISTS (SELECT 1 FROM transaction_splits s WHERE s.transaction_id = t.id) THEN 'Split' ELSE c.name END AS category FROM transactions t LEFT JOIN categories c ON c.id = t.category_id LEFT JOIN payees p ON p.id = t.payee_id";
+
+/// The account's transactions that could be proposed as matches: uncleared manual ones, and
+/// imported ones with the same amount as one of them within the matching window. Uncleared manual
+/// transactions are few, so this stays small however long the account's history is.
+pub async fn get_match_candidates(conn: &Pool<Sqlite>, account_id: i32) -> Option<Vec<Transaction>> {
+    let query = format!(
+        "{} WHERE t.account_id = ?1 AND ((NOT t.imported AND NOT t.cleared) OR t.id IN (
+            SELECT i.id FROM transactions m JOIN transactions i ON i.account_id = m.account_id
+            WHERE m.account_id = ?1 AND NOT m.imported AND NOT m.cleared AND i.imported
+                AND i.date >= date(m.date, ?2) AND i.date < date(m.date, ?3)
+                AND i.inflow - i.outflow = m.inflow - m.outflow
+        ))",
+        TRANSACTION_SELECT,
+    );
+    let result = sqlx::query_as::<_, Transaction>(&query)
+        .bind(account_id)
+        .bind(format!("-{} days", DATE_WINDOW_DAYS))
+        .bind(format!("+{} days", DATE_WINDOW_DAYS + 1))
         .fetch_all(conn)
         .await;
 
     match result {
-        Ok(row) => Some(row),
+        Ok(rows) => Some(rows),
         Err(e) => {
             println!("{:?}", e);
             None
@@ -137,63 +198,1817 @@ pub async fn get_transactions_for_account(conn: &Pool<Sqlite>, id: i32) -> Optio
     }
 }
 
-pub async fn create_account(conn: &Pool<Sqlite>, user_id: i32, name: &str, starting_balance: i64) -> Result<(), &'static str> {
-    let insert_result = sqlx::query("INSERT INTO accounts (user_id, name) values (?, ?)")
-        .bind(user_id)
-        .bind(name)
-        .execute(conn)
+/// What the account's cleared transactions add up to.
+pub async fn get_cleared_balance(conn: &Pool<Sqlite>, account_id: i32) -> i64 {
+    let result = sqlx::query_scalar("SELECT COALESCE(SUM(inflow - outflow), 0) FROM transactions WHERE account_id = ? AND cleared")
+        .bind(account_id)
+        .fetch_one(conn)
         .await;
 
-    if insert_result.is_err() {
-        return Err("failed to create account");
+    match result {
+        Ok(balance) => balance,
+        Err(e) => {
+            println!("{:?}", e);
+            0
+        }
     }
+}
 
-    let starting_balance_result = sqlx::query("INSERT INTO transactions (account_id, date, memo, inflow, cleared) values (?, datetime(), ?, ?, ?)")
-        .bind(insert_result.as_ref().unwrap().last_insert_rowid())
-        .bind("Starting balance")
-        .bind(starting_balance)
-        .bind(true)
-        .execute(conn)
+pub async fn get_transaction(conn: &Pool<Sqlite>, id: i64) -> Option<Transaction> {
+    let result = sqlx::query_as::<_, Transaction>(&format!("{} WHERE t.id = ?", TRANSACTION_SELECT))
+        .bind(id)
+        .fetch_one(conn)
+        .await;
+
+    result.ok()
+}
+
+/// Gets one of the budget's transactions.
+pub async fn get_transaction_for_budget(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Option<Transaction> {
+    let result = sqlx::query_as::<_, Transaction>(&format!("{} JOIN accounts a ON a.id = t.account_id WHERE t.id = ? AND a.budget_id = ?", TRANSACTION_SELECT))
+        .bind(id)
+        .bind(budget_id)
+        .fetch_one(conn)
+        .await;
+
+    result.ok()
+}
+
+/// The parts of a transaction that only mean something to the user.
+pub struct TransactionDetails {
+    pub flag: Option<String>,
+    pub tags: Vec<String>,
+    pub notes: Option<String>,
+}
+
+/// Sets the flag, tags and notes of one of the budget's transactions, as a change the user can
+/// undo. Tags are created the first time they are used.
+pub async fn update_transaction_details(conn: &Pool<Sqlite>, user_id: i32, budget_id: i32, id: i64, details: &TransactionDetails) -> Result<(), &'static str> {
+    let result: Result<u64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut recorder = history::Recorder::default();
+        recorder.watch(&mut tx, Table::Transactions, "id", &[id]).await?;
+        recorder.watch(&mut tx, Table::TransactionTags, "transaction_id", &[id]).await?;
+        let updated = sqlx::query("UPDATE transactions SET flag = ?, notes = ? WHERE id = ? AND account_id IN (SELECT id FROM accounts WHERE budget_id = ?)")
+            .bind(&details.flag)
+            .bind(&details.notes)
+            .bind(id)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?;
+        if updated.rows_affected() == 1 {
+            sqlx::query("DELETE FROM transaction_tags WHERE transaction_id = ?")
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            for tag in &details.tags {
+                sqlx::query("INSERT OR IGNORE INTO tags (budget_id, name) VALUES (?, ?)")
+                    .bind(budget_id)
+                    .bind(tag)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("INSERT OR IGNORE INTO transaction_tags (transaction_id, tag_id) SELECT ?, id FROM tags WHERE budget_id = ? AND name = ?")
+                    .bind(id)
+                    .bind(budget_id)
+                    .bind(tag)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            recorder.save(&mut tx, user_id.into(), budget_id.into(), "Changed a transaction's flag, tags and notes").await?;
+        }
+        tx.commit().await?;
+        Ok(updated.rows_affected())
+    }.await;
+
+    match result {
+        Ok(1) => Ok(()),
+        Ok(_) => Err("transaction not found"),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to update transaction")
+        }
+    }
+}
+
+/// A change made to many transactions at once.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BulkEdit {
+    /// Also undoes any splits.
+    Categorise(i64),
+    /// The payee is created if it is new.
+    SetPayee(String),
+    SetCleared(bool),
+    SetFlag(Option<String>),
+    /// To another account in the same currency.
+    Move(i64),
+    Delete,
+}
+
+/// Has the recorder watch the transactions, with their tags and splits, and the other halves of
+/// any transfers, which lose their link when these are deleted.
+async fn watch_transactions(conn: &mut SqliteConnection, recorder: &mut history::Recorder, ids: &[i64]) -> sqlx::Result<()> {
+    let mut query = sqlx::QueryBuilder::<Sqlite>::new("SELECT id FROM transactions WHERE transfer_id IN ");
+    push_ids(&mut query, ids);
+    let transfers: Vec<(i64,)> = query.build_query_as().fetch_all(&mut *conn).await?;
+    let transfers: Vec<i64> = transfers.into_iter().map(|(id,)| id).collect();
+
+    recorder.watch(&mut *conn, Table::Transactions, "id", ids).await?;
+    recorder.watch(&mut *conn, Table::Transactions, "id", &transfers).await?;
+    recorder.watch(&mut *conn, Table::TransactionTags, "transaction_id", ids).await?;
+    recorder.watch(conn, Table::TransactionSplits, "transaction_id", ids).await
+}
+
+/// Adds `(1, 2, 3)` for the ids to the query.
+fn push_ids(query: &mut sqlx::QueryBuilder<Sqlite>, ids: &[i64]) {
+    query.push("(");
+    let mut separated = query.separated(", ");
+    for id in ids {
+        separated.push_bind(*id);
+    }
+    separated.push_unseparated(")");
+}
+
+impl BulkEdit {
+    /// What the change did, for the history.
+    fn describe(&self, count: usize) -> String {
+        let transactions = if count == 1 { "1 transaction".to_string() } else { format!("{} transactions", count) };
+        match self {
+            BulkEdit::Categorise(_) => format!("Categorised {}", transactions),
+            BulkEdit::SetPayee(name) => format!("Set the payee of {} to {}", transactions, name),
+            BulkEdit::SetCleared(true) => format!("Cleared {}", transactions),
+            BulkEdit::SetCleared(false) => format!("Uncleared {}", transactions),
+            BulkEdit::SetFlag(Some(flag)) => format!("Flagged {} {}", transactions, flag),
+            BulkEdit::SetFlag(None) => format!("Removed the flag from {}", transactions),
+            BulkEdit::Move(_) => format!("Moved {}", transactions),
+            BulkEdit::Delete => format!("Deleted {}", transactions),
+        }
+    }
+}
+
+/// Makes the change to every one of the budget's transactions with these ids in one database
+/// transaction, so either all of them change or none do, and records it so the user can undo it.
+/// Nothing changes if any of them can't be found. Returns the storage keys of the attachments of
+/// deleted transactions, whose files are left for the caller to remove.
+pub async fn bulk_edit(conn: &Pool<Sqlite>, user_id: i32, budget_id: i32, ids: &[i64], edit: &BulkEdit) -> Result<Vec<String>, &'static str> {
+    if ids.is_empty() {
+        return Err("No transactions are selected.");
+    }
+    let mut ids = ids.to_vec();
+    ids.sort_unstable();
+    ids.dedup();
+    let ids = ids.as_slice();
+
+    let result: Result<Result<Vec<String>, &'static str>, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut query = sqlx::QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM transactions t JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ");
+        query.push_bind(budget_id).push(" AND t.id IN ");
+        push_ids(&mut query, ids);
+        let (found,): (i64,) = query.build_query_as().fetch_one(&mut *tx).await?;
+        if found != ids.len() as i64 {
+            return Ok(Err("Some of the transactions could not be found."));
+        }
+
+        let mut recorder = history::Recorder::default();
+        watch_transactions(&mut tx, &mut recorder, ids).await?;
+
+        let mut storage_keys = vec![];
+        let mut query = match edit {
+            BulkEdit::Categorise(category_id) => {
+                let owned: Option<(i64,)> = sqlx::query_as("SELECT c.id FROM categories c JOIN category_groups g ON g.id = c.group_id WHERE c.id = ? AND g.budget_id = ?")
+                    .bind(category_id)
+                    .bind(budget_id)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                if owned.is_none() {
+                    return Ok(Err("That category could not be found."));
+                }
+                let mut splits = sqlx::QueryBuilder::<Sqlite>::new("DELETE FROM transaction_splits WHERE transaction_id IN ");
+                push_ids(&mut splits, ids);
+                splits.build().execute(&mut *tx).await?;
+
+                let mut query = sqlx::QueryBuilder::new("UPDATE transactions SET category_id = ");
+                query.push_bind(*category_id);
+                query
+            }
+            BulkEdit::SetPayee(name) => {
+                sqlx::query("INSERT INTO payees (budget_id, name) VALUES (?, ?) ON CONFLICT (budget_id, name) DO NOTHING")
+                    .bind(budget_id)
+                    .bind(name)
+                    .execute(&mut *tx)
+                    .await?;
+                let (payee_id,): (i64,) = sqlx::query_as("SELECT id FROM payees WHERE budget_id = ? AND name = ?")
+                    .bind(budget_id)
+                    .bind(name)
+                    .fetch_one(&mut *tx)
+                    .await?;
+
+                let mut query = sqlx::QueryBuilder::new("UPDATE transactions SET payee_id = ");
+                query.push_bind(payee_id);
+                query
+            }
+            BulkEdit::SetCleared(cleared) => {
+                let mut query = sqlx::QueryBuilder::new("UPDATE transactions SET cleared = ");
+                query.push_bind(*cleared);
+                query
+            }
+            BulkEdit::SetFlag(flag) => {
+                let mut query = sqlx::QueryBuilder::new("UPDATE transactions SET flag = ");
+                query.push_bind(flag.clone());
+                query
+            }
+            BulkEdit::Move(account_id) => {
+                let currency: Option<(String,)> = sqlx::query_as("SELECT currency FROM accounts WHERE id = ? AND budget_id = ?")
+                    .bind(account_id)
+                    .bind(budget_id)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                let Some((currency,)) = currency else {
+                    return Ok(Err("That account could not be found."));
+                };
+                let mut query = sqlx::QueryBuilder::<Sqlite>::new("SELECT COUNT(*) FROM transactions t JOIN accounts a ON a.id = t.account_id WHERE a.currency <> ");
+                query.push_bind(currency).push(" AND t.id IN ");
+                push_ids(&mut query, ids);
+                let (other_currencies,): (i64,) = query.build_query_as().fetch_one(&mut *tx).await?;
+                if other_currencies > 0 {
+                    return Ok(Err("Transactions can only be moved to an account in the same currency."));
+                }
+
+                let mut query = sqlx::QueryBuilder::new("UPDATE transactions SET account_id = ");
+                query.push_bind(*account_id);
+                query
+            }
+            BulkEdit::Delete => {
+                let mut keys = sqlx::QueryBuilder::<Sqlite>::new("SELECT storage_key FROM attachments WHERE transaction_id IN ");
+                push_ids(&mut keys, ids);
+                let keys: Vec<(String,)> = keys.build_query_as().fetch_all(&mut *tx).await?;
+                storage_keys = keys.into_iter().map(|(key,)| key).collect();
+
+                sqlx::QueryBuilder::new("DELETE FROM transactions")
+            }
+        };
+        query.push(" WHERE id IN ");
+        push_ids(&mut query, ids);
+        query.build().execute(&mut *tx).await?;
+        recorder.save(&mut tx, user_id.into(), budget_id.into(), &edit.describe(ids.len())).await?;
+
+        tx.commit().await?;
+        Ok(Ok(storage_keys))
+    }.await;
+
+    match result {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to update the transactions.")
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub struct Attachment {
+    pub id: i64,
+    pub transaction_id: i64,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub storage_key: String,
+}
+
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        self.content_type.starts_with("image/")
+    }
+}
+
+/// The attachments of every transaction in the account.
+pub async fn get_attachments_for_account(conn: &Pool<Sqlite>, account_id: i32) -> Vec<Attachment> {
+    let result = sqlx::query_as::<_, Attachment>("SELECT f.id, f.transaction_id, f.filename, f.content_type, f.size, f.storage_key FROM attachments f JOIN transactions t ON t.id = f.transaction_id WHERE t.account_id = ? ORDER BY f.id")
+        .bind(account_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+pub async fn get_attachments_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Attachment> {
+    let result = sqlx::query_as::<_, Attachment>("SELECT f.id, f.transaction_id, f.filename, f.content_type, f.size, f.storage_key FROM attachments f JOIN transactions t ON t.id = f.transaction_id JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ? ORDER BY f.id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+pub async fn get_attachments_for_transaction(conn: &Pool<Sqlite>, transaction_id: i64) -> Vec<Attachment> {
+    let result = sqlx::query_as::<_, Attachment>("SELECT id, transaction_id, filename, content_type, size, storage_key FROM attachments WHERE transaction_id = ? ORDER BY id")
+        .bind(transaction_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+/// Gets one of the budget's attachments.
+pub async fn get_attachment(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Option<Attachment> {
+    let result = sqlx::query_as::<_, Attachment>("SELECT f.id, f.transaction_id, f.filename, f.content_type, f.size, f.storage_key FROM attachments f JOIN transactions t ON t.id = f.transaction_id JOIN accounts a ON a.id = t.account_id WHERE f.id = ? AND a.budget_id = ?")
+        .bind(id)
+        .bind(budget_id)
+        .fetch_one(conn)
         .await;
 
-    return match starting_balance_result {
+    result.ok()
+}
+
+/// Records a file that has already been written to storage.
+pub async fn create_attachment(conn: &Pool<Sqlite>, transaction_id: i64, filename: &str, content_type: &str, size: i64, storage_key: &str) -> Result<i64, &'static str> {
+    let result: Result<i64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = sqlx::query("INSERT INTO attachments (transaction_id, filename, content_type, size, storage_key) VALUES (?, ?, ?, ?, ?)")
+            .bind(transaction_id)
+            .bind(filename)
+            .bind(content_type)
+            .bind(size)
+            .bind(storage_key)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        let mut audit = Audit::default();
+        audit.created("attachments", "id", &[id]);
+        audit.save(&mut tx, None).await?;
+        tx.commit().await?;
+        Ok(id)
+    }.await;
+
+    match result {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to create attachment")
+        }
+    }
+}
+
+/// Forgets an attachment. Removing the file from storage is up to the caller.
+pub async fn delete_attachment(conn: &Pool<Sqlite>, id: i64) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "attachments", "id", &[id]).await?;
+        sqlx::query("DELETE FROM attachments WHERE id = ?")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save(&mut tx, None).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
         Ok(_) => Ok(()),
-        Err(e)=> {
+        Err(e) => {
             println!("{:?}", e);
-            Err("failed to create starting balance")
-        },
+            Err("failed to delete attachment")
+        }
     }
 }
 
-#[derive(Debug)]
-pub struct CreateError;
+/// Adds a condition for each part of the filter to a query over transactions `t`.
+fn push_filter(query: &mut sqlx::QueryBuilder<Sqlite>, filter: &search::Filter) {
+    if let Some(text) = &filter.text {
+        query.push(" AND t.id IN (SELECT rowid FROM transactions_fts WHERE transactions_fts MATCH ").push_bind(text.clone()).push(")");
+    }
+    if let Some(from) = filter.from {
+        query.push(" AND t.date >= ").push_bind(from.and_hms_opt(0, 0, 0).unwrap());
+    }
+    if let Some(to) = filter.to.and_then(|to| to.succ_opt()) {
+        query.push(" AND t.date < ").push_bind(to.and_hms_opt(0, 0, 0).unwrap());
+    }
+    if let Some(min) = filter.min_amount {
+        query.push(" AND abs(t.inflow - t.outflow) >= ").push_bind(min);
+    }
+    if let Some(max) = filter.max_amount {
+        query.push(" AND abs(t.inflow - t.outflow) <= ").push_bind(max);
+    }
+    if let Some(category_id) = filter.category_id {
+        query.push(" AND (t.category_id = ").push_bind(category_id);
+        query.push(" OR EXISTS (SELECT 1 FROM transaction_splits s WHERE s.transaction_id = t.id AND s.category_id = ").push_bind(category_id).push("))");
+    }
+    if let Some(account_id) = filter.account_id {
+        query.push(" AND t.account_id = ").push_bind(account_id);
+    }
+    if let Some(cleared) = filter.cleared {
+        query.push(" AND t.cleared = ").push_bind(cleared);
+    }
+    if let Some(flag) = &filter.flag {
+        query.push(" AND t.flag = ").push_bind(flag.clone());
+    }
+    if let Some(tag) = &filter.tag {
+        query.push(" AND EXISTS (SELECT 1 FROM transaction_tags tt JOIN tags g ON g.id = tt.tag_id WHERE tt.transaction_id = t.id AND g.name = ").push_bind(tag.clone()).push(")");
+    }
+}
 
-pub async fn create_user(conn: &Pool<Sqlite>, user: User) -> Result<bool, CreateError> {
-    let result = sqlx::query("INSERT INTO users (name, email, password, active) VALUES (?, ?, ?, ?)")
-        .bind(user.name)
-        .bind(user.email)
-        .bind(user.password)
-        .bind(user.active)
-        .execute(conn)
+/// What the transactions matching a search add up to in one currency.
+#[derive(Debug, PartialEq, FromRow)]
+pub struct RegisterTotal {
+    pub currency: String,
+    pub transactions: i64,
+    pub inflow: i64,
+    pub outflow: i64,
+}
+
+/// Totals of every transaction that matches the filter, not just the page shown, for each currency.
+pub async fn get_register_totals(conn: &Pool<Sqlite>, budget_id: i32, filter: &search::Filter) -> Vec<RegisterTotal> {
+    let mut query = sqlx::QueryBuilder::<Sqlite>::new("SELECT a.currency, COUNT(*) AS transactions, SUM(t.inflow) AS inflow, SUM(t.outflow) AS outflow FROM transactions t JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ");
+    query.push_bind(budget_id);
+    push_filter(&mut query, filter);
+    query.push(" GROUP BY a.currency ORDER BY a.currency");
+
+    match query.build_query_as::<RegisterTotal>().fetch_all(conn).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// How many transactions the register shows at a time.
+pub const REGISTER_PAGE_SIZE: i64 = 100;
+
+/// A transaction in the register.
+#[derive(Debug, FromRow)]
+pub struct RegisterRow {
+    #[sqlx(flatten)]
+    pub transaction: Transaction,
+    /// The account's balance once this and every earlier transaction are counted.
+    pub balance: i64,
+}
+
+fn sort_key(sort: search::Sort) -> &'static str {
+    match sort {
+        search::Sort::Date => "t.date",
+        search::Sort::Payee => "lower(COALESCE(t.payee, ''))",
+        search::Sort::Category => "lower(COALESCE(t.category, ''))",
+        search::Sort::Amount => "t.inflow - t.outflow",
+    }
+}
+
+/// A page of the budget's transactions that match the filter, in the given order. `after` is the
+/// last transaction of the previous page. Balances are worked out before filtering, so they are
+/// always the account's real balance.
+pub async fn get_register(conn: &Pool<Sqlite>, budget_id: i32, filter: &search::Filter, order: search::Order, after: Option<i64>) -> Vec<RegisterRow> {
+    let mut query = sqlx::QueryBuilder::<Sqlite>::new("WITH register AS (SELECT r.*, SUM(r.inflow - r.outflow) OVER (PARTITION BY r.account_id ORDER BY r.date, r.id) AS balance FROM (");
+    query.push(TRANSACTION_SELECT).push(" JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ").push_bind(budget_id);
+    if let Some(account_id) = filter.account_id {
+        query.push(" AND t.account_id = ").push_bind(account_id);
+    }
+    query.push(") r) SELECT * FROM register t WHERE TRUE");
+    push_filter(&mut query, filter);
+
+    let key = sort_key(order.sort);
+    let (comparison, direction) = if order.descending { ("<", "DESC") } else { (">", "ASC") };
+    if let Some(after) = after {
+        query.push(format!(" AND ({}, t.id) {} (SELECT {}, t.id FROM register t WHERE t.id = ", key, comparison, key)).push_bind(after).push(")");
+    }
+    query.push(format!(" ORDER BY {} {}, t.id {} LIMIT ", key, direction, direction)).push_bind(REGISTER_PAGE_SIZE);
+
+    match query.build_query_as::<RegisterRow>().fetch_all(conn).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// The names of every tag in the budget.
+pub async fn get_tags_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<String> {
+    let result: Result<Vec<(String,)>, sqlx::Error> = sqlx::query_as("SELECT name FROM tags WHERE budget_id = ? ORDER BY name COLLATE NOCASE")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default().into_iter().map(|(name,)| name).collect()
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Tag {
+    pub name: String,
+    pub currency: String,
+    pub transactions: i64,
+    pub inflow: i64,
+    pub outflow: i64,
+}
+
+/// Every tag used in the budget, with how much money went in and out under it in each currency.
+/// Split transactions count once, at their full amount.
+pub async fn get_tag_totals(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Tag> {
+    let result = sqlx::query_as::<_, Tag>("SELECT g.name, a.currency, count(*) AS transactions, COALESCE(sum(t.inflow), 0) AS inflow, COALESCE(sum(t.outflow), 0) AS outflow FROM tags g JOIN transaction_tags tt ON tt.tag_id = g.id JOIN transactions t ON t.id = tt.transaction_id JOIN accounts a ON a.id = t.account_id WHERE g.budget_id = ? GROUP BY g.id, a.currency ORDER BY g.name COLLATE NOCASE, a.currency")
+        .bind(budget_id)
+        .fetch_all(conn)
         .await;
 
     match result {
-        Ok(_) => Ok(true),
-        Err(_) => Err(CreateError)
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// A transaction that has not been written to the database yet.
+pub struct NewTransaction {
+    pub account_id: i64,
+    pub date: chrono::NaiveDateTime,
+    pub value_date: Option<chrono::NaiveDate>,
+    pub payee: Option<String>,
+    pub memo: String,
+    pub inflow: i64,
+    pub outflow: i64,
+    pub cleared: bool,
+    pub import_id: Option<String>,
+    pub imported: bool,
+    pub category_id: Option<i64>,
+}
+
+/// Finds the payee with this name or alias in the account's budget, creating it if there is none,
+/// and returns its id and default category.
+async fn payee_for_account(conn: &mut SqliteConnection, account_id: i64, name: &str) -> Result<(i64, Option<i64>), sqlx::Error> {
+    let find = || sqlx::query_as("SELECT p.id, p.default_category_id FROM payees p JOIN accounts a ON a.budget_id = p.budget_id WHERE a.id = ? AND (p.name = ? OR p.id IN (SELECT payee_id FROM payee_aliases WHERE name = ?)) ORDER BY p.name = ? DESC LIMIT 1")
+        .bind(account_id)
+        .bind(name)
+        .bind(name)
+        .bind(name);
+
+    if let Some(payee) = find().fetch_optional(&mut *conn).await? {
+        return Ok(payee);
     }
+    sqlx::query("INSERT INTO payees (budget_id, name) SELECT budget_id, ? FROM accounts WHERE id = ? ON CONFLICT (budget_id, name) DO NOTHING")
+        .bind(name)
+        .bind(account_id)
+        .execute(&mut *conn)
+        .await?;
+    find().fetch_optional(conn).await?.ok_or(sqlx::Error::RowNotFound)
 }
 
+/// Inserts a transaction and queues `transaction.created` webhooks for the account's budget.
+///
+/// The budget's rules run first and may rename the payee, set the memo, category and flag, though a
+/// category the transaction was entered with is kept. The payee is created if it is new, and its
+/// default category is used when the transaction still has none.
+pub async fn create_transaction(conn: &Pool<Sqlite>, transaction: &NewTransaction) -> Result<i64, &'static str> {
+    let result: Result<i64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = insert_transaction(&mut tx, transaction).await?;
+        tx.commit().await?;
+        Ok(id)
+    }.await;
 
-#[sqlx::test]
-async fn test_get_user(pool: Pool<Sqlite>) -> sqlx::Result<()> {
-    // Setup
-    sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?;
+    let id = match result {
+        Ok(id) => id,
+        Err(e) => {
+            println!("{:?}", e);
+            return Err("failed to create transaction");
+        }
+    };
 
-    // Act
-    let user = get_user(&pool, "test@example.com".to_string()).await.expect("User is not found.");
+    transaction_created(conn, id).await;
+    Ok(id)
+}
 
-    // Assert
-    assert_eq!(user.name, "test");
+/// Inserts a transaction like `create_transaction`, as part of a larger change such as an import.
+/// Webhooks are left to `transaction_created` once the change is committed.
+pub async fn insert_transaction(conn: &mut SqliteConnection, transaction: &NewTransaction) -> sqlx::Result<i64> {
+    let mut subject = rules::Subject {
+        account_id: transaction.account_id,
+        payee: transaction.payee.clone(),
+        memo: transaction.memo.clone(),
+        amount: transaction.inflow - transaction.outflow,
+        category_id: None,
+        flag: None,
+    };
+    rules::apply(&get_rules_for_account(&mut *conn, transaction.account_id).await, &mut subject);
+
+    let (payee_id, default_category_id) = match subject.payee.as_deref().map(str::trim).filter(|p| !p.is_empty()) {
+        Some(name) => {
+            let (id, category_id) = payee_for_account(&mut *conn, transaction.account_id, name).await?;
+            (Some(id), category_id)
+        }
+        None => (None, None),
+    };
+
+    let id = sqlx::query("INSERT INTO transactions (account_id, date, value_date, payee, payee_id, memo, inflow, outflow, cleared, import_id, imported, category_id, flag) values (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+        .bind(transaction.account_id)
+        .bind(transaction.date)
+        .bind(transaction.value_date)
+        .bind(&subject.payee)
+        .bind(payee_id)
+        .bind(&subject.memo)
+        .bind(transaction.inflow)
+        .bind(transaction.outflow)
+        .bind(transaction.cleared)
+        .bind(&transaction.import_id)
+        .bind(transaction.imported)
+        .bind(transaction.category_id.or(subject.category_id).or(default_category_id))
+        .bind(&subject.flag)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    let (budget_id,): (i32,) = sqlx::query_as("SELECT budget_id FROM accounts WHERE id = ?")
+        .bind(transaction.account_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let mut audit = Audit::default();
+    audit.created("transactions", "id", &[id]);
+    audit.save_in_budget(conn, budget_id.into()).await?;
+    Ok(id)
+}
+
+/// Queues `transaction.created` webhooks for a transaction that was just committed.
+pub async fn transaction_created(conn: &Pool<Sqlite>, id: i64) {
+    if let Some(t) = get_transaction(conn, id).await {
+        webhooks::transaction_created(conn, &t).await;
+    }
+}
+
+pub async fn transaction_import_id_exists(conn: impl SqliteExecutor<'_>, account_id: i64, import_id: &str) -> bool {
+    let result: Result<(i64,), sqlx::Error> = sqlx::query_as("SELECT count(*) FROM transactions WHERE account_id = ? AND import_id = ?")
+        .bind(account_id)
+        .bind(import_id)
+        .fetch_one(conn)
+        .await;
+
+    matches!(result, Ok((count,)) if count > 0)
+}
+
+/// Pairs of `(imported, manual)` transaction ids in the account that the user said are not the
+/// same transaction.
+pub async fn get_dismissed_matches(conn: &Pool<Sqlite>, account_id: i64) -> HashSet<(i32, i32)> {
+    let result: Result<Vec<(i32, i32)>, sqlx::Error> = sqlx::query_as("SELECT d.imported_id, d.manual_id FROM dismissed_matches d JOIN transactions t ON t.id = d.imported_id WHERE t.account_id = ?")
+        .bind(account_id)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(rows) => rows.into_iter().collect(),
+        Err(e) => {
+            println!("{:?}", e);
+            HashSet::new()
+        }
+    }
+}
+
+pub async fn dismiss_match(conn: &Pool<Sqlite>, imported_id: i64, manual_id: i64) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "dismissed_matches", "imported_id", &[imported_id]).await?;
+        sqlx::query("INSERT OR IGNORE INTO dismissed_matches (imported_id, manual_id) VALUES (?, ?)")
+            .bind(imported_id)
+            .bind(manual_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save(&mut tx, None).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to dismiss match")
+        }
+    }
+}
+
+/// Merges an imported transaction into the manually entered one it duplicates. The manual row keeps
+/// its payee and memo, takes the bank's import id and value date, and is marked cleared. The
+/// imported row is deleted. The merge is recorded so the user can undo it.
+pub async fn merge_matched_transactions(conn: &Pool<Sqlite>, user_id: i32, account_id: i64, imported_id: i64, manual_id: i64) -> Result<(), &'static str> {
+    let imported = get_transaction(conn, imported_id).await.filter(|t| i64::from(t.account_id) == account_id && t.imported);
+    let manual = get_transaction(conn, manual_id).await.filter(|t| i64::from(t.account_id) == account_id && !t.imported);
+    let imported = match (imported, manual) {
+        (Some(i), Some(_)) => i,
+        _ => return Err("transactions to match were not found"),
+    };
+
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let ids = [imported_id, manual_id];
+        let mut recorder = history::Recorder::default();
+        watch_transactions(&mut tx, &mut recorder, &ids).await?;
+        sqlx::query("DELETE FROM transactions WHERE id = ?")
+            .bind(imported_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE transactions SET cleared = 1, import_id = ?, value_date = COALESCE(?, value_date) WHERE id = ?")
+            .bind(&imported.import_id)
+            .bind(imported.value_date)
+            .bind(manual_id)
+            .execute(&mut *tx)
+            .await?;
+        let (budget_id,): (i64,) = sqlx::query_as("SELECT budget_id FROM accounts WHERE id = ?")
+            .bind(account_id)
+            .fetch_one(&mut *tx)
+            .await?;
+        recorder.save(&mut tx, user_id.into(), budget_id, "Merged an imported transaction").await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to merge transactions")
+        }
+    }
+}
+
+/// Creates an account with no transactions and queues `account.created` webhooks.
+pub async fn create_empty_account(conn: &Pool<Sqlite>, budget_id: i32, name: &str, currency: &str) -> Result<i64, &'static str> {
+    let insert_result: Result<i64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = insert_account(&mut tx, budget_id, name, currency).await?;
+        tx.commit().await?;
+        Ok(id)
+    }.await;
+
+    let account_id = match insert_result {
+        Ok(id) => id,
+        Err(_) => return Err("failed to create account"),
+    };
+
+    account_created(conn, budget_id, account_id, name).await;
+    Ok(account_id)
+}
+
+/// Inserts an account like `create_empty_account`, as part of a larger change such as an import.
+/// Webhooks are left to `account_created` once the change is committed.
+pub async fn insert_account(conn: &mut SqliteConnection, budget_id: i32, name: &str, currency: &str) -> sqlx::Result<i64> {
+    let id = sqlx::query("INSERT INTO accounts (budget_id, name, currency) values (?, ?, ?)")
+        .bind(budget_id)
+        .bind(name)
+        .bind(currency)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    let mut audit = Audit::default();
+    audit.created("accounts", "id", &[id]);
+    audit.save_in_budget(conn, budget_id.into()).await?;
+    Ok(id)
+}
+
+/// Queues `account.created` webhooks for an account that was just committed.
+pub async fn account_created(conn: &Pool<Sqlite>, budget_id: i32, id: i64, name: &str) {
+    webhooks::dispatch(conn, budget_id, webhooks::Event::AccountCreated, serde_json::json!({
+        "id": id,
+        "name": name,
+    })).await;
+}
+
+pub async fn create_account(conn: &Pool<Sqlite>, budget_id: i32, name: &str, currency: &str, starting_balance: i64) -> Result<i64, &'static str> {
+    let account_id = create_empty_account(conn, budget_id, name, currency).await?;
+
+    let starting_balance_result = create_transaction(conn, &NewTransaction {
+        account_id,
+        date: chrono::Utc::now().naive_utc(),
+        value_date: None,
+        payee: None,
+        memo: "Starting balance".to_string(),
+        inflow: starting_balance,
+        outflow: 0,
+        cleared: true,
+        import_id: None,
+        imported: false,
+        category_id: None,
+    }).await;
+
+    match starting_balance_result {
+        Ok(_) => Ok(account_id),
+        Err(_) => Err("failed to create starting balance"),
+    }
+}
+
+#[derive(Debug)]
+pub struct CreateError;
+
+pub async fn create_user(conn: &Pool<Sqlite>, user: User) -> Result<bool, CreateError> {
+    let name = user.name.clone();
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES (?, ?, ?, ?)")
+            .bind(user.name)
+            .bind(user.email)
+            .bind(user.password)
+            .bind(user.active)
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        // Everyone starts with a budget of their own.
+        let budget_id = budgets::insert(&mut tx, id, &format!("{}'s budget", name), "GBP").await?;
+        // Someone signing up is the one making their account.
+        let mut audit = Audit::default();
+        audit.created("users", "id", &[id]);
+        audit.created("budgets", "id", &[budget_id]);
+        audit.created("budget_members", "budget_id", &[budget_id]);
+        audit::in_budget(budget_id, audit.save(&mut tx, Some(id))).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(true),
+        Err(_) => Err(CreateError)
+    }
+}
+
+
+pub async fn set_reconcile_target(conn: &Pool<Sqlite>, account_id: i64, date: chrono::NaiveDate, balance: i64) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "accounts", "id", &[account_id]).await?;
+        sqlx::query("UPDATE accounts SET reconcile_balance = ?, reconcile_date = ? WHERE id = ?")
+            .bind(balance)
+            .bind(date)
+            .bind(account_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save(&mut tx, None).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to set reconciliation target")
+        }
+    }
+}
+
+/// Makes the account a tracking account, or brings it back into the budget.
+pub async fn set_on_budget(conn: &Pool<Sqlite>, account_id: i64, on_budget: bool) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "accounts", "id", &[account_id]).await?;
+        sqlx::query("UPDATE accounts SET on_budget = ? WHERE id = ?")
+            .bind(on_budget)
+            .bind(account_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save(&mut tx, None).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to change whether the account is on budget")
+        }
+    }
+}
+
+pub async fn get_csv_mapping(conn: &Pool<Sqlite>, account_id: i64) -> Option<CsvMapping> {
+    let result: Result<(String,), sqlx::Error> = sqlx::query_as("SELECT mapping FROM csv_import_mappings WHERE account_id = ?")
+        .bind(account_id)
+        .fetch_one(conn)
+        .await;
+
+    result.ok().and_then(|(mapping,)| serde_json::from_str(&mapping).ok())
+}
+
+pub async fn save_csv_mapping(conn: &Pool<Sqlite>, account_id: i64, mapping: &CsvMapping) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "csv_import_mappings", "account_id", &[account_id]).await?;
+        sqlx::query("INSERT INTO csv_import_mappings (account_id, mapping) VALUES (?, ?) ON CONFLICT (account_id) DO UPDATE SET mapping = excluded.mapping")
+            .bind(account_id)
+            .bind(serde_json::to_string(mapping).unwrap())
+            .execute(&mut *tx)
+            .await?;
+        audit.save(&mut tx, None).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to save csv mapping")
+        }
+    }
+}
+
+/// Returns the id of the budget's category group called `name`, creating it if needed.
+pub async fn insert_category_group(conn: &mut SqliteConnection, budget_id: i32, name: &str) -> sqlx::Result<i64> {
+    let mut audit = Audit::default();
+    audit.watch(&mut *conn, "category_groups", "budget_id", &[budget_id.into()]).await?;
+    let (id,) = sqlx::query_as("INSERT INTO category_groups (budget_id, name) VALUES (?, ?) ON CONFLICT (budget_id, name) DO UPDATE SET name = excluded.name RETURNING id")
+        .bind(budget_id)
+        .bind(name)
+        .fetch_one(&mut *conn)
+        .await?;
+    audit.save_in_budget(conn, budget_id.into()).await?;
+    Ok(id)
+}
+
+/// Returns the id of the category called `name` in the group, creating it if needed.
+pub async fn insert_category(conn: &mut SqliteConnection, group_id: i64, name: &str) -> sqlx::Result<i64> {
+    let mut audit = Audit::default();
+    audit.watch(&mut *conn, "categories", "group_id", &[group_id]).await?;
+    let (id,) = sqlx::query_as("INSERT INTO categories (group_id, name) VALUES (?, ?) ON CONFLICT (group_id, name) DO UPDATE SET name = excluded.name RETURNING id")
+        .bind(group_id)
+        .bind(name)
+        .fetch_one(&mut *conn)
+        .await?;
+    audit.save(conn, None).await?;
+    Ok(id)
+}
+
+async fn upsert_assigned(conn: &mut SqliteConnection, category_id: i64, month: chrono::NaiveDate, assigned: i64) -> sqlx::Result<()> {
+    sqlx::query("INSERT INTO category_budgets (category_id, month, assigned) VALUES (?, ?, ?) ON CONFLICT (category_id, month) DO UPDATE SET assigned = excluded.assigned")
+        .bind(category_id)
+        .bind(month)
+        .bind(assigned)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Sets the amount assigned to a category for the month starting on `month`.
+pub async fn assign_to_category(conn: &mut SqliteConnection, category_id: i64, month: chrono::NaiveDate, assigned: i64) -> sqlx::Result<()> {
+    let mut audit = Audit::default();
+    audit.watch(&mut *conn, "category_budgets", "category_id", &[category_id]).await?;
+    upsert_assigned(&mut *conn, category_id, month, assigned).await?;
+    audit.save(conn, None).await?;
+    Ok(())
+}
+
+/// A category's month: what was assigned to it and what came in and went out of it, and what is
+/// left once the month is over, counting every earlier month too. Splits count by part, and
+/// tracking accounts are left out.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct CategoryBalance {
+    pub id: i64,
+    pub group_name: String,
+    pub name: String,
+    pub assigned: i64,
+    pub activity: i64,
+    pub available: i64,
+}
+
+const CATEGORY_BALANCE_SELECT: &str = "WITH activity AS (
+    SELECT t.category_id, t.date, t.inflow - t.outflow AS amount FROM transactions t JOIN accounts a ON a.id = t.account_id
+    WHERE a.budget_id = ?1 AND a.on_budget AND NOT EXISTS (SELECT 1 FROM transaction_splits s WHERE s.transaction_id = t.id)
+    UNION ALL
+    SELECT s.category_id, t.date, s.inflow - s.outflow FROM transaction_splits s JOIN transactions t ON t.id = s.transaction_id JOIN accounts a ON a.id = t.account_id
+    WHERE a.budget_id = ?1 AND a.on_budget
+)
+SELECT c.id, g.name AS group_name, c.name,
+    COALESCE((SELECT b.assigned FROM category_budgets b WHERE b.category_id = c.id AND b.month = ?2), 0) AS assigned,
+    COALESCE((SELECT SUM(l.amount) FROM activity l WHERE l.category_id = c.id AND date(l.date) >= ?2 AND date(l.date) < date(?2, '+1 month')), 0) AS activity,
+    COALESCE((SELECT SUM(b.assigned) FROM category_budgets b WHERE b.category_id = c.id AND b.month <= ?2), 0)
+        + COALESCE((SELECT SUM(l.amount) FROM activity l WHERE l.category_id = c.id AND date(l.date) < date(?2, '+1 month')), 0) AS available
+FROM categories c JOIN category_groups g ON g.id = c.group_id WHERE g.budget_id = ?1";
+
+/// Every category in the budget for the month starting on `month`.
+pub async fn get_category_balances(conn: &Pool<Sqlite>, budget_id: i32, month: chrono::NaiveDate) -> Vec<CategoryBalance> {
+    let result = sqlx::query_as::<_, CategoryBalance>(&format!("{} ORDER BY g.name, c.name", CATEGORY_BALANCE_SELECT))
+        .bind(budget_id)
+        .bind(month)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(balances) => balances,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// One of the budget's categories for the month starting on `month`.
+pub async fn get_category_balance(conn: &Pool<Sqlite>, budget_id: i32, category_id: i64, month: chrono::NaiveDate) -> Option<CategoryBalance> {
+    let result = sqlx::query_as::<_, CategoryBalance>(&format!("{} AND c.id = ?3", CATEGORY_BALANCE_SELECT))
+        .bind(budget_id)
+        .bind(month)
+        .bind(category_id)
+        .fetch_optional(conn)
+        .await;
+
+    match result {
+        Ok(balance) => balance,
+        Err(e) => {
+            println!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// How much a transaction changed each of its categories by, counting splits by part. Transactions
+/// in tracking accounts change no categories.
+pub async fn get_transaction_category_changes(conn: &Pool<Sqlite>, transaction_id: i64) -> Vec<(i64, i64)> {
+    let result = sqlx::query_as("SELECT category_id, SUM(amount) FROM (
+            SELECT t.category_id, t.inflow - t.outflow AS amount FROM transactions t
+            WHERE t.id = ?1 AND NOT EXISTS (SELECT 1 FROM transaction_splits s WHERE s.transaction_id = t.id)
+            UNION ALL
+            SELECT category_id, inflow - outflow FROM transaction_splits WHERE transaction_id = ?1
+        ) WHERE category_id IS NOT NULL
+        AND (SELECT a.on_budget FROM transactions t JOIN accounts a ON a.id = t.account_id WHERE t.id = ?1)
+        GROUP BY category_id")
+        .bind(transaction_id)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(changes) => changes,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+/// Changes what is assigned to categories for the month starting on `month` as one change the
+/// user can undo, with `assigned` working out each category's new amount from its current one.
+async fn reassign(conn: &Pool<Sqlite>, user_id: i32, budget_id: i32, month: chrono::NaiveDate, category_ids: &[i64], assigned: impl Fn(i64, i64) -> i64, description: &str) -> Result<(), &'static str> {
+    let mut changes = vec![];
+    let result: Result<Result<(), &'static str>, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut query = sqlx::QueryBuilder::<Sqlite>::new("SELECT count(*) FROM categories c JOIN category_groups g ON g.id = c.group_id WHERE g.budget_id = ");
+        query.push_bind(budget_id).push(" AND c.id IN (");
+        let mut separated = query.separated(", ");
+        for id in category_ids {
+            separated.push_bind(*id);
+        }
+        separated.push_unseparated(")");
+        let (found,): (i64,) = query.build_query_as().fetch_one(&mut *tx).await?;
+        if found as usize != category_ids.len() {
+            return Ok(Err("category not found"));
+        }
+
+        let mut recorder = history::Recorder::default();
+        recorder.watch(&mut tx, Table::CategoryBudgets, "category_id", category_ids).await?;
+        for category_id in category_ids {
+            let current: Option<(i64,)> = sqlx::query_as("SELECT assigned FROM category_budgets WHERE category_id = ? AND month = ?")
+                .bind(category_id)
+                .bind(month)
+                .fetch_optional(&mut *tx)
+                .await?;
+            let current = current.map_or(0, |(a,)| a);
+            let new = assigned(*category_id, current);
+            upsert_assigned(&mut tx, *category_id, month, new).await?;
+            changes.push((*category_id, new - current));
+        }
+        recorder.save(&mut tx, user_id.into(), budget_id.into(), description).await?;
+        tx.commit().await?;
+        Ok(Ok(()))
+    }.await;
+
+    match result {
+        Ok(Ok(())) => {
+            for (category_id, change) in changes {
+                webhooks::category_changed(conn, budget_id, category_id, month, change).await;
+            }
+            Ok(())
+        }
+        Ok(Err(e)) => Err(e),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to assign money")
+        }
+    }
+}
+
+/// Sets what is assigned to the category for the month starting on `month`.
+pub async fn set_category_assigned(conn: &Pool<Sqlite>, user_id: i32, budget_id: i32, category_id: i64, month: chrono::NaiveDate, assigned: i64) -> Result<(), &'static str> {
+    reassign(conn, user_id, budget_id, month, &[category_id], |_, _| assigned, "Assigned money to a category").await
+}
+
+/// Moves `amount` of what is assigned for the month starting on `month` from one category to
+/// another.
+pub async fn move_assigned(conn: &Pool<Sqlite>, user_id: i32, budget_id: i32, from_id: i64, to_id: i64, month: chrono::NaiveDate, amount: i64) -> Result<(), &'static str> {
+    if from_id == to_id {
+        return Err("Money can only be moved to another category.");
+    }
+    reassign(conn, user_id, budget_id, month, &[from_id, to_id], |id, current| if id == from_id { current - amount } else { current + amount }, "Moved money between categories").await
+}
+
+pub async fn insert_split(conn: &mut SqliteConnection, transaction_id: i64, category_id: Option<i64>, payee: Option<&str>, memo: &str, inflow: i64, outflow: i64) -> sqlx::Result<i64> {
+    let id = sqlx::query("INSERT INTO transaction_splits (transaction_id, category_id, payee, memo, inflow, outflow) VALUES (?, ?, ?, ?, ?, ?)")
+        .bind(transaction_id)
+        .bind(category_id)
+        .bind(payee)
+        .bind(memo)
+        .bind(inflow)
+        .bind(outflow)
+        .execute(&mut *conn)
+        .await?
+        .last_insert_rowid();
+    let mut audit = Audit::default();
+    audit.created("transaction_splits", "id", &[id]);
+    audit.save(conn, None).await?;
+    Ok(id)
+}
+
+/// Records that `transaction_id` is the other side of a transfer from `other_id`.
+pub async fn link_transfer(conn: &mut SqliteConnection, transaction_id: i64, other_id: i64) -> sqlx::Result<()> {
+    let mut audit = Audit::default();
+    audit.watch(&mut *conn, "transactions", "id", &[transaction_id]).await?;
+    sqlx::query("UPDATE transactions SET transfer_id = ? WHERE id = ?")
+        .bind(other_id)
+        .bind(transaction_id)
+        .execute(&mut *conn)
+        .await?;
+    audit.save(conn, None).await?;
+    Ok(())
+}
+
+/// The currency the budget's totals are shown in.
+pub async fn get_budget_currency(conn: &Pool<Sqlite>, budget_id: i32) -> String {
+    let result: Result<(String,), sqlx::Error> = sqlx::query_as("SELECT currency FROM budgets WHERE id = ?")
+        .bind(budget_id)
+        .fetch_one(conn)
+        .await;
+
+    result.map(|r| r.0).unwrap_or_else(|_| "GBP".to_string())
+}
+
+pub async fn set_budget_currency(conn: &Pool<Sqlite>, budget_id: i32, currency: &str) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "budgets", "id", &[budget_id.into()]).await?;
+        sqlx::query("UPDATE budgets SET currency = ? WHERE id = ?")
+            .bind(currency)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to set budget currency")
+        }
+    }
+}
+
+pub async fn set_locale(conn: &Pool<Sqlite>, user_id: i32, locale: &str) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "users", "id", &[user_id.into()]).await?;
+        sqlx::query("UPDATE users SET locale = ? WHERE id = ?")
+            .bind(locale)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save(&mut tx, Some(user_id.into())).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to set locale")
+        }
+    }
+}
+
+pub async fn get_exchange_rates(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<ExchangeRate> {
+    let result = sqlx::query_as::<_, ExchangeRate>("SELECT date, base, quote, rate FROM exchange_rates WHERE budget_id = ? ORDER BY date DESC, base, quote")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+/// Saves the rates, replacing any the budget already has for the same day and pair of currencies.
+pub async fn save_exchange_rates(conn: &Pool<Sqlite>, budget_id: i32, rates: &[ExchangeRate]) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "exchange_rates", "budget_id", &[budget_id.into()]).await?;
+        for rate in rates {
+            sqlx::query("INSERT INTO exchange_rates (budget_id, date, base, quote, rate) VALUES (?, ?, ?, ?, ?) ON CONFLICT (budget_id, date, base, quote) DO UPDATE SET rate = excluded.rate")
+                .bind(budget_id)
+                .bind(rate.date)
+                .bind(&rate.base)
+                .bind(&rate.quote)
+                .bind(rate.rate)
+                .execute(&mut *tx)
+                .await?;
+        }
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to save exchange rates")
+        }
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Category {
+    pub id: i64,
+    pub group_name: String,
+    pub name: String,
+}
+
+pub async fn get_categories_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Category> {
+    let result = sqlx::query_as::<_, Category>("SELECT c.id, g.name AS group_name, c.name FROM categories c JOIN category_groups g ON g.id = c.group_id WHERE g.budget_id = ? ORDER BY g.name, c.name")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Payee {
+    pub id: i64,
+    pub name: String,
+    pub default_category_id: Option<i64>,
+    /// How many transactions have this payee.
+    pub transactions: i64,
+}
+
+pub async fn get_payees_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Payee> {
+    let result = sqlx::query_as::<_, Payee>("SELECT p.id, p.name, p.default_category_id, (SELECT count(*) FROM transactions t WHERE t.payee_id = p.id) AS transactions FROM payees p WHERE p.budget_id = ? ORDER BY p.name COLLATE NOCASE")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
+
+/// Renames the payee and sets its default category. Every transaction with the payee shows the
+/// new name, and the old name is kept as an alias.
+pub async fn update_payee(conn: &Pool<Sqlite>, budget_id: i32, id: i64, name: &str, default_category_id: Option<i64>) -> Result<(), &'static str> {
+    let result: Result<u64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "payees", "id", &[id]).await?;
+        audit.watch(&mut tx, "payee_aliases", "payee_id", &[id]).await?;
+        sqlx::query("INSERT OR IGNORE INTO payee_aliases (payee_id, name) SELECT id, name FROM payees WHERE id = ? AND budget_id = ? AND name <> ?")
+            .bind(id)
+            .bind(budget_id)
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        let updated = sqlx::query("UPDATE payees SET name = ?, default_category_id = (SELECT c.id FROM categories c JOIN category_groups g ON g.id = c.group_id WHERE c.id = ? AND g.budget_id = ?) WHERE id = ? AND budget_id = ?")
+            .bind(name)
+            .bind(default_category_id)
+            .bind(budget_id)
+            .bind(id)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await?;
+        Ok(updated.rows_affected())
+    }.await;
+
+    match result {
+        Ok(1) => Ok(()),
+        Ok(_) => Err("payee not found"),
+        Err(sqlx::Error::Database(e)) if e.is_unique_violation() => Err("A payee with that name already exists."),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to update payee")
+        }
+    }
+}
+
+/// Moves every transaction and alias from one payee to another and deletes the first, keeping its
+/// name as an alias of the other.
+pub async fn merge_payees(conn: &Pool<Sqlite>, budget_id: i32, from_id: i64, into_id: i64) -> Result<(), &'static str> {
+    if from_id == into_id {
+        return Err("A payee cannot be merged into itself.");
+    }
+    let owned: Result<(i64,), sqlx::Error> = sqlx::query_as("SELECT count(*) FROM payees WHERE budget_id = ? AND id IN (?, ?)")
+        .bind(budget_id)
+        .bind(from_id)
+        .bind(into_id)
+        .fetch_one(conn)
+        .await;
+    if !matches!(owned, Ok((2,))) {
+        return Err("payee not found");
+    }
+
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let transaction_ids: Vec<(i64,)> = sqlx::query_as("SELECT id FROM transactions WHERE payee_id = ?")
+            .bind(from_id)
+            .fetch_all(&mut *tx)
+            .await?;
+        let transaction_ids: Vec<i64> = transaction_ids.into_iter().map(|(id,)| id).collect();
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "transactions", "id", &transaction_ids).await?;
+        audit.watch(&mut tx, "payees", "id", &[from_id, into_id]).await?;
+        audit.watch(&mut tx, "payee_aliases", "payee_id", &[from_id, into_id]).await?;
+        sqlx::query("UPDATE transactions SET payee_id = ? WHERE payee_id = ?")
+            .bind(into_id)
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("INSERT OR IGNORE INTO payee_aliases (payee_id, name) SELECT ?, name FROM payee_aliases WHERE payee_id = ? UNION SELECT ?, name FROM payees WHERE id = ?")
+            .bind(into_id)
+            .bind(from_id)
+            .bind(into_id)
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM payees WHERE id = ?")
+            .bind(from_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await
+    }.await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to merge payees")
+        }
+    }
+}
+
+/// Deletes a payee that no transaction uses.
+pub async fn delete_payee(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Result<(), &'static str> {
+    let result: Result<SqliteQueryResult, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "payees", "id", &[id]).await?;
+        audit.watch(&mut tx, "payee_aliases", "payee_id", &[id]).await?;
+        let deleted = sqlx::query("DELETE FROM payees WHERE id = ? AND budget_id = ? AND NOT EXISTS (SELECT 1 FROM transactions t WHERE t.payee_id = payees.id)")
+            .bind(id)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }.await;
+
+    match result {
+        Ok(r) if r.rows_affected() == 1 => Ok(()),
+        Ok(_) => Err("Only payees with no transactions can be deleted."),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to delete payee")
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct RuleRow {
+    id: i64,
+    name: String,
+    priority: i64,
+    conditions: String,
+    actions: String,
+}
+
+impl RuleRow {
+    fn rule(self) -> Option<Rule> {
+        Some(Rule {
+            id: self.id,
+            name: self.name,
+            priority: self.priority,
+            conditions: serde_json::from_str(&self.conditions).ok()?,
+            actions: serde_json::from_str(&self.actions).ok()?,
+        })
+    }
+}
+
+/// The budget's rules in the order they run.
+pub async fn get_rules_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Rule> {
+    let result = sqlx::query_as::<_, RuleRow>("SELECT id, name, priority, conditions, actions FROM rules WHERE budget_id = ? ORDER BY priority, id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default().into_iter().filter_map(RuleRow::rule).collect()
+}
+
+/// The rules of the account's budget, for running over its new transactions.
+pub async fn get_rules_for_account(conn: impl SqliteExecutor<'_>, account_id: i64) -> Vec<Rule> {
+    let result = sqlx::query_as::<_, RuleRow>("SELECT r.id, r.name, r.priority, r.conditions, r.actions FROM rules r JOIN accounts a ON a.budget_id = r.budget_id WHERE a.id = ? ORDER BY r.priority, r.id")
+        .bind(account_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default().into_iter().filter_map(RuleRow::rule).collect()
+}
+
+pub async fn create_rule(conn: &Pool<Sqlite>, budget_id: i32, rule: &Rule) -> Result<i64, &'static str> {
+    let result: Result<i64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = sqlx::query("INSERT INTO rules (budget_id, name, priority, conditions, actions) VALUES (?, ?, ?, ?, ?)")
+            .bind(budget_id)
+            .bind(&rule.name)
+            .bind(rule.priority)
+            .bind(serde_json::to_string(&rule.conditions).unwrap())
+            .bind(serde_json::to_string(&rule.actions).unwrap())
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        let mut audit = Audit::default();
+        audit.created("rules", "id", &[id]);
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await?;
+        Ok(id)
+    }.await;
+
+    match result {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to create rule")
+        }
+    }
+}
+
+pub async fn delete_rule(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Result<(), &'static str> {
+    let result: Result<SqliteQueryResult, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "rules", "id", &[id]).await?;
+        let deleted = sqlx::query("DELETE FROM rules WHERE id = ? AND budget_id = ?")
+            .bind(id)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }.await;
+
+    match result {
+        Ok(r) if r.rows_affected() == 1 => Ok(()),
+        Ok(_) => Err("rule not found"),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to delete rule")
+        }
+    }
+}
+
+/// Every transaction in the budget's accounts, newest first.
+pub async fn get_transactions_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Vec<Transaction> {
+    let result = sqlx::query_as::<_, Transaction>(&format!("{} JOIN accounts a ON a.id = t.account_id WHERE a.budget_id = ? ORDER BY t.date DESC, t.id DESC", TRANSACTION_SELECT))
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct Webhook {
+    pub id: i64,
+    pub budget_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub events: String,
+    pub active: bool,
+}
+
+impl Webhook {
+    pub fn subscribes_to(&self, event: &str) -> bool {
+        self.events.split(',').any(|e| e == event)
+    }
+}
+
+pub async fn get_webhooks_for_budget(conn: &Pool<Sqlite>, budget_id: i32) -> Option<Vec<Webhook>> {
+    let result = sqlx::query_as::<_, Webhook>("SELECT * FROM webhooks WHERE budget_id = ? ORDER BY id")
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(rows) => Some(rows),
+        Err(e) => {
+            println!("{:?}", e);
+            None
+        }
+    }
+}
+
+pub async fn create_webhook(conn: &Pool<Sqlite>, budget_id: i32, url: &str, secret: &str, events: &[&str]) -> Result<i64, &'static str> {
+    let result: Result<i64, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let id = sqlx::query("INSERT INTO webhooks (budget_id, url, secret, events, active) VALUES (?, ?, ?, ?, 1)")
+            .bind(budget_id)
+            .bind(url)
+            .bind(secret)
+            .bind(events.join(","))
+            .execute(&mut *tx)
+            .await?
+            .last_insert_rowid();
+        let mut audit = Audit::default();
+        audit.created("webhooks", "id", &[id]);
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await?;
+        Ok(id)
+    }.await;
+
+    match result {
+        Ok(id) => Ok(id),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to create webhook")
+        }
+    }
+}
+
+pub async fn delete_webhook(conn: &Pool<Sqlite>, budget_id: i32, id: i64) -> Result<(), &'static str> {
+    let result: Result<SqliteQueryResult, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut audit = Audit::default();
+        audit.watch(&mut tx, "webhooks", "id", &[id]).await?;
+        let deleted = sqlx::query("DELETE FROM webhooks WHERE id = ? AND budget_id = ?")
+            .bind(id)
+            .bind(budget_id)
+            .execute(&mut *tx)
+            .await?;
+        audit.save_in_budget(&mut tx, budget_id.into()).await?;
+        tx.commit().await?;
+        Ok(deleted)
+    }.await;
+
+    match result {
+        Ok(r) if r.rows_affected() == 1 => Ok(()),
+        Ok(_) => Err("webhook not found"),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to delete webhook")
+        }
+    }
+}
+
+/// Deliveries are a log of their own, so they aren't in the audit log.
+pub async fn queue_webhook_delivery(conn: &Pool<Sqlite>, webhook_id: i64, event: &str, payload: &str) -> Result<i64, &'static str> {
+    let now = chrono::Utc::now().naive_utc();
+    let result = sqlx::query("INSERT INTO webhook_deliveries (webhook_id, event, payload, status, attempts, next_attempt_at, created_at) VALUES (?, ?, ?, 'pending', 0, ?, ?)")
+        .bind(webhook_id)
+        .bind(event)
+        .bind(payload)
+        .bind(now)
+        .bind(now)
+        .execute(conn)
+        .await;
+
+    match result {
+        Ok(r) => Ok(r.last_insert_rowid()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to queue webhook delivery")
+        }
+    }
+}
+
+#[derive(Serialize, FromRow)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub webhook_id: i64,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i64,
+    pub next_attempt_at: chrono::NaiveDateTime,
+    pub response_status: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: chrono::NaiveDateTime,
+}
+
+const WEBHOOK_DELIVERY_SELECT: &str = "SELECT d.id, d.webhook_id, w.url, w.secret, d.event, d.payload, d.status, d.attempts, d.next_attempt_at, d.response_status, d.last_error, d.created_at FROM webhook_deliveries d INNER JOIN webhooks w ON w.id = d.webhook_id";
+
+/// Pending deliveries whose next attempt is due, oldest first.
+pub async fn get_due_webhook_deliveries(conn: &Pool<Sqlite>, now: chrono::NaiveDateTime, limit: i64) -> Vec<WebhookDelivery> {
+    let result = sqlx::query_as::<_, WebhookDelivery>(&format!("{} WHERE d.status = 'pending' AND w.active = 1 AND d.next_attempt_at <= ? ORDER BY d.next_attempt_at LIMIT ?", WEBHOOK_DELIVERY_SELECT))
+        .bind(now)
+        .bind(limit)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(rows) => rows,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+pub async fn get_webhook_deliveries_for_budget(conn: &Pool<Sqlite>, budget_id: i32, limit: i64) -> Option<Vec<WebhookDelivery>> {
+    let result = sqlx::query_as::<_, WebhookDelivery>(&format!("{} WHERE w.budget_id = ? ORDER BY d.id DESC LIMIT ?", WEBHOOK_DELIVERY_SELECT))
+        .bind(budget_id)
+        .bind(limit)
+        .fetch_all(conn)
+        .await;
+
+    match result {
+        Ok(rows) => Some(rows),
+        Err(e) => {
+            println!("{:?}", e);
+            None
+        }
+    }
+}
+
+/// Records the outcome of a delivery attempt. `status` is one of `delivered`, `pending` (retry at
+/// `next_attempt_at`) or `failed` (no more retries).
+pub async fn record_webhook_attempt(conn: &Pool<Sqlite>, id: i64, status: &str, next_attempt_at: chrono::NaiveDateTime, response_status: Option<u16>, error: Option<&str>) -> Result<(), &'static str> {
+    let result = sqlx::query("UPDATE webhook_deliveries SET status = ?, attempts = attempts + 1, next_attempt_at = ?, response_status = ?, last_error = ? WHERE id = ?")
+        .bind(status)
+        .bind(next_attempt_at)
+        .bind(response_status)
+        .bind(error)
+        .bind(id)
+        .execute(conn)
+        .await;
+
+    match result {
+        Ok(_) => Ok(()),
+        Err(e) => {
+            println!("{:?}", e);
+            Err("failed to record webhook attempt")
+        }
+    }
+}
+
+#[sqlx::test]
+async fn test_get_user(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+    // Setup
+    sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?;
+
+    // Act
+    let user = get_user(&pool, "test@example.com".to_string()).await.expect("User is not found.");
+
+    // Assert
+    assert_eq!(user.name, "test");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_get_register(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+    // Setup
+    let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+    let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+    let account_id = create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+    let new = |day: u32, payee: &str, inflow: i64, outflow: i64| NewTransaction {
+        account_id,
+        date: chrono::NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        value_date: None,
+        payee: Some(payee.to_string()),
+        memo: String::new(),
+        inflow,
+        outflow,
+        cleared: false,
+        import_id: None,
+        imported: false,
+        category_id: None,
+    };
+    for transaction in [new(5, "Tesco", 0, 500), new(1, "Salary", 10000, 0), new(3, "aldi", 0, 2000)] {
+        create_transaction(&pool, &transaction).await.unwrap();
+    }
+    for _ in 0..120 {
+        create_transaction(&pool, &new(20, "Bus", 0, 1)).await.unwrap();
+    }
+    let january = |from: u32, to: u32| search::Filter {
+        from: chrono::NaiveDate::from_ymd_opt(2024, 1, from),
+        to: chrono::NaiveDate::from_ymd_opt(2024, 1, to),
+        account_id: Some(account_id),
+        ..Default::default()
+    };
+
+    // Act
+    let newest_first = get_register(&pool, budget_id, &january(1, 10), search::Order::default_for(search::Sort::Date), None).await;
+    let by_payee = get_register(&pool, budget_id, &january(1, 10), search::Order { sort: search::Sort::Payee, descending: false }, None).await;
+    let first_page = get_register(&pool, budget_id, &january(20, 20), search::Order::default_for(search::Sort::Date), None).await;
+    let second_page = get_register(&pool, budget_id, &january(20, 20), search::Order::default_for(search::Sort::Date), Some(first_page.last().unwrap().transaction.id.into())).await;
+
+    // Assert
+    let summary = |rows: &[RegisterRow]| rows.iter().map(|r| (r.transaction.payee.clone().unwrap(), r.balance)).collect::<Vec<_>>();
+    assert_eq!(summary(&newest_first), vec![("Tesco".to_string(), 7500), ("aldi".to_string(), 8000), ("Salary".to_string(), 10000)]);
+    assert_eq!(summary(&by_payee), vec![("aldi".to_string(), 8000), ("Salary".to_string(), 10000), ("Tesco".to_string(), 7500)], "Payees sort ignoring case and keep their balance");
+    assert_eq!((first_page.len(), second_page.len()), (100, 20));
+    let mut ids: Vec<i32> = first_page.iter().chain(&second_page).map(|r| r.transaction.id).collect();
+    ids.dedup();
+    assert_eq!(ids.len(), 120, "Pages don't overlap, even when transactions share a date");
+    assert_eq!(second_page.last().unwrap().balance, 7499, "The earliest bus fare comes straight after Tesco");
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_get_match_candidates(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+    // Setup
+    let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+    let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+    let account_id = create_empty_account(&pool, budget_id, "Current", "GBP").await.unwrap();
+    let new = |day: u32, payee: &str, outflow: i64, cleared: bool, imported: bool| NewTransaction {
+        account_id,
+        date: chrono::NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+        value_date: None,
+        payee: Some(payee.to_string()),
+        memo: String::new(),
+        inflow: 0,
+        outflow,
+        cleared,
+        import_id: None,
+        imported,
+        category_id: None,
+    };
+    for transaction in [
+        new(10, "Tesco", 1250, false, false),
+        new(15, "TESCO STORES", 1250, true, true),
+        new(16, "TESCO STORES", 1250, true, true),
+        new(4, "TESCO STORES", 1250, true, true),
+        new(12, "Aldi", 900, true, true),
+        new(2, "Rent", 50000, true, false),
+    ] {
+        create_transaction(&pool, &transaction).await.unwrap();
+    }
+
+    // Act
+    let candidates = get_match_candidates(&pool, account_id as i32).await.unwrap();
+    let cleared = get_cleared_balance(&pool, account_id as i32).await;
+
+    // Assert
+    assert_eq!(
+        candidates.iter().map(|t| (chrono::Datelike::day(&t.date), t.payee.clone().unwrap())).collect::<Vec<_>>(),
+        vec![(10, "Tesco".to_string()), (15, "TESCO STORES".to_string())],
+        "Only imported transactions of the same amount within five days are loaded"
+    );
+    assert_eq!(cleared, -(1250 * 3 + 900 + 50000));
+
+    Ok(())
+}
+
+#[sqlx::test]
+async fn test_changes_are_audited(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+    // Setup
+    let user = User::from_form("test".to_string(), "test@example.com".to_string(), "secret".to_string()).unwrap();
+
+    // Act
+    create_user(&pool, user).await.unwrap();
+    let user_id = get_user(&pool, "test@example.com".to_string()).await.unwrap().id.unwrap();
+    let budget_id = budgets::get_for_user(&pool, user_id).await[0].id;
+    let account_id = create_account(&pool, budget_id, "Current", "GBP", 1000).await.unwrap();
+
+    // Assert
+    let entries: Vec<(Option<i64>, String, i64, String)> = sqlx::query_as("SELECT actor_id, entity, entity_id, action FROM audit_log ORDER BY id")
+        .fetch_all(&pool)
+        .await?;
+    let summary: Vec<(Option<i64>, &str, i64, &str)> = entries.iter().map(|e| (e.0, e.1.as_str(), e.2, e.3.as_str())).collect();
+    assert_eq!(summary[..4], [
+        (Some(user_id.into()), "budget_members", 1, "create"),
+        (Some(user_id.into()), "budgets", budget_id.into(), "create"),
+        (Some(user_id.into()), "users", user_id.into(), "create"),
+        (Some(user_id.into()), "accounts", account_id, "create"),
+    ]);
+    assert_eq!((summary[4].0, summary[4].1), (Some(user_id.into()), "transactions"), "The starting balance is audited too");
+    let (elsewhere,): (i64,) = sqlx::query_as("SELECT count(*) FROM audit_log WHERE budget_id IS NOT ?")
+        .bind(budget_id)
+        .fetch_one(&pool)
+        .await?;
+    assert_eq!(elsewhere, 0, "Every change is logged against the budget");
+    let (before, after): (Option<String>, String) = sqlx::query_as("SELECT before, after FROM audit_log WHERE entity = 'users'")
+        .fetch_one(&pool)
+        .await?;
+    assert!(before.is_none());
+    let created: serde_json::Value = serde_json::from_str(&after).unwrap();
+    assert_eq!(created["email"], "test@example.com");
+    assert!(created.get("password").is_none(), "Passwords are left out");
+    assert!(sqlx::query("DELETE FROM audit_log").execute(&pool).await.is_err(), "The log can only be added to");
 
     Ok(())
 }
@@ -1,16 +1,40 @@
-use maud::html;
-use poem::{handler, http::{header, StatusCode}, session::Session, web::{Data, Form, Html, Path}, IntoResponse, Response};
+use chrono::Datelike;
+use maud::{html, PreEscaped};
+use poem::{handler, http::{header, StatusCode}, session::Session, web::{Data, Form, Html, Multipart, Path, Query}, IntoResponse, Request, Response};
 use serde::Deserialize;
 use sqlx::{Pool, Sqlite};
+use std::sync::Arc;
 
-use crate::{db::User, helpers::{get_money_from_string, get_total_as_formatted_string}, views::{self, simple_error}};
+use crate::{archive, attachments::{self, Storage}, audit::{self, AuditParams}, budgets::{self, Budget, Role}, currency::{self, Rates}, db::{Account, User}, history::{self, Table}, ledger::Dialect, helpers::{format_money, parse_money, Locale, LOCALES}, import::{self, csv::CsvMapping, ynab::{self, YnabFiles}}, matching, reports::{self, ReportParams}, rules, search::{self, SearchParams}, views::{self, simple_error}, webhooks};
 use crate::db;
 
 fn needs_login(session: &Session) -> bool {
+    session.get::<String>("user").is_none()
+}
+
+async fn current_user(pool: &Pool<Sqlite>, session: &Session) -> Option<User> {
     match session.get::<String>("user") {
-        Some(_) => false,
-        None => true
+        Some(email) => db::get_user(pool, email).await,
+        None => None
+    }
+}
+
+/// The logged in user and the budget they are working in, as long as their role in it allows what
+/// needs `needed`. The budget is remembered in the session so it stays the same between requests.
+async fn current_budget(pool: &Pool<Sqlite>, session: &Session, needed: Role) -> Result<(User, Budget), Response> {
+    let user = match current_user(pool, session).await {
+        Some(u) => u,
+        None => return Err(StatusCode::UNAUTHORIZED.into()),
+    };
+    let budget = match budgets::current(pool, user.id.unwrap(), session.get("budget")).await {
+        Some(b) => b,
+        None => return Err(StatusCode::FORBIDDEN.with_body("You aren't a member of any budget.").into_response()),
+    };
+    session.set("budget", budget.id);
+    if !budget.role.allows(needed) {
+        return Err(StatusCode::FORBIDDEN.with_body("Your role in this budget doesn't allow that.").into_response());
     }
+    Ok((user, budget))
 }
 
 fn _redirect(location: &str) -> Response {
@@ -38,58 +62,373 @@ fn redirect_to_home() -> Response {
 struct CreateAccountBody {
     name: String,
     starting_balance: String,
+    /// Defaults to the budget currency.
+    #[serde(default)]
+    currency: String,
+    /// `tracking` for an account kept out of the budget.
+    #[serde(default, rename = "type")]
+    kind: String,
+}
+
+/// The budget currency and the total of the accounts in the budget, converted at the latest rates.
+/// Tracking accounts, and accounts with no rate to the budget currency, are left out, and the
+/// currencies with no rate named.
+async fn budget_total(pool: &Pool<Sqlite>, budget_id: i32, accounts: &[Account], locale: &Locale) -> (String, String) {
+    let currency = db::get_budget_currency(pool, budget_id).await;
+    let rates = Rates::new(db::get_exchange_rates(pool, budget_id).await);
+    let accounts: Vec<&Account> = accounts.iter().filter(|a| a.on_budget).collect();
+    let (total, missing) = rates.total(&accounts, &currency, chrono::Utc::now().date_naive());
+
+    let mut total = format_money(total, &currency, locale);
+    if !missing.is_empty() {
+        total = format!("{} (excluding {})", total, missing.into_iter().collect::<Vec<_>>().join(", "));
+    }
+    (currency, total)
 }
 
 #[handler]
 pub async fn get_accounts(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
-    if needs_login(session) {
-        return StatusCode::UNAUTHORIZED.into();
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let accounts = db::get_accounts_for_budget(&pool, budget.id).await;
+    if accounts.is_none() {
+        return Html(simple_error("Could not get accounts.")).into_response();
     }
 
-    let user = db::get_user(&pool, session.get("user").unwrap()).await;
-    if user.is_none() {
-        return Html(simple_error("Could not get user.")).into_response();
+    let (currency, budget_total) = budget_total(&pool, budget.id, accounts.as_ref().unwrap(), user.locale()).await;
+
+    Html(views::accounts_partial(accounts.unwrap(), budget_total, &currency, user.locale()).into_string()).into_response()
+}
+
+/// Pages htmx asks for are swapped into the content area. Opened directly, such as from a shared
+/// link, they are shown inside the whole app instead.
+async fn page(pool: &Pool<Sqlite>, user: &User, budget: &Budget, req: &Request, content: Response) -> Response {
+    if req.headers().contains_key("HX-Request") || !content.status().is_success() {
+        return content;
     }
-    let accounts = db::get_accounts_for_user(&pool, user.unwrap().id.unwrap()).await;
-    if accounts.is_none() {
-        return Html(simple_error("Could not get accounts.")).into_response();
+    let accounts = db::get_accounts_for_budget(pool, budget.id).await.unwrap_or_default();
+    let (currency, budget_total) = budget_total(pool, budget.id, &accounts, user.locale()).await;
+    let budgets = budgets::get_for_user(pool, user.id.unwrap()).await;
+    let content = content.into_body().into_string().await.unwrap_or_default();
+    Html(views::home(&budgets, Some(budget), accounts, budget_total, &currency, user.locale(), Some(PreEscaped(content))).into_string()).into_response()
+}
+
+/// Like `page`, for someone who isn't in any budget, such as after being removed from their only
+/// one. There is nothing of a budget's own to show around the content.
+async fn page_without_budget(pool: &Pool<Sqlite>, user: &User, req: &Request, content: Response) -> Response {
+    if req.headers().contains_key("HX-Request") || !content.status().is_success() {
+        return content;
     }
+    let budgets = budgets::get_for_user(pool, user.id.unwrap()).await;
+    let content = content.into_body().into_string().await.unwrap_or_default();
+    Html(views::home(&budgets, None, vec![], String::new(), "", user.locale(), Some(PreEscaped(content))).into_string()).into_response()
+}
 
-    let budget_total = accounts.as_ref().unwrap().iter().fold(0, |acc, x| {
-        acc + x.total
-    });
+#[handler]
+pub async fn get_transactions(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Path(id): Path<i64>, Query(search): Query<SearchParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
 
-    Html(views::accounts_partial(accounts.unwrap(), get_total_as_formatted_string(budget_total)).into_string()).into_response()
+    let content = register(&pool, &user, &budget, Some(&account), &search).await;
+    page(&pool, &user, &budget, req, content).await
 }
 
+/// Every account's transactions in one register.
 #[handler]
-pub async fn get_transactions(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<String>) -> impl IntoResponse {
-    if needs_login(session) {
-        return StatusCode::UNAUTHORIZED.into();
+pub async fn all_accounts(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(search): Query<SearchParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let content = register(&pool, &user, &budget, None, &search).await;
+    page(&pool, &user, &budget, req, content).await
+}
+
+/// What the search narrows the register down to. Amounts are read in the account's currency, or
+/// the budget currency across every account.
+async fn register_filter(user: &User, budget: &Budget, account: Option<&Account>, search: &SearchParams) -> Result<search::Filter, String> {
+    let currency = match account {
+        Some(account) => account.currency.clone(),
+        None => budget.currency.clone(),
+    };
+    let mut filter = search.filter(&currency, user.locale())?;
+    if let Some(account) = account {
+        filter.account_id = Some(account.id.into());
+    }
+    Ok(filter)
+}
+
+/// The transactions that match the search, in one account or all of them. An account's register
+/// also proposes matches between imported and manually entered transactions.
+async fn register(pool: &Pool<Sqlite>, user: &User, budget: &Budget, account: Option<&Account>, search: &SearchParams) -> Response {
+    let budget_id = budget.id;
+    let (cleared, matches) = match account {
+        Some(account) => match db::get_match_candidates(pool, account.id).await {
+            Some(candidates) => {
+                let dismissed = db::get_dismissed_matches(pool, account.id.into()).await;
+                (db::get_cleared_balance(pool, account.id).await, matching::propose(&candidates, &dismissed))
+            }
+            None => return Html(html! { p { "Failed to load accounts." } }.into_string()).into_response(),
+        },
+        None => (0, vec![]),
+    };
+    let (rows, totals, message) = match register_filter(user, budget, account, search).await {
+        Ok(filter) => {
+            let rows = db::get_register(pool, budget_id, &filter, search.order(), None).await;
+            (rows, db::get_register_totals(pool, budget_id, &filter).await, None)
+        }
+        Err(message) => (vec![], vec![], Some(message)),
+    };
+    let payees = db::get_payees_for_budget(pool, budget_id).await;
+    let accounts = db::get_accounts_for_budget(pool, budget_id).await.unwrap_or_default();
+    let categories = db::get_categories_for_budget(pool, budget_id).await;
+    let tags = db::get_tags_for_budget(pool, budget_id).await;
+    let attachments = match account {
+        Some(account) => db::get_attachments_for_account(pool, account.id).await,
+        None => db::get_attachments_for_budget(pool, budget_id).await,
+    };
+    let register = views::Register {
+        account,
+        rows,
+        totals: &totals,
+        matches: &matches,
+        payees: &payees,
+        attachments: &attachments,
+        cleared,
+        search,
+        options: views::SearchOptions { accounts: &accounts, categories: &categories, tags: &tags },
+        message: message.as_deref(),
+    };
+    Html(views::transactions_list(register, user.locale()).into_string()).into_response()
+}
+
+#[derive(Deserialize)]
+struct RegisterPage {
+    /// The last transaction already shown, or none for the first page.
+    after: Option<i64>,
+}
+
+/// A page of rows in the register, for one account or all of them.
+async fn register_page(pool: &Pool<Sqlite>, user: &User, budget: &Budget, account: Option<&Account>, search: &SearchParams, after: Option<i64>) -> Response {
+    let filter = match register_filter(user, budget, account, search).await {
+        Ok(f) => f,
+        Err(message) => return StatusCode::BAD_REQUEST.with_body(message).into_response(),
+    };
+
+    let rows = db::get_register(pool, budget.id, &filter, search.order(), after).await;
+    let accounts = db::get_accounts_for_budget(pool, budget.id).await.unwrap_or_default();
+    let attachments = match account {
+        Some(account) => db::get_attachments_for_account(pool, account.id).await,
+        None => db::get_attachments_for_budget(pool, budget.id).await,
+    };
+    Html(views::register_rows(account, &accounts, &rows, &attachments, search, user.locale()).into_string()).into_response()
+}
+
+/// The account's register rows, as it scrolls or after a transaction changes.
+#[handler]
+pub async fn get_register_page(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Query(search): Query<SearchParams>, Query(page): Query<RegisterPage>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    register_page(&pool, &user, &budget, Some(&account), &search, page.after).await
+}
+
+/// Rows of the register across every account.
+#[handler]
+pub async fn get_all_register_page(pool: Data<&Pool<Sqlite>>, session: &Session, Query(search): Query<SearchParams>, Query(page): Query<RegisterPage>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    register_page(&pool, &user, &budget, None, &search, page.after).await
+}
+
+#[derive(Deserialize)]
+struct CreateTransactionBody {
+    /// Only sent from the register of every account.
+    #[serde(default)]
+    account_id: Option<i64>,
+    date: String,
+    payee: String,
+    memo: String,
+    inflow: String,
+    outflow: String,
+}
+
+fn optional_money(value: &str, currency: &str, locale: &Locale) -> Result<i64, &'static str> {
+    if value.trim().is_empty() {
+        Ok(0)
+    } else {
+        parse_money(value, currency, locale)
+    }
+}
+
+/// Adds a transaction entered by hand to the account, or returns why it couldn't.
+async fn add_transaction(pool: &Pool<Sqlite>, user: &User, budget: &Budget, account: &Account, body: CreateTransactionBody) -> Result<(), Response> {
+    let date = match chrono::NaiveDate::parse_from_str(&body.date, "%Y-%m-%d") {
+        Ok(d) => d,
+        Err(_) => return Err(StatusCode::BAD_REQUEST.with_body("Invalid date").into_response()),
+    };
+    let (inflow, outflow) = match (optional_money(&body.inflow, &account.currency, user.locale()), optional_money(&body.outflow, &account.currency, user.locale())) {
+        (Ok(i), Ok(o)) => (i, o),
+        (Err(e), _) | (_, Err(e)) => return Err(StatusCode::BAD_REQUEST.with_body(e).into_response()),
+    };
+
+    let result = db::create_transaction(pool, &db::NewTransaction {
+        account_id: account.id.into(),
+        date: date.and_hms_opt(0, 0, 0).unwrap(),
+        value_date: None,
+        payee: Some(body.payee.trim().to_string()).filter(|p| !p.is_empty()),
+        memo: body.memo,
+        inflow,
+        outflow,
+        cleared: false,
+        import_id: None,
+        imported: false,
+        category_id: None,
+    }).await;
+
+    let id = result.map_err(|message| {
+        println!("{}", message);
+        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    })?;
+    // Failing to record the history doesn't undo adding the transaction.
+    let _ = history::record_created(pool, user.id.unwrap().into(), budget.id.into(), Table::Transactions, &[id], &format!("Added a transaction to {}", account.name)).await;
+    Ok(())
+}
+
+#[handler]
+pub async fn create_transaction(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<CreateTransactionBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    match add_transaction(&pool, &user, &budget, &account, body).await {
+        Ok(_) => register(&pool, &user, &budget, Some(&account), &SearchParams::default()).await.with_header("HX-Trigger", "accountsUpdated").into_response(),
+        Err(response) => response,
     }
+}
+
+/// Adds a transaction from the register of every account, to the account picked in the form.
+#[handler]
+pub async fn create_transaction_in_any_account(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<CreateTransactionBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match body.account_id {
+        Some(id) => db::get_account(&pool, budget.id, id).await,
+        None => None,
+    };
+    let account = match account {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
 
-    match db::get_transactions_for_account(&pool, id.parse().unwrap()).await {
-        Some(t) => Html(views::transactions_list(t).into_string()).into_response(),
-        None => Html(html! { p { "Failed to load accounts." } }.into_string()).into_response()
+    match add_transaction(&pool, &user, &budget, &account, body).await {
+        Ok(_) => register(&pool, &user, &budget, None, &SearchParams::default()).await.with_header("HX-Trigger", "accountsUpdated").into_response(),
+        Err(response) => response,
     }
 }
 
+#[derive(Deserialize)]
+struct MatchBody {
+    imported_id: i64,
+    manual_id: i64,
+}
+
 #[handler]
-pub async fn create_account(pool: Data<&Pool<Sqlite>>, session: &Session, data: Form<CreateAccountBody>) -> impl IntoResponse {
-    if needs_login(session) {
-        return StatusCode::UNAUTHORIZED.into();
+pub async fn approve_match(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<MatchBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    match db::merge_matched_transactions(&pool, user.id.unwrap(), id, body.imported_id, body.manual_id).await {
+        Ok(_) => register(&pool, &user, &budget, Some(&account), &SearchParams::default()).await.with_header("HX-Trigger", "accountsUpdated").into_response(),
+        Err(message) => StatusCode::BAD_REQUEST.with_body(message).into_response(),
+    }
+}
+
+#[handler]
+pub async fn dismiss_match(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<MatchBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let belongs_to_account = |t: Option<db::Transaction>| t.is_some_and(|t| i64::from(t.account_id) == id);
+    if !belongs_to_account(db::get_transaction(&pool, body.imported_id).await) || !belongs_to_account(db::get_transaction(&pool, body.manual_id).await) {
+        return StatusCode::NOT_FOUND.into_response();
     }
 
-    let user = db::get_user(&pool, session.get("user").unwrap()).await.unwrap();
+    match db::dismiss_match(&pool, body.imported_id, body.manual_id).await {
+        Ok(_) => register(&pool, &user, &budget, Some(&account), &SearchParams::default()).await,
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn create_account(pool: Data<&Pool<Sqlite>>, session: &Session, data: Form<CreateAccountBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let currency = if data.currency.trim().is_empty() {
+        budget.currency.clone()
+    } else {
+        match currency::code(&data.currency) {
+            Some(c) => c,
+            None => return StatusCode::BAD_REQUEST.with_body("Unknown currency").into_response(),
+        }
+    };
 
-    let starting_balance: i64 = match get_money_from_string(data.starting_balance.clone()) {
+    let starting_balance: i64 = match parse_money(&data.starting_balance, &currency, user.locale()) {
         Ok(v) => v,
         Err(e) => {
             return StatusCode::BAD_REQUEST.with_body(e).into_response()
         }
     };
 
-    match db::create_account(&pool, user.id.unwrap(), &data.name, starting_balance).await {
+    let result = match db::create_account(&pool, budget.id, &data.name, &currency, starting_balance).await {
+        Ok(id) if data.kind == "tracking" => db::set_on_budget(&pool, id, false).await,
+        Ok(_) => Ok(()),
+        Err(message) => Err(message),
+    };
+    match result {
         Ok(_) => StatusCode::OK.with_header("HX-Trigger", "accountsUpdated").into_response(),
         Err(message) => {
             println!("{}", message);
@@ -127,10 +466,21 @@ pub async fn login(pool: Data<&Pool<Sqlite>>, session: &Session, params: Form<Lo
     let user = db::auth_user(&pool, params.email.to_owned(), params.password.to_owned()).await;
     match user {
         Some(u) => {
+            // Undo only reaches back to when the user logged in.
+            let user_id = db::get_user(&pool, u.email.clone()).await.and_then(|u| u.id).unwrap_or_default();
+            session.set("history_since", history::latest(&pool, user_id.into()).await);
+            session.remove("budget");
             session.set("user", u.email);
-            Response::default()
-                .set_status(StatusCode::OK)
-                .with_header("HX-Redirect", "/")
+            // Someone who followed an invitation before logging in goes back to it.
+            let location = match session.get::<String>("invitation") {
+                Some(token) => {
+                    session.remove("invitation");
+                    format!("/invitations/{}", token)
+                }
+                None => "/".to_string(),
+            };
+            StatusCode::OK
+                .with_header("HX-Redirect", location)
                 .into_response()
         },
         None => Html(views::error_message("User not found").into_string()).into_response()
@@ -148,8 +498,7 @@ pub async fn sign_up(pool: Data<&Pool<Sqlite>>, params: Form<Signup>) -> impl In
     match User::from_form(params.name.to_owned(), params.email.to_owned(), params.password.to_string()) {
         Ok(u) => {
             match db::create_user(&pool, u).await {
-                Ok(_) => Response::default()
-                    .set_status(StatusCode::OK)
+                Ok(_) => StatusCode::OK
                     .with_header("HX-Location", "/")
                     .into_response(),
                 _ => Html(html! { p class="text-red-600 font-semibold" { "Failed to create user." } }).into_response()
@@ -160,32 +509,1632 @@ pub async fn sign_up(pool: Data<&Pool<Sqlite>>, params: Form<Signup>) -> impl In
 }
 
 #[handler]
-pub async fn home(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+pub async fn home(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request) -> impl IntoResponse {
     if needs_login(session) {
         return redirect_to_login();
     }
 
-    let user = db::get_user(&pool, session.get("user").unwrap()).await;
-    if user.is_none() {
-        return Html(simple_error("Could not get user.")).into_response();
-    }
-    let accounts = db::get_accounts_for_user(&pool, user.unwrap().id.unwrap()).await;
+    let user = match current_user(&pool, session).await {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into(),
+    };
+    let budget = match budgets::current(&pool, user.id.unwrap(), session.get("budget")).await {
+        Some(b) => b,
+        None => {
+            let content = budget_list(&pool, &user, None, Some("You aren't a member of any budget. Create one to get started.")).await;
+            return page_without_budget(&pool, &user, req, content).await;
+        }
+    };
+    session.set("budget", budget.id);
+    let accounts = db::get_accounts_for_budget(&pool, budget.id).await;
     if accounts.is_none() {
         return Html(simple_error("Could not get accounts.")).into_response();
     }
 
-    let budget_total = accounts.as_ref().unwrap().iter().fold(0, |acc, x| {
-        acc + x.total
-    });
+    let (currency, budget_total) = budget_total(&pool, budget.id, accounts.as_ref().unwrap(), user.locale()).await;
 
-    Html(views::home(accounts.unwrap(), get_total_as_formatted_string(budget_total)).into_string()).into_response()
+    let budgets = budgets::get_for_user(&pool, user.id.unwrap()).await;
+    Html(views::home(&budgets, Some(&budget), accounts.unwrap(), budget_total, &currency, user.locale(), None).into_string()).into_response()
 }
 
 #[handler]
 pub fn logout(session: &Session) -> impl IntoResponse {
     session.remove("user");
+    session.remove("budget");
     Response::builder()
                 .status(StatusCode::FOUND)
                 .header(header::LOCATION, "/login")
                 .finish()
 }
+
+#[handler]
+pub async fn get_webhooks(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    match db::get_webhooks_for_budget(&pool, budget.id).await {
+        Some(w) => Html(views::webhooks(w).into_string()).into_response(),
+        None => Html(simple_error("Could not get webhooks.")).into_response()
+    }
+}
+
+#[handler]
+pub async fn create_webhook(pool: Data<&Pool<Sqlite>>, session: &Session, Form(fields): Form<Vec<(String, String)>>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let url = fields.iter().find(|(k, _)| k == "url").map(|(_, v)| v.trim()).unwrap_or_default();
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return StatusCode::BAD_REQUEST.with_body("Webhook url must start with http:// or https://").into_response();
+    }
+
+    let events: Vec<&str> = fields.iter()
+        .filter(|(k, _)| k == "event")
+        .filter_map(|(_, v)| webhooks::Event::from_str(v))
+        .map(|e| e.as_str())
+        .collect();
+    if events.is_empty() {
+        return StatusCode::BAD_REQUEST.with_body("Choose at least one event").into_response();
+    }
+
+    match db::create_webhook(&pool, budget.id, url, &webhooks::generate_secret(), &events).await {
+        Ok(_) => StatusCode::OK.with_header("HX-Trigger", "webhooksUpdated").into_response(),
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn delete_webhook(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    match db::delete_webhook(&pool, budget.id, id).await {
+        Ok(_) => StatusCode::OK.with_header("HX-Trigger", "webhooksUpdated").into_response(),
+        Err(message) => StatusCode::NOT_FOUND.with_body(message).into_response()
+    }
+}
+
+#[handler]
+pub async fn get_webhook_deliveries(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    match db::get_webhook_deliveries_for_budget(&pool, budget.id, 100).await {
+        Some(d) => Html(views::webhook_deliveries(d).into_string()).into_response(),
+        None => Html(simple_error("Could not get webhook deliveries.")).into_response()
+    }
+}
+
+#[handler]
+pub async fn import_page(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    match db::get_account(&pool, budget.id, id).await {
+        Some(account) => Html(views::import_upload(&account).into_string()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+/// Picks whichever of `,`, `;` or tab appears most in the first line.
+fn guess_delimiter(content: &str) -> char {
+    let first_line = content.lines().next().unwrap_or_default();
+    [',', ';', '\t'].into_iter()
+        .max_by_key(|d| first_line.matches(*d).count())
+        .unwrap()
+}
+
+#[handler]
+pub async fn upload_import(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, mut multipart: Multipart) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let mut content = None;
+    let mut file_name = String::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            file_name = field.file_name().unwrap_or_default().to_string();
+            content = field.text().await.ok();
+        }
+    }
+    let content = match content {
+        Some(c) if !c.trim().is_empty() => c,
+        _ => return Html(views::error_message("Choose a file to import.").into_string()).into_response(),
+    };
+
+    if let Some(format) = import::Format::detect(&file_name, &content) {
+        return Html(views::import_statement(&account, format, &content, false, user.locale()).into_string()).into_response();
+    }
+
+    let mapping = match db::get_csv_mapping(&pool, account.id.into()).await {
+        Some(m) => m,
+        None => {
+            let delimiter = guess_delimiter(&content);
+            CsvMapping::guess(&import::csv::headers(&content, delimiter, true), delimiter)
+        }
+    };
+
+    Html(views::import_mapping(&account, &content, &mapping, user.locale()).into_string()).into_response()
+}
+
+#[derive(Deserialize)]
+struct CsvImportForm {
+    content: String,
+    delimiter: String,
+    has_header: Option<String>,
+    date_column: String,
+    date_format: String,
+    payee_column: String,
+    memo_column: String,
+    amount_column: String,
+    inflow_column: String,
+    outflow_column: String,
+    decimal_separator: String,
+}
+
+impl CsvImportForm {
+    fn mapping(&self) -> CsvMapping {
+        let column = |value: &str| value.parse::<usize>().ok();
+        CsvMapping {
+            delimiter: match self.delimiter.as_str() {
+                "tab" => '\t',
+                d => d.chars().next().unwrap_or(','),
+            },
+            has_header: self.has_header.is_some(),
+            date_column: column(&self.date_column).unwrap_or(0),
+            date_format: self.date_format.clone(),
+            payee_column: column(&self.payee_column),
+            memo_column: column(&self.memo_column),
+            amount_column: column(&self.amount_column),
+            inflow_column: column(&self.inflow_column),
+            outflow_column: column(&self.outflow_column),
+            decimal_separator: if self.decimal_separator == "," { ',' } else { '.' },
+        }
+    }
+}
+
+#[handler]
+pub async fn preview_import(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(form): Form<CsvImportForm>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    match db::get_account(&pool, budget.id, id).await {
+        Some(account) => Html(views::import_mapping(&account, &form.content, &form.mapping(), user.locale()).into_string()).into_response(),
+        None => StatusCode::NOT_FOUND.into_response()
+    }
+}
+
+#[handler]
+pub async fn confirm_import(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(form): Form<CsvImportForm>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let mapping = form.mapping();
+    let (transactions, errors) = import::csv::parse(&form.content, &mapping, currency::exponent(&account.currency));
+
+    if let Err(message) = db::save_csv_mapping(&pool, account.id.into(), &mapping).await {
+        println!("{}", message);
+    }
+
+    match import::save(&pool, account.id.into(), &transactions).await {
+        Ok(saved) => Html(views::import_result(&account, &saved, &errors, None, user.locale()).into_string())
+            .with_header("HX-Trigger", "accountsUpdated")
+            .into_response(),
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct StatementImportForm {
+    content: String,
+    format: String,
+    day_first: Option<String>,
+}
+
+#[handler]
+pub async fn preview_statement_import(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(form): Form<StatementImportForm>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    match import::Format::from_str(&form.format) {
+        Some(format) => Html(views::import_statement(&account, format, &form.content, form.day_first.is_some(), user.locale()).into_string()).into_response(),
+        None => StatusCode::BAD_REQUEST.with_body("Unknown import format").into_response()
+    }
+}
+
+#[handler]
+pub async fn confirm_statement_import(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(form): Form<StatementImportForm>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let account = match db::get_account(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let format = match import::Format::from_str(&form.format) {
+        Some(f) => f,
+        None => return StatusCode::BAD_REQUEST.with_body("Unknown import format").into_response(),
+    };
+
+    let decimals = currency::exponent(&account.currency);
+    let (transactions, errors) = format.parse(&form.content, form.day_first.is_some(), decimals);
+    let saved = import::save(&pool, account.id.into(), &transactions).await;
+
+    match saved {
+        Ok(saved) => {
+            let closing_balance = format.closing_balance(&form.content, decimals);
+            if let Some(balance) = closing_balance {
+                if let Err(message) = db::set_reconcile_target(&pool, account.id.into(), balance.date, balance.amount).await {
+                    println!("{}", message);
+                }
+            }
+
+            Html(views::import_result(&account, &saved, &errors, closing_balance, user.locale()).into_string())
+                .with_header("HX-Trigger", "accountsUpdated")
+                .into_response()
+        }
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn ynab_import_page(session: &Session) -> impl IntoResponse {
+    if needs_login(session) {
+        return StatusCode::UNAUTHORIZED.into();
+    }
+
+    Html(views::ynab_upload().into_string()).into_response()
+}
+
+#[handler]
+pub async fn upload_ynab_import(pool: Data<&Pool<Sqlite>>, session: &Session, mut multipart: Multipart) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let mut files = YnabFiles::default();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            let file_name = field.file_name().unwrap_or_default().to_string();
+            if let Ok(content) = field.text().await {
+                files.add(&file_name, content);
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Html(views::error_message("Choose a YNAB register export or .yfull file to import.").into_string()).into_response();
+    }
+
+    Html(views::ynab_preview(&files, currency::exponent(&budget.currency)).into_string()).into_response()
+}
+
+#[handler]
+pub async fn confirm_ynab_import(pool: Data<&Pool<Sqlite>>, session: &Session, Form(files): Form<YnabFiles>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let (ynab_budget, errors) = files.parse(currency::exponent(&budget.currency));
+    match ynab::save(&pool, budget.id, &ynab_budget).await {
+        Ok(saved) => Html(views::ynab_result(&ynab_budget.summary(), &saved, &errors).into_string())
+            .with_header("HX-Trigger", "accountsUpdated")
+            .into_response(),
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn backup_page(session: &Session) -> impl IntoResponse {
+    if needs_login(session) {
+        return StatusCode::UNAUTHORIZED.into();
+    }
+
+    Html(views::backup().into_string()).into_response()
+}
+
+#[handler]
+pub async fn export_archive(pool: Data<&Pool<Sqlite>>, storage: Data<&Arc<dyn Storage>>, session: &Session) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let result = match archive::export(&pool, budget.id, user.id.unwrap()).await {
+        Ok(mut archive) => archive::load_attachments(&mut archive, storage.as_ref()).map(|_| archive).map_err(sqlx::Error::Io),
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(archive) => {
+            let file_name = format!("ymnab-{}.json", archive.exported_at.format("%Y-%m-%d"));
+            serde_json::to_string_pretty(&archive)
+                .unwrap()
+                .with_content_type("application/json")
+                .with_header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name))
+                .into_response()
+        }
+        Err(e) => {
+            println!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn export_journal(pool: Data<&Pool<Sqlite>>, session: &Session, Path(format): Path<String>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let dialect = match Dialect::from_str(&format) {
+        Some(d) => d,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    match archive::export(&pool, budget.id, user.id.unwrap()).await {
+        Ok(archive) => {
+            let file_name = format!("ymnab-{}.{}", archive.exported_at.format("%Y-%m-%d"), dialect.extension());
+            dialect
+                .export(&archive)
+                .with_content_type("text/plain; charset=utf-8")
+                .with_header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name))
+                .into_response()
+        }
+        Err(e) => {
+            println!("{:?}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn restore_archive(pool: Data<&Pool<Sqlite>>, storage: Data<&Arc<dyn Storage>>, session: &Session, mut multipart: Multipart) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let mut content = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            content = field.text().await.ok();
+        }
+    }
+    let archive: archive::Archive = match content.as_deref().map(serde_json::from_str) {
+        Some(Ok(a)) => a,
+        Some(Err(_)) => return Html(views::error_message("This file is not a ymnab archive.").into_string()).into_response(),
+        None => return Html(views::error_message("Choose an archive to restore.").into_string()).into_response(),
+    };
+
+    match archive::restore(&pool, storage.as_ref(), budget.id, user.id.unwrap(), &archive).await {
+        Ok(_) => Html(views::restored(&archive).into_string())
+            .with_header("HX-Trigger", "accountsUpdated")
+            .into_response(),
+        Err(message) => Html(views::error_message(message).into_string()).into_response(),
+    }
+}
+
+/// The budget currency, locale and exchange rates, with an optional message about the last change.
+async fn currencies(pool: &Pool<Sqlite>, user: &User, budget: &Budget, message: Option<&str>) -> Response {
+    let currency = db::get_budget_currency(pool, budget.id).await;
+    let rates = db::get_exchange_rates(pool, budget.id).await;
+    Html(views::currencies(&currency, user.locale(), &rates, message).into_string()).into_response()
+}
+
+#[handler]
+pub async fn currencies_page(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    currencies(&pool, &user, &budget, None).await
+}
+
+#[derive(Deserialize)]
+struct CurrencySettingsBody {
+    currency: String,
+}
+
+#[handler]
+pub async fn save_currency_settings(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<CurrencySettingsBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let currency = match currency::code(&body.currency) {
+        Some(c) => c,
+        None => return StatusCode::BAD_REQUEST.with_body("Unknown currency").into_response(),
+    };
+
+    match db::set_budget_currency(&pool, budget.id, &currency).await {
+        Ok(_) => currencies(&pool, &user, &budget, Some("Budget currency saved."))
+            .await
+            .with_header("HX-Trigger", "accountsUpdated")
+            .into_response(),
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LocaleBody {
+    locale: String,
+}
+
+/// The number format is the user's own, so anyone logged in can change it whatever their role.
+#[handler]
+pub async fn save_locale(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<LocaleBody>) -> impl IntoResponse {
+    let mut user = match current_user(&pool, session).await {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into(),
+    };
+    if !LOCALES.iter().any(|l| l.code == body.locale) {
+        return StatusCode::BAD_REQUEST.with_body("Unknown locale").into_response();
+    }
+
+    if let Err(message) = db::set_locale(&pool, user.id.unwrap(), &body.locale).await {
+        println!("{}", message);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    user.locale = body.locale;
+    let response = match budgets::current(&pool, user.id.unwrap(), session.get("budget")).await {
+        Some(budget) => currencies(&pool, &user, &budget, Some("Number format saved.")).await,
+        None => Html(html! { div id="currencies" class="p-4" { p class="text-sm" { "Number format saved." } } }.into_string()).into_response(),
+    };
+    response.with_header("HX-Trigger", "accountsUpdated").into_response()
+}
+
+#[derive(Deserialize)]
+struct ExchangeRateBody {
+    date: String,
+    base: String,
+    quote: String,
+    rate: String,
+}
+
+#[handler]
+pub async fn add_exchange_rate(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<ExchangeRateBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let rates = match currency::parse_csv(&[body.date, body.base, body.quote, body.rate].join(",")) {
+        Ok(r) if r.len() == 1 => r,
+        Ok(_) => return StatusCode::BAD_REQUEST.with_body("Invalid date").into_response(),
+        Err(e) => return StatusCode::BAD_REQUEST.with_body(e).into_response(),
+    };
+
+    match db::save_exchange_rates(&pool, budget.id, &rates).await {
+        Ok(_) => currencies(&pool, &user, &budget, Some("Exchange rate saved."))
+            .await
+            .with_header("HX-Trigger", "accountsUpdated")
+            .into_response(),
+        Err(message) => {
+            println!("{}", message);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn import_exchange_rates(pool: Data<&Pool<Sqlite>>, session: &Session, mut multipart: Multipart) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let mut content = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            content = field.text().await.ok();
+        }
+    }
+    let rates = match content.as_deref().map(currency::parse) {
+        Some(Ok(r)) => r,
+        Some(Err(e)) => return Html(views::error_message(&e).into_string()).into_response(),
+        None => return Html(views::error_message("Choose a file of exchange rates to import.").into_string()).into_response(),
+    };
+
+    match db::save_exchange_rates(&pool, budget.id, &rates).await {
+        Ok(_) => currencies(&pool, &user, &budget, Some(&format!("Imported {} exchange rates.", rates.len())))
+            .await
+            .with_header("HX-Trigger", "accountsUpdated")
+            .into_response(),
+        Err(message) => Html(views::error_message(message).into_string()).into_response(),
+    }
+}
+
+/// The budget's payees, with an optional message about the last change.
+async fn payees(pool: &Pool<Sqlite>, budget_id: i32, message: Option<&str>) -> Response {
+    let payees = db::get_payees_for_budget(pool, budget_id).await;
+    let categories = db::get_categories_for_budget(pool, budget_id).await;
+    Html(views::payees(&payees, &categories, message).into_string()).into_response()
+}
+
+#[handler]
+pub async fn payees_page(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    payees(&pool, budget.id, None).await
+}
+
+#[derive(Deserialize)]
+struct PayeeBody {
+    name: String,
+    /// Empty for no default category.
+    default_category_id: String,
+}
+
+#[handler]
+pub async fn update_payee(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<PayeeBody>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let name = body.name.trim();
+    if name.is_empty() {
+        return payees(&pool, budget.id, Some("Payees need a name.")).await;
+    }
+
+    let message = match db::update_payee(&pool, budget.id, id, name, body.default_category_id.parse().ok()).await {
+        Ok(_) => "Payee saved.",
+        Err(message) => message,
+    };
+    payees(&pool, budget.id, Some(message)).await
+}
+
+#[derive(Deserialize)]
+struct MergePayeeBody {
+    into_id: i64,
+}
+
+#[handler]
+pub async fn merge_payee(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<MergePayeeBody>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let message = match db::merge_payees(&pool, budget.id, id, body.into_id).await {
+        Ok(_) => "Payees merged.",
+        Err(message) => message,
+    };
+    payees(&pool, budget.id, Some(message)).await
+}
+
+#[handler]
+pub async fn delete_payee(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let message = match db::delete_payee(&pool, budget.id, id).await {
+        Ok(_) => "Payee deleted.",
+        Err(message) => message,
+    };
+    payees(&pool, budget.id, Some(message)).await
+}
+
+#[derive(Deserialize)]
+struct MonthParams {
+    /// Such as `2024-06`, this month when empty.
+    #[serde(default)]
+    month: String,
+}
+
+/// The first day of `month`, such as `2024-06`, or of this month if it isn't one.
+fn parse_month(month: &str) -> chrono::NaiveDate {
+    chrono::NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .unwrap_or_else(|_| chrono::Utc::now().date_naive().with_day(1).unwrap())
+}
+
+/// The budget's categories for the month starting on `month`, with an optional message about the
+/// last change.
+async fn category_list(pool: &Pool<Sqlite>, user: &User, budget: &Budget, month: chrono::NaiveDate, message: Option<&str>) -> Response {
+    let balances = db::get_category_balances(pool, budget.id, month).await;
+    Html(views::categories(&balances, month, &budget.currency, user.locale(), message).into_string()).into_response()
+}
+
+#[handler]
+pub async fn categories_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(params): Query<MonthParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let content = category_list(&pool, &user, &budget, parse_month(&params.month), None).await;
+    page(&pool, &user, &budget, req, content).await
+}
+
+#[derive(Deserialize)]
+struct AssignBody {
+    month: String,
+    assigned: String,
+}
+
+#[handler]
+pub async fn assign_category(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<AssignBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let month = parse_month(&body.month);
+
+    let message = match optional_money(&body.assigned, &budget.currency, user.locale()) {
+        Ok(assigned) => match db::set_category_assigned(&pool, user.id.unwrap(), budget.id, id, month, assigned).await {
+            Ok(_) => "Assigned.",
+            Err(message) => message,
+        },
+        Err(message) => message,
+    };
+    category_list(&pool, &user, &budget, month, Some(message)).await
+}
+
+#[derive(Deserialize)]
+struct MoveMoneyBody {
+    month: String,
+    from_id: i64,
+    to_id: i64,
+    amount: String,
+}
+
+#[handler]
+pub async fn move_money(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<MoveMoneyBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let month = parse_month(&body.month);
+
+    let message = match parse_money(&body.amount, &budget.currency, user.locale()) {
+        Ok(amount) => match db::move_assigned(&pool, user.id.unwrap(), budget.id, body.from_id, body.to_id, month, amount).await {
+            Ok(_) => "Money moved.",
+            Err(message) => message,
+        },
+        Err(message) => message,
+    };
+    category_list(&pool, &user, &budget, month, Some(message)).await
+}
+
+/// The budget's rules, with an optional message about the last change.
+async fn rule_list(pool: &Pool<Sqlite>, user: &User, budget: &Budget, message: Option<&str>) -> Response {
+    let rules = db::get_rules_for_budget(pool, budget.id).await;
+    let accounts = db::get_accounts_for_budget(pool, budget.id).await.unwrap_or_default();
+    let categories = db::get_categories_for_budget(pool, budget.id).await;
+    let currency = db::get_budget_currency(pool, budget.id).await;
+    Html(views::rules(&rules, &accounts, &categories, &currency, user.locale(), message).into_string()).into_response()
+}
+
+#[handler]
+pub async fn rules_page(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    rule_list(&pool, &user, &budget, None).await
+}
+
+/// A rule as entered on the rules page. Every field is optional apart from the name, and empty
+/// fields add no condition or action.
+#[derive(Deserialize)]
+struct RuleForm {
+    name: String,
+    #[serde(default)]
+    priority: String,
+    #[serde(default)]
+    payee_operator: String,
+    #[serde(default)]
+    payee: String,
+    #[serde(default)]
+    memo_operator: String,
+    #[serde(default)]
+    memo: String,
+    #[serde(default)]
+    min_amount: String,
+    #[serde(default)]
+    max_amount: String,
+    #[serde(default)]
+    account_id: String,
+    #[serde(default)]
+    rename_payee: String,
+    #[serde(default)]
+    category_id: String,
+    #[serde(default)]
+    set_memo: String,
+    #[serde(default)]
+    flag: String,
+}
+
+impl RuleForm {
+    /// Amounts are read in the budget currency, as a signed number where outflows are negative.
+    fn rule(&self, currency: &str, locale: &Locale) -> Result<rules::Rule, String> {
+        use rules::{Action, Condition, Field, Operator};
+
+        let operator = |value: &str| if value == "regex" { Operator::Regex } else { Operator::Contains };
+        let amount = |value: &str| match value.trim() {
+            "" => Ok(None),
+            value => parse_money(value, currency, locale).map(Some).map_err(|_| format!("{} is not an amount.", value)),
+        };
+
+        let mut conditions = vec![];
+        if !self.payee.is_empty() {
+            conditions.push(Condition::Text { field: Field::Payee, operator: operator(&self.payee_operator), value: self.payee.clone() });
+        }
+        if !self.memo.is_empty() {
+            conditions.push(Condition::Text { field: Field::Memo, operator: operator(&self.memo_operator), value: self.memo.clone() });
+        }
+        let (min, max) = (amount(&self.min_amount)?, amount(&self.max_amount)?);
+        if min.is_some() || max.is_some() {
+            conditions.push(Condition::Amount { min, max });
+        }
+        if let Ok(account_id) = self.account_id.parse() {
+            conditions.push(Condition::Account { account_id });
+        }
+
+        let mut actions = vec![];
+        if !self.rename_payee.trim().is_empty() {
+            actions.push(Action::RenamePayee { payee: self.rename_payee.trim().to_string() });
+        }
+        if let Ok(category_id) = self.category_id.parse() {
+            actions.push(Action::SetCategory { category_id });
+        }
+        if !self.set_memo.is_empty() {
+            actions.push(Action::SetMemo { memo: self.set_memo.clone() });
+        }
+        if !self.flag.is_empty() {
+            actions.push(Action::SetFlag { flag: self.flag.clone() });
+        }
+
+        let rule = rules::Rule {
+            id: 0,
+            name: self.name.trim().to_string(),
+            priority: self.priority.trim().parse().unwrap_or(0),
+            conditions,
+            actions,
+        };
+        rule.validate()?;
+        Ok(rule)
+    }
+}
+
+#[handler]
+pub async fn create_rule(pool: Data<&Pool<Sqlite>>, session: &Session, Form(form): Form<RuleForm>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let currency = db::get_budget_currency(&pool, budget.id).await;
+
+    let rule = match form.rule(&currency, user.locale()) {
+        Ok(rule) => rule,
+        Err(message) => return rule_list(&pool, &user, &budget, Some(&message)).await,
+    };
+    let message = match db::create_rule(&pool, budget.id, &rule).await {
+        Ok(_) => "Rule saved. It will run on new transactions.",
+        Err(message) => message,
+    };
+    rule_list(&pool, &user, &budget, Some(message)).await
+}
+
+#[handler]
+pub async fn delete_rule(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let message = match db::delete_rule(&pool, budget.id, id).await {
+        Ok(_) => "Rule deleted.",
+        Err(message) => message,
+    };
+    rule_list(&pool, &user, &budget, Some(message)).await
+}
+
+/// Runs a rule that has not been saved over the budget's existing transactions, showing which would
+/// match and how they would change. Nothing is written.
+#[handler]
+pub async fn preview_rule(pool: Data<&Pool<Sqlite>>, session: &Session, Form(form): Form<RuleForm>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let currency = db::get_budget_currency(&pool, budget.id).await;
+
+    let rule = match form.rule(&currency, user.locale()) {
+        Ok(rule) => rule,
+        Err(message) => return Html(views::error_message(&message).into_string()).into_response(),
+    };
+    let accounts = db::get_accounts_for_budget(&pool, budget.id).await.unwrap_or_default();
+    let categories = db::get_categories_for_budget(&pool, budget.id).await;
+
+    let matches: Vec<(db::Transaction, rules::Subject)> = db::get_transactions_for_budget(&pool, budget.id)
+        .await
+        .into_iter()
+        .filter_map(|transaction| {
+            let mut subject = rules::Subject {
+                account_id: transaction.account_id.into(),
+                payee: transaction.payee.clone(),
+                memo: transaction.memo.clone(),
+                amount: transaction.inflow - transaction.outflow,
+                category_id: transaction.category_id,
+                flag: transaction.flag.clone(),
+            };
+            if rules::apply(std::slice::from_ref(&rule), &mut subject).is_empty() {
+                return None;
+            }
+            Some((transaction, subject))
+        })
+        .collect();
+
+    Html(views::rule_preview(&matches, &accounts, &categories, user.locale()).into_string()).into_response()
+}
+
+#[handler]
+pub async fn transaction_details(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    match db::get_transaction_for_budget(&pool, budget.id, id).await {
+        Some(transaction) => details(&pool, &transaction, None).await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// The transaction's flag, tags, notes and attachments, with an optional message about the last
+/// change.
+async fn details(pool: &Pool<Sqlite>, transaction: &db::Transaction, message: Option<&str>) -> Response {
+    let attachments = db::get_attachments_for_transaction(pool, transaction.id.into()).await;
+    Html(views::transaction_details(transaction, &attachments, message).into_string()).into_response()
+}
+
+#[derive(Deserialize)]
+struct TransactionDetailsBody {
+    #[serde(default)]
+    flag: String,
+    /// Separated by commas.
+    #[serde(default)]
+    tags: String,
+    #[serde(default)]
+    notes: String,
+}
+
+#[handler]
+pub async fn update_transaction_details(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>, Form(body): Form<TransactionDetailsBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let transaction = match db::get_transaction_for_budget(&pool, budget.id, id).await {
+        Some(t) => t,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let flag = Some(body.flag).filter(|f| !f.is_empty());
+    if flag.as_deref().is_some_and(|f| !rules::FLAGS.contains(&f)) {
+        return StatusCode::BAD_REQUEST.with_body("Unknown flag").into_response();
+    }
+    let mut tags: Vec<String> = body.tags.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect();
+    tags.sort();
+    tags.dedup();
+    if tags.iter().any(|t| t.chars().count() > 50) {
+        return StatusCode::BAD_REQUEST.with_body("Tags can be at most 50 characters").into_response();
+    }
+
+    let changes = db::TransactionDetails { flag, tags, notes: Some(body.notes.trim().to_string()).filter(|n| !n.is_empty()) };
+    if let Err(message) = db::update_transaction_details(&pool, user.id.unwrap(), budget.id, id, &changes).await {
+        println!("{}", message);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    let transaction = db::get_transaction(&pool, transaction.id.into()).await.unwrap_or(transaction);
+    details(&pool, &transaction, Some("Saved.")).await.with_header("HX-Trigger", "transactionsUpdated").into_response()
+}
+
+#[handler]
+pub async fn tags_page(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let tags = db::get_tag_totals(&pool, budget.id).await;
+    Html(views::tags(&tags, user.locale()).into_string()).into_response()
+}
+
+#[handler]
+pub async fn upload_attachment(pool: Data<&Pool<Sqlite>>, storage: Data<&Arc<dyn Storage>>, session: &Session, Path(id): Path<i64>, mut multipart: Multipart) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let transaction = match db::get_transaction_for_budget(&pool, budget.id, id).await {
+        Some(t) => t,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let mut file = None;
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            let filename = attachments::clean_filename(field.file_name().unwrap_or_default());
+            file = field.bytes().await.ok().map(|bytes| (filename, bytes));
+        }
+    }
+    let (filename, bytes) = match file {
+        Some(f) => f,
+        None => return details(&pool, &transaction, Some("Choose a file to attach.")).await,
+    };
+    let content_type = match attachments::validate(&bytes) {
+        Ok(c) => c,
+        Err(message) => return details(&pool, &transaction, Some(&message)).await,
+    };
+
+    let key = attachments::generate_key();
+    if let Err(e) = storage.put(&key, &bytes) {
+        println!("{:?}", e);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    if let Err(message) = db::create_attachment(&pool, id, &filename, content_type, bytes.len() as i64, &key).await {
+        println!("{}", message);
+        storage.delete(&key).unwrap_or_default();
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    details(&pool, &transaction, Some("Attached.")).await
+}
+
+#[handler]
+pub async fn get_attachment(pool: Data<&Pool<Sqlite>>, storage: Data<&Arc<dyn Storage>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let attachment = match db::get_attachment(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    match storage.get(&attachment.storage_key) {
+        Ok(bytes) => bytes
+            .with_content_type(attachment.content_type)
+            .with_header(header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", attachment.filename))
+            .with_header("X-Content-Type-Options", "nosniff")
+            .into_response(),
+        Err(e) => {
+            println!("{:?}", e);
+            StatusCode::NOT_FOUND.into_response()
+        }
+    }
+}
+
+#[handler]
+pub async fn delete_attachment(pool: Data<&Pool<Sqlite>>, storage: Data<&Arc<dyn Storage>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let attachment = match db::get_attachment(&pool, budget.id, id).await {
+        Some(a) => a,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    if let Err(message) = db::delete_attachment(&pool, id).await {
+        println!("{}", message);
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    }
+    if let Err(e) = storage.delete(&attachment.storage_key) {
+        println!("{:?}", e);
+    }
+    match db::get_transaction_for_budget(&pool, budget.id, attachment.transaction_id).await {
+        Some(transaction) => details(&pool, &transaction, Some("Attachment deleted.")).await,
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// The bulk edit form. Ticked transactions arrive as repeated `ids` fields, which a struct can't
+/// be read from, so the form is read as pairs.
+#[derive(Default)]
+struct BulkEditForm {
+    ids: Vec<i64>,
+    action: String,
+    category_id: String,
+    payee: String,
+    flag: String,
+    account_id: String,
+}
+
+impl BulkEditForm {
+    fn new(pairs: Vec<(String, String)>) -> Result<Self, String> {
+        let mut form = Self::default();
+        for (name, value) in pairs {
+            match name.as_str() {
+                "ids" => form.ids.push(value.parse().map_err(|_| format!("{} is not a transaction.", value))?),
+                "action" => form.action = value,
+                "category_id" => form.category_id = value,
+                "payee" => form.payee = value,
+                "flag" => form.flag = value,
+                "account_id" => form.account_id = value,
+                _ => {}
+            }
+        }
+        form.ids.sort_unstable();
+        form.ids.dedup();
+        Ok(form)
+    }
+
+    fn edit(&self) -> Result<db::BulkEdit, String> {
+        let id = |value: &str, what: &str| value.parse().map_err(|_| format!("Choose {}.", what));
+        match self.action.as_str() {
+            "categorise" => Ok(db::BulkEdit::Categorise(id(&self.category_id, "a category")?)),
+            "payee" => match self.payee.trim() {
+                "" => Err("Enter a payee.".to_string()),
+                payee => Ok(db::BulkEdit::SetPayee(payee.to_string())),
+            },
+            "cleared" => Ok(db::BulkEdit::SetCleared(true)),
+            "uncleared" => Ok(db::BulkEdit::SetCleared(false)),
+            "flag" => match self.flag.as_str() {
+                "" => Ok(db::BulkEdit::SetFlag(None)),
+                flag if rules::FLAGS.contains(&flag) => Ok(db::BulkEdit::SetFlag(Some(flag.to_string()))),
+                flag => Err(format!("{} is not a flag colour.", flag)),
+            },
+            "move" => Ok(db::BulkEdit::Move(id(&self.account_id, "an account")?)),
+            "delete" => Ok(db::BulkEdit::Delete),
+            action => Err(format!("{} is not something that can be done to transactions.", action)),
+        }
+    }
+}
+
+/// Changes every ticked transaction at once, then has the register and sidebar refresh. Files
+/// attached to deleted transactions are removed once the transactions are gone, so undoing the
+/// delete brings the transactions back without them.
+#[handler]
+pub async fn bulk_edit(pool: Data<&Pool<Sqlite>>, storage: Data<&Arc<dyn Storage>>, session: &Session, Form(pairs): Form<Vec<(String, String)>>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let form = BulkEditForm::new(pairs);
+    let (ids, edit) = match form.and_then(|f| f.edit().map(|edit| (f.ids, edit))) {
+        Ok(edit) => edit,
+        Err(message) => return Html(message).into_response(),
+    };
+
+    match db::bulk_edit(&pool, user.id.unwrap(), budget.id, &ids, &edit).await {
+        Ok(storage_keys) => {
+            for key in storage_keys {
+                if let Err(e) = storage.delete(&key) {
+                    println!("{:?}", e);
+                }
+            }
+            Html(format!("{} transactions updated.", ids.len())).with_header("HX-Trigger", "transactionsUpdated").into_response()
+        }
+        Err(message) => Html(message).into_response(),
+    }
+}
+
+/// The latest change set from before the user logged in. Sessions from before undo existed start
+/// from now.
+async fn history_since(pool: &Pool<Sqlite>, session: &Session, user_id: i64) -> i64 {
+    match session.get::<i64>("history_since") {
+        Some(since) => since,
+        None => {
+            let since = history::latest(pool, user_id).await;
+            session.set("history_since", since);
+            since
+        }
+    }
+}
+
+async fn step_history(pool: &Pool<Sqlite>, session: &Session, backwards: bool) -> Response {
+    let (user, budget) = match current_budget(pool, session, Role::Editor).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let (user_id, budget_id) = (user.id.unwrap().into(), budget.id.into());
+    let since = history_since(pool, session, user_id).await;
+    let result = if backwards { history::undo(pool, user_id, budget_id, since).await } else { history::redo(pool, user_id, budget_id, since).await };
+
+    match result {
+        Ok(Some(description)) => {
+            let message = format!("{}: {}.", if backwards { "Undone" } else { "Redone" }, description);
+            Html(message)
+                .with_header("HX-Trigger", "transactionsUpdated, historyUpdated")
+                .into_response()
+        }
+        Ok(None) => Html(if backwards { "Nothing to undo." } else { "Nothing to redo." }).into_response(),
+        Err(message) => Html(message).into_response(),
+    }
+}
+
+/// Undoes the latest change made this session.
+#[handler]
+pub async fn undo(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    step_history(&pool, session, true).await
+}
+
+/// Redoes the change undone last, as long as nothing else has been done since.
+#[handler]
+pub async fn redo(pool: Data<&Pool<Sqlite>>, session: &Session) -> impl IntoResponse {
+    step_history(&pool, session, false).await
+}
+
+#[handler]
+pub async fn history_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let change_sets = history::get_change_sets(&pool, user.id.unwrap().into(), budget.id.into()).await;
+    let content = Html(views::history(&change_sets).into_string()).into_response();
+    page(&pool, &user, &budget, req, content).await
+}
+
+/// The changes made to the budget, narrowed down by the filters in the query string.
+#[handler]
+pub async fn audit_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(params): Query<AuditParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let budget_id = budget.id.into();
+
+    let entities = audit::get_entities(&pool, budget_id).await;
+    let content = match audit::get_entries(&pool, budget_id, &params).await {
+        Ok(entries) => views::audit_log(&entries, &entities, &params, None),
+        Err(message) => views::audit_log(&[], &entities, &params, Some(&message)),
+    };
+    page(&pool, &user, &budget, req, Html(content.into_string()).into_response()).await
+}
+
+/// The budget's members and pending invitations, with an optional message about the last change.
+/// The budget is read again so a member who changed their own role sees what they can now do.
+async fn members(pool: &Pool<Sqlite>, user: &User, budget: &Budget, message: Option<&str>) -> Response {
+    let budget = match budgets::get(pool, user.id.unwrap(), budget.id).await {
+        Some(b) => b,
+        None => return StatusCode::OK.with_header("HX-Redirect", "/").into_response(),
+    };
+    let members = budgets::get_members(pool, budget.id).await;
+    let invitations = if budget.role == Role::Owner { budgets::get_pending_invitations(pool, budget.id).await } else { vec![] };
+    Html(views::members(&budget, &members, &invitations, message).into_string()).into_response()
+}
+
+#[handler]
+pub async fn members_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let content = members(&pool, &user, &budget, None).await;
+    page(&pool, &user, &budget, req, content).await
+}
+
+#[derive(Deserialize)]
+struct InviteBody {
+    email: String,
+    role: String,
+}
+
+#[handler]
+pub async fn invite_member(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<InviteBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let role = match Role::from_str(&body.role) {
+        Some(r) => r,
+        None => return StatusCode::BAD_REQUEST.with_body("Unknown role").into_response(),
+    };
+
+    let message = match budgets::invite(&pool, budget.id, user.id.unwrap(), &body.email, role).await {
+        Ok(_) => format!("Invited {}. Send them the link below to join.", body.email.trim()),
+        Err(message) => message.to_string(),
+    };
+    members(&pool, &user, &budget, Some(&message)).await
+}
+
+#[derive(Deserialize)]
+struct MemberBody {
+    /// Empty to remove the member.
+    #[serde(default)]
+    role: String,
+}
+
+#[handler]
+pub async fn update_member(pool: Data<&Pool<Sqlite>>, session: &Session, Path(user_id): Path<i32>, Form(body): Form<MemberBody>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let role = match body.role.as_str() {
+        "" => None,
+        role => match Role::from_str(role) {
+            Some(r) => Some(r),
+            None => return StatusCode::BAD_REQUEST.with_body("Unknown role").into_response(),
+        },
+    };
+
+    let message = match budgets::set_member_role(&pool, budget.id, user_id, role).await {
+        Ok(_) if role.is_none() => "Member removed.",
+        Ok(_) => "Role saved.",
+        Err(message) => message,
+    };
+    members(&pool, &user, &budget, Some(message)).await
+}
+
+#[handler]
+pub async fn cancel_invitation(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i64>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Owner).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let message = match budgets::cancel_invitation(&pool, budget.id, id).await {
+        Ok(_) => "Invitation cancelled.",
+        Err(message) => message,
+    };
+    members(&pool, &user, &budget, Some(message)).await
+}
+
+/// Shows an invitation to whoever has its link. Anyone not logged in is asked to first, and comes
+/// back here afterwards.
+#[handler]
+pub async fn invitation_page(pool: Data<&Pool<Sqlite>>, session: &Session, Path(token): Path<String>) -> impl IntoResponse {
+    if needs_login(session) {
+        session.set("invitation", token);
+        return redirect_to_login();
+    }
+
+    match budgets::get_invitation(&pool, &token).await {
+        Some(invitation) => Html(views::invitation(&invitation).into_string()).into_response(),
+        None => StatusCode::NOT_FOUND.with_body("This invitation doesn't exist, or was cancelled.").into_response(),
+    }
+}
+
+/// Joins the invitation's budget and starts working in it.
+#[handler]
+pub async fn accept_invitation(pool: Data<&Pool<Sqlite>>, session: &Session, Path(token): Path<String>) -> impl IntoResponse {
+    let user = match current_user(&pool, session).await {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into(),
+    };
+
+    match budgets::accept_invitation(&pool, &token, &user).await {
+        Ok(budget_id) => {
+            session.set("budget", budget_id);
+            StatusCode::OK.with_header("HX-Redirect", "/").into_response()
+        }
+        Err(message) => Html(views::error_message(message).into_string()).into_response(),
+    }
+}
+
+/// The user's budgets, with an optional message about the last change.
+async fn budget_list(pool: &Pool<Sqlite>, user: &User, budget: Option<&Budget>, message: Option<&str>) -> Response {
+    let budgets = budgets::get_for_user(pool, user.id.unwrap()).await;
+    Html(views::budgets(&budgets, budget, message).into_string()).into_response()
+}
+
+#[handler]
+pub async fn budgets_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request) -> impl IntoResponse {
+    let user = match current_user(&pool, session).await {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into(),
+    };
+    let budget = budgets::current(&pool, user.id.unwrap(), session.get("budget")).await;
+
+    let content = budget_list(&pool, &user, budget.as_ref(), None).await;
+    match budget {
+        Some(budget) => page(&pool, &user, &budget, req, content).await,
+        None => page_without_budget(&pool, &user, req, content).await,
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateBudgetBody {
+    name: String,
+    currency: String,
+}
+
+/// Creates a budget and starts working in it. Anyone signed in can, including someone who isn't in
+/// any budget yet.
+#[handler]
+pub async fn create_budget(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<CreateBudgetBody>) -> impl IntoResponse {
+    let user = match current_user(&pool, session).await {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into(),
+    };
+    let budget = budgets::current(&pool, user.id.unwrap(), session.get("budget")).await;
+    let budget = budget.as_ref();
+    let name = body.name.trim();
+    if name.is_empty() {
+        return budget_list(&pool, &user, budget, Some("The budget needs a name.")).await;
+    }
+    let currency = match currency::code(&body.currency) {
+        Some(c) => c,
+        None => return budget_list(&pool, &user, budget, Some("Unknown currency.")).await,
+    };
+
+    match budgets::create(&pool, user.id.unwrap(), name, &currency).await {
+        Ok(id) => {
+            session.set("budget", id);
+            StatusCode::OK.with_header("HX-Redirect", "/").into_response()
+        }
+        Err(message) => budget_list(&pool, &user, budget, Some(message)).await,
+    }
+}
+
+#[derive(Deserialize)]
+struct SwitchBudgetBody {
+    budget_id: i32,
+}
+
+/// Starts working in another of the user's budgets.
+#[handler]
+pub async fn switch_budget(pool: Data<&Pool<Sqlite>>, session: &Session, Form(body): Form<SwitchBudgetBody>) -> impl IntoResponse {
+    let user = match current_user(&pool, session).await {
+        Some(u) => u,
+        None => return StatusCode::UNAUTHORIZED.into(),
+    };
+    if budgets::get(&pool, user.id.unwrap(), body.budget_id).await.is_none() {
+        return StatusCode::NOT_FOUND.with_body("You aren't a member of that budget.").into_response();
+    }
+
+    session.set("budget", body.budget_id);
+    StatusCode::OK.with_header("HX-Redirect", "/").into_response()
+}
+
+/// One of the user's budgets, as long as they own it. Owners can change any of their budgets from
+/// the list, not only the one they are working in.
+async fn owned_budget(pool: &Pool<Sqlite>, session: &Session, id: i32) -> Result<(User, Budget), Response> {
+    let (user, budget) = current_budget(pool, session, Role::Viewer).await?;
+    match budgets::get(pool, user.id.unwrap(), id).await {
+        Some(b) if b.role.allows(Role::Owner) => Ok((user, budget)),
+        Some(_) => Err(StatusCode::FORBIDDEN.with_body("Your role in this budget doesn't allow that.").into_response()),
+        None => Err(StatusCode::NOT_FOUND.with_body("You aren't a member of that budget.").into_response()),
+    }
+}
+
+#[derive(Deserialize)]
+struct RenameBudgetBody {
+    name: String,
+}
+
+#[handler]
+pub async fn rename_budget(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i32>, Form(body): Form<RenameBudgetBody>) -> impl IntoResponse {
+    let (user, budget) = match owned_budget(&pool, session, id).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let name = body.name.trim();
+    if name.is_empty() {
+        return budget_list(&pool, &user, Some(&budget), Some("The budget needs a name.")).await;
+    }
+
+    let message = match budgets::rename(&pool, id, name).await {
+        Ok(_) => "Budget renamed.",
+        Err(message) => message,
+    };
+    budget_list(&pool, &user, Some(&budget), Some(message)).await
+}
+
+#[derive(Deserialize)]
+struct ArchiveBudgetBody {
+    archived: bool,
+}
+
+/// Archives or brings back a budget. Archiving the budget being worked in moves the user on to
+/// another, so the whole page is reloaded.
+#[handler]
+pub async fn archive_budget(pool: Data<&Pool<Sqlite>>, session: &Session, Path(id): Path<i32>, Form(body): Form<ArchiveBudgetBody>) -> impl IntoResponse {
+    let (user, budget) = match owned_budget(&pool, session, id).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let message = match budgets::set_archived(&pool, id, body.archived).await {
+        Ok(_) if body.archived && id == budget.id => return StatusCode::OK.with_header("HX-Redirect", "/").into_response(),
+        Ok(_) if body.archived => "Budget archived.",
+        Ok(_) => "Budget brought back.",
+        Err(message) => message,
+    };
+    budget_list(&pool, &user, Some(&budget), Some(message)).await
+}
+
+/// Spending over a period, broken down by category group, category or payee. Covers the last three
+/// months unless asked for others.
+#[handler]
+pub async fn reports_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let (period, message) = report_period(&params, reports::Period::last_months(chrono::Utc::now().date_naive(), 3));
+
+    let rates = Rates::new(db::get_exchange_rates(&pool, budget.id).await);
+    let spending = reports::spending(&pool, budget.id, period, params.breakdown(), &budget.currency, &rates).await;
+    let content = views::spending_report(&spending, &params, period, user.locale(), message.as_deref());
+    page(&pool, &user, &budget, req, Html(content.into_string()).into_response()).await
+}
+
+/// The transactions behind a row of a report.
+#[handler]
+pub async fn report_lines(pool: Data<&Pool<Sqlite>>, session: &Session, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let period = match params.period(reports::Period::last_months(chrono::Utc::now().date_naive(), 3)) {
+        Ok(period) => period,
+        Err(message) => return Html(views::error_message(&message).into_string()).into_response(),
+    };
+
+    let lines = reports::lines(&pool, budget.id, period, params.breakdown(), &params.key).await;
+    Html(views::report_lines(&lines, user.locale()).into_string()).into_response()
+}
+
+/// The net worth over a period, the last year unless asked for another.
+async fn net_worth(pool: &Pool<Sqlite>, budget: &Budget, params: &ReportParams) -> (reports::NetWorth, reports::Period, Option<String>) {
+    let (period, message) = report_period(params, reports::Period::last_months(chrono::Utc::now().date_naive(), 12));
+    let rates = Rates::new(db::get_exchange_rates(pool, budget.id).await);
+    (reports::net_worth(pool, budget.id, period, &budget.currency, &rates).await, period, message)
+}
+
+#[handler]
+pub async fn net_worth_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let (net_worth, period, message) = net_worth(&pool, &budget, &params).await;
+    let content = views::net_worth_report(&net_worth, period, user.locale(), message.as_deref());
+    page(&pool, &user, &budget, req, Html(content.into_string()).into_response()).await
+}
+
+#[handler]
+pub async fn export_net_worth(pool: Data<&Pool<Sqlite>>, session: &Session, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let (net_worth, period, _) = net_worth(&pool, &budget, &params).await;
+    download(&net_worth, reports::Export::Csv, "net-worth", period)
+}
+
+/// Sends a report as a file named after it and the months it covers.
+fn download(report: &impl reports::Report, format: reports::Export, name: &str, period: reports::Period) -> Response {
+    let file_name = format!("{}-{}-{}.{}", name, period.from.format("%Y-%m"), period.to.format("%Y-%m"), format.extension());
+    format
+        .write(report)
+        .with_content_type(format.content_type())
+        .with_header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", file_name))
+        .into_response()
+}
+
+/// The period asked for, or `default` along with why it couldn't be read.
+fn report_period(params: &ReportParams, default: reports::Period) -> (reports::Period, Option<String>) {
+    match params.period(default) {
+        Ok(period) => (period, None),
+        Err(message) => (default, Some(message)),
+    }
+}
+
+/// Income against spending over the last year unless asked for another period.
+async fn income_expense(pool: &Pool<Sqlite>, budget: &Budget, params: &ReportParams) -> (reports::IncomeExpense, reports::Period, Option<String>) {
+    let (period, message) = report_period(params, reports::Period::last_months(chrono::Utc::now().date_naive(), 12));
+    let rates = Rates::new(db::get_exchange_rates(pool, budget.id).await);
+    (reports::income_expense(pool, budget.id, period, &budget.currency, &rates).await, period, message)
+}
+
+#[handler]
+pub async fn income_expense_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let (report, period, message) = income_expense(&pool, &budget, &params).await;
+    let content = views::income_expense_report(&report, period, user.locale(), message.as_deref());
+    page(&pool, &user, &budget, req, Html(content.into_string()).into_response()).await
+}
+
+#[handler]
+pub async fn export_income_expense(pool: Data<&Pool<Sqlite>>, session: &Session, Path(format): Path<String>, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let format = match reports::Export::from_str(&format) {
+        Some(f) => f,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let (report, period, _) = income_expense(&pool, &budget, &params).await;
+    download(&report, format, "income-and-spending", period)
+}
+
+/// Each account's cash flow over the last three months unless asked for another period.
+fn cash_flow_period(params: &ReportParams) -> (reports::Period, Option<String>) {
+    report_period(params, reports::Period::last_months(chrono::Utc::now().date_naive(), 3))
+}
+
+#[handler]
+pub async fn cash_flow_page(pool: Data<&Pool<Sqlite>>, session: &Session, req: &Request, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (user, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+
+    let (period, message) = cash_flow_period(&params);
+    let report = reports::cash_flow(&pool, budget.id, period).await;
+    let content = views::cash_flow_report(&report, user.locale(), message.as_deref());
+    page(&pool, &user, &budget, req, Html(content.into_string()).into_response()).await
+}
+
+#[handler]
+pub async fn export_cash_flow(pool: Data<&Pool<Sqlite>>, session: &Session, Path(format): Path<String>, Query(params): Query<ReportParams>) -> impl IntoResponse {
+    let (_, budget) = match current_budget(&pool, session, Role::Viewer).await {
+        Ok(b) => b,
+        Err(response) => return response,
+    };
+    let format = match reports::Export::from_str(&format) {
+        Some(f) => f,
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let (period, _) = cash_flow_period(&params);
+    let report = reports::cash_flow(&pool, budget.id, period).await;
+    download(&report, format, "cash-flow", period)
+}
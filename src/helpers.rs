@@ -1,60 +1,282 @@
-use rusty_money::{iso, Money};
+use rusty_money::iso;
 
-pub fn get_total_as_formatted_string(total: i64) -> String {
-    Money::from_minor(total, iso::GBP).to_string()
+use crate::currency;
+
+/// How a user writes amounts of money.
+#[derive(Debug, PartialEq)]
+pub struct Locale {
+    /// A BCP 47 tag such as `en-GB`.
+    pub code: &'static str,
+    pub name: &'static str,
+    pub decimal_separator: char,
+    /// Put between each group of three digits, as in "1,234".
+    pub group_separator: char,
+    /// Whether the currency symbol goes before the amount, as in "£12.00", or after, as in "12,00 €".
+    pub symbol_first: bool,
+    /// Whether there is a space between the symbol and the amount.
+    pub symbol_space: bool,
 }
 
-pub fn get_money_from_string(value: String) -> Result<i64, &'static str> {
-    let mut b = value.to_string();
-    if !b.contains(".") {
-        b.insert_str(b.len(), ".00");
+/// The locales a user can choose from. The first is the default.
+pub static LOCALES: [Locale; 7] = [
+    Locale { code: "en-GB", name: "English (UK)", decimal_separator: '.', group_separator: ',', symbol_first: true, symbol_space: false },
+    Locale { code: "en-US", name: "English (US)", decimal_separator: '.', group_separator: ',', symbol_first: true, symbol_space: false },
+    Locale { code: "de-DE", name: "Deutsch", decimal_separator: ',', group_separator: '.', symbol_first: false, symbol_space: true },
+    Locale { code: "fr-FR", name: "Français", decimal_separator: ',', group_separator: '\u{a0}', symbol_first: false, symbol_space: true },
+    Locale { code: "nl-NL", name: "Nederlands", decimal_separator: ',', group_separator: '.', symbol_first: true, symbol_space: true },
+    Locale { code: "de-CH", name: "Deutsch (Schweiz)", decimal_separator: '.', group_separator: '\'', symbol_first: true, symbol_space: true },
+    Locale { code: "ja-JP", name: "日本語", decimal_separator: '.', group_separator: ',', symbol_first: true, symbol_space: false },
+];
+
+impl Locale {
+    /// The locale with the given tag, or the default if there is none.
+    pub fn find(code: &str) -> &'static Locale {
+        LOCALES.iter().find(|l| l.code.eq_ignore_ascii_case(code)).unwrap_or(&LOCALES[0])
+    }
+
+    fn is_group_separator(&self, c: char) -> bool {
+        // Spaces are hard to type the right way, so any space separates groups where one does.
+        c == self.group_separator || (self.group_separator.is_whitespace() && c.is_whitespace())
     }
-    match b.trim().replace(",", "").replace(".", "").parse::<i64>() {
-        Ok(v) => Ok(v),
-        Err(e) => {
-            println!("{:?}", e);
-            Err("Failed to parse input string")
+}
+
+/// Formats an amount in the currency's minor units the way the locale writes it, such as "£1,200.00",
+/// "1.200,00 €" or "¥1,200".
+pub fn format_money(amount: i64, currency: &str, locale: &Locale) -> String {
+    let exponent = currency::exponent(currency);
+    let divisor = 10_u64.pow(exponent);
+    let (whole, fraction) = (amount.unsigned_abs() / divisor, amount.unsigned_abs() % divisor);
+
+    let digits = whole.to_string();
+    let mut number = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            number.push(locale.group_separator);
         }
+        number.push(digit);
+    }
+    if exponent > 0 {
+        number.push(locale.decimal_separator);
+        number.push_str(&format!("{:0width$}", fraction, width = exponent as usize));
+    }
+
+    let symbol = iso::find(currency).map_or(currency, |c| c.symbol);
+    let space = if locale.symbol_space { " " } else { "" };
+    let sign = if amount < 0 { "-" } else { "" };
+    if locale.symbol_first {
+        format!("{}{}{}{}", sign, symbol, space, number)
+    } else {
+        format!("{}{}{}{}", sign, number, space, symbol)
     }
 }
 
-#[cfg(test)] 
+/// Writes an amount in the currency's minor units as a plain number for files other programs read,
+/// such as "-12.50" or "1200" for yen.
+pub fn format_decimal(amount: i64, currency: &str) -> String {
+    let exponent = currency::exponent(currency);
+    if exponent == 0 {
+        return amount.to_string();
+    }
+
+    let divisor = 10_i64.pow(exponent);
+    let sign = if amount < 0 { "-" } else { "" };
+    format!("{}{}.{:0width$}", sign, amount.abs() / divisor, amount.abs() % divisor, width = exponent as usize)
+}
+
+/// Reads an amount written the way the locale writes it, such as "1,234.56" or "1.234,56", into
+/// minor units of `decimals` digits. Groups must be three digits, fewer decimals than `decimals`
+/// are padded, and negatives may be written as "-12", "12-" or "(12)".
+pub fn parse_number(value: &str, decimals: u32, locale: &Locale) -> Result<i64, &'static str> {
+    const INVALID: &str = "Failed to parse input string";
+
+    let value = value.trim();
+    let (negative, value) = if let Some(v) = value.strip_prefix('-').or_else(|| value.strip_suffix('-')) {
+        (true, v.trim())
+    } else if let Some(v) = value.strip_prefix('(').and_then(|v| v.strip_suffix(')')) {
+        (true, v.trim())
+    } else {
+        (false, value)
+    };
+
+    let (whole, fraction) = value.split_once(locale.decimal_separator).unwrap_or((value, ""));
+    let is_digits = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    if (whole.is_empty() && fraction.is_empty()) || !is_digits(fraction) || fraction.len() > decimals as usize {
+        return Err(INVALID);
+    }
+
+    let groups: Vec<&str> = whole.split(|c| locale.is_group_separator(c)).collect();
+    let well_grouped = groups.len() == 1 || (
+        (1..=3).contains(&groups[0].len()) && groups[1..].iter().all(|g| g.len() == 3)
+    );
+    if !well_grouped || !groups.iter().all(|g| is_digits(g)) {
+        return Err(INVALID);
+    }
+
+    let whole: i64 = match groups.concat() {
+        digits if digits.is_empty() => 0,
+        digits => digits.parse().map_err(|_| INVALID)?,
+    };
+    let fraction: i64 = match decimals {
+        0 => 0,
+        _ => format!("{:0<width$}", fraction, width = decimals as usize).parse().map_err(|_| INVALID)?,
+    };
+    let amount = whole
+        .checked_mul(10_i64.pow(decimals))
+        .and_then(|w| w.checked_add(fraction))
+        .ok_or(INVALID)?;
+
+    Ok(if negative { -amount } else { amount })
+}
+
+/// Reads an amount of the currency typed the way the locale writes it into the currency's minor
+/// units. The currency's symbol or code may be included.
+pub fn parse_money(value: &str, currency: &str, locale: &Locale) -> Result<i64, &'static str> {
+    let mut value = value.trim().replace(currency, "").replace(&currency.to_lowercase(), "");
+    if let Some(c) = iso::find(currency) {
+        value = value.replace(c.symbol, "");
+    }
+
+    // With the symbol gone, "-£12.00" and "£-12.00" read the same.
+    parse_number(&value, currency::exponent(currency), locale)
+}
+
+#[cfg(test)]
+#[allow(clippy::inconsistent_digit_grouping)]
 mod tests {
     use super::*;
 
+    fn en_gb() -> &'static Locale {
+        Locale::find("en-GB")
+    }
+
     #[test]
     fn test_formatted_string_less_than_100() {
-        assert_eq!(get_total_as_formatted_string(12), String::from("£0.12"));
+        assert_eq!(format_money(12, "GBP", en_gb()), String::from("£0.12"));
     }
 
     #[test]
     fn test_formatted_string_more_than_100() {
-        assert_eq!(get_total_as_formatted_string(1200), String::from("£12.00"));
+        assert_eq!(format_money(1200, "GBP", en_gb()), String::from("£12.00"));
     }
 
     #[test]
     fn test_formatted_string_more_than_1000() {
-        assert_eq!(get_total_as_formatted_string(1_200_00), String::from("£1,200.00"));
-        assert_eq!(get_total_as_formatted_string(12_200_00), String::from("£12,200.00"));
-        assert_eq!(get_total_as_formatted_string(123_200_00), String::from("£123,200.00"));
+        assert_eq!(format_money(1_200_00, "GBP", en_gb()), String::from("£1,200.00"));
+        assert_eq!(format_money(12_200_00, "GBP", en_gb()), String::from("£12,200.00"));
+        assert_eq!(format_money(123_200_00, "GBP", en_gb()), String::from("£123,200.00"));
         // I don't think I need to worry about millionaires, but it's here
-        assert_eq!(get_total_as_formatted_string(1_123_200_00), String::from("£1,123,200.00"));
-    }
-
-    #[test]
-    fn test_get_money_from_string() {
-        // no pence
-        assert_eq!(get_money_from_string(String::from("1,000")), Ok(1_000_00));
-        assert_eq!(get_money_from_string(String::from("1000")), Ok(1_000_00));
-        assert_eq!(get_money_from_string(String::from("1100.21")), Ok(1_100_21));
-        assert_eq!(get_money_from_string(String::from("10,000")), Ok(10_000_00));
-        assert_eq!(get_money_from_string(String::from("10,000.22")), Ok(10_000_22));
-        assert_eq!(get_money_from_string(String::from("10000.22")), Ok(10_000_22));
-        assert_eq!(get_money_from_string(String::from("00.00")), Ok(0));
-        assert_eq!(get_money_from_string(String::from("00")), Ok(0));
-        assert_eq!(get_money_from_string(String::from("0")), Ok(0));
-        assert_eq!(get_money_from_string(String::from("100,00.22")), Ok(10_000_22));
-        assert_eq!(get_money_from_string(String::from("100,00.22")), Ok(10_000_22));
-        assert_eq!(get_money_from_string(String::from("abcdefg")), Err("Failed to parse input string"));
+        assert_eq!(format_money(1_123_200_00, "GBP", en_gb()), String::from("£1,123,200.00"));
+    }
+
+    #[test]
+    fn test_format_decimal() {
+        assert_eq!(format_decimal(-1250, "GBP"), "-12.50");
+        assert_eq!(format_decimal(5, "EUR"), "0.05");
+        assert_eq!(format_decimal(-5, "EUR"), "-0.05");
+        assert_eq!(format_decimal(1200, "JPY"), "1200");
+    }
+
+    #[test]
+    fn test_format_money() {
+        let cases = [
+            (1_200_00, "USD", "en-US", "$1,200.00"),
+            (1_200_00, "EUR", "en-GB", "€1,200.00"),
+            (1_234_56, "EUR", "de-DE", "1.234,56 €"),
+            (-1_234_56, "EUR", "de-DE", "-1.234,56 €"),
+            (1_234_567_89, "EUR", "fr-FR", "1\u{a0}234\u{a0}567,89 €"),
+            (1_234_56, "EUR", "nl-NL", "€ 1.234,56"),
+            (-5, "GBP", "en-GB", "-£0.05"),
+            (0, "GBP", "en-GB", "£0.00"),
+            (1200, "JPY", "ja-JP", "¥1,200"),
+            (1200, "JPY", "de-DE", "1.200 ¥"),
+            (1_234_56, "CHF", "de-CH", "Fr 1'234.56"),
+            (1_234_567, "BHD", "en-GB", "د.ب1,234.567"),
+        ];
+
+        for (amount, currency, locale, expected) in cases {
+            assert_eq!(format_money(amount, currency, Locale::find(locale)), expected, "{} {} in {}", amount, currency, locale);
+        }
+    }
+
+    #[test]
+    fn test_parse_money() {
+        let invalid = Err("Failed to parse input string");
+        let cases = [
+            // The cases get_money_from_string used to handle.
+            ("1,000", "GBP", "en-GB", Ok(1_000_00)),
+            ("1000", "GBP", "en-GB", Ok(1_000_00)),
+            ("1100.21", "GBP", "en-GB", Ok(1_100_21)),
+            ("10,000", "GBP", "en-GB", Ok(10_000_00)),
+            ("10,000.22", "GBP", "en-GB", Ok(10_000_22)),
+            ("10000.22", "GBP", "en-GB", Ok(10_000_22)),
+            ("00.00", "GBP", "en-GB", Ok(0)),
+            ("00", "GBP", "en-GB", Ok(0)),
+            ("0", "GBP", "en-GB", Ok(0)),
+            ("abcdefg", "GBP", "en-GB", invalid),
+            // Groups must be three digits.
+            ("100,00.22", "GBP", "en-GB", invalid),
+            ("1,0000", "GBP", "en-GB", invalid),
+            (",100", "GBP", "en-GB", invalid),
+            ("1,", "GBP", "en-GB", invalid),
+            // Fewer decimals are padded, more are refused.
+            ("12.5", "GBP", "en-GB", Ok(12_50)),
+            (".5", "GBP", "en-GB", Ok(50)),
+            ("12.", "GBP", "en-GB", Ok(12_00)),
+            ("1.001", "GBP", "en-GB", invalid),
+            ("1.2.3", "GBP", "en-GB", invalid),
+            ("", "GBP", "en-GB", invalid),
+            (".", "GBP", "en-GB", invalid),
+            // Negatives.
+            ("-12.50", "GBP", "en-GB", Ok(-12_50)),
+            ("12.50-", "GBP", "en-GB", Ok(-12_50)),
+            ("(12.50)", "GBP", "en-GB", Ok(-12_50)),
+            ("--12", "GBP", "en-GB", invalid),
+            // Symbols and codes.
+            ("£1,234.56", "GBP", "en-GB", Ok(1_234_56)),
+            ("-£12", "GBP", "en-GB", Ok(-12_00)),
+            ("£-12", "GBP", "en-GB", Ok(-12_00)),
+            ("$ 12", "USD", "en-US", Ok(12_00)),
+            ("12 usd", "USD", "en-US", Ok(12_00)),
+            ("1.234,56 €", "EUR", "de-DE", Ok(1_234_56)),
+            ("EUR 12,5", "EUR", "nl-NL", Ok(12_50)),
+            // Other locales.
+            ("1.234,56", "EUR", "de-DE", Ok(1_234_56)),
+            ("1234,56", "EUR", "de-DE", Ok(1_234_56)),
+            ("12,5", "EUR", "de-DE", Ok(12_50)),
+            ("-0,01", "EUR", "de-DE", Ok(-1)),
+            ("1,234.56", "EUR", "de-DE", invalid),
+            ("12,5", "GBP", "en-GB", invalid),
+            ("1 234 567,89", "EUR", "fr-FR", Ok(1_234_567_89)),
+            ("1\u{a0}234,5", "EUR", "fr-FR", Ok(1_234_50)),
+            ("1'234.56", "CHF", "de-CH", Ok(1_234_56)),
+            // Minor units follow the currency.
+            ("1,200", "JPY", "ja-JP", Ok(1200)),
+            ("¥1200", "JPY", "ja-JP", Ok(1200)),
+            ("1200.5", "JPY", "ja-JP", invalid),
+            ("1.234", "BHD", "en-GB", Ok(1234)),
+            ("1.2345", "BHD", "en-GB", invalid),
+            // Too big to store.
+            ("99999999999999999999", "GBP", "en-GB", invalid),
+        ];
+
+        for (value, currency, locale, expected) in cases {
+            assert_eq!(parse_money(value, currency, Locale::find(locale)), expected, "{:?} {} in {}", value, currency, locale);
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for locale in &LOCALES {
+            for (amount, currency) in [(1_234_567_89, "EUR"), (-5, "GBP"), (1200, "JPY")] {
+                let formatted = format_money(amount, currency, locale);
+                assert_eq!(parse_money(&formatted, currency, locale), Ok(amount), "{} in {}", formatted, locale.code);
+            }
+        }
+    }
+
+    #[test]
+    fn test_unknown_locale_is_the_default() {
+        assert_eq!(Locale::find("xx-XX"), en_gb());
+        assert_eq!(Locale::find("de-de").code, "de-DE");
     }
 }
@@ -0,0 +1,241 @@
+use serde::Serialize;
+use sqlx::{prelude::FromRow, Pool, Sqlite, SqliteConnection};
+
+use crate::audit::{self, Audit};
+
+/// The tables changes are recorded for. Their names are put straight into SQL, so only these can
+/// be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Table {
+    Transactions,
+    TransactionTags,
+    TransactionSplits,
+    CategoryBudgets,
+}
+
+impl Table {
+    fn name(&self) -> &'static str {
+        match self {
+            Table::Transactions => "transactions",
+            Table::TransactionTags => "transaction_tags",
+            Table::TransactionSplits => "transaction_splits",
+            Table::CategoryBudgets => "category_budgets",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Table::Transactions, Table::TransactionTags, Table::TransactionSplits, Table::CategoryBudgets].into_iter().find(|t| t.name() == name)
+    }
+}
+
+/// Collects how rows look before and after a change so it can be saved as one change set, and
+/// logged. See `audit::Audit`.
+#[derive(Default)]
+pub struct Recorder {
+    audit: Audit,
+}
+
+impl Recorder {
+    /// Remembers the rows of `table` whose `column` is one of `ids`, before they change.
+    pub async fn watch(&mut self, conn: &mut SqliteConnection, table: Table, column: &'static str, ids: &[i64]) -> sqlx::Result<()> {
+        self.audit.watch(conn, table.name(), column, ids).await
+    }
+
+    /// Records rows that are about to be created, and so have nothing before.
+    pub fn created(&mut self, table: Table, column: &'static str, ids: &[i64]) {
+        self.audit.created(table.name(), column, ids);
+    }
+
+    /// Saves how the watched rows changed as the user's change set in the budget. Nothing is saved
+    /// when nothing changed. Change sets that were undone can't be redone after this.
+    pub async fn save(self, conn: &mut SqliteConnection, user_id: i64, budget_id: i64, description: &str) -> sqlx::Result<()> {
+        let changes = self.audit.save(&mut *conn, Some(user_id)).await?;
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query("UPDATE change_sets SET status = 'discarded' WHERE user_id = ? AND budget_id = ? AND status = 'undone'")
+            .bind(user_id)
+            .bind(budget_id)
+            .execute(&mut *conn)
+            .await?;
+        let change_set_id = sqlx::query("INSERT INTO change_sets (user_id, budget_id, description) VALUES (?, ?, ?)")
+            .bind(user_id)
+            .bind(budget_id)
+            .bind(description)
+            .execute(&mut *conn)
+            .await?
+            .last_insert_rowid();
+        for change in changes {
+            sqlx::query("INSERT INTO changes (change_set_id, table_name, row_id, before, after) VALUES (?, ?, ?, ?, ?)")
+                .bind(change_set_id)
+                .bind(change.table)
+                .bind(change.rowid)
+                .bind(change.before)
+                .bind(change.after)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Records rows that were just created outside of a `Recorder`, such as a transaction entered by
+/// hand, as one change set.
+pub async fn record_created(conn: &Pool<Sqlite>, user_id: i64, budget_id: i64, table: Table, ids: &[i64], description: &str) -> Result<(), &'static str> {
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut recorder = Recorder::default();
+        recorder.created(table, "id", ids);
+        recorder.save(&mut tx, user_id, budget_id, description).await?;
+        tx.commit().await
+    }.await;
+
+    result.map_err(|e| {
+        println!("{:?}", e);
+        "failed to record the change"
+    })
+}
+
+#[derive(Debug, FromRow)]
+struct Change {
+    table_name: String,
+    row_id: i64,
+    before: Option<String>,
+    after: Option<String>,
+}
+
+/// Puts a row back the way `image` has it, or removes it when there is no image. `exists` is
+/// whether the row is there now.
+async fn restore(conn: &mut SqliteConnection, table: Table, rowid: i64, exists: bool, image: Option<&str>) -> sqlx::Result<()> {
+    let Some(image) = image else {
+        sqlx::query(&format!("DELETE FROM \"{}\" WHERE rowid = ?", table.name()))
+            .bind(rowid)
+            .execute(conn)
+            .await?;
+        return Ok(());
+    };
+
+    let columns = audit::columns(&mut *conn, table.name()).await?;
+    let names: Vec<String> = columns.iter().map(|c| format!("\"{}\"", c)).collect();
+    let values: Vec<String> = columns.iter().map(|c| format!("json_extract(j, '$.\"{}\"')", c)).collect();
+    let query = if exists {
+        format!("UPDATE \"{}\" SET ({}) = (SELECT {} FROM (SELECT ? AS j)) WHERE rowid = ?", table.name(), names.join(", "), values.join(", "))
+    } else {
+        format!("INSERT INTO \"{}\" ({}, rowid) SELECT {}, ? FROM (SELECT ? AS j)", table.name(), names.join(", "), values.join(", "))
+    };
+    let query = sqlx::query(&query);
+    let query = if exists { query.bind(image).bind(rowid) } else { query.bind(rowid).bind(image) };
+    query.execute(conn).await?;
+    Ok(())
+}
+
+/// Undoes the user's latest change set in the budget since `since`, or redoes the one undone last, inside one
+/// database transaction. Returns what was undone or redone, or `None` if there was nothing to.
+/// Nothing happens if any of the rows have been changed since in a way the change set doesn't know
+/// about.
+async fn step(conn: &Pool<Sqlite>, user_id: i64, budget_id: i64, since: i64, undo: bool) -> Result<Option<String>, &'static str> {
+    let result: Result<Result<Option<String>, &'static str>, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let query = if undo {
+            "SELECT id, description FROM change_sets WHERE user_id = ? AND budget_id = ? AND id > ? AND status = 'done' ORDER BY id DESC LIMIT 1"
+        } else {
+            "SELECT id, description FROM change_sets WHERE user_id = ? AND budget_id = ? AND id > ? AND status = 'undone' ORDER BY id LIMIT 1"
+        };
+        let change_set: Option<(i64, String)> = sqlx::query_as(query)
+            .bind(user_id)
+            .bind(budget_id)
+            .bind(since)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some((id, description)) = change_set else {
+            return Ok(Ok(None));
+        };
+
+        let changes: Vec<Change> = sqlx::query_as("SELECT table_name, row_id, before, after FROM changes WHERE change_set_id = ? ORDER BY id")
+            .bind(id)
+            .fetch_all(&mut *tx)
+            .await?;
+        let mut steps = vec![];
+        for change in &changes {
+            let Some(table) = Table::parse(&change.table_name) else {
+                return Ok(Err("This change can't be undone any more."));
+            };
+            let (from, to) = if undo { (&change.after, &change.before) } else { (&change.before, &change.after) };
+            let now = audit::images(&mut tx, table.name(), "rowid", &[change.row_id]).await?.pop().map(|(_, image)| image);
+            if now != *from {
+                return Ok(Err("Those transactions have been changed since, so this can't be done."));
+            }
+            steps.push((table, change.row_id, from.is_some(), to.as_deref()));
+        }
+
+        // Rows are removed before they are changed or put back, so nothing clashes with a row that
+        // is on its way out, and references are only checked once everything is in place.
+        sqlx::query("PRAGMA defer_foreign_keys = ON").execute(&mut *tx).await?;
+        steps.sort_by_key(|(_, _, _, image)| image.is_some());
+        let mut audit = Audit::default();
+        for (table, rowid, _, _) in &steps {
+            audit.watch(&mut tx, table.name(), "rowid", &[*rowid]).await?;
+        }
+        for (table, rowid, exists, image) in steps {
+            restore(&mut tx, table, rowid, exists, image).await?;
+        }
+        audit.save(&mut tx, Some(user_id)).await?;
+        sqlx::query("UPDATE change_sets SET status = ? WHERE id = ?")
+            .bind(if undo { "undone" } else { "done" })
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(Ok(Some(description)))
+    }.await;
+
+    match result {
+        Ok(result) => result,
+        Err(e) => {
+            println!("{:?}", e);
+            Err("Failed to change the transactions back.")
+        }
+    }
+}
+
+pub async fn undo(conn: &Pool<Sqlite>, user_id: i64, budget_id: i64, since: i64) -> Result<Option<String>, &'static str> {
+    step(conn, user_id, budget_id, since, true).await
+}
+
+pub async fn redo(conn: &Pool<Sqlite>, user_id: i64, budget_id: i64, since: i64) -> Result<Option<String>, &'static str> {
+    step(conn, user_id, budget_id, since, false).await
+}
+
+/// The id of the user's latest change set in any budget, so a session can undo only what was done
+/// during it.
+pub async fn latest(conn: &Pool<Sqlite>, user_id: i64) -> i64 {
+    let result: Result<(Option<i64>,), sqlx::Error> = sqlx::query_as("SELECT MAX(id) FROM change_sets WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(conn)
+        .await;
+
+    result.ok().and_then(|(id,)| id).unwrap_or_default()
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct ChangeSet {
+    pub id: i64,
+    pub description: String,
+    pub created_at: chrono::NaiveDateTime,
+    /// `done`, `undone` or `discarded`.
+    pub status: String,
+    /// How many rows it changed.
+    pub changes: i64,
+}
+
+/// Everything the user has done in the budget that can be undone, newest first.
+pub async fn get_change_sets(conn: &Pool<Sqlite>, user_id: i64, budget_id: i64) -> Vec<ChangeSet> {
+    let result = sqlx::query_as::<_, ChangeSet>("SELECT s.id, s.description, s.created_at, s.status, (SELECT COUNT(*) FROM changes c WHERE c.change_set_id = s.id) AS changes FROM change_sets s WHERE s.user_id = ? AND s.budget_id = ? ORDER BY s.id DESC LIMIT 200")
+        .bind(user_id)
+        .bind(budget_id)
+        .fetch_all(conn)
+        .await;
+
+    result.unwrap_or_default()
+}
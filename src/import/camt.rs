@@ -0,0 +1,288 @@
+use chrono::NaiveDate;
+use quick_xml::{events::Event, Reader};
+
+use super::{parse_amount, ClosingBalance, ImportedTransaction, ParseError};
+
+/// Whether `content` looks like an ISO 20022 camt.053 bank to customer statement.
+pub fn is_camt053(content: &str) -> bool {
+    content.contains("camt.053") || content.contains("BkToCstmrStmt")
+}
+
+/// Reads an ISO date or date time, such as `2024-01-31` or `2024-01-31T10:00:00+01:00`.
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    value.get(..10).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+}
+
+/// Applies a `CdtDbtInd` of `CRDT` or `DBIT` to an unsigned camt amount.
+fn signed_amount(amount: &str, indicator: &str, decimals: u32) -> Result<i64, String> {
+    let value = parse_amount(amount, '.', decimals).map_err(|_| format!("Could not read amount \"{}\"", amount))?;
+    match indicator {
+        "CRDT" => Ok(value),
+        "DBIT" => Ok(-value),
+        other => Err(format!("Unknown credit/debit indicator \"{}\"", other)),
+    }
+}
+
+/// The fields of one `<Ntry>` that ymnab uses.
+#[derive(Default)]
+struct Entry {
+    line: usize,
+    amount: String,
+    indicator: String,
+    status: String,
+    booking_date: String,
+    value_date: String,
+    reference: Option<String>,
+    creditor: Option<String>,
+    debtor: Option<String>,
+    remittance: Vec<String>,
+    additional_info: Option<String>,
+}
+
+impl Entry {
+    fn set(&mut self, path: &[String], value: String) {
+        let ends_with = |suffix: &[&str]| path.len() >= suffix.len() && path[path.len() - suffix.len()..].iter().zip(suffix).all(|(a, b)| a == b);
+
+        if ends_with(&["Ntry", "Amt"]) {
+            self.amount = value;
+        } else if ends_with(&["Ntry", "CdtDbtInd"]) {
+            self.indicator = value;
+        } else if ends_with(&["Ntry", "Sts"]) || ends_with(&["Ntry", "Sts", "Cd"]) {
+            self.status = value;
+        } else if ends_with(&["BookgDt", "Dt"]) || ends_with(&["BookgDt", "DtTm"]) {
+            self.booking_date = value;
+        } else if ends_with(&["ValDt", "Dt"]) || ends_with(&["ValDt", "DtTm"]) {
+            self.value_date = value;
+        } else if ends_with(&["Ntry", "AcctSvcrRef"]) || (ends_with(&["Ntry", "NtryRef"]) && self.reference.is_none()) {
+            self.reference = Some(value);
+        } else if ends_with(&["Cdtr", "Nm"]) || ends_with(&["Cdtr", "Pty", "Nm"]) {
+            self.creditor.get_or_insert(value);
+        } else if ends_with(&["Dbtr", "Nm"]) || ends_with(&["Dbtr", "Pty", "Nm"]) {
+            self.debtor.get_or_insert(value);
+        } else if ends_with(&["RmtInf", "Ustrd"]) {
+            self.remittance.push(value);
+        } else if ends_with(&["Ntry", "AddtlNtryInf"]) {
+            self.additional_info = Some(value);
+        }
+    }
+
+    fn into_transaction(self, decimals: u32) -> Result<ImportedTransaction, ParseError> {
+        let line = self.line;
+        let date = parse_date(&self.booking_date)
+            .ok_or_else(|| ParseError::new(line, format!("Could not read booking date \"{}\"", self.booking_date)))?;
+        let amount = signed_amount(&self.amount, &self.indicator, decimals).map_err(|e| ParseError::new(line, e))?;
+
+        // The counterparty is whoever is on the other side of the money's direction.
+        let payee = if amount < 0 { self.creditor.or(self.debtor) } else { self.debtor.or(self.creditor) };
+        let memo = if self.remittance.is_empty() { self.additional_info } else { Some(self.remittance.join(" ")) };
+
+        Ok(ImportedTransaction {
+            date,
+            value_date: parse_date(&self.value_date),
+            payee,
+            memo,
+            amount,
+            import_id: self.reference,
+        })
+    }
+}
+
+/// The fields of one `<Bal>`.
+#[derive(Default)]
+struct Balance {
+    code: String,
+    amount: String,
+    indicator: String,
+    date: String,
+}
+
+fn line_at(content: &str, position: usize) -> usize {
+    content[..position.min(content.len())].matches('\n').count() + 1
+}
+
+/// Walks the document, returning every booked entry and every closing balance (`CLBD`).
+fn read(content: &str, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>, Vec<ClosingBalance>) {
+    let mut reader = Reader::from_str(content);
+    reader.trim_text(true);
+
+    let mut transactions = vec![];
+    let mut errors = vec![];
+    let mut balances = vec![];
+    let mut path: Vec<String> = vec![];
+    let mut entry: Option<Entry> = None;
+    let mut balance: Option<Balance> = None;
+
+    loop {
+        let event = reader.read_event();
+        let line = line_at(content, reader.buffer_position());
+        match event {
+            Ok(Event::Start(e)) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).to_string();
+                match name.as_str() {
+                    "Ntry" => entry = Some(Entry { line, ..Default::default() }),
+                    "Bal" => balance = Some(Balance::default()),
+                    _ => {}
+                }
+                path.push(name);
+            }
+            Ok(Event::End(_)) => {
+                match path.pop().as_deref() {
+                    Some("Ntry") => {
+                        if let Some(e) = entry.take().filter(|e| e.status != "PDNG" && e.status != "INFO") {
+                            match e.into_transaction(decimals) {
+                                Ok(t) => transactions.push(t),
+                                Err(e) => errors.push(e),
+                            }
+                        }
+                    }
+                    Some("Bal") => {
+                        if let Some(b) = balance.take().filter(|b| b.code == "CLBD") {
+                            if let (Some(date), Ok(amount)) = (parse_date(&b.date), signed_amount(&b.amount, &b.indicator, decimals)) {
+                                balances.push(ClosingBalance { date, amount });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Ok(Event::Text(e)) => {
+                let value = e.unescape().map(|v| v.to_string()).unwrap_or_default();
+                if let Some(current) = entry.as_mut() {
+                    current.set(&path, value);
+                } else if let Some(current) = balance.as_mut() {
+                    match path.last().map(|p| p.as_str()) {
+                        Some("Cd") => current.code = value,
+                        Some("Amt") => current.amount = value,
+                        Some("CdtDbtInd") => current.indicator = value,
+                        Some("Dt") | Some("DtTm") => current.date = value,
+                        _ => {}
+                    }
+                }
+            }
+            Ok(Event::Eof) => break,
+            Ok(_) => {}
+            Err(e) => {
+                errors.push(ParseError::new(line, format!("Invalid XML: {}", e)));
+                break;
+            }
+        }
+    }
+
+    (transactions, errors, balances)
+}
+
+/// Reads the booked entries of every `<Stmt>` in a camt.053 file. Pending entries are skipped.
+/// The bank's `AcctSvcrRef` is used as the import id.
+pub fn parse(content: &str, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>) {
+    let (transactions, errors, _) = read(content, decimals);
+    (transactions, errors)
+}
+
+pub fn closing_balance(content: &str, decimals: u32) -> Option<ClosingBalance> {
+    read(content, decimals).2.into_iter().max_by_key(|b| b.date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATEMENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.053.001.02">
+  <BkToCstmrStmt>
+    <Stmt>
+      <Id>STMT-1</Id>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>OPBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">1000.00</Amt><CdtDbtInd>CRDT</CdtDbtInd><Dt><Dt>2024-01-01</Dt></Dt>
+      </Bal>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>CLBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">987.50</Amt><CdtDbtInd>CRDT</CdtDbtInd><Dt><Dt>2024-01-31</Dt></Dt>
+      </Bal>
+      <Ntry>
+        <Amt Ccy="EUR">12.5</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <Sts>BOOK</Sts>
+        <BookgDt><Dt>2024-01-05</Dt></BookgDt>
+        <ValDt><Dt>2024-01-04</Dt></ValDt>
+        <AcctSvcrRef>REF-1</AcctSvcrRef>
+        <NtryDtls><TxDtls>
+          <RltdPties><Dbtr><Nm>Me</Nm></Dbtr><Cdtr><Nm>Tesco Stores</Nm></Cdtr></RltdPties>
+          <RmtInf><Ustrd>Card payment</Ustrd><Ustrd>2231</Ustrd></RmtInf>
+        </TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">5.00</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <Sts>PDNG</Sts>
+        <BookgDt><Dt>2024-01-31</Dt></BookgDt>
+      </Ntry>
+    </Stmt>
+    <Stmt>
+      <Id>STMT-2</Id>
+      <Bal>
+        <Tp><CdOrPrtry><Cd>CLBD</Cd></CdOrPrtry></Tp>
+        <Amt Ccy="EUR">2987.50</Amt><CdtDbtInd>CRDT</CdtDbtInd><Dt><Dt>2024-02-29</Dt></Dt>
+      </Bal>
+      <Ntry>
+        <Amt Ccy="EUR">2000.00</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+        <Sts><Cd>BOOK</Cd></Sts>
+        <BookgDt><DtTm>2024-02-01T09:00:00</DtTm></BookgDt>
+        <AcctSvcrRef>REF-2</AcctSvcrRef>
+        <NtryDtls><TxDtls>
+          <RltdPties><Dbtr><Pty><Nm>ACME Ltd</Nm></Pty></Dbtr></RltdPties>
+        </TxDtls></NtryDtls>
+        <AddtlNtryInf>Salary</AddtlNtryInf>
+      </Ntry>
+      <Ntry>
+        <Amt Ccy="EUR">1.00</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <Sts>BOOK</Sts>
+        <BookgDt><Dt>soon</Dt></BookgDt>
+      </Ntry>
+    </Stmt>
+  </BkToCstmrStmt>
+</Document>"#;
+
+    #[test]
+    fn test_parse() {
+        let (transactions, errors) = parse(STATEMENT, 2);
+
+        assert_eq!(transactions, vec![
+            ImportedTransaction {
+                date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap(),
+                value_date: NaiveDate::from_ymd_opt(2024, 1, 4),
+                payee: Some("Tesco Stores".to_string()),
+                memo: Some("Card payment 2231".to_string()),
+                amount: -1250,
+                import_id: Some("REF-1".to_string()),
+            },
+            ImportedTransaction {
+                date: NaiveDate::from_ymd_opt(2024, 2, 1).unwrap(),
+                value_date: None,
+                payee: Some("ACME Ltd".to_string()),
+                memo: Some("Salary".to_string()),
+                amount: 200000,
+                import_id: Some("REF-2".to_string()),
+            },
+        ]);
+        assert_eq!(errors, vec![ParseError::new(50, "Could not read booking date \"soon\"")]);
+    }
+
+    #[test]
+    fn test_closing_balance_uses_latest_statement() {
+        assert_eq!(closing_balance(STATEMENT, 2), Some(ClosingBalance {
+            date: NaiveDate::from_ymd_opt(2024, 2, 29).unwrap(),
+            amount: 298750,
+        }));
+    }
+
+    #[test]
+    fn test_invalid_xml() {
+        let (transactions, errors) = parse("<Document><BkToCstmrStmt></Stmt></Document>", 2);
+
+        assert!(transactions.is_empty());
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use super::{parse_amount, ImportedTransaction, ParseError};
+
+/// Date formats offered when mapping a CSV file, as `(chrono format, label)`.
+pub const DATE_FORMATS: [(&str, &str); 6] = [
+    ("%Y-%m-%d", "2024-01-31"),
+    ("%d/%m/%Y", "31/01/2024"),
+    ("%m/%d/%Y", "01/31/2024"),
+    ("%d-%m-%Y", "31-01-2024"),
+    ("%d.%m.%Y", "31.01.2024"),
+    ("%Y/%m/%d", "2024/01/31"),
+];
+
+/// How the columns of a bank's CSV export map onto a transaction. Saved per account so the next
+/// import from the same bank needs no changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CsvMapping {
+    pub delimiter: char,
+    pub has_header: bool,
+    pub date_column: usize,
+    pub date_format: String,
+    pub payee_column: Option<usize>,
+    pub memo_column: Option<usize>,
+    /// A single signed amount column. When unset, `inflow_column` and `outflow_column` are used.
+    pub amount_column: Option<usize>,
+    pub inflow_column: Option<usize>,
+    pub outflow_column: Option<usize>,
+    pub decimal_separator: char,
+}
+
+impl CsvMapping {
+    /// Guesses a mapping from the header row, falling back to the first column for everything.
+    pub fn guess(headers: &[String], delimiter: char) -> Self {
+        let find = |names: &[&str]| {
+            headers.iter().position(|h| {
+                let h = h.to_lowercase();
+                names.iter().any(|n| h.contains(n))
+            })
+        };
+
+        let amount_column = find(&["amount"]);
+        let split_column = |names: &[&str]| if amount_column.is_some() { None } else { find(names) };
+        Self {
+            delimiter,
+            has_header: true,
+            date_column: find(&["date"]).unwrap_or(0),
+            date_format: DATE_FORMATS[0].0.to_string(),
+            payee_column: find(&["payee", "description", "name", "merchant"]),
+            memo_column: find(&["memo", "reference", "notes"]),
+            amount_column,
+            inflow_column: split_column(&["paid in", "credit", "inflow", "money in"]),
+            outflow_column: split_column(&["paid out", "debit", "outflow", "money out"]),
+            decimal_separator: if delimiter == ';' { ',' } else { '.' },
+        }
+    }
+}
+
+fn reader(content: &str, delimiter: char, has_header: bool) -> ::csv::Reader<&[u8]> {
+    ::csv::ReaderBuilder::new()
+        .delimiter(delimiter as u8)
+        .has_headers(has_header)
+        .flexible(true)
+        .trim(::csv::Trim::All)
+        .from_reader(content.as_bytes())
+}
+
+/// The column names of the file, or `Column 1`, `Column 2`... when it has no header row.
+pub fn headers(content: &str, delimiter: char, has_header: bool) -> Vec<String> {
+    let mut reader = reader(content, delimiter, false);
+    match reader.records().next() {
+        Some(Ok(first)) if has_header => first.iter().map(|h| h.to_string()).collect(),
+        Some(Ok(first)) => (1..=first.len()).map(|i| format!("Column {}", i)).collect(),
+        _ => vec![],
+    }
+}
+
+fn column(record: &::csv::StringRecord, index: Option<usize>) -> Option<&str> {
+    index.and_then(|i| record.get(i)).filter(|v| !v.is_empty())
+}
+
+fn parse_record(record: &::csv::StringRecord, mapping: &CsvMapping, decimals: u32) -> Result<ImportedTransaction, String> {
+    let date_value = column(record, Some(mapping.date_column)).ok_or("Missing date")?;
+    let date = NaiveDate::parse_from_str(date_value, &mapping.date_format)
+        .map_err(|_| format!("Could not read date \"{}\"", date_value))?;
+
+    let amount_of = |index: Option<usize>| match column(record, index) {
+        Some(v) => parse_amount(v, mapping.decimal_separator, decimals).map_err(|_| format!("Could not read amount \"{}\"", v)),
+        None => Ok(0),
+    };
+
+    let amount = match mapping.amount_column {
+        Some(_) => amount_of(mapping.amount_column)?,
+        None => amount_of(mapping.inflow_column)?.abs() - amount_of(mapping.outflow_column)?.abs(),
+    };
+
+    Ok(ImportedTransaction {
+        date,
+        value_date: None,
+        payee: column(record, mapping.payee_column).map(String::from),
+        memo: column(record, mapping.memo_column).map(String::from),
+        amount,
+        import_id: None,
+    })
+}
+
+/// Reads every row of `content` using `mapping`, with amounts in minor units of a currency with
+/// `decimals` decimal places. Rows that cannot be read are returned as errors alongside the rows
+/// that could.
+pub fn parse(content: &str, mapping: &CsvMapping, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>) {
+    let mut transactions = vec![];
+    let mut errors = vec![];
+
+    for (index, record) in reader(content, mapping.delimiter, mapping.has_header).records().enumerate() {
+        let line = match &record {
+            Ok(r) => r.position().map(|p| p.line() as usize).unwrap_or(index + 1),
+            Err(e) => e.position().map(|p| p.line() as usize).unwrap_or(index + 1),
+        };
+
+        match record.map_err(|e| e.to_string()).and_then(|r| parse_record(&r, mapping, decimals)) {
+            Ok(t) => transactions.push(t),
+            Err(message) => errors.push(ParseError::new(line, message)),
+        }
+    }
+
+    (transactions, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_guess_mapping() {
+        let content = "Date,Description,Paid out,Paid in,Reference\n";
+        let mapping = CsvMapping::guess(&headers(content, ',', true), ',');
+
+        assert_eq!(mapping.date_column, 0);
+        assert_eq!(mapping.payee_column, Some(1));
+        assert_eq!(mapping.amount_column, None);
+        assert_eq!(mapping.outflow_column, Some(2));
+        assert_eq!(mapping.inflow_column, Some(3));
+        assert_eq!(mapping.memo_column, Some(4));
+    }
+
+    #[test]
+    fn test_parse_signed_amount() {
+        let content = "Date,Payee,Amount\n2024-02-01,Tesco,-12.50\n2024-02-03,Employer,\"2,000.00\"\n";
+        let mapping = CsvMapping::guess(&headers(content, ',', true), ',');
+
+        let (transactions, errors) = parse(content, &mapping, 2);
+
+        assert!(errors.is_empty());
+        assert_eq!(transactions, vec![
+            ImportedTransaction { date: date(2024, 2, 1), value_date: None, payee: Some("Tesco".to_string()), memo: None, amount: -1250, import_id: None },
+            ImportedTransaction { date: date(2024, 2, 3), value_date: None, payee: Some("Employer".to_string()), memo: None, amount: 200000, import_id: None },
+        ]);
+    }
+
+    #[test]
+    fn test_parse_inflow_outflow_with_decimal_comma() {
+        let content = "01.02.2024;Miete;1.200,00;\n02.02.2024;Gehalt;;2.500,50\n";
+        let mapping = CsvMapping {
+            delimiter: ';',
+            has_header: false,
+            date_column: 0,
+            date_format: "%d.%m.%Y".to_string(),
+            payee_column: Some(1),
+            memo_column: None,
+            amount_column: None,
+            inflow_column: Some(3),
+            outflow_column: Some(2),
+            decimal_separator: ',',
+        };
+
+        let (transactions, errors) = parse(content, &mapping, 2);
+
+        assert!(errors.is_empty());
+        assert_eq!(transactions[0].amount, -120000);
+        assert_eq!(transactions[1].amount, 250050);
+        assert_eq!(headers(content, ';', false), vec!["Column 1", "Column 2", "Column 3", "Column 4"]);
+    }
+
+    #[test]
+    fn test_parse_reports_bad_rows() {
+        let content = "Date,Payee,Amount\n2024-02-01,Tesco,-12.50\nyesterday,Aldi,-3.00\n2024-02-03,Boots,lots\n";
+        let mapping = CsvMapping::guess(&headers(content, ',', true), ',');
+
+        let (transactions, errors) = parse(content, &mapping, 2);
+
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(errors, vec![
+            ParseError::new(3, "Could not read date \"yesterday\""),
+            ParseError::new(4, "Could not read amount \"lots\""),
+        ]);
+    }
+}
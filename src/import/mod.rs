@@ -0,0 +1,257 @@
+pub mod camt;
+pub mod csv;
+pub mod mt940;
+pub mod ofx;
+pub mod qif;
+pub mod ynab;
+
+use chrono::NaiveDate;
+use sqlx::{Pool, Sqlite};
+
+use crate::{db::{self, NewTransaction}, helpers::{parse_number, Locale}};
+
+/// A transaction read from a bank file, before it is attached to an account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedTransaction {
+    pub date: NaiveDate,
+    /// When the money actually moved, if the bank gives it separately from the booking `date`.
+    pub value_date: Option<NaiveDate>,
+    pub payee: Option<String>,
+    pub memo: Option<String>,
+    /// Positive amounts are inflows, negative amounts are outflows.
+    pub amount: i64,
+    /// A stable id from the bank, such as an OFX FITID, used to skip transactions that were
+    /// already imported.
+    pub import_id: Option<String>,
+}
+
+impl ImportedTransaction {
+    pub fn to_new_transaction(&self, account_id: i64) -> NewTransaction {
+        NewTransaction {
+            account_id,
+            date: self.date.and_hms_opt(0, 0, 0).unwrap(),
+            value_date: self.value_date,
+            payee: self.payee.clone(),
+            memo: self.memo.clone().unwrap_or_default(),
+            inflow: self.amount.max(0),
+            outflow: (-self.amount).max(0),
+            cleared: true,
+            import_id: self.import_id.clone(),
+            imported: true,
+            category_id: None,
+        }
+    }
+}
+
+/// Bank file formats that describe their own fields, so need no column mapping like CSV does.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Ofx,
+    Qif,
+    Camt053,
+    Mt940,
+}
+
+impl Format {
+    pub const ALL: [Format; 4] = [Format::Ofx, Format::Qif, Format::Camt053, Format::Mt940];
+
+    /// Works out the format from the file name or content. `None` means the file is treated as CSV.
+    pub fn detect(file_name: &str, content: &str) -> Option<Self> {
+        let file_name = file_name.to_lowercase();
+        if file_name.ends_with(".ofx") || file_name.ends_with(".qfx") || ofx::is_ofx(content) {
+            Some(Format::Ofx)
+        } else if file_name.ends_with(".qif") || qif::is_qif(content) {
+            Some(Format::Qif)
+        } else if camt::is_camt053(content) {
+            Some(Format::Camt053)
+        } else if file_name.ends_with(".sta") || file_name.ends_with(".mt940") || mt940::is_mt940(content) {
+            Some(Format::Mt940)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Ofx => "ofx",
+            Format::Qif => "qif",
+            Format::Camt053 => "camt.053",
+            Format::Mt940 => "mt940",
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|f| f.as_str() == value)
+    }
+
+    /// Reads `content` in this format, with amounts in minor units of a currency with `decimals`
+    /// decimal places. `day_first` is only used by formats without a fixed date order, such as QIF.
+    pub fn parse(&self, content: &str, day_first: bool, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>) {
+        match self {
+            Format::Ofx => ofx::parse(content, decimals),
+            Format::Qif => qif::parse(content, day_first, decimals),
+            Format::Camt053 => camt::parse(content, decimals),
+            Format::Mt940 => mt940::parse(content, decimals),
+        }
+    }
+
+    /// The closing balance of the latest statement in the file, for formats that include one.
+    pub fn closing_balance(&self, content: &str, decimals: u32) -> Option<ClosingBalance> {
+        match self {
+            Format::Ofx | Format::Qif => None,
+            Format::Camt053 => camt::closing_balance(content, decimals),
+            Format::Mt940 => mt940::closing_balance(content, decimals),
+        }
+    }
+}
+
+/// The booked balance at the end of a statement, used as the account's reconciliation target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClosingBalance {
+    pub date: NaiveDate,
+    pub amount: i64,
+}
+
+/// A line of an import file that could not be read. The rest of the file is still imported.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl ParseError {
+    pub fn new(line: usize, message: impl Into<String>) -> Self {
+        Self { line, message: message.into() }
+    }
+}
+
+/// Parses an amount as written in a bank file into minor units of a currency with `decimals`
+/// decimal places, where `decimal_separator` is either `.` or `,`. Currency symbols and spaces
+/// are ignored.
+pub fn parse_amount(value: &str, decimal_separator: char, decimals: u32) -> Result<i64, &'static str> {
+    let cleaned: String = value
+        .chars()
+        .filter(|c| !matches!(c, '£' | '$' | '€' | '¥' | ' ' | '\u{a0}'))
+        .collect();
+
+    // Some banks write every currency with two decimal places, such as 1500.00 yen.
+    let cleaned = match cleaned.split_once(decimal_separator) {
+        Some((whole, fraction)) if fraction.get(decimals as usize..).is_some_and(|extra| !extra.is_empty() && extra.chars().all(|c| c == '0')) => {
+            format!("{}{}{}", whole, decimal_separator, &fraction[..decimals as usize])
+        }
+        _ => cleaned,
+    };
+
+    // Bank files are read like UK numbers, or German ones when the decimal separator is a comma.
+    let locale = Locale::find(if decimal_separator == ',' { "de-DE" } else { "en-GB" });
+    parse_number(&cleaned, decimals, locale)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Saved {
+    pub imported: usize,
+    /// Rows skipped because a transaction with the same import id is already in the account.
+    pub duplicates: usize,
+}
+
+/// Creates a transaction in `account_id` for each imported row that has not been imported before.
+/// The rows are saved together, so if any of them fails none are.
+pub async fn save(conn: &Pool<Sqlite>, account_id: i64, transactions: &[ImportedTransaction]) -> Result<Saved, &'static str> {
+    let mut saved = Saved { imported: 0, duplicates: 0 };
+
+    let result: Result<Vec<i64>, sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        let mut ids = Vec::new();
+        for transaction in transactions {
+            if let Some(import_id) = &transaction.import_id {
+                if db::transaction_import_id_exists(&mut *tx, account_id, import_id).await {
+                    saved.duplicates += 1;
+                    continue;
+                }
+            }
+
+            match db::insert_transaction(&mut tx, &transaction.to_new_transaction(account_id)).await {
+                Ok(id) => ids.push(id),
+                // Rolled back now rather than whenever `tx` is dropped, so the rows are gone by the
+                // time the error is returned.
+                Err(e) => return tx.rollback().await.and(Err(e)),
+            }
+        }
+        tx.commit().await?;
+        Ok(ids)
+    }.await;
+
+    let ids = match result {
+        Ok(ids) => ids,
+        Err(e) => {
+            println!("{:?}", e);
+            return Err("failed to save the imported transactions");
+        }
+    };
+    for id in &ids {
+        db::transaction_created(conn, *id).await;
+    }
+
+    saved.imported = ids.len();
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budgets;
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("1,234.56", '.', 2), Ok(123456));
+        assert_eq!(parse_amount("1.234,56", ',', 2), Ok(123456));
+        assert_eq!(parse_amount("-12.30", '.', 2), Ok(-1230));
+        assert_eq!(parse_amount("£ 5", '.', 2), Ok(500));
+        assert_eq!(parse_amount("12.5", '.', 2), Ok(1250));
+        assert_eq!(parse_amount("87,", ',', 2), Ok(8700));
+        assert!(parse_amount("twelve", '.', 2).is_err());
+
+        // Yen have no minor units, and Bahraini dinars have three.
+        assert_eq!(parse_amount("1500", '.', 0), Ok(1500));
+        assert_eq!(parse_amount("¥1,500", '.', 0), Ok(1500));
+        assert_eq!(parse_amount("1500.00", '.', 0), Ok(1500));
+        assert!(parse_amount("1500.50", '.', 0).is_err());
+        assert_eq!(parse_amount("12.345", '.', 3), Ok(12345));
+        assert_eq!(parse_amount("1.234,5", ',', 3), Ok(1234500));
+        assert_eq!(parse_amount("-0.250", '.', 3), Ok(-250));
+        assert!(parse_amount("1.2345", '.', 3).is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_save_is_all_or_nothing(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        let account_id = db::create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        // The database refuses the row with this memo, as it would one breaking a constraint.
+        sqlx::query("CREATE TRIGGER refuse_broken BEFORE INSERT ON transactions WHEN NEW.memo = 'broken' BEGIN SELECT RAISE(ABORT, 'broken row'); END").execute(&pool).await?;
+        let row = |import_id: &str, memo: &str| ImportedTransaction {
+            date: NaiveDate::from_ymd_opt(2024, 1, 2).unwrap(),
+            value_date: None,
+            payee: Some("Tesco".to_string()),
+            memo: Some(memo.to_string()),
+            amount: -1250,
+            import_id: Some(import_id.to_string()),
+        };
+        let count = "SELECT count(*) FROM transactions WHERE account_id = ?";
+
+        // Act
+        let failed = save(&pool, account_id, &[row("1", "fine"), row("2", "broken"), row("3", "also fine")]).await;
+        let (after_failure,): (i64,) = sqlx::query_as(count).bind(account_id).fetch_one(&pool).await?;
+        let saved = save(&pool, account_id, &[row("1", "fine"), row("1", "fine"), row("3", "also fine")]).await;
+        let (after_success,): (i64,) = sqlx::query_as(count).bind(account_id).fetch_one(&pool).await?;
+
+        // Assert
+        assert_eq!(failed, Err("failed to save the imported transactions"));
+        assert_eq!(after_failure, 1, "Only the starting balance is left, not the row before the broken one");
+        assert_eq!(saved, Ok(Saved { imported: 2, duplicates: 1 }), "Repeats within the same file are skipped");
+        assert_eq!(after_success, 3);
+
+        Ok(())
+    }
+}
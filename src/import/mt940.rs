@@ -0,0 +1,252 @@
+use chrono::{Datelike, NaiveDate};
+
+use super::{parse_amount, ClosingBalance, ImportedTransaction, ParseError};
+
+/// Whether `content` looks like a SWIFT MT940 statement.
+pub fn is_mt940(content: &str) -> bool {
+    content.contains(":20:") && content.contains(":61:")
+}
+
+/// A `:tag:value` field with the line it starts on. Continuation lines are kept in `value`,
+/// separated by newlines.
+struct Field<'a> {
+    tag: &'a str,
+    value: String,
+    line: usize,
+}
+
+fn fields(content: &str) -> Vec<Field<'_>> {
+    let mut fields: Vec<Field> = vec![];
+
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim_end();
+        let tag = line
+            .strip_prefix(':')
+            .and_then(|rest| rest.find(':').map(|end| &rest[..end]))
+            .filter(|tag| !tag.is_empty() && tag.len() <= 3 && tag.chars().all(|c| c.is_ascii_alphanumeric()));
+
+        match tag {
+            Some(tag) => fields.push(Field { tag, value: line[tag.len() + 2..].to_string(), line: index + 1 }),
+            None if line == "-" || line.starts_with('{') => {}
+            None => {
+                if let Some(field) = fields.last_mut() {
+                    field.value.push('\n');
+                    field.value.push_str(line);
+                }
+            }
+        }
+    }
+
+    fields
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(&format!("20{}", value.get(..6)?), "%Y%m%d").ok()
+}
+
+/// Reads a `:61:` statement line, returning the value date, the booking date if given, the signed
+/// amount and the bank's reference.
+fn parse_statement_line(value: &str, decimals: u32) -> Result<(NaiveDate, Option<NaiveDate>, i64, Option<String>), String> {
+    let value = value.lines().next().unwrap_or_default();
+    let value_date = parse_date(value).ok_or_else(|| format!("Could not read value date in \"{}\"", value))?;
+    let mut rest = &value[6..];
+
+    let mut booking_date = None;
+    if let Some(digits) = rest.get(..4).filter(|d| d.chars().all(|c| c.is_ascii_digit())) {
+        let (month, day) = (digits[..2].parse().unwrap_or(0), digits[2..].parse().unwrap_or(0));
+        // The booking date has no year, and may fall either side of a new year from the value date.
+        let year = match month as i32 - value_date.month() as i32 {
+            d if d > 6 => value_date.year() - 1,
+            d if d < -6 => value_date.year() + 1,
+            _ => value_date.year(),
+        };
+        booking_date = NaiveDate::from_ymd_opt(year, month, day);
+        rest = &rest[4..];
+    }
+
+    let (sign, mark_length) = if rest.starts_with("RC") {
+        (-1, 2)
+    } else if rest.starts_with("RD") {
+        (1, 2)
+    } else if rest.starts_with('C') {
+        (1, 1)
+    } else if rest.starts_with('D') {
+        (-1, 1)
+    } else {
+        return Err(format!("Could not read debit/credit mark in \"{}\"", value));
+    };
+    rest = &rest[mark_length..];
+
+    // An optional funds code is the last letter of the currency.
+    if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        rest = &rest[1..];
+    }
+
+    let amount_length = rest.find(|c: char| !c.is_ascii_digit() && c != ',').unwrap_or(rest.len());
+    let amount = parse_amount(&rest[..amount_length], ',', decimals)
+        .map_err(|_| format!("Could not read amount in \"{}\"", value))?;
+    rest = &rest[amount_length..];
+
+    let reference = rest
+        .split_once("//")
+        .map(|(_, bank_reference)| bank_reference.trim().to_string())
+        .filter(|r| !r.is_empty());
+
+    Ok((value_date, booking_date, sign * amount, reference))
+}
+
+/// Reads `:86:` information to account owner into a counterparty and remittance text. German
+/// banks use `?nn` subfields, where `?20`-`?29` are the remittance and `?32`-`?33` the name.
+fn parse_information(value: &str) -> (Option<String>, Option<String>) {
+    let joined = value.replace('\n', "");
+    let structured = joined.len() > 3 && joined[..3].chars().all(|c| c.is_ascii_digit()) && joined[3..].starts_with('?');
+
+    if !structured {
+        let text = value.lines().map(|l| l.trim()).collect::<Vec<&str>>().join(" ");
+        return (None, Some(text).filter(|t| !t.is_empty()));
+    }
+
+    let mut remittance = vec![];
+    let mut name = String::new();
+    for subfield in joined.split('?').skip(1) {
+        match (subfield.get(..2), subfield.get(2..)) {
+            (Some(code), Some(text)) if ("20".."30").contains(&code) => remittance.push(text.trim()),
+            (Some("32"), Some(text)) | (Some("33"), Some(text)) => name.push_str(text),
+            _ => {}
+        }
+    }
+
+    let memo = remittance.join(" ");
+    (
+        Some(name.trim().to_string()).filter(|n| !n.is_empty()),
+        Some(memo.trim().to_string()).filter(|m| !m.is_empty()),
+    )
+}
+
+/// Reads every `:61:` line of every statement in the file, with its `:86:` details. The bank
+/// reference after `//` is used as the import id.
+pub fn parse(content: &str, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>) {
+    let mut transactions: Vec<ImportedTransaction> = vec![];
+    let mut errors = vec![];
+    let mut last_was_transaction = false;
+
+    for field in fields(content) {
+        match field.tag {
+            "61" => match parse_statement_line(&field.value, decimals) {
+                Ok((value_date, booking_date, amount, import_id)) => {
+                    transactions.push(ImportedTransaction {
+                        date: booking_date.unwrap_or(value_date),
+                        value_date: Some(value_date),
+                        payee: None,
+                        memo: None,
+                        amount,
+                        import_id,
+                    });
+                    last_was_transaction = true;
+                }
+                Err(message) => {
+                    errors.push(ParseError::new(field.line, message));
+                    last_was_transaction = false;
+                }
+            },
+            "86" if last_was_transaction => {
+                let (payee, memo) = parse_information(&field.value);
+                if let Some(transaction) = transactions.last_mut() {
+                    transaction.payee = payee;
+                    transaction.memo = memo;
+                }
+                last_was_transaction = false;
+            }
+            _ => last_was_transaction = false,
+        }
+    }
+
+    (transactions, errors)
+}
+
+/// The final closing balance (`:62F:`) of the latest statement in the file.
+pub fn closing_balance(content: &str, decimals: u32) -> Option<ClosingBalance> {
+    fields(content)
+        .iter()
+        .filter(|f| f.tag == "62F")
+        .filter_map(|f| {
+            let sign = match f.value.get(..1)? {
+                "C" => 1,
+                "D" => -1,
+                _ => return None,
+            };
+            let date = parse_date(f.value.get(1..)?)?;
+            let amount = parse_amount(f.value.get(10..)?.trim(), ',', decimals).ok()?;
+            Some(ClosingBalance { date, amount: sign * amount })
+        })
+        .max_by_key(|b| b.date)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATEMENT: &str = ":20:STARTUMSE
+:25:10020030/1234567
+:28C:00001/001
+:60F:C231201EUR1000,00
+:61:2312291229D12,50NTRFNONREF//B4A05
+:86:166?00SEPA-UEBERWEISUNG?20EREF+INV-123?21SVWZ+Invoice
+ 123?32TESCO STORES?33UK
+:61:2312310102C2000,NTRFNONREF
+:86:Salary December
+ACME Ltd
+:62F:C231231EUR2987,50
+-
+:20:STARTUMSE
+:25:10020030/1234567
+:28C:00002/001
+:60F:C240101EUR2987,50
+:61:240105X5,00NTRF
+:61:240106RD1,00NCHGNONREF//B4A09
+:62F:C240131EUR2988,50
+-
+";
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let (transactions, errors) = parse(STATEMENT, 2);
+
+        assert_eq!(transactions, vec![
+            ImportedTransaction {
+                date: date(2023, 12, 29),
+                value_date: Some(date(2023, 12, 29)),
+                payee: Some("TESCO STORESUK".to_string()),
+                memo: Some("EREF+INV-123 SVWZ+Invoice 123".to_string()),
+                amount: -1250,
+                import_id: Some("B4A05".to_string()),
+            },
+            ImportedTransaction {
+                date: date(2024, 1, 2),
+                value_date: Some(date(2023, 12, 31)),
+                payee: None,
+                memo: Some("Salary December ACME Ltd".to_string()),
+                amount: 200000,
+                import_id: None,
+            },
+            ImportedTransaction {
+                date: date(2024, 1, 6),
+                value_date: Some(date(2024, 1, 6)),
+                payee: None,
+                memo: None,
+                amount: 100,
+                import_id: Some("B4A09".to_string()),
+            },
+        ]);
+        assert_eq!(errors, vec![ParseError::new(17, "Could not read debit/credit mark in \"240105X5,00NTRF\"")]);
+    }
+
+    #[test]
+    fn test_closing_balance_uses_latest_statement() {
+        assert_eq!(closing_balance(STATEMENT, 2), Some(ClosingBalance { date: date(2024, 1, 31), amount: 298850 }));
+    }
+}
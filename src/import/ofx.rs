@@ -0,0 +1,235 @@
+use chrono::NaiveDate;
+
+use super::{parse_amount, ImportedTransaction, ParseError};
+
+/// A tag or the text between tags, with the line it starts on.
+enum Token<'a> {
+    Open(&'a str, usize),
+    Close(&'a str),
+    Text(&'a str),
+}
+
+/// Splits OFX into tags and text. This copes with both OFX 1.x SGML, where leaf elements are
+/// never closed, and OFX 2.x XML, where they are.
+fn tokenize(content: &str) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut rest = content;
+    let mut line = 1;
+
+    while let Some(start) = rest.find('<') {
+        let text = rest[..start].trim();
+        if !text.is_empty() {
+            tokens.push(Token::Text(text));
+        }
+        line += rest[..start].matches('\n').count();
+
+        let end = match rest[start..].find('>') {
+            Some(e) => start + e,
+            None => break,
+        };
+        let tag = rest[start + 1..end].trim();
+        if let Some(name) = tag.strip_prefix('/') {
+            tokens.push(Token::Close(name));
+        } else if !tag.starts_with('?') && !tag.starts_with('!') {
+            tokens.push(Token::Open(tag.trim_end_matches('/'), line));
+        }
+
+        line += rest[start..end].matches('\n').count();
+        rest = &rest[end + 1..];
+    }
+
+    tokens
+}
+
+fn decode(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&nbsp;", " ")
+        .replace("&amp;", "&")
+}
+
+/// The leaf elements of one `<STMTTRN>` block.
+#[derive(Default)]
+struct Fields {
+    line: usize,
+    date: Option<String>,
+    amount: Option<String>,
+    fitid: Option<String>,
+    name: Option<String>,
+    memo: Option<String>,
+}
+
+impl Fields {
+    fn set(&mut self, tag: &str, value: &str) {
+        let value = Some(decode(value));
+        match tag.to_uppercase().as_str() {
+            "DTPOSTED" => self.date = value,
+            "TRNAMT" => self.amount = value,
+            "FITID" => self.fitid = value,
+            "NAME" | "PAYEE" => self.name = self.name.take().or(value),
+            "MEMO" => self.memo = value,
+            _ => {}
+        }
+    }
+
+    fn into_transaction(self, decimals: u32) -> Result<ImportedTransaction, ParseError> {
+        let line = self.line;
+        let date_value = self.date.ok_or_else(|| ParseError::new(line, "Missing DTPOSTED"))?;
+        let date = date_value
+            .get(..8)
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok())
+            .ok_or_else(|| ParseError::new(line, format!("Could not read date \"{}\"", date_value)))?;
+
+        let amount_value = self.amount.ok_or_else(|| ParseError::new(line, "Missing TRNAMT"))?;
+        let decimal_separator = if amount_value.contains(',') && !amount_value.contains('.') { ',' } else { '.' };
+        let amount = parse_amount(&amount_value, decimal_separator, decimals)
+            .map_err(|_| ParseError::new(line, format!("Could not read amount \"{}\"", amount_value)))?;
+
+        Ok(ImportedTransaction {
+            date,
+            value_date: None,
+            payee: self.name,
+            memo: self.memo,
+            amount,
+            import_id: self.fitid,
+        })
+    }
+}
+
+/// Whether `content` looks like an OFX or QFX file.
+pub fn is_ofx(content: &str) -> bool {
+    content.contains("<OFX>") || content.contains("OFXHEADER")
+}
+
+/// Reads every `<STMTTRN>` in an OFX or QFX file. The FITID is used as the import id so the same
+/// transaction is not imported twice.
+pub fn parse(content: &str, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>) {
+    if !is_ofx(content) {
+        return (vec![], vec![ParseError::new(1, "Not an OFX file")]);
+    }
+
+    let mut transactions = vec![];
+    let mut errors = vec![];
+    let mut current: Option<Fields> = None;
+    let mut open_tag: Option<&str> = None;
+
+    for token in tokenize(content) {
+        match token {
+            Token::Open(tag, line) if tag.eq_ignore_ascii_case("STMTTRN") => {
+                current = Some(Fields { line, ..Default::default() });
+                open_tag = None;
+            }
+            Token::Close(tag) if tag.eq_ignore_ascii_case("STMTTRN") => {
+                if let Some(fields) = current.take() {
+                    match fields.into_transaction(decimals) {
+                        Ok(t) => transactions.push(t),
+                        Err(e) => errors.push(e),
+                    }
+                }
+            }
+            Token::Open(tag, _) => open_tag = Some(tag),
+            Token::Close(_) => open_tag = None,
+            Token::Text(text) => {
+                if let (Some(fields), Some(tag)) = (current.as_mut(), open_tag.take()) {
+                    fields.set(tag, text);
+                }
+            }
+        }
+    }
+
+    if let Some(fields) = current {
+        errors.push(ParseError::new(fields.line, "Transaction is missing </STMTTRN>"));
+    }
+
+    (transactions, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SGML: &str = "OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS>
+<BANKTRANLIST>
+<STMTTRN>
+<TRNTYPE>DEBIT
+<DTPOSTED>20240105120000[0:GMT]
+<TRNAMT>-12.50
+<FITID>202401050001
+<NAME>TESCO STORES 2231
+<MEMO>CARD PAYMENT
+</STMTTRN>
+<STMTTRN>
+<TRNTYPE>CREDIT
+<DTPOSTED>2024-01-06
+<TRNAMT>100.00
+<FITID>202401060001
+</STMTTRN>
+<STMTTRN>
+<TRNTYPE>CREDIT
+<DTPOSTED>20240107
+<TRNAMT>2000.00
+<FITID>202401070001
+<NAME>M&amp;S
+</STMTTRN>
+</BANKTRANLIST>
+</STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>
+";
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<?OFX OFXHEADER="200" VERSION="220"?>
+<OFX>
+  <CREDITCARDMSGSRSV1><CCSTMTTRNRS><CCSTMTRS><BANKTRANLIST>
+    <STMTTRN><TRNTYPE>DEBIT</TRNTYPE><DTPOSTED>20240210</DTPOSTED><TRNAMT>-3,20</TRNAMT><FITID>A1</FITID><NAME>Coffee</NAME></STMTTRN>
+  </BANKTRANLIST></CCSTMTRS></CCSTMTTRNRS></CREDITCARDMSGSRSV1>
+</OFX>"#;
+
+    #[test]
+    fn test_parse_sgml() {
+        let (transactions, errors) = parse(SGML, 2);
+
+        assert_eq!(transactions, vec![
+            ImportedTransaction {
+                date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap(),
+                value_date: None,
+                payee: Some("TESCO STORES 2231".to_string()),
+                memo: Some("CARD PAYMENT".to_string()),
+                amount: -1250,
+                import_id: Some("202401050001".to_string()),
+            },
+            ImportedTransaction {
+                date: NaiveDate::from_ymd_opt(2024, 1, 7).unwrap(),
+                value_date: None,
+                payee: Some("M&S".to_string()),
+                memo: None,
+                amount: 200000,
+                import_id: Some("202401070001".to_string()),
+            },
+        ]);
+        assert_eq!(errors, vec![ParseError::new(16, "Could not read date \"2024-01-06\"")]);
+    }
+
+    #[test]
+    fn test_parse_xml() {
+        let (transactions, errors) = parse(XML, 2);
+
+        assert!(errors.is_empty());
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].amount, -320);
+        assert_eq!(transactions[0].payee.as_deref(), Some("Coffee"));
+        assert_eq!(transactions[0].import_id.as_deref(), Some("A1"));
+    }
+
+    #[test]
+    fn test_parse_rejects_other_files() {
+        assert_eq!(parse("Date,Amount\n", 2).1, vec![ParseError::new(1, "Not an OFX file")]);
+    }
+}
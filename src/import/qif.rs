@@ -0,0 +1,177 @@
+use chrono::NaiveDate;
+
+use super::{parse_amount, ImportedTransaction, ParseError};
+
+/// Account types whose records are plain transactions. Investment and list sections are skipped.
+const TRANSACTION_TYPES: [&str; 5] = ["bank", "cash", "ccard", "oth a", "oth l"];
+
+/// Whether `content` looks like a QIF file.
+pub fn is_qif(content: &str) -> bool {
+    content.trim_start().starts_with("!Type:") || content.trim_start().starts_with("!Account")
+}
+
+/// Reads a QIF date such as `01/31/2024`, `1/31'24` or `31.01.24`. QIF has no fixed field order,
+/// so the caller says whether the day comes first.
+pub(super) fn parse_date(value: &str, day_first: bool) -> Option<NaiveDate> {
+    let parts: Vec<u32> = value
+        .split(['/', '\'', '-', '.'])
+        .map(|p| p.trim().parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+
+    if parts.len() != 3 {
+        return None;
+    }
+
+    let (day, month) = if day_first { (parts[0], parts[1]) } else { (parts[1], parts[0]) };
+    let year = match parts[2] {
+        y if y >= 100 => y as i32,
+        y if value.contains('\'') || y < 70 => 2000 + y as i32,
+        y => 1900 + y as i32,
+    };
+
+    NaiveDate::from_ymd_opt(year, month, day)
+}
+
+#[derive(Default)]
+struct Record {
+    date: Option<NaiveDate>,
+    amount: Option<i64>,
+    payee: Option<String>,
+    memo: Option<String>,
+}
+
+/// Reads every transaction record of a QIF file. QIF has no transaction ids, so nothing is
+/// de-duplicated on re-import.
+pub fn parse(content: &str, day_first: bool, decimals: u32) -> (Vec<ImportedTransaction>, Vec<ParseError>) {
+    let mut transactions = vec![];
+    let mut errors = vec![];
+    let mut in_transactions = false;
+    let mut record = Record::default();
+    let mut record_valid = true;
+
+    for (index, raw) in content.lines().enumerate() {
+        let line = index + 1;
+        let raw = raw.trim_end();
+        if raw.is_empty() {
+            continue;
+        }
+
+        if let Some(header) = raw.strip_prefix('!') {
+            if let Some(kind) = header.strip_prefix("Type:") {
+                in_transactions = TRANSACTION_TYPES.contains(&kind.trim().to_lowercase().as_str());
+            } else if !header.starts_with("Option") && !header.starts_with("Clear") {
+                in_transactions = false;
+            }
+            continue;
+        }
+
+        if !in_transactions {
+            continue;
+        }
+
+        let code = raw.chars().next().unwrap();
+        let value = raw[code.len_utf8()..].trim();
+        match code {
+            'D' => match parse_date(value, day_first) {
+                Some(d) => record.date = Some(d),
+                None => {
+                    errors.push(ParseError::new(line, format!("Could not read date \"{}\"", value)));
+                    record_valid = false;
+                }
+            },
+            'T' | 'U' => match parse_amount(value, '.', decimals) {
+                Ok(a) => record.amount = Some(a),
+                Err(_) => {
+                    errors.push(ParseError::new(line, format!("Could not read amount \"{}\"", value)));
+                    record_valid = false;
+                }
+            },
+            'P' => record.payee = Some(value.to_string()),
+            'M' => record.memo = Some(value.to_string()),
+            '^' => {
+                let finished = std::mem::take(&mut record);
+                if record_valid {
+                    match (finished.date, finished.amount) {
+                        (Some(date), Some(amount)) => transactions.push(ImportedTransaction {
+                            date,
+                            value_date: None,
+                            payee: finished.payee,
+                            memo: finished.memo,
+                            amount,
+                            import_id: None,
+                        }),
+                        (None, _) => errors.push(ParseError::new(line, "Transaction has no date")),
+                        (_, None) => errors.push(ParseError::new(line, "Transaction has no amount")),
+                    }
+                }
+                record_valid = true;
+            }
+            _ => {}
+        }
+    }
+
+    (transactions, errors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(parse_date("01/31/2024", false), NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(parse_date("1/31'24", false), NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(parse_date("31/01/99", true), NaiveDate::from_ymd_opt(1999, 1, 31));
+        assert_eq!(parse_date("31.01.24", true), NaiveDate::from_ymd_opt(2024, 1, 31));
+        assert_eq!(parse_date("31/01/2024", false), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let content = "!Type:Bank
+D01/05/2024
+T-1,012.50
+PTesco
+MWeekly shop
+^
+D01/06/2024
+Tlots
+PAldi
+^
+D13/06/2024
+T5.00
+^
+D01/07/2024
+T2,000.00
+PEmployer
+^
+!Type:Cat
+NGroceries
+^
+";
+        let (transactions, errors) = parse(content, false, 2);
+
+        assert_eq!(transactions, vec![
+            ImportedTransaction {
+                date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap(),
+                value_date: None,
+                payee: Some("Tesco".to_string()),
+                memo: Some("Weekly shop".to_string()),
+                amount: -101250,
+                import_id: None,
+            },
+            ImportedTransaction {
+                date: NaiveDate::from_ymd_opt(2024, 1, 7).unwrap(),
+                value_date: None,
+                payee: Some("Employer".to_string()),
+                memo: None,
+                amount: 200000,
+                import_id: None,
+            },
+        ]);
+        assert_eq!(errors, vec![
+            ParseError::new(8, "Could not read amount \"lots\""),
+            ParseError::new(11, "Could not read date \"13/06/2024\""),
+        ]);
+    }
+}
@@ -0,0 +1,925 @@
+use std::collections::{HashMap, HashSet};
+
+use chrono::NaiveDate;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{Pool, Sqlite};
+
+use crate::db::{self, NewTransaction};
+
+use super::{parse_amount, qif, ParseError, Saved};
+
+/// YNAB's income categories, which are "Ready to Assign" rather than somewhere to spend from.
+const INCOME_GROUPS: [&str; 2] = ["Inflow", "Income"];
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CategoryName {
+    pub group: String,
+    pub name: String,
+}
+
+/// The amount assigned to a category in a month.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assigned {
+    /// The first day of the month.
+    pub month: NaiveDate,
+    pub category: CategoryName,
+    pub amount: i64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct YnabSplit {
+    pub payee: Option<String>,
+    pub category: Option<CategoryName>,
+    pub memo: String,
+    pub amount: i64,
+    pub transfer_account: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct YnabTransaction {
+    pub account: String,
+    pub date: NaiveDate,
+    pub payee: Option<String>,
+    pub category: Option<CategoryName>,
+    pub memo: String,
+    /// Positive amounts are inflows, negative amounts are outflows.
+    pub amount: i64,
+    pub cleared: bool,
+    /// The other account, when this is one side of a transfer.
+    pub transfer_account: Option<String>,
+    /// The parts of a split transaction. Their amounts add up to `amount`.
+    pub splits: Vec<YnabSplit>,
+    /// YNAB's own id in `.yfull` files. CSV exports have none, so `derive_import_ids` makes one.
+    pub import_id: Option<String>,
+}
+
+/// Everything read from a YNAB export, before any of it is written.
+#[derive(Debug, Default, PartialEq)]
+pub struct Budget {
+    pub accounts: Vec<String>,
+    pub categories: Vec<CategoryName>,
+    pub assigned: Vec<Assigned>,
+    pub transactions: Vec<YnabTransaction>,
+}
+
+/// One side of a transfer: the transaction's index and, for a split line, the split's index.
+type Leg = (usize, Option<usize>);
+
+/// What an import will create, shown before anything is written.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub accounts: usize,
+    pub category_groups: usize,
+    pub categories: usize,
+    pub months: usize,
+    pub payees: usize,
+    pub transactions: usize,
+    pub transfers: usize,
+    pub splits: usize,
+}
+
+impl Budget {
+    fn add_account(&mut self, name: &str) {
+        if !self.accounts.iter().any(|a| a == name) {
+            self.accounts.push(name.to_string());
+        }
+    }
+
+    fn add_category(&mut self, category: &Option<CategoryName>) {
+        if let Some(category) = category {
+            if !self.categories.contains(category) {
+                self.categories.push(category.clone());
+            }
+        }
+    }
+
+    /// Pairs the two sides of each transfer. YNAB exports both sides, so each one is matched with
+    /// a transaction in the other account on the same day for the opposite amount.
+    pub fn transfer_pairs(&self) -> Vec<(Leg, Leg)> {
+        let mut legs: Vec<(Leg, &str, &str, NaiveDate, i64)> = vec![];
+        for (index, t) in self.transactions.iter().enumerate() {
+            if let Some(to) = &t.transfer_account {
+                legs.push(((index, None), &t.account, to, t.date, t.amount));
+            }
+            for (split_index, split) in t.splits.iter().enumerate() {
+                if let Some(to) = &split.transfer_account {
+                    legs.push(((index, Some(split_index)), &t.account, to, t.date, split.amount));
+                }
+            }
+        }
+
+        let mut used = HashSet::new();
+        let mut pairs = vec![];
+        for (i, a) in legs.iter().enumerate() {
+            if used.contains(&i) {
+                continue;
+            }
+            let other = legs.iter().enumerate().position(|(j, b)| {
+                j != i && !used.contains(&j) && b.1 == a.2 && b.2 == a.1 && b.3 == a.3 && b.4 == -a.4
+            });
+            if let Some(j) = other {
+                used.insert(i);
+                used.insert(j);
+                pairs.push((a.0, legs[j].0));
+            }
+        }
+
+        pairs
+    }
+
+    pub fn summary(&self) -> Summary {
+        let mut payees = HashSet::new();
+        for t in &self.transactions {
+            if t.transfer_account.is_none() {
+                payees.extend(t.payee.as_deref());
+            }
+            for split in t.splits.iter().filter(|s| s.transfer_account.is_none()) {
+                payees.extend(split.payee.as_deref());
+            }
+        }
+
+        Summary {
+            accounts: self.accounts.len(),
+            category_groups: self.categories.iter().map(|c| &c.group).collect::<HashSet<_>>().len(),
+            categories: self.categories.len(),
+            months: self.assigned.iter().map(|a| a.month).collect::<HashSet<_>>().len(),
+            payees: payees.len(),
+            transactions: self.transactions.len(),
+            transfers: self.transfer_pairs().len(),
+            splits: self.transactions.iter().filter(|t| !t.splits.is_empty()).count(),
+        }
+    }
+}
+
+/// Whether `content` looks like a YNAB 4 `.yfull` budget file.
+pub fn is_yfull(content: &str) -> bool {
+    content.trim_start().starts_with('{') && content.contains("masterCategories")
+}
+
+/// Whether `content` is a YNAB register export rather than a budget export.
+pub fn is_register(content: &str) -> bool {
+    content.lines().next().is_some_and(|header| header.contains("Account") && header.contains("Payee"))
+}
+
+fn transfer_account(payee: &str) -> Option<String> {
+    payee
+        .strip_prefix("Transfer : ")
+        .or_else(|| payee.strip_prefix("Transfer: "))
+        .map(|account| account.trim().to_string())
+}
+
+/// Builds a category from separate group and name columns, falling back to a combined
+/// "Group: Name" column. Income categories come back as `None`.
+fn category(group: Option<&str>, name: Option<&str>, combined: Option<&str>) -> Option<CategoryName> {
+    let (group, name) = match (group.filter(|g| !g.is_empty()), name.filter(|n| !n.is_empty())) {
+        (Some(group), Some(name)) => (group.to_string(), name.to_string()),
+        _ => {
+            let (group, name) = combined?.split_once(':')?;
+            (group.trim().to_string(), name.trim().to_string())
+        }
+    };
+
+    if group.is_empty() || name.is_empty() || INCOME_GROUPS.contains(&group.as_str()) {
+        None
+    } else {
+        Some(CategoryName { group, name })
+    }
+}
+
+/// Reads an amount in whichever decimal style YNAB used for the budget's currency, which has
+/// `decimals` decimal places.
+fn amount(value: &str, decimals: u32) -> Result<i64, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(0);
+    }
+
+    let decimal_separator = match value.rfind(['.', ',']) {
+        Some(i) if value[i + 1..].chars().filter(|c| c.is_ascii_digit()).count() <= decimals.max(2) as usize => value[i..].chars().next().unwrap(),
+        _ => '.',
+    };
+    parse_amount(value, decimal_separator, decimals).map_err(|_| format!("Could not read amount \"{}\"", value))
+}
+
+/// Reads `Split (1/3) memo` from nYNAB or `(Split 1/3) memo` from YNAB 4, returning the line
+/// number, the number of lines and the rest of the memo.
+fn split_marker(memo: &str) -> Option<(usize, usize, String)> {
+    let rest = memo.strip_prefix("Split (").or_else(|| memo.strip_prefix("(Split "))?;
+    let (position, rest) = rest.split_once(')')?;
+    let (index, count) = position.split_once('/')?;
+    Some((index.trim().parse().ok()?, count.trim().parse().ok()?, rest.trim().to_string()))
+}
+
+/// Whether the dates in an export are written day first. YNAB uses the budget's date format, so a
+/// day above 12 anywhere in the file decides it. Month first is assumed when nothing does.
+fn dates_are_day_first<'a>(dates: impl Iterator<Item = &'a str>) -> bool {
+    for date in dates {
+        let parts: Vec<u32> = date.split(['/', '-', '.']).filter_map(|p| p.trim().parse().ok()).collect();
+        match parts.as_slice() {
+            [first, ..] if *first > 31 => continue,
+            [first, _, _] if *first > 12 => return true,
+            [_, second, _] if *second > 12 => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn parse_date(value: &str, day_first: bool) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(&value.replace('/', "-"), "%Y-%m-%d")
+        .ok()
+        .or_else(|| qif::parse_date(value, day_first))
+}
+
+/// Reads a budget month such as `Jan 2024`, `January 2024` or `2024-01`.
+fn parse_month(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    ["%d %b %Y", "%d %B %Y"]
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(&format!("1 {}", value), format).ok())
+        .or_else(|| NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d").ok())
+}
+
+/// Column positions of a YNAB CSV export, found by header name so both nYNAB and YNAB 4 exports
+/// can be read.
+struct Columns {
+    headers: Vec<String>,
+}
+
+impl Columns {
+    fn new(headers: &::csv::StringRecord) -> Self {
+        Self { headers: headers.iter().map(|h| h.trim_start_matches('\u{feff}').trim().to_string()).collect() }
+    }
+
+    fn find(&self, names: &[&str]) -> Option<usize> {
+        names.iter().find_map(|name| self.headers.iter().position(|h| h.eq_ignore_ascii_case(name)))
+    }
+
+    fn category(&self, record: &::csv::StringRecord) -> Option<CategoryName> {
+        let value = |index: Option<usize>| index.and_then(|i| record.get(i)).map(|v| v.trim());
+        category(
+            value(self.find(&["Category Group", "Master Category"])),
+            value(self.find(&["Sub Category", "Category"])),
+            value(self.find(&["Category Group/Category", "Category"])),
+        )
+    }
+}
+
+fn reader(content: &str) -> ::csv::Reader<&[u8]> {
+    let first_line = content.lines().next().unwrap_or_default();
+    let delimiter = if first_line.contains('\t') && !first_line.contains(',') { b'\t' } else { b',' };
+    ::csv::ReaderBuilder::new()
+        .delimiter(delimiter)
+        .flexible(true)
+        .from_reader(content.as_bytes())
+}
+
+fn read_register(content: &str, decimals: u32, budget: &mut Budget, errors: &mut Vec<ParseError>) {
+    let mut reader = reader(content);
+    let columns = match reader.headers() {
+        Ok(headers) => Columns::new(headers),
+        Err(e) => return errors.push(ParseError::new(1, format!("Could not read header: {}", e))),
+    };
+    let (Some(account_column), Some(date_column)) = (columns.find(&["Account"]), columns.find(&["Date"])) else {
+        return errors.push(ParseError::new(1, "Register export needs Account and Date columns"));
+    };
+    let column = |record: &::csv::StringRecord, names: &[&str]| {
+        columns.find(names).and_then(|i| record.get(i)).unwrap_or_default().trim().to_string()
+    };
+
+    let records: Vec<(usize, ::csv::StringRecord)> = reader
+        .records()
+        .filter_map(|r| match r {
+            Ok(record) => Some((record.position().map_or(0, |p| p.line() as usize), record)),
+            Err(e) => {
+                errors.push(ParseError::new(e.position().map_or(0, |p| p.line() as usize), e.to_string()));
+                None
+            }
+        })
+        .collect();
+    let day_first = dates_are_day_first(records.iter().filter_map(|(_, r)| r.get(date_column)));
+
+    let mut split: Option<(YnabTransaction, usize)> = None;
+    for (line, record) in records {
+        let account = record.get(account_column).unwrap_or_default().trim().to_string();
+        let date_value = record.get(date_column).unwrap_or_default().trim();
+        let Some(date) = parse_date(date_value, day_first) else {
+            errors.push(ParseError::new(line, format!("Could not read date \"{}\"", date_value)));
+            continue;
+        };
+        let amount = match (amount(&column(&record, &["Inflow"]), decimals), amount(&column(&record, &["Outflow"]), decimals)) {
+            (Ok(inflow), Ok(outflow)) => inflow - outflow,
+            (Err(e), _) | (_, Err(e)) => {
+                errors.push(ParseError::new(line, e));
+                continue;
+            }
+        };
+        let payee = Some(column(&record, &["Payee"])).filter(|p| !p.is_empty());
+        let transfer = payee.as_deref().and_then(transfer_account);
+        let category = if transfer.is_some() { None } else { columns.category(&record) };
+        let memo = column(&record, &["Memo"]);
+        let cleared = column(&record, &["Cleared"]);
+
+        budget.add_account(&account);
+        budget.add_category(&category);
+
+        match split_marker(&memo) {
+            Some((index, count, memo)) => {
+                if index == 1 {
+                    budget.transactions.extend(split.take().map(|(t, _)| t));
+                }
+                let (parent, _) = split.get_or_insert_with(|| (YnabTransaction {
+                    account,
+                    date,
+                    payee: payee.clone(),
+                    category: None,
+                    memo: String::new(),
+                    amount: 0,
+                    cleared: !cleared.is_empty() && !cleared.starts_with('U'),
+                    transfer_account: None,
+                    splits: vec![],
+                    import_id: None,
+                }, count));
+                if parent.payee != payee {
+                    parent.payee = None;
+                }
+                parent.amount += amount;
+                parent.splits.push(YnabSplit { payee, category, memo, amount, transfer_account: transfer });
+                if index >= count {
+                    budget.transactions.extend(split.take().map(|(t, _)| t));
+                }
+            }
+            None => {
+                budget.transactions.extend(split.take().map(|(t, _)| t));
+                budget.transactions.push(YnabTransaction {
+                    account,
+                    date,
+                    payee,
+                    category,
+                    memo,
+                    amount,
+                    cleared: !cleared.is_empty() && !cleared.starts_with('U'),
+                    transfer_account: transfer,
+                    splits: vec![],
+                    import_id: None,
+                });
+            }
+        }
+    }
+    budget.transactions.extend(split.take().map(|(t, _)| t));
+    derive_import_ids(&mut budget.transactions);
+}
+
+/// Gives each transaction an id made from its account, date, amount and payee, numbered in the
+/// order they appear when several share them, so importing the same export again skips the
+/// transactions that were already imported.
+fn derive_import_ids(transactions: &mut [YnabTransaction]) {
+    let mut occurrences: HashMap<String, usize> = HashMap::new();
+    for t in transactions.iter_mut().filter(|t| t.import_id.is_none()) {
+        let key = format!("{}:{}:{}:{}", t.account, t.date, t.amount, t.payee.as_deref().unwrap_or_default());
+        let occurrence = occurrences.entry(key.clone()).or_default();
+        t.import_id = Some(format!("ynab-csv:{}:{}", key, occurrence));
+        *occurrence += 1;
+    }
+}
+
+fn read_budget(content: &str, decimals: u32, budget: &mut Budget, errors: &mut Vec<ParseError>) {
+    let mut reader = reader(content);
+    let columns = match reader.headers() {
+        Ok(headers) => Columns::new(headers),
+        Err(e) => return errors.push(ParseError::new(1, format!("Could not read header: {}", e))),
+    };
+    let (Some(month_column), Some(amount_column)) = (columns.find(&["Month"]), columns.find(&["Assigned", "Budgeted"])) else {
+        return errors.push(ParseError::new(1, "Budget export needs Month and Assigned columns"));
+    };
+
+    for record in reader.records() {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                errors.push(ParseError::new(e.position().map_or(0, |p| p.line() as usize), e.to_string()));
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |p| p.line() as usize);
+        let month_value = record.get(month_column).unwrap_or_default();
+        let Some(month) = parse_month(month_value) else {
+            errors.push(ParseError::new(line, format!("Could not read month \"{}\"", month_value)));
+            continue;
+        };
+        let Some(category) = columns.category(&record) else {
+            continue;
+        };
+        match amount(record.get(amount_column).unwrap_or_default(), decimals) {
+            Ok(0) => budget.add_category(&Some(category)),
+            Ok(amount) => {
+                budget.add_category(&Some(category.clone()));
+                budget.assigned.push(Assigned { month, category, amount });
+            }
+            Err(e) => errors.push(ParseError::new(line, e)),
+        }
+    }
+}
+
+/// Reads nYNAB's or YNAB 4's CSV export: the register, and optionally the budget with each
+/// month's assigned amounts. Amounts are in minor units of a currency with `decimals` decimal
+/// places.
+pub fn parse_csv(register: &str, budget_csv: Option<&str>, decimals: u32) -> (Budget, Vec<ParseError>) {
+    let mut budget = Budget::default();
+    let mut errors = vec![];
+
+    read_register(register, decimals, &mut budget, &mut errors);
+    if let Some(content) = budget_csv.filter(|c| !c.trim().is_empty()) {
+        read_budget(content, decimals, &mut budget, &mut errors);
+    }
+
+    (budget, errors)
+}
+
+fn yfull_amount(value: &Value, decimals: u32) -> i64 {
+    (value.as_f64().unwrap_or_default() * 10_f64.powi(decimals as i32)).round() as i64
+}
+
+fn is_tombstone(value: &Value) -> bool {
+    value["isTombstone"].as_bool().unwrap_or(false)
+}
+
+/// Reads a YNAB 4 `.yfull` budget file. The file has no line numbers to speak of, so errors give
+/// the transaction's position in the file's list of transactions instead. Amounts are in minor
+/// units of a currency with `decimals` decimal places.
+pub fn parse_yfull(content: &str, decimals: u32) -> (Budget, Vec<ParseError>) {
+    let mut budget = Budget::default();
+    let mut errors = vec![];
+
+    let root: Value = match serde_json::from_str(content) {
+        Ok(v) => v,
+        Err(e) => return (budget, vec![ParseError::new(e.line(), format!("Invalid budget file: {}", e))]),
+    };
+    let list = |key: &str| root[key].as_array().cloned().unwrap_or_default().into_iter().filter(|v| !is_tombstone(v));
+
+    let mut accounts = HashMap::new();
+    for account in list("accounts") {
+        let (Some(id), Some(name)) = (account["entityId"].as_str(), account["accountName"].as_str()) else {
+            continue;
+        };
+        accounts.insert(id.to_string(), name.to_string());
+        budget.add_account(name);
+    }
+
+    let mut categories = HashMap::new();
+    for group in list("masterCategories") {
+        let group_name = group["name"].as_str().unwrap_or_default();
+        for sub in group["subCategories"].as_array().into_iter().flatten().filter(|v| !is_tombstone(v)) {
+            let (Some(id), Some(name)) = (sub["entityId"].as_str(), sub["name"].as_str()) else {
+                continue;
+            };
+            if let Some(category) = category(Some(group_name), Some(name), None) {
+                budget.add_category(&Some(category.clone()));
+                categories.insert(id.to_string(), category);
+            }
+        }
+    }
+
+    for month in list("monthlyBudgets") {
+        let Some(date) = month["month"].as_str().and_then(|m| parse_date(m, false)) else {
+            continue;
+        };
+        for entry in month["monthlySubCategoryBudgets"].as_array().into_iter().flatten().filter(|v| !is_tombstone(v)) {
+            let amount = yfull_amount(&entry["budgeted"], decimals);
+            if let (Some(category), true) = (entry["categoryId"].as_str().and_then(|id| categories.get(id)), amount != 0) {
+                budget.assigned.push(Assigned { month: date, category: category.clone(), amount });
+            }
+        }
+    }
+
+    let payees: HashMap<String, String> = list("payees")
+        .filter_map(|p| Some((p["entityId"].as_str()?.to_string(), p["name"].as_str()?.to_string())))
+        .collect();
+    let transactions: Vec<Value> = list("transactions").collect();
+    let transaction_accounts: HashMap<&str, &str> = transactions
+        .iter()
+        .filter_map(|t| Some((t["entityId"].as_str()?, t["accountId"].as_str()?)))
+        .chain(transactions.iter().flat_map(|t| {
+            let account = t["accountId"].as_str();
+            t["subTransactions"].as_array().into_iter().flatten().filter_map(move |s| Some((s["entityId"].as_str()?, account?)))
+        }))
+        .collect();
+
+    // A transfer's payee is "Payee/Transfer:<account id>", and a split line's transfer points at
+    // the transaction on the other side.
+    let payee_and_transfer = |value: &Value| -> (Option<String>, Option<String>) {
+        let transfer = value["payeeId"]
+            .as_str()
+            .and_then(|id| id.strip_prefix("Payee/Transfer:"))
+            .or_else(|| value["transferTransactionId"].as_str().and_then(|id| transaction_accounts.get(id).copied()))
+            .and_then(|id| accounts.get(id))
+            .cloned();
+        match transfer {
+            Some(account) => (Some(format!("Transfer : {}", account)), Some(account)),
+            None => (value["payeeId"].as_str().and_then(|id| payees.get(id)).cloned(), None),
+        }
+    };
+
+    for (index, t) in transactions.iter().enumerate() {
+        let Some(account) = t["accountId"].as_str().and_then(|id| accounts.get(id)) else {
+            errors.push(ParseError::new(index + 1, "Transaction is not in a known account"));
+            continue;
+        };
+        let Some(date) = t["date"].as_str().and_then(|d| parse_date(d, false)) else {
+            errors.push(ParseError::new(index + 1, format!("Could not read date {}", t["date"])));
+            continue;
+        };
+        let (payee, transfer_account) = payee_and_transfer(t);
+        let splits = t["subTransactions"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter(|s| !is_tombstone(s))
+            .map(|s| {
+                let (payee, transfer_account) = payee_and_transfer(s);
+                YnabSplit {
+                    payee,
+                    category: s["categoryId"].as_str().and_then(|id| categories.get(id)).cloned(),
+                    memo: s["memo"].as_str().unwrap_or_default().to_string(),
+                    amount: yfull_amount(&s["amount"], decimals),
+                    transfer_account,
+                }
+            })
+            .collect();
+
+        budget.transactions.push(YnabTransaction {
+            account: account.clone(),
+            date,
+            payee,
+            category: t["categoryId"].as_str().and_then(|id| categories.get(id)).cloned(),
+            memo: t["memo"].as_str().unwrap_or_default().to_string(),
+            amount: yfull_amount(&t["amount"], decimals),
+            cleared: t["cleared"].as_str().is_some_and(|c| c != "Uncleared"),
+            transfer_account,
+            splits,
+            import_id: t["entityId"].as_str().map(|id| format!("ynab:{}", id)),
+        });
+    }
+
+    (budget, errors)
+}
+
+/// The files of a YNAB export, as uploaded and then posted back to confirm the import.
+#[derive(Debug, Default, Deserialize)]
+pub struct YnabFiles {
+    pub register: Option<String>,
+    pub budget: Option<String>,
+    pub yfull: Option<String>,
+}
+
+impl YnabFiles {
+    /// Sorts an uploaded file into the register, budget or `.yfull` slot.
+    pub fn add(&mut self, file_name: &str, content: String) {
+        if file_name.to_lowercase().ends_with(".yfull") || is_yfull(&content) {
+            self.yfull = Some(content);
+        } else if is_register(&content) {
+            self.register = Some(content);
+        } else {
+            self.budget = Some(content);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.register.is_none() && self.yfull.is_none()
+    }
+
+    /// Reads the uploaded files, with amounts in minor units of a currency with `decimals` decimal
+    /// places, such as the budget's being imported into.
+    pub fn parse(&self, decimals: u32) -> (Budget, Vec<ParseError>) {
+        match (&self.yfull, &self.register) {
+            (Some(yfull), _) => parse_yfull(yfull, decimals),
+            (None, Some(register)) => parse_csv(register, self.budget.as_deref(), decimals),
+            (None, None) => (Budget::default(), vec![ParseError::new(1, "No register or .yfull file was uploaded")]),
+        }
+    }
+}
+
+/// Writes the YNAB budget into the accounts and categories of `budget_id`, creating any that do not
+/// exist by name yet. Transactions from a `.yfull` file that were imported before are skipped. The
+/// whole budget is saved together, so if any part of it fails nothing is.
+pub async fn save(conn: &Pool<Sqlite>, budget_id: i32, budget: &Budget) -> Result<Saved, &'static str> {
+    let mut accounts: HashMap<String, i64> = db::get_accounts_for_budget(conn, budget_id)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .map(|a| (a.name, a.id.into()))
+        .collect();
+    let currency = db::get_budget_currency(conn, budget_id).await;
+    let mut saved = Saved { imported: 0, duplicates: 0 };
+    let mut new_accounts = Vec::new();
+    let mut ids = HashMap::new();
+
+    let result: Result<(), sqlx::Error> = async {
+        let mut tx = conn.begin().await?;
+        for name in &budget.accounts {
+            if !accounts.contains_key(name) {
+                let id = db::insert_account(&mut tx, budget_id, name, &currency).await?;
+                accounts.insert(name.clone(), id);
+                new_accounts.push((id, name));
+            }
+        }
+
+        let mut groups = HashMap::new();
+        let mut categories = HashMap::new();
+        for category in &budget.categories {
+            let group_id = match groups.get(&category.group) {
+                Some(id) => *id,
+                None => {
+                    let id = db::insert_category_group(&mut tx, budget_id, &category.group).await?;
+                    groups.insert(category.group.clone(), id);
+                    id
+                }
+            };
+            categories.insert(category.clone(), db::insert_category(&mut tx, group_id, &category.name).await?);
+        }
+        let category_id = |category: &Option<CategoryName>| category.as_ref().and_then(|c| categories.get(c)).copied();
+
+        for assigned in &budget.assigned {
+            if let Some(id) = categories.get(&assigned.category) {
+                db::assign_to_category(&mut tx, *id, assigned.month, assigned.amount).await?;
+            }
+        }
+
+        for (index, t) in budget.transactions.iter().enumerate() {
+            let account_id = accounts[&t.account];
+            if let Some(import_id) = &t.import_id {
+                if db::transaction_import_id_exists(&mut *tx, account_id, import_id).await {
+                    saved.duplicates += 1;
+                    continue;
+                }
+            }
+
+            let id = db::insert_transaction(&mut tx, &NewTransaction {
+                account_id,
+                date: t.date.and_hms_opt(0, 0, 0).unwrap(),
+                value_date: None,
+                payee: t.payee.clone(),
+                memo: t.memo.clone(),
+                inflow: t.amount.max(0),
+                outflow: (-t.amount).max(0),
+                cleared: t.cleared,
+                import_id: t.import_id.clone(),
+                imported: false,
+                category_id: category_id(&t.category),
+            }).await?;
+            for split in &t.splits {
+                db::insert_split(&mut tx, id, category_id(&split.category), split.payee.as_deref(), &split.memo, split.amount.max(0), (-split.amount).max(0)).await?;
+            }
+
+            ids.insert(index, id);
+            saved.imported += 1;
+        }
+
+        // A split line has no row of its own, so the other side points at the split transaction.
+        for ((a, a_split), (b, b_split)) in budget.transfer_pairs() {
+            if let (Some(a_id), Some(b_id)) = (ids.get(&a), ids.get(&b)) {
+                if a_split.is_none() {
+                    db::link_transfer(&mut tx, *a_id, *b_id).await?;
+                }
+                if b_split.is_none() {
+                    db::link_transfer(&mut tx, *b_id, *a_id).await?;
+                }
+            }
+        }
+        tx.commit().await
+    }.await;
+
+    if let Err(e) = result {
+        println!("{:?}", e);
+        return Err("failed to save the YNAB budget");
+    }
+    for (id, name) in new_accounts {
+        db::account_created(conn, budget_id, id, name).await;
+    }
+    for id in ids.into_values() {
+        db::transaction_created(conn, id).await;
+    }
+
+    Ok(saved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{budgets, search};
+
+    const REGISTER: &str = "\u{feff}\"Account\",\"Flag\",\"Date\",\"Payee\",\"Category Group/Category\",\"Category Group\",\"Category\",\"Memo\",\"Outflow\",\"Inflow\",\"Cleared\"
+\"Current\",\"\",\"01/01/2024\",\"Starting Balance\",\"Inflow: Ready to Assign\",\"Inflow\",\"Ready to Assign\",\"\",£0.00,£1000.00,\"Reconciled\"
+\"Current\",\"\",\"05/01/2024\",\"Tesco\",\"\",\"\",\"\",\"Split (1/2) Food\",£30.00,£0.00,\"Cleared\"
+\"Current\",\"\",\"05/01/2024\",\"Tesco\",\"\",\"\",\"\",\"Split (2/2) Cleaning\",£5.50,£0.00,\"Cleared\"
+\"Current\",\"\",\"13/01/2024\",\"Transfer : Savings\",\"\",\"\",\"\",\"\",£200.00,£0.00,\"Uncleared\"
+\"Savings\",\"\",\"13/01/2024\",\"Transfer : Current\",\"\",\"\",\"\",\"\",£0.00,£200.00,\"Uncleared\"
+\"Current\",\"\",\"14/01/2024\",\"Landlord\",\"Bills: Rent\",\"Bills\",\"Rent\",\"January\",£650.00,£0.00,\"Cleared\"
+\"Current\",\"\",\"someday\",\"Landlord\",\"Bills: Rent\",\"Bills\",\"Rent\",\"\",£1.00,£0.00,\"Cleared\"
+";
+
+    const BUDGET: &str = "\"Month\",\"Category Group/Category\",\"Category Group\",\"Category\",\"Assigned\",\"Activity\",\"Available\"
+\"Jan 2024\",\"Bills: Rent\",\"Bills\",\"Rent\",£650.00,-£650.00,£0.00
+\"Jan 2024\",\"Bills: Energy\",\"Bills\",\"Energy\",£0.00,£0.00,£0.00
+\"Feb 2024\",\"Bills: Rent\",\"Bills\",\"Rent\",\"£1,300.00\",£0.00,£650.00
+";
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn category(group: &str, name: &str) -> CategoryName {
+        CategoryName { group: group.to_string(), name: name.to_string() }
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let (budget, errors) = parse_csv(REGISTER, Some(BUDGET), 2);
+
+        assert_eq!(errors, vec![ParseError::new(8, "Could not read date \"someday\"")]);
+        assert_eq!(budget.accounts, vec!["Current", "Savings"]);
+        assert_eq!(budget.categories, vec![category("Bills", "Rent"), category("Bills", "Energy")]);
+        assert_eq!(budget.assigned, vec![
+            Assigned { month: date(2024, 1, 1), category: category("Bills", "Rent"), amount: 65000 },
+            Assigned { month: date(2024, 2, 1), category: category("Bills", "Rent"), amount: 130000 },
+        ]);
+
+        assert_eq!(budget.transactions.len(), 5);
+        assert_eq!(budget.transactions[0].category, None, "Ready to Assign is not a category");
+        let split = &budget.transactions[1];
+        assert_eq!((split.date, split.amount, split.payee.as_deref()), (date(2024, 1, 5), -3550, Some("Tesco")));
+        assert_eq!(split.splits.iter().map(|s| s.memo.as_str()).collect::<Vec<_>>(), vec!["Food", "Cleaning"]);
+        assert_eq!(budget.transactions[2].transfer_account.as_deref(), Some("Savings"));
+        assert!(!budget.transactions[2].cleared);
+        assert_eq!(budget.transactions[4].category, Some(category("Bills", "Rent")));
+    }
+
+    #[test]
+    fn test_summary() {
+        let (budget, _) = parse_csv(REGISTER, Some(BUDGET), 2);
+
+        assert_eq!(budget.summary(), Summary {
+            accounts: 2,
+            category_groups: 1,
+            categories: 2,
+            months: 2,
+            payees: 3,
+            transactions: 5,
+            transfers: 1,
+            splits: 1,
+        });
+    }
+
+    #[test]
+    fn test_parse_yfull() {
+        let content = r#"{
+            "accounts": [
+                {"entityId": "A1", "accountName": "Current"},
+                {"entityId": "A2", "accountName": "Savings"},
+                {"entityId": "A3", "accountName": "Closed", "isTombstone": true}
+            ],
+            "masterCategories": [
+                {"entityId": "MC1", "name": "Bills", "subCategories": [{"entityId": "C1", "name": "Rent"}, {"entityId": "C2", "name": "Energy"}]}
+            ],
+            "monthlyBudgets": [
+                {"month": "2024-01-01", "monthlySubCategoryBudgets": [{"categoryId": "C1", "budgeted": 650}, {"categoryId": "C2", "budgeted": 0}]}
+            ],
+            "payees": [{"entityId": "P1", "name": "Landlord"}],
+            "transactions": [
+                {"entityId": "T1", "accountId": "A1", "date": "2024-01-14", "amount": -650, "payeeId": "P1", "categoryId": "C1", "cleared": "Cleared"},
+                {"entityId": "T2", "accountId": "A1", "date": "2024-01-15", "amount": -120.5, "categoryId": "Category/__Split__", "cleared": "Uncleared",
+                    "subTransactions": [
+                        {"entityId": "S1", "amount": -20.5, "categoryId": "C2", "memo": "Gas"},
+                        {"entityId": "S2", "amount": -100, "transferTransactionId": "T3"}
+                    ]},
+                {"entityId": "T3", "accountId": "A2", "date": "2024-01-15", "amount": 100, "payeeId": "Payee/Transfer:A1", "cleared": "Cleared", "transferTransactionId": "S2"},
+                {"entityId": "T4", "accountId": "A3", "date": "2024-01-16", "amount": 1},
+                {"entityId": "T5", "accountId": "A1", "date": "2024-01-16", "amount": 1, "isTombstone": true}
+            ]
+        }"#;
+
+        let (budget, errors) = parse_yfull(content, 2);
+        let (yen, _) = parse_yfull(content, 0);
+
+        assert_eq!(errors, vec![ParseError::new(4, "Transaction is not in a known account")]);
+        assert_eq!(budget.accounts, vec!["Current", "Savings"]);
+        assert_eq!(budget.assigned, vec![Assigned { month: date(2024, 1, 1), category: category("Bills", "Rent"), amount: 65000 }]);
+        assert_eq!(yen.assigned[0].amount, 650, "Yen have no minor units");
+        assert_eq!(budget.transactions.len(), 3);
+        assert_eq!(budget.transactions[0].payee.as_deref(), Some("Landlord"));
+        assert_eq!(budget.transactions[0].import_id.as_deref(), Some("ynab:T1"));
+        assert_eq!(budget.transactions[1].splits[1].transfer_account.as_deref(), Some("Savings"));
+        assert_eq!(budget.transactions[2].payee.as_deref(), Some("Transfer : Current"));
+        assert_eq!(budget.transfer_pairs(), vec![((1, Some(1)), (2, None))]);
+    }
+
+    #[sqlx::test]
+    async fn test_save(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)")
+            .execute(&pool)
+            .await?
+            .last_insert_rowid() as i32;
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id.into(), "Budget", "GBP").await? as i32;
+        let existing = db::create_account(&pool, budget_id, "Savings", "GBP", 0).await.unwrap();
+        let (budget, _) = parse_csv(REGISTER, Some(BUDGET), 2);
+
+        // Act
+        let saved = save(&pool, budget_id, &budget).await.unwrap();
+
+        // Assert
+        assert_eq!(saved, Saved { imported: 5, duplicates: 0 });
+        let accounts = db::get_accounts_for_budget(&pool, budget_id).await.unwrap();
+        assert_eq!(accounts.len(), 2, "Savings is reused rather than created again");
+
+        let current = accounts.iter().find(|a| a.name == "Current").unwrap();
+        assert_eq!(current.total, 100000 - 3550 - 20000 - 65000);
+        let register = |account_id: i64| {
+            let filter = search::Filter { account_id: Some(account_id), ..Default::default() };
+            let order = search::Order { sort: search::Sort::Date, descending: false };
+            let pool = pool.clone();
+            async move {
+                let mut transactions: Vec<db::Transaction> = db::get_register(&pool, budget_id, &filter, order, None).await.into_iter().map(|row| row.transaction).collect();
+                transactions.sort_by_key(|t| t.id);
+                transactions
+            }
+        };
+        let transactions = register(current.id.into()).await;
+        assert_eq!(transactions[1].category.as_deref(), Some("Split"));
+        assert_eq!(transactions[3].category.as_deref(), Some("Rent"));
+
+        let savings = register(existing).await;
+        let transfer = savings.iter().find(|t| t.transfer_id.is_some()).unwrap();
+        assert_eq!(transfer.transfer_id, Some(transactions[2].id.into()));
+        assert_eq!(transactions[2].transfer_id, Some(transfer.id.into()));
+
+        let (splits,): (i64,) = sqlx::query_as("SELECT count(*) FROM transaction_splits").fetch_one(&pool).await?;
+        assert_eq!(splits, 2);
+        let (assigned,): (i64,) = sqlx::query_as("SELECT sum(assigned) FROM category_budgets").fetch_one(&pool).await?;
+        assert_eq!(assigned, 195000);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_reimporting_csv_skips_imported_transactions(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)")
+            .execute(&pool)
+            .await?
+            .last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        let coffee = "\"Current\",\"\",\"20/01/2024\",\"Cafe\",\"\",\"\",\"\",\"\",£3.00,£0.00,\"Cleared\"\n";
+        let register = format!("{}{}{}", REGISTER, coffee, coffee);
+        let (first, _) = parse_csv(&register, Some(BUDGET), 2);
+        save(&pool, budget_id, &first).await.unwrap();
+        let (again, _) = parse_csv(&format!("{}{}", register, coffee), Some(BUDGET), 2);
+
+        // Act
+        let saved = save(&pool, budget_id, &again).await.unwrap();
+
+        // Assert
+        assert_eq!(first.transactions[5].import_id, Some("ynab-csv:Current:2024-01-20:-300:Cafe:0".to_string()));
+        assert_eq!(first.transactions[6].import_id, Some("ynab-csv:Current:2024-01-20:-300:Cafe:1".to_string()));
+        assert_eq!(saved, Saved { imported: 1, duplicates: 7 }, "Only the third coffee is new");
+        let (transactions,): (i64,) = sqlx::query_as("SELECT count(*) FROM transactions").fetch_one(&pool).await?;
+        assert_eq!(transactions, 8);
+        let (splits,): (i64,) = sqlx::query_as("SELECT count(*) FROM transaction_splits").fetch_one(&pool).await?;
+        assert_eq!(splits, 2);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_save_is_all_or_nothing(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)")
+            .execute(&pool)
+            .await?
+            .last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        sqlx::query("CREATE TRIGGER refuse_splits BEFORE INSERT ON transaction_splits BEGIN SELECT RAISE(ABORT, 'no splits'); END").execute(&pool).await?;
+        let (budget, _) = parse_csv(REGISTER, Some(BUDGET), 2);
+
+        // Act
+        let saved = save(&pool, budget_id, &budget).await;
+
+        // Assert
+        assert_eq!(saved, Err("failed to save the YNAB budget"));
+        let count = |table: &str| format!("SELECT count(*) FROM {}", table);
+        for table in ["accounts", "category_groups", "categories", "category_budgets", "transactions"] {
+            let (rows,): (i64,) = sqlx::query_as(&count(table)).fetch_one(&pool).await?;
+            assert_eq!(rows, 0, "Nothing is left in {} after a failed import", table);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_dates_are_day_first() {
+        assert!(dates_are_day_first(["01/02/2024", "13/02/2024"].into_iter()));
+        assert!(!dates_are_day_first(["01/02/2024", "02/13/2024"].into_iter()));
+        assert!(!dates_are_day_first(["2024-02-13"].into_iter()));
+    }
+}
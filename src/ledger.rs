@@ -0,0 +1,540 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use chrono::NaiveDate;
+
+use crate::{archive::{Archive, ArchivedAccount, ArchivedSplit, ArchivedTransaction}, helpers::format_decimal};
+
+/// Plain-text accounting formats ymnab can export to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    Ledger,
+    Hledger,
+    Beancount,
+}
+
+/// One side of a balanced transaction.
+#[derive(Debug, PartialEq)]
+struct Posting {
+    account: Vec<String>,
+    amount: i64,
+    currency: String,
+    /// What the whole posting cost in the entry's other currency, when money moved between
+    /// accounts in different currencies.
+    price: Option<(i64, String)>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Entry {
+    date: NaiveDate,
+    cleared: bool,
+    payee: Option<String>,
+    memo: String,
+    tags: Vec<String>,
+    postings: Vec<Posting>,
+}
+
+const OPENING_BALANCES: [&str; 2] = ["Equity", "Opening Balances"];
+
+fn is_starting_balance(t: &ArchivedTransaction) -> bool {
+    let starting = |value: &str| value.eq_ignore_ascii_case("starting balance");
+    t.category_id.is_none() && (t.payee.as_deref().is_some_and(starting) || (t.payee.is_none() && starting(&t.memo)))
+}
+
+/// Where money goes when it has no category: income for inflows, expenses for outflows.
+fn uncategorised(amount: i64) -> Vec<String> {
+    if amount > 0 {
+        vec!["Income".to_string(), "Uncategorized".to_string()]
+    } else {
+        vec!["Expenses".to_string(), "Uncategorized".to_string()]
+    }
+}
+
+/// Turns ymnab's accounts, categories and transactions into balanced entries. Each transfer is
+/// written once, as a single entry moving money between the two accounts. Accounts that owe money
+/// overall, such as credit cards and loans, are liabilities rather than assets.
+fn entries(archive: &Archive) -> Vec<Entry> {
+    let accounts: HashMap<i64, &ArchivedAccount> = archive.accounts.iter().map(|a| (a.id, a)).collect();
+    let groups: HashMap<i64, &str> = archive.category_groups.iter().map(|g| (g.id, g.name.as_str())).collect();
+    let categories: HashMap<i64, Vec<String>> = archive
+        .categories
+        .iter()
+        .map(|c| (c.id, vec!["Expenses".to_string(), groups.get(&c.group_id).copied().unwrap_or_default().to_string(), c.name.clone()]))
+        .collect();
+    let transactions: HashMap<i64, &ArchivedTransaction> = archive.transactions.iter().map(|t| (t.id, t)).collect();
+    let mut splits: HashMap<i64, Vec<&ArchivedSplit>> = HashMap::new();
+    for split in &archive.splits {
+        splits.entry(split.transaction_id).or_default().push(split);
+    }
+
+    let mut balances: HashMap<i64, i64> = HashMap::new();
+    for t in &archive.transactions {
+        *balances.entry(t.account_id).or_default() += t.inflow - t.outflow;
+    }
+
+    let account_for = |t: &ArchivedTransaction| -> (Vec<String>, String) {
+        let kind = if balances.get(&t.account_id).is_some_and(|b| *b < 0) { "Liabilities" } else { "Assets" };
+        match accounts.get(&t.account_id) {
+            Some(a) => (vec![kind.to_string(), a.name.clone()], a.currency.clone()),
+            None => (vec![kind.to_string(), "Unknown".to_string()], "GBP".to_string()),
+        }
+    };
+    // The other side of a transfer in another currency is priced at what left this one.
+    let price = |amount: i64, currency: &str, other_currency: &str| Some((amount.abs(), currency.to_string())).filter(|_| currency != other_currency);
+
+    // A transfer made from a split line only points one way: the other side points at the split
+    // transaction, which has no single transfer of its own.
+    let is_pair = |t: &ArchivedTransaction, other: i64| transactions.get(&other).is_some_and(|o| o.transfer_id == Some(t.id));
+    let mut split_transfers: HashMap<i64, Vec<&ArchivedTransaction>> = HashMap::new();
+    for t in &archive.transactions {
+        if let Some(other) = t.transfer_id.filter(|o| !is_pair(t, *o) && splits.contains_key(o)) {
+            split_transfers.entry(other).or_default().push(t);
+        }
+    }
+
+    let mut ordered: Vec<&ArchivedTransaction> = archive.transactions.iter().collect();
+    ordered.sort_by_key(|t| (t.date, t.id));
+
+    let mut used = HashSet::new();
+    let mut entries = vec![];
+    for t in ordered {
+        let amount = t.inflow - t.outflow;
+        let (account, currency) = account_for(t);
+        let mut postings = vec![Posting { account, amount, currency: currency.clone(), price: None }];
+
+        match t.transfer_id {
+            Some(other) if is_pair(t, other) => {
+                if t.id > other {
+                    continue;
+                }
+                let other = transactions[&other];
+                let (account, other_currency) = account_for(other);
+                let price = price(amount, &currency, &other_currency);
+                postings.push(Posting { account, amount: other.inflow - other.outflow, currency: other_currency, price });
+            }
+            Some(other) if splits.contains_key(&other) => continue,
+            _ => match splits.get(&t.id) {
+                Some(lines) => {
+                    for split in lines {
+                        let split_amount = split.inflow - split.outflow;
+                        let leg = split_transfers
+                            .get(&t.id)
+                            .and_then(|legs| legs.iter().find(|l| !used.contains(&l.id) && l.inflow - l.outflow == -split_amount));
+                        match leg {
+                            Some(leg) => {
+                                used.insert(leg.id);
+                                let (account, leg_currency) = account_for(leg);
+                                let price = price(split_amount, &currency, &leg_currency);
+                                postings.push(Posting { account, amount: leg.inflow - leg.outflow, currency: leg_currency, price });
+                            }
+                            None => postings.push(Posting {
+                                account: split.category_id.and_then(|id| categories.get(&id)).cloned().unwrap_or_else(|| uncategorised(split_amount)),
+                                amount: -split_amount,
+                                currency: currency.clone(),
+                                price: None,
+                            }),
+                        }
+                    }
+                }
+                None => {
+                    let counter = if is_starting_balance(t) {
+                        OPENING_BALANCES.iter().map(|c| c.to_string()).collect()
+                    } else {
+                        t.category_id.and_then(|id| categories.get(&id)).cloned().unwrap_or_else(|| uncategorised(amount))
+                    };
+                    postings.push(Posting { account: counter, amount: -amount, currency, price: None });
+                }
+            },
+        }
+
+        entries.push(Entry {
+            date: t.date.date(),
+            cleared: t.cleared,
+            payee: t.payee.clone().filter(|p| !p.trim().is_empty()),
+            memo: t.memo.clone(),
+            tags: t.tags.clone(),
+            postings,
+        });
+    }
+
+    entries
+}
+
+/// Writes minor units in the currency's own number of decimal places, such as `-12.50 GBP` or
+/// `1200 JPY`.
+fn format_amount(amount: i64, currency: &str) -> String {
+    format!("{} {}", format_decimal(amount, currency), currency)
+}
+
+fn one_line(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// Tags in every dialect are single words, so anything other than letters, digits, dashes and
+/// underscores becomes a dash, such as `tax-deductible`.
+fn tag(value: &str) -> String {
+    value.trim().chars().map(|c| if c.is_alphanumeric() || c == '_' { c } else { '-' }).collect()
+}
+
+impl Dialect {
+    pub const ALL: [Dialect; 3] = [Dialect::Ledger, Dialect::Hledger, Dialect::Beancount];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Dialect::Ledger => "ledger",
+            Dialect::Hledger => "hledger",
+            Dialect::Beancount => "beancount",
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|d| d.as_str() == value)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Dialect::Ledger => "Ledger",
+            Dialect::Hledger => "hledger",
+            Dialect::Beancount => "Beancount",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Dialect::Ledger => "ledger",
+            Dialect::Hledger => "journal",
+            Dialect::Beancount => "beancount",
+        }
+    }
+
+    /// Joins account name parts in the dialect's syntax. Ledger and hledger allow spaces but not
+    /// colons inside a part. Beancount parts must start with a capital letter or digit and may
+    /// only hold letters, digits and dashes.
+    fn account(&self, parts: &[String]) -> String {
+        let parts = parts.iter().map(|part| match self {
+            Dialect::Ledger | Dialect::Hledger => one_line(&part.replace(':', " ")),
+            Dialect::Beancount => {
+                let words: Vec<String> = part
+                    .split(|c: char| !c.is_alphanumeric())
+                    .filter(|w| !w.is_empty())
+                    .map(|w| {
+                        let mut chars = w.chars();
+                        let first = chars.next().unwrap();
+                        first.to_uppercase().chain(chars).collect()
+                    })
+                    .collect();
+                match words.join("-") {
+                    w if w.is_empty() => "Unnamed".to_string(),
+                    w => w,
+                }
+            }
+        });
+        parts.collect::<Vec<String>>().join(":")
+    }
+
+    fn header(&self, entry: &Entry) -> String {
+        let flag = if entry.cleared { "*" } else { "!" };
+        let date = entry.date.format("%Y-%m-%d");
+        let payee = entry.payee.as_deref().map(one_line);
+        let memo = Some(one_line(&entry.memo)).filter(|m| !m.is_empty());
+
+        let tags: Vec<String> = entry.tags.iter().map(|t| tag(t)).collect();
+
+        match self {
+            Dialect::Ledger => {
+                let mut header = format!("{} {} {}", date, flag, payee.as_deref().or(memo.as_deref()).unwrap_or_default());
+                if let (Some(_), Some(memo)) = (&payee, &memo) {
+                    header.push_str(&format!("\n    ; {}", memo));
+                }
+                if !tags.is_empty() {
+                    header.push_str(&format!("\n    ; :{}:", tags.join(":")));
+                }
+                header
+            }
+            Dialect::Hledger => {
+                let mut header = match (payee, memo) {
+                    (Some(payee), Some(memo)) => format!("{} {} {} | {}", date, flag, payee, memo),
+                    (payee, memo) => format!("{} {} {}", date, flag, payee.or(memo).unwrap_or_default()),
+                };
+                if !tags.is_empty() {
+                    header.push_str(&format!("  ; {}", tags.iter().map(|t| format!("{}:", t)).collect::<Vec<String>>().join(", ")));
+                }
+                header
+            }
+            Dialect::Beancount => {
+                let quote = |value: &str| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""));
+                let mut header = match payee {
+                    Some(payee) => format!("{} {} {} {}", date, flag, quote(&payee), quote(&memo.unwrap_or_default())),
+                    None => format!("{} {} {}", date, flag, quote(&memo.unwrap_or_default())),
+                };
+                for t in &tags {
+                    header.push_str(&format!(" #{}", t));
+                }
+                header
+            }
+        }
+    }
+
+    /// Writes the archive's transactions as a journal in this dialect, declaring every account it
+    /// uses first.
+    pub fn export(&self, archive: &Archive) -> String {
+        let entries = entries(archive);
+        let indent = if *self == Dialect::Beancount { "  " } else { "    " };
+
+        let mut used: BTreeSet<String> = BTreeSet::new();
+        let mut currencies: HashMap<String, &str> = HashMap::new();
+        for posting in entries.iter().flat_map(|e| &e.postings) {
+            let name = self.account(&posting.account);
+            if posting.account[0] == "Assets" || posting.account[0] == "Liabilities" {
+                currencies.insert(name.clone(), &posting.currency);
+            }
+            used.insert(name);
+        }
+
+        let mut out = String::new();
+        let opened = entries.iter().map(|e| e.date).min().unwrap_or_default();
+        for account in &used {
+            match self {
+                Dialect::Ledger | Dialect::Hledger => out.push_str(&format!("account {}\n", account)),
+                Dialect::Beancount => match currencies.get(account) {
+                    Some(currency) => out.push_str(&format!("{} open {} {}\n", opened.format("%Y-%m-%d"), account, currency)),
+                    None => out.push_str(&format!("{} open {}\n", opened.format("%Y-%m-%d"), account)),
+                },
+            }
+        }
+
+        // Exchange rates become price directives, so the tools can value accounts in one currency.
+        if !archive.exchange_rates.is_empty() {
+            out.push('\n');
+        }
+        for rate in &archive.exchange_rates {
+            let date = rate.date.format("%Y-%m-%d");
+            match self {
+                Dialect::Ledger | Dialect::Hledger => out.push_str(&format!("P {} {} {} {}\n", date, rate.base, rate.rate, rate.quote)),
+                Dialect::Beancount => out.push_str(&format!("{} price {} {} {}\n", date, rate.base, rate.rate, rate.quote)),
+            }
+        }
+
+        for entry in &entries {
+            out.push('\n');
+            out.push_str(&self.header(entry));
+            out.push('\n');
+            for posting in &entry.postings {
+                let mut amount = format_amount(posting.amount, &posting.currency);
+                if let Some((price, currency)) = &posting.price {
+                    amount.push_str(&format!(" @@ {}", format_amount(*price, currency)));
+                }
+                out.push_str(&format!("{}{:<40}  {}\n", indent, self.account(&posting.account), amount));
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{archive::{ArchivedCategory, ArchivedCategoryGroup, VERSION}, currency::ExchangeRate};
+
+    use super::*;
+
+    fn transaction(id: i64, account_id: i64, day: u32, payee: Option<&str>, memo: &str, amount: i64) -> ArchivedTransaction {
+        ArchivedTransaction {
+            id,
+            account_id,
+            date: NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            value_date: None,
+            payee: payee.map(|p| p.to_string()),
+            memo: memo.to_string(),
+            inflow: amount.max(0),
+            outflow: (-amount).max(0),
+            cleared: true,
+            import_id: None,
+            imported: false,
+            category_id: None,
+            transfer_id: None,
+            flag: None,
+            notes: None,
+            tags: vec![],
+        }
+    }
+
+    fn account(id: i64, name: &str, currency: &str) -> ArchivedAccount {
+        ArchivedAccount { id, name: name.to_string(), reconcile_balance: None, reconcile_date: None, currency: currency.to_string(), on_budget: true }
+    }
+
+    fn archive() -> Archive {
+        let mut rent = transaction(2, 1, 3, Some("Landlord"), "January", -65000);
+        rent.category_id = Some(1);
+        let mut to_savings = transaction(4, 1, 5, Some("Transfer : Savings"), "", -20000);
+        to_savings.transfer_id = Some(5);
+        to_savings.cleared = false;
+        let mut from_current = transaction(5, 2, 5, Some("Transfer : Current"), "", 20000);
+        from_current.transfer_id = Some(4);
+        let mut split_transfer = transaction(7, 2, 6, Some("Transfer : Current"), "", 1000);
+        split_transfer.transfer_id = Some(3);
+
+        Archive {
+            version: VERSION,
+            exported_at: NaiveDate::from_ymd_opt(2024, 2, 1).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            currency: "GBP".to_string(),
+            locale: "en-GB".to_string(),
+            accounts: vec![account(1, "Current", "GBP"), account(2, "Rainy Day: Savings", "GBP")],
+            category_groups: vec![ArchivedCategoryGroup { id: 1, name: "Bills".to_string() }],
+            categories: vec![ArchivedCategory { id: 1, group_id: 1, name: "Rent & Rates".to_string() }],
+            assigned: vec![],
+            payees: vec![],
+            transactions: vec![
+                transaction(1, 1, 1, None, "Starting balance", 100000),
+                rent,
+                transaction(3, 1, 6, Some("Tesco \"Extra\""), "", -3500),
+                to_savings,
+                from_current,
+                transaction(6, 1, 7, Some("Employer"), "", 250000),
+                split_transfer,
+            ],
+            splits: vec![
+                ArchivedSplit { transaction_id: 3, category_id: Some(1), payee: None, memo: String::new(), inflow: 0, outflow: 2500 },
+                ArchivedSplit { transaction_id: 3, category_id: None, payee: Some("Transfer : Savings".to_string()), memo: String::new(), inflow: 0, outflow: 1000 },
+            ],
+            csv_mappings: vec![],
+            webhooks: vec![],
+            exchange_rates: vec![],
+            rules: vec![],
+            attachments: vec![],
+        }
+    }
+
+    #[test]
+    fn test_entries_balance() {
+        let entries = entries(&archive());
+
+        assert_eq!(entries.len(), 5, "Each transfer is written once");
+        for entry in entries {
+            let weight = |p: &Posting| p.price.as_ref().map_or(p.amount, |(price, _)| price * p.amount.signum());
+            assert_eq!(entry.postings.iter().map(weight).sum::<i64>(), 0, "{:?} does not balance", entry);
+        }
+    }
+
+    #[test]
+    fn test_format_amount() {
+        assert_eq!(format_amount(-1250, "GBP"), "-12.50 GBP");
+        assert_eq!(format_amount(5, "EUR"), "0.05 EUR");
+        assert_eq!(format_amount(1200, "JPY"), "1200 JPY");
+        assert_eq!(format_amount(1234, "KWD"), "1.234 KWD");
+    }
+
+    #[test]
+    fn test_ledger() {
+        let journal = Dialect::Ledger.export(&archive());
+
+        assert_eq!(journal, "account Assets:Current
+account Assets:Rainy Day Savings
+account Equity:Opening Balances
+account Expenses:Bills:Rent & Rates
+account Income:Uncategorized
+
+2024-01-01 * Starting balance
+    Assets:Current                            1000.00 GBP
+    Equity:Opening Balances                   -1000.00 GBP
+
+2024-01-03 * Landlord
+    ; January
+    Assets:Current                            -650.00 GBP
+    Expenses:Bills:Rent & Rates               650.00 GBP
+
+2024-01-05 ! Transfer : Savings
+    Assets:Current                            -200.00 GBP
+    Assets:Rainy Day Savings                  200.00 GBP
+
+2024-01-06 * Tesco \"Extra\"
+    Assets:Current                            -35.00 GBP
+    Expenses:Bills:Rent & Rates               25.00 GBP
+    Assets:Rainy Day Savings                  10.00 GBP
+
+2024-01-07 * Employer
+    Assets:Current                            2500.00 GBP
+    Income:Uncategorized                      -2500.00 GBP
+");
+    }
+
+    #[test]
+    fn test_hledger_description() {
+        let journal = Dialect::Hledger.export(&archive());
+
+        assert!(journal.contains("2024-01-03 * Landlord | January\n"));
+        assert!(journal.contains("2024-01-01 * Starting balance\n"));
+    }
+
+    #[test]
+    fn test_beancount() {
+        let journal = Dialect::Beancount.export(&archive());
+
+        assert!(journal.starts_with("2024-01-01 open Assets:Current GBP
+2024-01-01 open Assets:Rainy-Day-Savings GBP
+2024-01-01 open Equity:Opening-Balances
+2024-01-01 open Expenses:Bills:Rent-Rates
+2024-01-01 open Income:Uncategorized
+"));
+        assert!(journal.contains("
+2024-01-06 * \"Tesco \\\"Extra\\\"\" \"\"
+  Assets:Current                            -35.00 GBP
+  Expenses:Bills:Rent-Rates                 25.00 GBP
+  Assets:Rainy-Day-Savings                  10.00 GBP
+"));
+        assert!(journal.contains("2024-01-01 * \"Starting balance\"\n"));
+    }
+
+    #[test]
+    fn test_tags() {
+        let mut archive = archive();
+        archive.transactions[1].tags = vec!["tax deductible".to_string(), "rent".to_string()];
+
+        assert!(Dialect::Ledger.export(&archive).contains("2024-01-03 * Landlord\n    ; January\n    ; :tax-deductible:rent:\n"));
+        assert!(Dialect::Hledger.export(&archive).contains("2024-01-03 * Landlord | January  ; tax-deductible:, rent:\n"));
+        assert!(Dialect::Beancount.export(&archive).contains("2024-01-03 * \"Landlord\" \"January\" #tax-deductible #rent\n"));
+    }
+
+    #[test]
+    fn test_cross_currency_transfers_are_priced() {
+        let mut archive = archive();
+        archive.accounts.push(account(3, "Girokonto", "EUR"));
+        let mut to_euros = transaction(8, 1, 8, Some("Transfer : Girokonto"), "", -20000);
+        to_euros.transfer_id = Some(9);
+        let mut from_pounds = transaction(9, 3, 8, Some("Transfer : Current"), "", 23000);
+        from_pounds.transfer_id = Some(8);
+        archive.transactions.extend([to_euros, from_pounds]);
+
+        let journal = Dialect::Ledger.export(&archive);
+
+        assert!(journal.contains("
+2024-01-08 * Transfer : Girokonto
+    Assets:Current                            -200.00 GBP
+    Assets:Girokonto                          230.00 EUR @@ 200.00 GBP
+"));
+        assert!(Dialect::Beancount.export(&archive).contains("  Assets:Girokonto                          230.00 EUR @@ 200.00 GBP\n"));
+    }
+
+    #[test]
+    fn test_accounts_that_owe_money_are_liabilities() {
+        let mut archive = archive();
+        archive.accounts.push(account(3, "Credit card", "GBP"));
+        let mut shopping = transaction(8, 3, 8, Some("Tesco"), "", -4000);
+        shopping.category_id = Some(1);
+        archive.transactions.push(shopping);
+
+        let journal = Dialect::Ledger.export(&archive);
+
+        assert!(journal.contains("account Liabilities:Credit card\n"));
+        assert!(journal.contains("    Liabilities:Credit card                   -40.00 GBP\n"));
+        assert!(Dialect::Beancount.export(&archive).contains("2024-01-01 open Liabilities:Credit-Card GBP\n"));
+    }
+
+    #[test]
+    fn test_exchange_rates_are_prices() {
+        let mut archive = archive();
+        archive.exchange_rates = vec![ExchangeRate { date: NaiveDate::from_ymd_opt(2024, 1, 5).unwrap(), base: "EUR".to_string(), quote: "GBP".to_string(), rate: 0.86 }];
+
+        assert!(Dialect::Ledger.export(&archive).contains("\nP 2024-01-05 EUR 0.86 GBP\n"));
+        assert!(Dialect::Beancount.export(&archive).contains("\n2024-01-05 price EUR 0.86 GBP\n"));
+    }
+}
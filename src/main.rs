@@ -3,32 +3,71 @@ mod handlers;
 mod db;
 mod app;
 mod helpers;
+mod import;
+mod webhooks;
+mod matching;
+mod archive;
+mod currency;
+mod ledger;
+mod rules;
+mod attachments;
+mod search;
+mod history;
+mod audit;
+mod budgets;
+mod reports;
 
-use std::env;
+use std::{env, str::FromStr, sync::Arc};
 
 use dotenvy::dotenv;
 use poem::{ 
     listener::TcpListener,
     Result, Server,
 };
-use sqlx::sqlite::SqlitePoolOptions;
+use sqlx::{sqlite::{SqliteConnectOptions, SqlitePoolOptions}, ConnectOptions, Connection};
 
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     dotenv().unwrap();
     let database_url = env::var("DATABASE_URL").expect("No database url set");
+    migrate(&database_url).await;
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
         .connect(&database_url.to_string())
         .await
         .expect("Could not connect to DB");
 
+    let attachments_dir = env::var("ATTACHMENTS_DIR").unwrap_or_else(|_| "attachments".to_string());
+    let storage = attachments::FileStorage::new(attachments_dir).expect("Could not create the attachments directory");
+
+    tokio::spawn(webhooks::run(pool.clone()));
+
+    Server::new(TcpListener::bind("0.0.0.0:3000"))
+        .run(app::app(pool, Arc::new(storage)))
+        .await
+}
+
+/// Migrations that rebuild a table have to run with foreign keys off, or dropping the old table
+/// would delete or orphan the rows that point at it. The keys are checked once they have run.
+async fn migrate(database_url: &str) {
+    let mut conn = SqliteConnectOptions::from_str(database_url)
+        .expect("Invalid database url")
+        .foreign_keys(false)
+        .connect()
+        .await
+        .expect("Could not connect to DB");
+
     sqlx::migrate!("./migrations")
-        .run(&pool)
+        .run(&mut conn)
         .await
         .expect("Failed to migrate the database");
 
-    Server::new(TcpListener::bind("0.0.0.0:3000"))
-        .run(app::app(pool))
+    let broken: Vec<(String, i64, String)> = sqlx::query_as("SELECT \"table\", rowid, parent FROM pragma_foreign_key_check")
+        .fetch_all(&mut conn)
         .await
+        .expect("Could not check foreign keys");
+    if let Some((table, rowid, parent)) = broken.first() {
+        panic!("Migrating left row {} of {} pointing at a missing {}", rowid, table, parent);
+    }
+    conn.close().await.expect("Could not close the migration connection");
 }
@@ -0,0 +1,220 @@
+use std::collections::HashSet;
+
+use crate::db::Transaction;
+
+/// How many days apart an imported transaction and a manually entered one may be and still match.
+/// Card payments are often booked a few days after the purchase.
+pub const DATE_WINDOW_DAYS: i64 = 5;
+
+/// Matches whose payees are less alike than this are not proposed.
+const MIN_PAYEE_SIMILARITY: f64 = 0.3;
+
+/// An imported transaction that looks like the same money as a manually entered one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub imported: Transaction,
+    pub manual: Transaction,
+    /// Between 0 and 1, where 1 is the same day and the same payee.
+    pub score: f64,
+}
+
+/// Lowercase letters and digits only, so "TESCO STORES 2231" and "Tesco Stores" compare well.
+fn normalise(value: &str) -> String {
+    value.chars().filter(|c| c.is_alphanumeric()).flat_map(|c| c.to_lowercase()).collect()
+}
+
+fn bigrams(value: &str) -> Vec<(char, char)> {
+    let chars: Vec<char> = value.chars().collect();
+    chars.windows(2).map(|w| (w[0], w[1])).collect()
+}
+
+/// The Sørensen–Dice coefficient of the two strings' character bigrams, between 0 and 1.
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (normalise(a), normalise(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    if a == b || a.contains(&b) || b.contains(&a) {
+        return 1.0;
+    }
+
+    let a_bigrams = bigrams(&a);
+    let mut b_bigrams = bigrams(&b);
+    let total = a_bigrams.len() + b_bigrams.len();
+    if total == 0 {
+        return 0.0;
+    }
+
+    let mut shared = 0;
+    for bigram in a_bigrams {
+        if let Some(index) = b_bigrams.iter().position(|b| *b == bigram) {
+            b_bigrams.swap_remove(index);
+            shared += 1;
+        }
+    }
+
+    (2 * shared) as f64 / total as f64
+}
+
+/// What the transaction was paid to, falling back to the memo when no payee was given.
+fn description(transaction: &Transaction) -> &str {
+    match transaction.payee.as_deref() {
+        Some(payee) if !payee.trim().is_empty() => payee,
+        _ => &transaction.memo,
+    }
+}
+
+/// Scores a possible pair, or `None` if the amounts differ or the dates are too far apart.
+fn score(imported: &Transaction, manual: &Transaction) -> Option<f64> {
+    if imported.inflow - imported.outflow != manual.inflow - manual.outflow {
+        return None;
+    }
+
+    let days = (imported.date.date() - manual.date.date()).num_days().abs();
+    if days > DATE_WINDOW_DAYS {
+        return None;
+    }
+
+    // When either side has nothing to compare, the amount and date alone decide.
+    let (imported_description, manual_description) = (description(imported), description(manual));
+    let payee = if normalise(imported_description).is_empty() || normalise(manual_description).is_empty() {
+        0.5
+    } else {
+        similarity(imported_description, manual_description)
+    };
+    if payee < MIN_PAYEE_SIMILARITY {
+        return None;
+    }
+
+    let closeness = 1.0 - days as f64 / (DATE_WINDOW_DAYS + 1) as f64;
+    Some((closeness + payee) / 2.0)
+}
+
+/// Pairs imported transactions with uncleared, manually entered ones in the same account. Each
+/// transaction is used at most once, best scores first. `dismissed` holds `(imported, manual)` id
+/// pairs the user has already rejected.
+pub fn propose(transactions: &[Transaction], dismissed: &HashSet<(i32, i32)>) -> Vec<Match> {
+    let imported: Vec<&Transaction> = transactions.iter().filter(|t| t.imported).collect();
+    let manual: Vec<&Transaction> = transactions.iter().filter(|t| !t.imported && !t.cleared).collect();
+
+    let mut candidates = vec![];
+    for i in &imported {
+        for m in &manual {
+            if i.account_id != m.account_id || dismissed.contains(&(i.id, m.id)) {
+                continue;
+            }
+            if let Some(score) = score(i, m) {
+                candidates.push((score, *i, *m));
+            }
+        }
+    }
+    candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.id.cmp(&b.1.id)).then(a.2.id.cmp(&b.2.id)));
+
+    let mut used = HashSet::new();
+    let mut matches = vec![];
+    for (score, i, m) in candidates {
+        if used.contains(&i.id) || used.contains(&m.id) {
+            continue;
+        }
+        used.insert(i.id);
+        used.insert(m.id);
+        matches.push(Match { imported: i.clone(), manual: m.clone(), score });
+    }
+
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn transaction(id: i32, day: u32, payee: &str, outflow: i64, imported: bool) -> Transaction {
+        Transaction {
+            id,
+            account_id: 1,
+            date: NaiveDate::from_ymd_opt(2024, 1, day).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            memo: String::new(),
+            inflow: 0,
+            outflow,
+            cleared: imported,
+            payee: Some(payee.to_string()),
+            payee_id: None,
+            import_id: None,
+            value_date: None,
+            imported,
+            category_id: None,
+            category: None,
+            transfer_id: None,
+            flag: None,
+            notes: None,
+            tags: None,
+        }
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(similarity("Tesco", "TESCO STORES 2231"), 1.0);
+        assert_eq!(similarity("", "Tesco"), 0.0);
+        assert!(similarity("Sainsburys", "SAINSBURY'S S/MKT") > 0.6);
+        assert!(similarity("Tesco", "Aldi") < 0.3);
+    }
+
+    #[test]
+    fn test_propose() {
+        // Setup
+        let transactions = vec![
+            transaction(1, 3, "Tesco", 1250, false),
+            transaction(2, 5, "TESCO STORES 2231", 1250, true),
+            // Same amount, but a different shop.
+            transaction(3, 4, "Aldi", 1250, true),
+            // Same shop and amount, but too long after.
+            transaction(4, 20, "Tesco", 1250, true),
+            transaction(5, 6, "Coffee", 300, false),
+            transaction(6, 6, "Coffee", 350, true),
+        ];
+
+        // Act
+        let matches = propose(&transactions, &HashSet::new());
+
+        // Assert
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].imported.id, matches[0].manual.id), (2, 1));
+    }
+
+    #[test]
+    fn test_propose_uses_each_transaction_once() {
+        // Setup
+        let transactions = vec![
+            transaction(1, 3, "Tesco", 1250, false),
+            transaction(2, 4, "Tesco", 1250, false),
+            transaction(3, 4, "Tesco", 1250, true),
+        ];
+
+        // Act
+        let matches = propose(&transactions, &HashSet::new());
+
+        // Assert
+        assert_eq!(matches.len(), 1);
+        assert_eq!((matches[0].imported.id, matches[0].manual.id), (3, 2));
+    }
+
+    #[test]
+    fn test_propose_skips_dismissed_and_cleared() {
+        // Setup
+        let mut cleared = transaction(1, 3, "Tesco", 1250, false);
+        cleared.cleared = true;
+        let transactions = vec![
+            cleared,
+            transaction(2, 3, "Tesco", 1250, false),
+            transaction(3, 3, "Tesco", 1250, true),
+        ];
+
+        // Act
+        let matches = propose(&transactions, &HashSet::from([(3, 2)]));
+
+        // Assert
+        assert!(matches.is_empty());
+    }
+}
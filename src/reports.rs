@@ -0,0 +1,864 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::{Datelike, Months, NaiveDate};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, Pool, Sqlite};
+
+use crate::{currency::Rates, helpers::format_decimal};
+
+/// A report that can be downloaded as well as viewed. JSON has every field of the report, and CSV
+/// the table the page shows.
+pub trait Report: Serialize {
+    fn csv(&self) -> String;
+}
+
+/// The formats a report can be downloaded in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Export {
+    Csv,
+    Json,
+}
+
+impl Export {
+    pub fn from_str(value: &str) -> Option<Self> {
+        match value {
+            "csv" => Some(Export::Csv),
+            "json" => Some(Export::Json),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Export::Csv => "csv",
+            Export::Json => "json",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Export::Csv => "text/csv; charset=utf-8",
+            Export::Json => "application/json",
+        }
+    }
+
+    pub fn write(&self, report: &impl Report) -> String {
+        match self {
+            Export::Csv => report.csv(),
+            Export::Json => serde_json::to_string_pretty(report).unwrap(),
+        }
+    }
+}
+
+/// Writes the records as CSV.
+fn write_csv(records: impl IntoIterator<Item = Vec<String>>) -> String {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for record in records {
+        writer.write_record(&record).unwrap();
+    }
+    String::from_utf8(writer.into_inner().unwrap()).unwrap()
+}
+
+/// Whether the transaction `t`, with payee `p`, is an account's starting balance rather than money
+/// coming in. Matches `ledger`, which books them as opening balances.
+const STARTING_BALANCE: &str = "(t.category_id IS NULL AND (lower(COALESCE(p.name, t.payee)) = 'starting balance' OR (COALESCE(p.name, t.payee) IS NULL AND lower(t.memo) = 'starting balance')))";
+
+/// Whether the transaction `t`, in account `a`, is a transfer between two accounts in the budget.
+/// A transfer to or from a tracking account moves money out of or into the budget, so isn't one.
+const BUDGET_TRANSFER: &str = "(a.on_budget AND EXISTS (SELECT 1 FROM transactions o JOIN accounts oa ON oa.id = o.account_id WHERE o.id = t.transfer_id AND oa.on_budget))";
+
+/// Every line of money coming into or going out of the budget, with `amount` positive for money
+/// going out. Split transactions are one line per part, with the part's own category and payee.
+/// Transfers between budget accounts, starting balances and tracking accounts are left out, since
+/// none of them is money coming into or leaving the budget.
+fn lines_query() -> String {
+    format!("WITH lines AS (
+    SELECT t.id AS transaction_id, t.date, t.account_id, a.currency, t.category_id, COALESCE(p.name, t.payee) AS payee, t.memo, t.outflow - t.inflow AS amount
+    FROM transactions t JOIN accounts a ON a.id = t.account_id LEFT JOIN payees p ON p.id = t.payee_id
+    WHERE a.budget_id = ?1 AND a.on_budget AND NOT {transfer} AND NOT {starting} AND NOT EXISTS (SELECT 1 FROM transaction_splits s WHERE s.transaction_id = t.id)
+    UNION ALL
+    SELECT t.id, t.date, t.account_id, a.currency, s.category_id, COALESCE(NULLIF(trim(s.payee), ''), p.name, t.payee), COALESCE(NULLIF(s.memo, ''), t.memo), s.outflow - s.inflow
+    FROM transaction_splits s JOIN transactions t ON t.id = s.transaction_id JOIN accounts a ON a.id = t.account_id LEFT JOIN payees p ON p.id = t.payee_id
+    WHERE a.budget_id = ?1 AND a.on_budget AND NOT {transfer}
+)", transfer = BUDGET_TRANSFER, starting = STARTING_BALANCE)
+}
+
+/// A report as it appears in the query string, such as `?from=2024-01-01&to=2024-03-31&by=payee`,
+/// so reports can be bookmarked. Empty dates fall back to the report's usual period.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReportParams {
+    pub from: String,
+    pub to: String,
+    /// What spending is broken down by, see `Breakdown`.
+    pub by: String,
+    /// Which row to list the transactions of, as the row's `key`.
+    pub key: String,
+}
+
+/// What spending can be broken down by.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Breakdown {
+    Group,
+    #[default]
+    Category,
+    Payee,
+}
+
+impl Breakdown {
+    pub const ALL: [Breakdown; 3] = [Breakdown::Group, Breakdown::Category, Breakdown::Payee];
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "group" => Breakdown::Group,
+            "payee" => Breakdown::Payee,
+            _ => Breakdown::Category,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Breakdown::Group => "group",
+            Breakdown::Category => "category",
+            Breakdown::Payee => "payee",
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Breakdown::Group => "Category group",
+            Breakdown::Category => "Category",
+            Breakdown::Payee => "Payee",
+        }
+    }
+
+    /// Identifies a line's row, as text so every breakdown compares the same way. Lines with no
+    /// category or payee share the empty key.
+    fn key(&self) -> &'static str {
+        match self {
+            Breakdown::Group => "COALESCE(CAST(g.id AS TEXT), '')",
+            Breakdown::Category => "COALESCE(CAST(c.id AS TEXT), '')",
+            Breakdown::Payee => "COALESCE(l.payee, '')",
+        }
+    }
+
+    fn row_name(&self) -> &'static str {
+        match self {
+            Breakdown::Group => "COALESCE(g.name, 'Uncategorised')",
+            Breakdown::Category => "COALESCE(g.name || ': ' || c.name, 'Uncategorised')",
+            Breakdown::Payee => "COALESCE(NULLIF(l.payee, ''), 'No payee')",
+        }
+    }
+}
+
+/// The days a report covers, both included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Period {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+fn first_of_month(date: NaiveDate) -> NaiveDate {
+    date.with_day(1).unwrap()
+}
+
+impl Period {
+    /// The month `today` is in and the `months - 1` before it.
+    pub fn last_months(today: NaiveDate, months: u32) -> Self {
+        Period { from: first_of_month(today) - Months::new(months.saturating_sub(1)), to: today }
+    }
+
+    /// The last day of `month` the period covers.
+    fn month_end(&self, month: NaiveDate) -> NaiveDate {
+        (month + Months::new(1)).pred_opt().unwrap().min(self.to)
+    }
+
+    /// The first day of every month the period touches.
+    pub fn months(&self) -> Vec<NaiveDate> {
+        let mut months = vec![];
+        let mut month = first_of_month(self.from);
+        while month <= self.to {
+            months.push(month);
+            month = month + Months::new(1);
+        }
+        months
+    }
+}
+
+impl ReportParams {
+    pub fn breakdown(&self) -> Breakdown {
+        Breakdown::parse(&self.by)
+    }
+
+    /// Reads the period, falling back to `default` for dates left empty.
+    pub fn period(&self, default: Period) -> Result<Period, String> {
+        let date = |value: &str, default: NaiveDate| match value.trim() {
+            "" => Ok(default),
+            value => NaiveDate::parse_from_str(value, "%Y-%m-%d").map_err(|_| format!("{} is not a date.", value)),
+        };
+        let period = Period { from: date(&self.from, default.from)?, to: date(&self.to, default.to)? };
+        if period.to < period.from {
+            return Err("The report has to end after it starts.".to_string());
+        }
+        Ok(period)
+    }
+
+    /// The same report listing the transactions of the row with `key`.
+    pub fn drill_down(&self, key: &str) -> Self {
+        Self { key: key.to_string(), ..self.clone() }
+    }
+
+    /// The query string for these parameters, without the leading `?`.
+    pub fn query_string(&self) -> String {
+        serde_urlencoded::to_string(self).unwrap_or_default()
+    }
+}
+
+#[derive(FromRow)]
+struct MonthTotal {
+    category_id: Option<i64>,
+    key: String,
+    name: String,
+    month: NaiveDate,
+    currency: String,
+    amount: i64,
+}
+
+/// One row of a spending report, such as a category, with its spending in the report's currency.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Row {
+    pub key: String,
+    pub name: String,
+    pub total: i64,
+    /// The spending in each of the report's months, in order.
+    pub months: Vec<i64>,
+}
+
+/// Spending over a period broken down by category group, category or payee.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Spending {
+    pub currency: String,
+    pub months: Vec<NaiveDate>,
+    /// Rows that took money out over the period, biggest first. Categories more came into than went
+    /// out of over the period, such as income, are left out before the rows are added up.
+    pub rows: Vec<Row>,
+    /// Currencies with no exchange rate to the report's, whose spending is left out.
+    pub missing: BTreeSet<String>,
+}
+
+impl Spending {
+    pub fn total(&self) -> i64 {
+        self.rows.iter().map(|r| r.total).sum()
+    }
+
+    /// The spending of every row in each month.
+    pub fn month_totals(&self) -> Vec<i64> {
+        (0..self.months.len()).map(|i| self.rows.iter().map(|r| r.months[i]).sum()).collect()
+    }
+}
+
+/// What went out under each row, category and month in the period, converted into `currency` at
+/// the rate at the end of the month, or at the end of the period for the last one. Totals in
+/// currencies with no rate are left out, and the currencies returned alongside.
+async fn month_totals(conn: &Pool<Sqlite>, budget_id: i32, period: Period, breakdown: Breakdown, currency: &str, rates: &Rates) -> (Vec<(i64, MonthTotal)>, BTreeSet<String>) {
+    let query = format!(
+        "{} SELECT l.category_id, {} AS key, {} AS name, date(l.date, 'start of month') AS month, l.currency, SUM(l.amount) AS amount FROM lines l LEFT JOIN categories c ON c.id = l.category_id LEFT JOIN category_groups g ON g.id = c.group_id WHERE date(l.date) BETWEEN ?2 AND ?3 GROUP BY 1, 2, 4, 5",
+        lines_query(),
+        breakdown.key(),
+        breakdown.row_name(),
+    );
+    let totals = match sqlx::query_as::<_, MonthTotal>(&query).bind(budget_id).bind(period.from).bind(period.to).fetch_all(conn).await {
+        Ok(totals) => totals,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    };
+
+    let mut missing = BTreeSet::new();
+    let mut converted = vec![];
+    for total in totals {
+        match rates.convert(total.amount, &total.currency, currency, period.month_end(total.month)) {
+            Some(amount) => converted.push((amount, total)),
+            None => {
+                missing.insert(total.currency.clone());
+            }
+        }
+    }
+    (converted, missing)
+}
+
+/// Whether each category took more out than came in over the period. Those that didn't, such as
+/// income, aren't spending.
+fn spending_categories(totals: &[(i64, MonthTotal)]) -> HashMap<Option<i64>, bool> {
+    let mut by_category: HashMap<Option<i64>, i64> = HashMap::new();
+    for (amount, total) in totals {
+        *by_category.entry(total.category_id).or_default() += amount;
+    }
+    by_category.into_iter().map(|(category_id, amount)| (category_id, amount > 0)).collect()
+}
+
+/// The budget's spending over the period in `currency`.
+pub async fn spending(conn: &Pool<Sqlite>, budget_id: i32, period: Period, breakdown: Breakdown, currency: &str, rates: &Rates) -> Spending {
+    let (converted, missing) = month_totals(conn, budget_id, period, breakdown, currency, rates).await;
+    let months = period.months();
+    let is_spending = spending_categories(&converted);
+    let mut rows: HashMap<String, Row> = HashMap::new();
+    for (amount, total) in converted.into_iter().filter(|(_, t)| is_spending[&t.category_id]) {
+        let index = months.iter().position(|m| *m == total.month).unwrap_or_default();
+        let row = rows.entry(total.key.clone()).or_insert_with(|| Row { key: total.key, name: total.name, total: 0, months: vec![0; months.len()] });
+        row.total += amount;
+        row.months[index] += amount;
+    }
+
+    let mut rows: Vec<Row> = rows.into_values().filter(|r| r.total > 0).collect();
+    rows.sort_by(|a, b| b.total.cmp(&a.total).then_with(|| a.name.cmp(&b.name)));
+    Spending { currency: currency.to_string(), months, rows, missing }
+}
+
+/// A line behind a report, in the currency of its account.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct Line {
+    pub transaction_id: i64,
+    pub date: chrono::NaiveDateTime,
+    pub account: String,
+    pub currency: String,
+    pub payee: Option<String>,
+    pub category: Option<String>,
+    pub memo: String,
+    pub amount: i64,
+}
+
+/// Every line in the period under the row with `key`, newest first. Income under the row is
+/// listed too, even though `spending` leaves it out.
+pub async fn lines(conn: &Pool<Sqlite>, budget_id: i32, period: Period, breakdown: Breakdown, key: &str) -> Vec<Line> {
+    let query = format!(
+        "{} SELECT l.transaction_id, l.date, a.name AS account, l.currency, l.payee, CASE WHEN c.id IS NULL THEN NULL ELSE g.name || ': ' || c.name END AS category, l.memo, l.amount FROM lines l JOIN accounts a ON a.id = l.account_id LEFT JOIN categories c ON c.id = l.category_id LEFT JOIN category_groups g ON g.id = c.group_id WHERE date(l.date) BETWEEN ?2 AND ?3 AND {} = ?4 ORDER BY l.date DESC, l.transaction_id DESC",
+        lines_query(),
+        breakdown.key(),
+    );
+    match sqlx::query_as::<_, Line>(&query).bind(budget_id).bind(period.from).bind(period.to).bind(key).fetch_all(conn).await {
+        Ok(lines) => lines,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    }
+}
+
+#[derive(FromRow)]
+struct Movement {
+    account_id: i64,
+    name: String,
+    currency: String,
+    on_budget: bool,
+    /// The month the money moved in, or `None` for everything before the period.
+    month: Option<NaiveDate>,
+    amount: i64,
+}
+
+/// An account's balance at the end of each month, in the account's currency.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountBalances {
+    pub id: i64,
+    pub name: String,
+    pub currency: String,
+    pub on_budget: bool,
+    pub balances: Vec<i64>,
+}
+
+/// What every account in the budget, tracking accounts included, was worth at the end of each
+/// month. Accounts are assets in months they hold money and liabilities in months they owe it.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct NetWorth {
+    pub currency: String,
+    pub months: Vec<NaiveDate>,
+    pub accounts: Vec<AccountBalances>,
+    /// In `currency`, for each month.
+    pub assets: Vec<i64>,
+    /// In `currency`, for each month, as positive amounts.
+    pub liabilities: Vec<i64>,
+    /// Currencies with no exchange rate to the report's, whose accounts are left out of the totals.
+    pub missing: BTreeSet<String>,
+}
+
+impl NetWorth {
+    pub fn net_worth(&self) -> Vec<i64> {
+        self.assets.iter().zip(&self.liabilities).map(|(assets, liabilities)| assets - liabilities).collect()
+    }
+}
+
+impl Report for NetWorth {
+    /// A row for each month with the totals in the report's currency, followed by each account's
+    /// balance in its own currency.
+    fn csv(&self) -> String {
+        let mut header = vec![
+            "Month".to_string(),
+            format!("Assets ({})", self.currency),
+            format!("Liabilities ({})", self.currency),
+            format!("Net worth ({})", self.currency),
+        ];
+        header.extend(self.accounts.iter().map(|a| format!("{} ({})", a.name, a.currency)));
+
+        let net_worth = self.net_worth();
+        let rows = self.months.iter().enumerate().map(|(i, month)| {
+            let mut record = vec![
+                month.format("%Y-%m").to_string(),
+                format_decimal(self.assets[i], &self.currency),
+                format_decimal(self.liabilities[i], &self.currency),
+                format_decimal(net_worth[i], &self.currency),
+            ];
+            record.extend(self.accounts.iter().map(|a| format_decimal(a.balances[i], &a.currency)));
+            record
+        });
+        write_csv(std::iter::once(header).chain(rows))
+    }
+}
+
+/// The budget's net worth at the end of every month in the period, with each account's balance
+/// converted into `currency` at the rate on that day. Balances are added up from every transaction,
+/// transfers included, so they match the register.
+pub async fn net_worth(conn: &Pool<Sqlite>, budget_id: i32, period: Period, currency: &str, rates: &Rates) -> NetWorth {
+    let result = sqlx::query_as::<_, Movement>("SELECT a.id AS account_id, a.name, a.currency, a.on_budget, CASE WHEN date(t.date) >= ?2 THEN date(t.date, 'start of month') END AS month, COALESCE(SUM(t.inflow - t.outflow), 0) AS amount FROM accounts a LEFT JOIN transactions t ON t.account_id = a.id AND date(t.date) <= ?3 WHERE a.budget_id = ?1 GROUP BY a.id, 5 ORDER BY a.id, 5")
+        .bind(budget_id)
+        .bind(period.from)
+        .bind(period.to)
+        .fetch_all(conn)
+        .await;
+    let movements = match result {
+        Ok(movements) => movements,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    };
+
+    let months = period.months();
+    let mut accounts: Vec<AccountBalances> = vec![];
+    let mut changes: Vec<Vec<i64>> = vec![];
+    let mut opening: Vec<i64> = vec![];
+    for movement in movements {
+        if accounts.last().map(|a| a.id) != Some(movement.account_id) {
+            accounts.push(AccountBalances { id: movement.account_id, name: movement.name, currency: movement.currency, on_budget: movement.on_budget, balances: vec![] });
+            changes.push(vec![0; months.len()]);
+            opening.push(0);
+        }
+        let last = accounts.len() - 1;
+        match movement.month.and_then(|m| months.iter().position(|month| *month == m)) {
+            Some(index) => changes[last][index] += movement.amount,
+            None => opening[last] += movement.amount,
+        }
+    }
+
+    let mut missing = BTreeSet::new();
+    let mut assets = vec![0; months.len()];
+    let mut liabilities = vec![0; months.len()];
+    for (i, account) in accounts.iter_mut().enumerate() {
+        let mut balance = opening[i];
+        for (m, month) in months.iter().enumerate() {
+            balance += changes[i][m];
+            account.balances.push(balance);
+            match rates.convert(balance, &account.currency, currency, period.month_end(*month)) {
+                Some(amount) if amount >= 0 => assets[m] += amount,
+                Some(amount) => liabilities[m] -= amount,
+                None => {
+                    missing.insert(account.currency.clone());
+                }
+            }
+        }
+    }
+
+    NetWorth { currency: currency.to_string(), months, accounts, assets, liabilities, missing }
+}
+
+/// Money coming in and going out over a stretch of time, in the report's currency.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Flow {
+    pub income: i64,
+    pub spending: i64,
+    pub net: i64,
+    /// The share of income that wasn't spent, such as 0.25, or `None` with no income.
+    pub savings_rate: Option<f64>,
+}
+
+impl Flow {
+    fn new(income: i64, spending: i64) -> Self {
+        let net = income - spending;
+        Flow { income, spending, net, savings_rate: (income > 0).then(|| net as f64 / income as f64) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MonthFlow {
+    pub month: NaiveDate,
+    #[serde(flatten)]
+    pub flow: Flow,
+}
+
+/// Income against spending for each month of a period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct IncomeExpense {
+    pub currency: String,
+    pub months: Vec<MonthFlow>,
+    pub total: Flow,
+    /// Currencies with no exchange rate to the report's, whose money is left out.
+    pub missing: BTreeSet<String>,
+}
+
+/// A savings rate as a percentage with one decimal place, or nothing with no income.
+pub fn format_savings_rate(rate: Option<f64>) -> String {
+    rate.map(|rate| format!("{:.1}%", rate * 100.0)).unwrap_or_default()
+}
+
+impl Report for IncomeExpense {
+    fn csv(&self) -> String {
+        let header = ["Month".to_string(), format!("Income ({})", self.currency), format!("Spending ({})", self.currency), format!("Net ({})", self.currency), "Savings rate".to_string()];
+        let row = |label: String, flow: &Flow| vec![
+            label,
+            format_decimal(flow.income, &self.currency),
+            format_decimal(flow.spending, &self.currency),
+            format_decimal(flow.net, &self.currency),
+            format_savings_rate(flow.savings_rate),
+        ];
+        let months = self.months.iter().map(|m| row(m.month.format("%Y-%m").to_string(), &m.flow));
+        write_csv(std::iter::once(header.to_vec()).chain(months).chain(std::iter::once(row("Total".to_string(), &self.total))))
+    }
+}
+
+/// The budget's income and spending in each month of the period, in `currency`. Spending is the
+/// same as `spending` reports, and income is what came in under the categories it leaves out.
+pub async fn income_expense(conn: &Pool<Sqlite>, budget_id: i32, period: Period, currency: &str, rates: &Rates) -> IncomeExpense {
+    let (converted, missing) = month_totals(conn, budget_id, period, Breakdown::Category, currency, rates).await;
+    let months = period.months();
+    let is_spending = spending_categories(&converted);
+    let mut income = vec![0; months.len()];
+    let mut spending = vec![0; months.len()];
+    for (amount, total) in converted {
+        let index = months.iter().position(|m| *m == total.month).unwrap_or_default();
+        if is_spending[&total.category_id] {
+            spending[index] += amount;
+        } else {
+            income[index] -= amount;
+        }
+    }
+
+    let total = Flow::new(income.iter().sum(), spending.iter().sum());
+    let months = months.into_iter().enumerate().map(|(i, month)| MonthFlow { month, flow: Flow::new(income[i], spending[i]) }).collect();
+    IncomeExpense { currency: currency.to_string(), months, total, missing }
+}
+
+/// What went into and out of an account over a period, in the account's currency.
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct AccountFlow {
+    pub id: i64,
+    pub name: String,
+    pub currency: String,
+    pub on_budget: bool,
+    /// The balance before the period, counting the starting balance whenever it was entered.
+    pub opening: i64,
+    pub inflow: i64,
+    pub outflow: i64,
+    pub transfers_in: i64,
+    pub transfers_out: i64,
+    pub closing: i64,
+}
+
+/// A cash-flow statement for each account over a period.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CashFlow {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// Accounts in the budget first, then tracking accounts.
+    pub accounts: Vec<AccountFlow>,
+}
+
+impl Report for CashFlow {
+    fn csv(&self) -> String {
+        let header = ["Account", "Currency", "On budget", "Opening", "Inflow", "Outflow", "Transfers in", "Transfers out", "Closing"].map(String::from).to_vec();
+        let rows = self.accounts.iter().map(|a| vec![
+            a.name.clone(),
+            a.currency.clone(),
+            if a.on_budget { "yes" } else { "no" }.to_string(),
+            format_decimal(a.opening, &a.currency),
+            format_decimal(a.inflow, &a.currency),
+            format_decimal(a.outflow, &a.currency),
+            format_decimal(a.transfers_in, &a.currency),
+            format_decimal(a.transfers_out, &a.currency),
+            format_decimal(a.closing, &a.currency),
+        ]);
+        write_csv(std::iter::once(header).chain(rows))
+    }
+}
+
+/// Each account's balance before the period, the money that came in and went out during it with
+/// transfers between budget accounts kept apart, and its balance at the end. Split transactions count
+/// once, at their full amount.
+pub async fn cash_flow(conn: &Pool<Sqlite>, budget_id: i32, period: Period) -> CashFlow {
+    let query = format!(
+        "SELECT a.id, a.name, a.currency, a.on_budget, opening, inflow, outflow, transfers_in, transfers_out, opening + inflow - outflow + transfers_in - transfers_out AS closing FROM accounts a JOIN (
+            SELECT a.id AS account_id,
+                COALESCE(SUM(CASE WHEN date(t.date) < ?2 OR {starting} THEN t.inflow - t.outflow END), 0) AS opening,
+                COALESCE(SUM(CASE WHEN date(t.date) >= ?2 AND NOT {starting} AND NOT {transfer} THEN t.inflow END), 0) AS inflow,
+                COALESCE(SUM(CASE WHEN date(t.date) >= ?2 AND NOT {starting} AND NOT {transfer} THEN t.outflow END), 0) AS outflow,
+                COALESCE(SUM(CASE WHEN date(t.date) >= ?2 AND NOT {starting} AND {transfer} THEN t.inflow END), 0) AS transfers_in,
+                COALESCE(SUM(CASE WHEN date(t.date) >= ?2 AND NOT {starting} AND {transfer} THEN t.outflow END), 0) AS transfers_out
+            FROM accounts a LEFT JOIN transactions t ON t.account_id = a.id AND date(t.date) <= ?3 LEFT JOIN payees p ON p.id = t.payee_id
+            WHERE a.budget_id = ?1 GROUP BY a.id
+        ) f ON f.account_id = a.id ORDER BY a.on_budget DESC, a.id",
+        starting = STARTING_BALANCE,
+        transfer = BUDGET_TRANSFER,
+    );
+    let accounts = match sqlx::query_as::<_, AccountFlow>(&query).bind(budget_id).bind(period.from).bind(period.to).fetch_all(conn).await {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            println!("{:?}", e);
+            vec![]
+        }
+    };
+    CashFlow { from: period.from, to: period.to, accounts }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{budgets, db::{self, NewTransaction}};
+
+    fn date(value: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d").unwrap()
+    }
+
+    fn transaction(account_id: i64, on: &str, payee: &str, inflow: i64, outflow: i64, category_id: Option<i64>) -> NewTransaction {
+        NewTransaction {
+            account_id,
+            date: date(on).and_hms_opt(12, 0, 0).unwrap(),
+            value_date: None,
+            payee: Some(payee.to_string()),
+            memo: String::new(),
+            inflow,
+            outflow,
+            cleared: true,
+            import_id: None,
+            imported: false,
+            category_id,
+        }
+    }
+
+    #[test]
+    fn test_period() {
+        let default = Period::last_months(date("2024-03-15"), 3);
+        let params = ReportParams::default();
+        assert_eq!(params.period(default), Ok(Period { from: date("2024-01-01"), to: date("2024-03-15") }));
+        assert_eq!(params.period(default).unwrap().months(), vec![date("2024-01-01"), date("2024-02-01"), date("2024-03-01")]);
+        assert_eq!(Period::last_months(date("2024-03-15"), 12).from, date("2023-04-01"));
+
+        let params = ReportParams { from: "2023-12-20".to_string(), to: "2024-01-05".to_string(), ..ReportParams::default() };
+        assert_eq!(params.period(default).unwrap().months(), vec![date("2023-12-01"), date("2024-01-01")]);
+
+        let backwards = ReportParams { from: "2024-02-01".to_string(), to: "2024-01-01".to_string(), ..ReportParams::default() };
+        assert!(backwards.period(default).is_err());
+        let not_a_date = ReportParams { from: "last week".to_string(), ..ReportParams::default() };
+        assert_eq!(not_a_date.period(default), Err("last week is not a date.".to_string()));
+    }
+
+    #[sqlx::test]
+    async fn test_spending(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        let current = db::create_empty_account(&pool, budget_id, "Current", "GBP").await.unwrap();
+        let savings = db::create_empty_account(&pool, budget_id, "Savings", "GBP").await.unwrap();
+        let euros = db::create_empty_account(&pool, budget_id, "Euros", "EUR").await.unwrap();
+        let bills = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Bills").await.unwrap();
+        let everyday = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Everyday").await.unwrap();
+        let rent = db::insert_category(&mut pool.acquire().await.unwrap(), bills, "Rent").await.unwrap();
+        let energy = db::insert_category(&mut pool.acquire().await.unwrap(), bills, "Energy").await.unwrap();
+        let groceries = db::insert_category(&mut pool.acquire().await.unwrap(), everyday, "Groceries").await.unwrap();
+        let income = db::insert_category(&mut pool.acquire().await.unwrap(), everyday, "Income").await.unwrap();
+        for t in [
+            transaction(current, "2024-01-01", "Landlord", 0, 60000, Some(rent)),
+            transaction(current, "2024-02-01", "Landlord", 0, 60000, Some(rent)),
+            transaction(current, "2024-01-10", "Tesco", 0, 4000, Some(groceries)),
+            transaction(current, "2024-02-10", "Tesco", 0, 6000, Some(groceries)),
+            transaction(current, "2024-02-12", "Tesco", 1000, 0, Some(groceries)),
+            transaction(current, "2024-01-25", "Work", 200000, 0, Some(income)),
+            transaction(current, "2023-12-31", "Landlord", 0, 60000, Some(rent)),
+            transaction(euros, "2024-01-15", "Boulangerie", 0, 1000, Some(groceries)),
+        ] {
+            db::create_transaction(&pool, &t).await.unwrap();
+        }
+        let split = db::create_transaction(&pool, &transaction(current, "2024-02-20", "Tesco", 0, 5000, None)).await.unwrap();
+        db::insert_split(&mut pool.acquire().await.unwrap(), split, Some(energy), Some("Octopus"), "", 0, 3000).await.unwrap();
+        db::insert_split(&mut pool.acquire().await.unwrap(), split, Some(groceries), None, "", 0, 2000).await.unwrap();
+        let from = db::create_transaction(&pool, &transaction(current, "2024-02-25", "Transfer", 0, 20000, None)).await.unwrap();
+        let to = db::create_transaction(&pool, &transaction(savings, "2024-02-25", "Transfer", 20000, 0, None)).await.unwrap();
+        db::link_transfer(&mut pool.acquire().await.unwrap(), from, to).await.unwrap();
+        db::link_transfer(&mut pool.acquire().await.unwrap(), to, from).await.unwrap();
+        let period = Period { from: date("2024-01-01"), to: date("2024-02-29") };
+        let no_rates = Rates::new(vec![]);
+
+        // Act
+        let by_category = spending(&pool, budget_id, period, Breakdown::Category, "GBP", &no_rates).await;
+        let by_group = spending(&pool, budget_id, period, Breakdown::Group, "GBP", &no_rates).await;
+        let by_payee = spending(&pool, budget_id, period, Breakdown::Payee, "GBP", &no_rates).await;
+        let groceries_lines = lines(&pool, budget_id, period, Breakdown::Category, &groceries.to_string()).await;
+
+        // Assert
+        let rows = |spending: &Spending| spending.rows.iter().map(|r| (r.name.clone(), r.months.clone())).collect::<Vec<_>>();
+        assert_eq!(rows(&by_category), vec![
+            ("Bills: Rent".to_string(), vec![60000, 60000]),
+            ("Everyday: Groceries".to_string(), vec![4000, 7000]),
+            ("Bills: Energy".to_string(), vec![0, 3000]),
+        ], "Splits count under each part's category, and income and transfers are left out");
+        assert_eq!(by_category.month_totals(), vec![64000, 70000]);
+        assert_eq!(by_category.missing, BTreeSet::from(["EUR".to_string()]));
+        assert_eq!(rows(&by_group), vec![("Bills".to_string(), vec![60000, 63000]), ("Everyday".to_string(), vec![4000, 7000])]);
+        assert_eq!(rows(&by_payee), vec![("Landlord".to_string(), vec![60000, 60000]), ("Tesco".to_string(), vec![4000, 7000]), ("Octopus".to_string(), vec![0, 3000])]);
+        assert_eq!(groceries_lines.iter().map(|l| (l.payee.as_deref().unwrap(), l.amount)).collect::<Vec<_>>(), vec![("Tesco", 2000), ("Tesco", -1000), ("Tesco", 6000), ("Boulangerie", 1000), ("Tesco", 4000)]);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_net_worth(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        let current = db::create_empty_account(&pool, budget_id, "Current", "GBP").await.unwrap();
+        let card = db::create_empty_account(&pool, budget_id, "Credit card", "GBP").await.unwrap();
+        let pension = db::create_empty_account(&pool, budget_id, "Pension, workplace", "EUR").await.unwrap();
+        db::set_on_budget(&pool, pension, false).await.unwrap();
+        for t in [
+            transaction(current, "2023-12-20", "Opening", 100000, 0, None),
+            transaction(current, "2024-02-10", "Landlord", 0, 60000, None),
+            transaction(card, "2024-01-05", "Shop", 0, 20000, None),
+            transaction(card, "2024-03-01", "Payment", 20000, 0, None),
+            transaction(pension, "2024-01-31", "Valuation", 50000, 0, None),
+            transaction(pension, "2024-04-01", "Valuation", 10000, 0, None),
+        ] {
+            db::create_transaction(&pool, &t).await.unwrap();
+        }
+        let rates = Rates::new(crate::currency::parse_csv("2024-01-01,EUR,GBP,0.85\n2024-03-01,EUR,GBP,0.9").unwrap());
+        let period = Period { from: date("2024-01-01"), to: date("2024-03-15") };
+
+        // Act
+        let report = net_worth(&pool, budget_id, period, "GBP", &rates).await;
+        let without_rates = net_worth(&pool, budget_id, period, "GBP", &Rates::new(vec![])).await;
+
+        // Assert
+        assert_eq!(report.accounts.iter().map(|a| (a.name.as_str(), a.balances.clone())).collect::<Vec<_>>(), vec![
+            ("Current", vec![100000, 40000, 40000]),
+            ("Credit card", vec![-20000, -20000, 0]),
+            ("Pension, workplace", vec![50000, 50000, 50000]),
+        ], "Balances carry over from before the period, and later transactions are left out");
+        assert!(!report.accounts[2].on_budget);
+        assert_eq!(report.assets, vec![142500, 82500, 85000], "Converted at each month's closing rate");
+        assert_eq!(report.liabilities, vec![20000, 20000, 0]);
+        assert_eq!(report.net_worth(), vec![122500, 62500, 85000]);
+        assert_eq!(without_rates.assets, vec![100000, 40000, 40000]);
+        assert_eq!(without_rates.missing, BTreeSet::from(["EUR".to_string()]));
+        assert_eq!(report.csv(), "\
+Month,Assets (GBP),Liabilities (GBP),Net worth (GBP),Current (GBP),Credit card (GBP),\"Pension, workplace (EUR)\"
+2024-01,1425.00,200.00,1225.00,1000.00,-200.00,500.00
+2024-02,825.00,200.00,625.00,400.00,-200.00,500.00
+2024-03,850.00,0.00,850.00,400.00,0.00,500.00
+");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_income_expense_and_cash_flow(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        let current = db::create_empty_account(&pool, budget_id, "Current", "GBP").await.unwrap();
+        let savings = db::create_empty_account(&pool, budget_id, "Savings", "GBP").await.unwrap();
+        let pension = db::create_empty_account(&pool, budget_id, "Pension", "GBP").await.unwrap();
+        db::set_on_budget(&pool, pension, false).await.unwrap();
+        let everyday = db::insert_category_group(&mut pool.acquire().await.unwrap(), budget_id, "Everyday").await.unwrap();
+        let groceries = db::insert_category(&mut pool.acquire().await.unwrap(), everyday, "Groceries").await.unwrap();
+        let energy = db::insert_category(&mut pool.acquire().await.unwrap(), everyday, "Energy").await.unwrap();
+        let salary = db::insert_category(&mut pool.acquire().await.unwrap(), everyday, "Salary").await.unwrap();
+        for t in [
+            transaction(current, "2024-02-15", "Starting balance", 50000, 0, None),
+            transaction(current, "2023-12-20", "Tesco", 0, 1000, Some(groceries)),
+            transaction(current, "2024-01-25", "Work", 200000, 0, Some(salary)),
+            transaction(current, "2024-01-10", "Tesco", 0, 50000, Some(groceries)),
+            transaction(current, "2024-02-25", "Work", 200000, 0, Some(salary)),
+            transaction(pension, "2024-01-31", "Valuation", 300000, 0, Some(salary)),
+        ] {
+            db::create_transaction(&pool, &t).await.unwrap();
+        }
+        let split = db::create_transaction(&pool, &transaction(current, "2024-02-20", "Tesco", 0, 250000, None)).await.unwrap();
+        db::insert_split(&mut pool.acquire().await.unwrap(), split, Some(energy), Some("Octopus"), "", 0, 200000).await.unwrap();
+        db::insert_split(&mut pool.acquire().await.unwrap(), split, Some(groceries), None, "", 0, 50000).await.unwrap();
+        let from = db::create_transaction(&pool, &transaction(current, "2024-01-26", "Transfer", 0, 100000, None)).await.unwrap();
+        let to = db::create_transaction(&pool, &transaction(savings, "2024-01-26", "Transfer", 100000, 0, None)).await.unwrap();
+        db::link_transfer(&mut pool.acquire().await.unwrap(), from, to).await.unwrap();
+        db::link_transfer(&mut pool.acquire().await.unwrap(), to, from).await.unwrap();
+        let period = Period { from: date("2024-01-01"), to: date("2024-02-29") };
+
+        // Act
+        let report = income_expense(&pool, budget_id, period, "GBP", &Rates::new(vec![])).await;
+        let cash_flow = cash_flow(&pool, budget_id, period).await;
+
+        // Assert
+        assert_eq!(report.months.iter().map(|m| (m.flow.income, m.flow.spending, m.flow.net)).collect::<Vec<_>>(), vec![
+            (200000, 50000, 150000),
+            (200000, 250000, -50000),
+        ], "Transfers, starting balances and tracking accounts are left out, and splits count in full");
+        assert_eq!(report.total, Flow { income: 400000, spending: 300000, net: 100000, savings_rate: Some(0.25) });
+        assert_eq!(format_savings_rate(report.total.savings_rate), "25.0%");
+        assert_eq!(report.csv(), "\
+Month,Income (GBP),Spending (GBP),Net (GBP),Savings rate
+2024-01,2000.00,500.00,1500.00,75.0%
+2024-02,2000.00,2500.00,-500.00,-25.0%
+Total,4000.00,3000.00,1000.00,25.0%
+");
+        let json: serde_json::Value = serde_json::from_str(&Export::Json.write(&report)).unwrap();
+        assert_eq!(json["months"][0]["month"], "2024-01-01");
+        assert_eq!(json["months"][0]["income"], 200000);
+        assert_eq!(json["total"]["savings_rate"], 0.25);
+
+        assert_eq!(cash_flow.accounts.iter().map(|a| (a.name.as_str(), a.opening, a.inflow, a.outflow, a.transfers_in, a.transfers_out, a.closing)).collect::<Vec<_>>(), vec![
+            ("Current", 49000, 400000, 300000, 0, 100000, 49000),
+            ("Savings", 0, 0, 0, 100000, 0, 100000),
+            ("Pension", 0, 300000, 0, 0, 0, 300000),
+        ], "The starting balance is part of the opening balance whenever it was entered");
+        assert_eq!(cash_flow.csv().lines().nth(1), Some("Current,GBP,yes,490.00,4000.00,3000.00,0.00,1000.00,490.00"));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_transfers_to_tracking_accounts_leave_the_budget(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)").execute(&pool).await?.last_insert_rowid();
+        let budget_id = budgets::insert(&mut *pool.acquire().await?, user_id, "Budget", "GBP").await? as i32;
+        let current = db::create_empty_account(&pool, budget_id, "Current", "GBP").await.unwrap();
+        let savings = db::create_empty_account(&pool, budget_id, "Savings", "GBP").await.unwrap();
+        let pension = db::create_empty_account(&pool, budget_id, "Pension", "GBP").await.unwrap();
+        db::set_on_budget(&pool, pension, false).await.unwrap();
+        for (from_account, to_account, amount) in [(current, savings, 10000), (current, pension, 30000)] {
+            let from = db::create_transaction(&pool, &transaction(from_account, "2024-01-15", "Transfer", 0, amount, None)).await.unwrap();
+            let to = db::create_transaction(&pool, &transaction(to_account, "2024-01-15", "Transfer", amount, 0, None)).await.unwrap();
+            db::link_transfer(&mut pool.acquire().await.unwrap(), from, to).await.unwrap();
+            db::link_transfer(&mut pool.acquire().await.unwrap(), to, from).await.unwrap();
+        }
+        let period = Period { from: date("2024-01-01"), to: date("2024-01-31") };
+
+        // Act
+        let report = income_expense(&pool, budget_id, period, "GBP", &Rates::new(vec![])).await;
+        let cash_flow = cash_flow(&pool, budget_id, period).await;
+
+        // Assert
+        assert_eq!(report.total.spending, 30000, "Only the transfer to the tracking account leaves the budget");
+        assert_eq!(cash_flow.accounts.iter().map(|a| (a.name.as_str(), a.inflow, a.outflow, a.transfers_in, a.transfers_out)).collect::<Vec<_>>(), vec![
+            ("Current", 0, 30000, 0, 10000),
+            ("Savings", 0, 0, 10000, 0),
+            ("Pension", 30000, 0, 0, 0),
+        ]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+/// The colours a transaction can be flagged with.
+pub const FLAGS: [&str; 6] = ["red", "orange", "yellow", "green", "blue", "purple"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    Payee,
+    Memo,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    Contains,
+    Regex,
+}
+
+/// Something a transaction must be for a rule to apply. Text is compared ignoring case.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    Text { field: Field, operator: Operator, value: String },
+    /// The signed amount in minor units, where both ends are included.
+    Amount { min: Option<i64>, max: Option<i64> },
+    Account { account_id: i64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Action {
+    RenamePayee { payee: String },
+    SetCategory { category_id: i64 },
+    SetMemo { memo: String },
+    SetFlag { flag: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rule {
+    pub id: i64,
+    pub name: String,
+    /// Rules with a lower number run first.
+    pub priority: i64,
+    /// All of them must match.
+    pub conditions: Vec<Condition>,
+    pub actions: Vec<Action>,
+}
+
+/// The parts of a transaction rules look at and change.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Subject {
+    pub account_id: i64,
+    pub payee: Option<String>,
+    pub memo: String,
+    pub amount: i64,
+    pub category_id: Option<i64>,
+    pub flag: Option<String>,
+}
+
+fn regex(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).case_insensitive(true).build()
+}
+
+impl Condition {
+    fn matches(&self, subject: &Subject) -> bool {
+        match self {
+            Condition::Text { field, operator, value } => {
+                let text = match field {
+                    Field::Payee => subject.payee.as_deref().unwrap_or_default(),
+                    Field::Memo => &subject.memo,
+                };
+                match operator {
+                    Operator::Contains => text.to_lowercase().contains(&value.to_lowercase()),
+                    Operator::Regex => regex(value).is_ok_and(|r| r.is_match(text)),
+                }
+            }
+            Condition::Amount { min, max } => {
+                !min.is_some_and(|min| subject.amount < min) && !max.is_some_and(|max| subject.amount > max)
+            }
+            Condition::Account { account_id } => subject.account_id == *account_id,
+        }
+    }
+}
+
+impl Rule {
+    /// Checks the rule can be saved: it needs at least one condition and one action, and any
+    /// patterns must be valid.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.trim().is_empty() {
+            return Err("Rules need a name.".to_string());
+        }
+        if self.conditions.is_empty() {
+            return Err("Rules need at least one condition.".to_string());
+        }
+        if self.actions.is_empty() {
+            return Err("Rules need at least one action.".to_string());
+        }
+        for condition in &self.conditions {
+            match condition {
+                Condition::Text { value, .. } if value.is_empty() => return Err("Text conditions need some text.".to_string()),
+                Condition::Text { operator: Operator::Regex, value, .. } => {
+                    regex(value).map_err(|e| format!("\"{}\" is not a valid pattern: {}", value, e))?;
+                }
+                Condition::Amount { min: Some(min), max: Some(max) } if min > max => {
+                    return Err("The smallest amount is more than the largest.".to_string());
+                }
+                _ => {}
+            }
+        }
+        for action in &self.actions {
+            if let Action::SetFlag { flag } = action {
+                if !FLAGS.contains(&flag.as_str()) {
+                    return Err(format!("{} is not a flag colour.", flag));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches(&self, subject: &Subject) -> bool {
+        !self.conditions.is_empty() && self.conditions.iter().all(|c| c.matches(subject))
+    }
+}
+
+/// Which fields a rule has already set.
+#[derive(Default)]
+struct Set {
+    payee: bool,
+    memo: bool,
+    category: bool,
+    flag: bool,
+}
+
+/// Runs every matching rule over the transaction, lowest priority number first, and returns the
+/// names of the rules that applied. A field set by one rule is not changed by a later one, but
+/// later rules see it, so one rule can clean up a payee and another categorise by the clean name.
+pub fn apply(rules: &[Rule], subject: &mut Subject) -> Vec<String> {
+    let mut ordered: Vec<&Rule> = rules.iter().collect();
+    ordered.sort_by_key(|r| (r.priority, r.id));
+
+    let mut set = Set::default();
+    let mut applied = vec![];
+    for rule in ordered {
+        if !rule.matches(subject) {
+            continue;
+        }
+        applied.push(rule.name.clone());
+        for action in &rule.actions {
+            match action {
+                Action::RenamePayee { payee } if !set.payee => {
+                    subject.payee = Some(payee.clone());
+                    set.payee = true;
+                }
+                Action::SetMemo { memo } if !set.memo => {
+                    subject.memo = memo.clone();
+                    set.memo = true;
+                }
+                Action::SetCategory { category_id } if !set.category => {
+                    subject.category_id = Some(*category_id);
+                    set.category = true;
+                }
+                Action::SetFlag { flag } if !set.flag => {
+                    subject.flag = Some(flag.clone());
+                    set.flag = true;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    applied
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(id: i64, priority: i64, conditions: Vec<Condition>, actions: Vec<Action>) -> Rule {
+        Rule { id, name: format!("Rule {}", id), priority, conditions, actions }
+    }
+
+    fn text(field: Field, operator: Operator, value: &str) -> Condition {
+        Condition::Text { field, operator, value: value.to_string() }
+    }
+
+    fn subject(payee: &str, amount: i64) -> Subject {
+        Subject { account_id: 1, payee: Some(payee.to_string()), amount, ..Default::default() }
+    }
+
+    #[test]
+    fn test_conditions() {
+        let tesco = subject("CARD PAYMENT TO TESCO STORES 2231", -1250);
+
+        assert!(rule(1, 0, vec![text(Field::Payee, Operator::Contains, "tesco")], vec![]).matches(&tesco));
+        assert!(rule(1, 0, vec![text(Field::Payee, Operator::Regex, r"^card payment to tesco stores \d+$")], vec![]).matches(&tesco));
+        assert!(!rule(1, 0, vec![text(Field::Memo, Operator::Contains, "tesco")], vec![]).matches(&tesco));
+        assert!(rule(1, 0, vec![Condition::Amount { min: Some(-2000), max: Some(-1000) }], vec![]).matches(&tesco));
+        assert!(!rule(1, 0, vec![Condition::Amount { min: None, max: Some(-2000) }], vec![]).matches(&tesco));
+        assert!(!rule(1, 0, vec![Condition::Account { account_id: 2 }], vec![]).matches(&tesco));
+        assert!(!rule(1, 0, vec![
+            text(Field::Payee, Operator::Contains, "tesco"),
+            Condition::Account { account_id: 2 },
+        ], vec![]).matches(&tesco), "Every condition must match");
+        assert!(!rule(1, 0, vec![], vec![]).matches(&tesco), "Rules without conditions match nothing");
+    }
+
+    #[test]
+    fn test_apply_in_priority_order() {
+        // Setup
+        let rules = vec![
+            rule(1, 10, vec![text(Field::Payee, Operator::Contains, "tesco")], vec![
+                Action::SetCategory { category_id: 2 },
+                Action::RenamePayee { payee: "Tesco Express".to_string() },
+            ]),
+            rule(2, 1, vec![text(Field::Payee, Operator::Regex, "tesco stores")], vec![
+                Action::RenamePayee { payee: "Tesco".to_string() },
+                Action::SetFlag { flag: "blue".to_string() },
+            ]),
+            rule(3, 5, vec![text(Field::Payee, Operator::Contains, "aldi")], vec![Action::SetMemo { memo: "Aldi".to_string() }]),
+        ];
+        let mut tesco = subject("CARD PAYMENT TO TESCO STORES 2231", -1250);
+
+        // Act
+        let applied = apply(&rules, &mut tesco);
+
+        // Assert
+        assert_eq!(applied, vec!["Rule 2", "Rule 1"]);
+        assert_eq!(tesco.payee.as_deref(), Some("Tesco"), "The first rule to rename the payee wins");
+        assert_eq!(tesco.category_id, Some(2), "Later rules match the renamed payee");
+        assert_eq!(tesco.flag.as_deref(), Some("blue"));
+        assert_eq!(tesco.memo, "");
+    }
+
+    #[test]
+    fn test_validate() {
+        let action = vec![Action::SetMemo { memo: "x".to_string() }];
+
+        assert!(rule(1, 0, vec![text(Field::Payee, Operator::Contains, "tesco")], action.clone()).validate().is_ok());
+        assert!(rule(1, 0, vec![text(Field::Payee, Operator::Regex, "(tesco")], action.clone()).validate().is_err());
+        assert!(rule(1, 0, vec![Condition::Amount { min: Some(10), max: Some(5) }], action.clone()).validate().is_err());
+        assert!(rule(1, 0, vec![], action.clone()).validate().is_err());
+        assert!(rule(1, 0, vec![Condition::Account { account_id: 1 }], vec![]).validate().is_err());
+        assert!(rule(1, 0, vec![Condition::Account { account_id: 1 }], vec![Action::SetFlag { flag: "pink".to_string() }]).validate().is_err());
+    }
+}
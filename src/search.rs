@@ -0,0 +1,231 @@
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+
+use crate::{helpers::{parse_money, Locale}, rules};
+
+/// A search as it appears in the query string, such as `?q=tesco&from=2024-01-01&flag=red`, so
+/// searches can be bookmarked and shared. Empty fields are ignored.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchParams {
+    /// Words to look for in the payee, memo and notes.
+    pub q: String,
+    pub from: String,
+    pub to: String,
+    /// Amounts are compared with the money in or out, whichever the transaction has.
+    pub min_amount: String,
+    pub max_amount: String,
+    pub category_id: String,
+    pub account_id: String,
+    /// `yes` or `no`.
+    pub cleared: String,
+    pub flag: String,
+    pub tag: String,
+    /// The column to sort by, see `Sort`.
+    pub sort: String,
+    /// `asc` or `desc`, or empty for the column's usual order.
+    pub order: String,
+}
+
+/// Which transactions to show. Every field that is set has to match.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Filter {
+    /// An FTS5 query, see `fts_query`.
+    pub text: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub min_amount: Option<i64>,
+    pub max_amount: Option<i64>,
+    pub category_id: Option<i64>,
+    pub account_id: Option<i64>,
+    pub cleared: Option<bool>,
+    pub flag: Option<String>,
+    pub tag: Option<String>,
+}
+
+/// What the register can be sorted by.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Sort {
+    #[default]
+    Date,
+    Payee,
+    Category,
+    Amount,
+}
+
+impl Sort {
+    fn parse(value: &str) -> Self {
+        match value {
+            "payee" => Sort::Payee,
+            "category" => Sort::Category,
+            "amount" => Sort::Amount,
+            _ => Sort::Date,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sort::Date => "date",
+            Sort::Payee => "payee",
+            Sort::Category => "category",
+            Sort::Amount => "amount",
+        }
+    }
+}
+
+/// How to order the register. Transactions that sort the same are kept in the order they were added.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Order {
+    pub sort: Sort,
+    pub descending: bool,
+}
+
+impl Order {
+    /// Dates are newest first, everything else starts from the top.
+    pub fn default_for(sort: Sort) -> Self {
+        Order { sort, descending: sort == Sort::Date }
+    }
+}
+
+/// Turns what the user typed into an FTS5 query that finds transactions with every word, each
+/// matched as the start of a word. Quotes are dropped so nothing typed is read as FTS5 syntax.
+pub fn fts_query(text: &str) -> Option<String> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| word.replace('"', ""))
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"*", word))
+        .collect();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
+impl SearchParams {
+    /// Whether nothing is being searched for. The account and the sort order don't count.
+    pub fn is_empty(&self) -> bool {
+        *self == Self { account_id: self.account_id.clone(), sort: self.sort.clone(), order: self.order.clone(), ..Self::default() }
+    }
+
+    pub fn order(&self) -> Order {
+        let sort = Sort::parse(&self.sort);
+        match self.order.as_str() {
+            "asc" => Order { sort, descending: false },
+            "desc" => Order { sort, descending: true },
+            _ => Order::default_for(sort),
+        }
+    }
+
+    /// The same search sorted by `sort`, or in the other direction if it is already sorted by it.
+    pub fn sorted_by(&self, sort: Sort) -> Self {
+        let current = self.order();
+        let descending = if current.sort == sort { !current.descending } else { Order::default_for(sort).descending };
+        Self { sort: sort.name().to_string(), order: if descending { "desc" } else { "asc" }.to_string(), ..self.clone() }
+    }
+
+    /// The query string for these parameters, without the leading `?`.
+    pub fn query_string(&self) -> String {
+        serde_urlencoded::to_string(self).unwrap_or_default()
+    }
+
+    /// Reads the parameters, with amounts in `currency` written the way `locale` writes them.
+    pub fn filter(&self, currency: &str, locale: &Locale) -> Result<Filter, String> {
+        let date = |value: &str| match value.trim() {
+            "" => Ok(None),
+            value => NaiveDate::parse_from_str(value, "%Y-%m-%d").map(Some).map_err(|_| format!("{} is not a date.", value)),
+        };
+        let amount = |value: &str| match value.trim() {
+            "" => Ok(None),
+            value => parse_money(value, currency, locale).map(|a| Some(a.abs())).map_err(|_| format!("{} is not an amount.", value)),
+        };
+        let flag = Some(self.flag.clone()).filter(|f| !f.is_empty());
+        if flag.as_deref().is_some_and(|f| !rules::FLAGS.contains(&f)) {
+            return Err(format!("{} is not a flag colour.", self.flag));
+        }
+
+        Ok(Filter {
+            text: fts_query(&self.q),
+            from: date(&self.from)?,
+            to: date(&self.to)?,
+            min_amount: amount(&self.min_amount)?,
+            max_amount: amount(&self.max_amount)?,
+            category_id: self.category_id.parse().ok(),
+            account_id: self.account_id.parse().ok(),
+            cleared: match self.cleared.as_str() {
+                "yes" => Some(true),
+                "no" => Some(false),
+                _ => None,
+            },
+            flag,
+            tag: Some(self.tag.trim().to_string()).filter(|t| !t.is_empty()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_query("tesco"), Some("\"tesco\"*".to_string()));
+        assert_eq!(fts_query("  card  \"tes  "), Some("\"card\"* \"tes\"*".to_string()));
+        assert_eq!(fts_query("a OR b"), Some("\"a\"* \"OR\"* \"b\"*".to_string()), "Operators are searched for as words");
+        assert_eq!(fts_query(" \" "), None);
+    }
+
+    #[test]
+    fn test_filter() {
+        // Setup
+        let params = SearchParams {
+            q: "tesco".to_string(),
+            from: "2024-01-01".to_string(),
+            min_amount: "-10.00".to_string(),
+            max_amount: "1,250.50".to_string(),
+            account_id: "3".to_string(),
+            cleared: "no".to_string(),
+            flag: "red".to_string(),
+            ..Default::default()
+        };
+
+        // Act
+        let filter = params.filter("GBP", Locale::find("en-GB"));
+
+        // Assert
+        assert_eq!(filter, Ok(Filter {
+            text: Some("\"tesco\"*".to_string()),
+            from: NaiveDate::from_ymd_opt(2024, 1, 1),
+            min_amount: Some(1000),
+            max_amount: Some(125050),
+            account_id: Some(3),
+            cleared: Some(false),
+            flag: Some("red".to_string()),
+            ..Default::default()
+        }));
+        assert!(SearchParams { to: "yesterday".to_string(), ..Default::default() }.filter("GBP", Locale::find("en-GB")).is_err());
+        assert!(SearchParams { flag: "pink".to_string(), ..Default::default() }.filter("GBP", Locale::find("en-GB")).is_err());
+    }
+
+    #[test]
+    fn test_query_string() {
+        let params = SearchParams { q: "fish & chips".to_string(), flag: "red".to_string(), ..Default::default() };
+
+        assert_eq!(params.query_string(), "q=fish+%26+chips&from=&to=&min_amount=&max_amount=&category_id=&account_id=&cleared=&flag=red&tag=&sort=&order=");
+        assert!(!params.is_empty());
+        assert!(SearchParams { account_id: "1".to_string(), ..Default::default() }.is_empty(), "The account is where the search is, not part of it");
+        assert!(SearchParams { sort: "payee".to_string(), ..Default::default() }.is_empty());
+    }
+
+    #[test]
+    fn test_order() {
+        let params = SearchParams::default();
+
+        assert_eq!(params.order(), Order { sort: Sort::Date, descending: true }, "Newest first");
+        assert_eq!(params.sorted_by(Sort::Date).order(), Order { sort: Sort::Date, descending: false });
+        assert_eq!(params.sorted_by(Sort::Payee).order(), Order { sort: Sort::Payee, descending: false });
+        assert_eq!(params.sorted_by(Sort::Payee).sorted_by(Sort::Payee).order(), Order { sort: Sort::Payee, descending: true });
+        assert_eq!(SearchParams { sort: "nonsense".to_string(), order: "asc".to_string(), ..Default::default() }.order(), Order { sort: Sort::Date, descending: false });
+    }
+}
@@ -1,6 +1,7 @@
+use chrono::{Months, NaiveDate};
 use maud::{html, Markup, PreEscaped, DOCTYPE};
 
-use crate::{db::{Account, Transaction}, helpers::get_total_as_formatted_string};
+use crate::{archive::Archive, audit::{AuditParams, Entry, ENTRY_LIMIT}, budgets::{Budget, Invitation, Member, Role}, ledger::Dialect, currency::{self, ExchangeRate}, db::{Account, Attachment, Category, CategoryBalance, Payee, RegisterRow, RegisterTotal, Tag, Transaction, REGISTER_PAGE_SIZE, Webhook, WebhookDelivery}, helpers::{format_decimal, format_money, Locale, LOCALES}, history::ChangeSet, import::{self, csv::{CsvMapping, DATE_FORMATS}, ynab::{self, YnabFiles}, ClosingBalance, Format, ImportedTransaction, ParseError, Saved}, matching::Match, reports::{self, Breakdown, CashFlow, IncomeExpense, NetWorth, Period, ReportParams, Spending}, rules::{self, Action, Condition, Field, Operator, Rule, Subject}, search::{SearchParams, Sort}, webhooks::Event};
 
 pub fn simple_error(message: &str) -> Markup {
     html! {
@@ -36,23 +37,344 @@ fn account(id: i32, name: &str, total: &str) -> Markup {
     }
 }
 
-pub fn transactions_list(transactions: Vec<Transaction>) -> Markup {
+fn reconcile_target(account: &Account, cleared: i64, locale: &Locale) -> Markup {
     html! {
-        div class="block w-full grid grid grid-cols-6" {
-            div { "Id" }
+        @if let (Some(balance), Some(date)) = (account.reconcile_balance, account.reconcile_date) {
+            div class="text-sm text-gray-400" {
+                "Bank balance on " (date.format("%Y-%m-%d")) ": " (format_money(balance, &account.currency, locale))
+                " · Cleared balance: " (format_money(cleared, &account.currency, locale))
+                @if cleared != balance {
+                    " · Difference: " (format_money(balance - cleared, &account.currency, locale))
+                }
+            }
+        }
+    }
+}
+
+fn signed_amount(transaction: &Transaction, currency: &str, locale: &Locale) -> String {
+    format_money(transaction.inflow - transaction.outflow, currency, locale)
+}
+
+fn proposed_matches(account: &Account, matches: &[Match], locale: &Locale) -> Markup {
+    html! {
+        @if !matches.is_empty() {
+            div class="p-2 space-y-2" {
+                h3 class="tracking-wide uppercase text-sm" { "Possible duplicates" }
+                p class="text-sm text-gray-400" { "These imported transactions look like ones you entered by hand. Matching keeps your payee and memo and marks the transaction cleared." }
+                @for m in matches {
+                    div class="rounded bg-gray-800 p-2 flex justify-between items-center" {
+                        div class="grid grid-cols-2 gap-x-4 text-sm" {
+                            p class="text-gray-400" { "Entered" }
+                            p class="text-gray-400" { "Imported" }
+                            p { (m.manual.date.format("%Y-%m-%d")) " " (m.manual.payee.clone().unwrap_or_else(|| m.manual.memo.clone())) }
+                            p { (m.imported.date.format("%Y-%m-%d")) " " (m.imported.payee.clone().unwrap_or_else(|| m.imported.memo.clone())) }
+                            p { (signed_amount(&m.manual, &account.currency, locale)) }
+                            p { (signed_amount(&m.imported, &account.currency, locale)) }
+                        }
+                        div class="space-x-2" {
+                            @let values = format!(r#"{{"imported_id": {}, "manual_id": {}}}"#, m.imported.id, m.manual.id);
+                            button hx-post=(format!("/accounts/{}/matches/approve", account.id)) hx-vals=(values) hx-target="#content" hx-swap="innerHTML" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Match" }
+                            button hx-post=(format!("/accounts/{}/matches/dismiss", account.id)) hx-vals=(values) hx-target="#content" hx-swap="innerHTML" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Not a match" }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Adds a transaction to the account, or to the one picked when the register shows every account.
+fn new_transaction_form(account: Option<&Account>, accounts: &[Account], payees: &[Payee]) -> Markup {
+    let action = account.map_or_else(|| "/transactions".to_string(), |a| format!("/accounts/{}/transactions", a.id));
+    html! {
+        form hx-post=(action) hx-target="#content" hx-swap="innerHTML" class="p-2 flex space-x-2" {
+            @if account.is_none() {
+                select class="rounded bg-gray-800 border border-gray-700 py-1 px-2" name="account_id" required {
+                    @for a in accounts {
+                        option value=(a.id) { (a.name) }
+                    }
+                }
+            }
+            input class="rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="date" required {}
+            input class="rounded bg-gray-800 border border-gray-700 py-1 px-2" type="text" name="payee" placeholder="Payee" list="payees" autocomplete="off" {}
+            datalist id="payees" {
+                @for payee in payees {
+                    option value=(payee.name) {}
+                }
+            }
+            input class="rounded bg-gray-800 border border-gray-700 py-1 px-2" type="text" name="memo" placeholder="Memo" {}
+            input class="rounded bg-gray-800 border border-gray-700 py-1 px-2 w-24" type="text" name="outflow" placeholder="Outflow" {}
+            input class="rounded bg-gray-800 border border-gray-700 py-1 px-2 w-24" type="text" name="inflow" placeholder="Inflow" {}
+            button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Add" }
+        }
+    }
+}
+
+/// Where the register for the account is, or for every account.
+fn register_path(account: Option<&Account>) -> String {
+    account.map_or_else(|| "/accounts".to_string(), |a| format!("/accounts/{}", a.id))
+}
+
+/// Where the register's rows are loaded from as it scrolls or changes.
+fn register_rows_path(account: Option<&Account>) -> String {
+    account.map_or_else(|| "/transactions".to_string(), |a| format!("/accounts/{}/transactions", a.id))
+}
+
+/// What a search can be narrowed down to.
+pub struct SearchOptions<'a> {
+    pub accounts: &'a [Account],
+    pub categories: &'a [Category],
+    pub tags: &'a [String],
+}
+
+/// The search bar and filters, narrowing down the register of the account or of every account.
+/// The address bar is updated so the search can be shared.
+fn search_form(params: &SearchParams, account: Option<&Account>, options: &SearchOptions, message: Option<&str>) -> Markup {
+    let action = register_path(account);
+    let input = "rounded bg-gray-800 border border-gray-700 py-1 px-2";
+    html! {
+        form hx-get=(action) hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="p-2 space-y-2 text-sm" {
+            input type="hidden" name="sort" value=(params.sort) {}
+            input type="hidden" name="order" value=(params.order) {}
+            div class="flex space-x-2" {
+                input class=(format!("{} flex-grow", input)) type="search" name="q" value=(params.q) placeholder="Search payees, memos and notes" {}
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Search" }
+                @if !params.is_empty() {
+                    a href=(format!("{}?{}", action, params.query_string())) class="py-1 text-blue-400" title="Open or share these results" { "Link" }
+                    a hx-get=(action) hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="py-1 text-blue-400 cursor-pointer" { "Clear" }
+                }
+            }
+            div class="flex flex-wrap gap-2" {
+                label { "From " input class=(input) type="date" name="from" value=(params.from) {} }
+                label { "To " input class=(input) type="date" name="to" value=(params.to) {} }
+                input class=(format!("{} w-24", input)) type="text" name="min_amount" value=(params.min_amount) placeholder="Min amount" {}
+                input class=(format!("{} w-24", input)) type="text" name="max_amount" value=(params.max_amount) placeholder="Max amount" {}
+                select class=(input) name="category_id" {
+                    option value="" { "Any category" }
+                    @for category in options.categories {
+                        option value=(category.id) selected[params.category_id == category.id.to_string()] { (category.group_name) ": " (category.name) }
+                    }
+                }
+                @if account.is_none() {
+                    select class=(input) name="account_id" {
+                        option value="" { "Any account" }
+                        @for a in options.accounts {
+                            option value=(a.id) selected[params.account_id == a.id.to_string()] { (a.name) }
+                        }
+                    }
+                }
+                select class=(input) name="cleared" {
+                    option value="" { "Cleared or not" }
+                    option value="yes" selected[params.cleared == "yes"] { "Cleared" }
+                    option value="no" selected[params.cleared == "no"] { "Uncleared" }
+                }
+                select class=(input) name="flag" {
+                    option value="" { "Any flag" }
+                    @for f in rules::FLAGS {
+                        option value=(f) selected[f == params.flag] { (f) }
+                    }
+                }
+                select class=(input) name="tag" {
+                    option value="" { "Any tag" }
+                    @for t in options.tags {
+                        option value=(t) selected[*t == params.tag] { (t) }
+                    }
+                }
+            }
+            @if let Some(message) = message {
+                p class="text-red-400" { (message) }
+            }
+        }
+    }
+}
+
+/// A small preview of each of a transaction's attachments, linking to the file.
+fn attachment_thumbnails(attachments: &[Attachment], transaction_id: i32) -> Markup {
+    html! {
+        @for attachment in attachments.iter().filter(|a| a.transaction_id == i64::from(transaction_id)) {
+            a href=(format!("/attachments/{}", attachment.id)) target="_blank" title=(attachment.filename) class="inline-block ml-1 align-middle" {
+                @if attachment.is_image() {
+                    img src=(format!("/attachments/{}", attachment.id)) alt=(attachment.filename) class="h-6 w-6 object-cover rounded" loading="lazy";
+                } @else {
+                    span class="rounded bg-gray-700 text-xs px-1" { "PDF" }
+                }
+            }
+        }
+    }
+}
+
+/// Everything the register shows, for one account or for every account.
+pub struct Register<'a> {
+    pub account: Option<&'a Account>,
+    /// The first page of the ones that match `search`.
+    pub rows: Vec<RegisterRow>,
+    /// What every transaction that matches `search` adds up to.
+    pub totals: &'a [RegisterTotal],
+    pub matches: &'a [Match],
+    /// For suggestions when entering a transaction.
+    pub payees: &'a [Payee],
+    pub attachments: &'a [Attachment],
+    /// What the account's cleared transactions add up to.
+    pub cleared: i64,
+    pub search: &'a SearchParams,
+    pub options: SearchOptions<'a>,
+    /// Why the search could not be run.
+    pub message: Option<&'a str>,
+}
+
+/// A column heading that sorts the register by it, with an arrow when it already does.
+fn sort_heading(account: Option<&Account>, search: &SearchParams, sort: Sort, label: &str) -> Markup {
+    let order = search.order();
+    html! {
+        div {
+            a hx-get=(format!("{}?{}", register_path(account), search.sorted_by(sort).query_string())) hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="cursor-pointer" {
+                (label)
+                @if order.sort == sort {
+                    @if order.descending { " ↓" } @else { " ↑" }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of the register. Across every account there is an account column instead of a balance.
+/// A full page ends with a placeholder that loads the next page once it scrolls into view.
+pub fn register_rows(account: Option<&Account>, accounts: &[Account], rows: &[RegisterRow], attachments: &[Attachment], search: &SearchParams, locale: &Locale) -> Markup {
+    html! {
+        @for row in rows {
+            @let transaction = &row.transaction;
+            @let row_account = account.or_else(|| accounts.iter().find(|a| a.id == transaction.account_id));
+            @let currency = row_account.map_or("", |a| a.currency.as_str());
+            div {
+                input type="checkbox" name="ids" value=(transaction.id) form="bulk-edit" class="mr-1" {}
+                a hx-get=(format!("/transactions/{}", transaction.id)) hx-target="#transaction-details" hx-swap="innerHTML" class="text-blue-400 cursor-pointer" title="Flag, tags and notes" { (transaction.date.format("%Y-%m-%d")) }
+            }
+            @if account.is_none() {
+                div { (row_account.map(|a| a.name.as_str()).unwrap_or_default()) }
+            }
+            div { (flag(transaction.flag.as_deref())) (transaction.payee.clone().unwrap_or_default()) }
+            div { (transaction.category.clone().unwrap_or_default()) }
+            div {
+                (transaction.memo)
+                @for tag in transaction.tags() {
+                    span class="rounded bg-gray-700 text-xs px-1 ml-1" { (tag) }
+                }
+                (attachment_thumbnails(attachments, transaction.id))
+                @if let Some(notes) = &transaction.notes {
+                    p class="text-xs text-gray-400 whitespace-pre-line" { (notes) }
+                }
+            }
+            div { @if transaction.cleared { "✓" } }
+            div { (format_money(transaction.outflow, currency, locale)) }
+            div { (format_money(transaction.inflow, currency, locale)) }
+            @if account.is_some() {
+                div { (format_money(row.balance, currency, locale)) }
+            }
+        }
+        @if let Some(last) = rows.last().filter(|_| rows.len() as i64 == REGISTER_PAGE_SIZE) {
+            div hx-get=(format!("{}?{}&after={}", register_rows_path(account), search.query_string(), last.transaction.id)) hx-trigger="revealed" hx-swap="outerHTML" class="col-span-8 text-sm text-gray-400" { "Loading more…" }
+        }
+    }
+}
+
+/// How many transactions match and what they add up to, in each currency.
+fn register_totals(search: &SearchParams, totals: &[RegisterTotal], locale: &Locale) -> Markup {
+    let count: i64 = totals.iter().map(|t| t.transactions).sum();
+    html! {
+        div class="p-2 text-sm text-gray-400" {
+            p { (count) @if search.is_empty() { " transactions" } @else { " transactions found" } }
+            @for total in totals {
+                p {
+                    (total.currency) ": in " (format_money(total.inflow, &total.currency, locale))
+                    " · out " (format_money(total.outflow, &total.currency, locale))
+                    " · net " (format_money(total.inflow - total.outflow, &total.currency, locale))
+                }
+            }
+        }
+    }
+}
+
+/// Changes every ticked transaction in the register at once.
+fn bulk_edit_form(account: Option<&Account>, options: &SearchOptions) -> Markup {
+    let input = "rounded bg-gray-800 border border-gray-700 py-1 px-2";
+    let button = "rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2";
+    html! {
+        form id="bulk-edit" hx-post="/transactions/bulk" hx-target="#bulk-message" hx-swap="innerHTML" class="p-2 flex flex-wrap gap-2 text-sm items-center" {
+            span class="text-gray-400" { "With the ticked transactions:" }
+            select class=(input) name="category_id" {
+                @for category in options.categories {
+                    option value=(category.id) { (category.group_name) ": " (category.name) }
+                }
+            }
+            button type="submit" name="action" value="categorise" class=(button) { "Categorise" }
+            input class=(format!("{} w-32", input)) type="text" name="payee" placeholder="Payee" list="payees" autocomplete="off" {}
+            button type="submit" name="action" value="payee" class=(button) { "Set payee" }
+            button type="submit" name="action" value="cleared" class=(button) { "Clear" }
+            button type="submit" name="action" value="uncleared" class=(button) { "Unclear" }
+            select class=(input) name="flag" {
+                option value="" { "No flag" }
+                @for f in rules::FLAGS {
+                    option value=(f) { (f) }
+                }
+            }
+            button type="submit" name="action" value="flag" class=(button) { "Flag" }
+            select class=(input) name="account_id" {
+                @for a in options.accounts.iter().filter(|a| Some(a.id) != account.map(|account| account.id)) {
+                    option value=(a.id) { (a.name) }
+                }
+            }
+            button type="submit" name="action" value="move" class=(button) { "Move" }
+            button type="button" hx-post="/transactions/bulk" hx-vals=r#"{"action": "delete"}"# hx-confirm="Delete the ticked transactions?" class=(button) { "Delete" }
+        }
+    }
+}
+
+/// The register, which reloads itself with the same search whenever transactions change. The
+/// details and bulk edit messages are outside it so they stay put.
+pub fn transactions_list(register: Register, locale: &Locale) -> Markup {
+    let refresh = format!("{}?{}", register_path(register.account), register.search.query_string());
+    html! {
+        div id="transaction-details" {}
+        p id="bulk-message" class="px-2 text-sm" {}
+        div id="register" hx-get=(refresh) hx-trigger="transactionsUpdated from:body" hx-select="#register" hx-swap="outerHTML" hx-disinherit="*" {
+            (register_contents(register, locale))
+        }
+    }
+}
+
+fn register_contents(register: Register, locale: &Locale) -> Markup {
+    let Register { account, rows, totals, matches, payees, attachments, cleared, search, options, message } = register;
+    html! {
+        div class="flex justify-between items-center p-2" {
+            @if let Some(account) = account {
+                (reconcile_target(account, cleared, locale))
+                button hx-get=(format!("/accounts/{}/import", account.id)) hx-target="#content" hx-swap="innerHTML" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Import" }
+            } @else {
+                h2 class="text-xl" { "All Accounts" }
+            }
+        }
+        @if let Some(account) = account {
+            (proposed_matches(account, matches, locale))
+        }
+        (new_transaction_form(account, options.accounts, payees))
+        (search_form(search, account, &options, message))
+        (register_totals(search, totals, locale))
+        (bulk_edit_form(account, &options))
+        div class="block w-full grid grid grid-cols-8" {
+            (sort_heading(account, search, Sort::Date, "Date"))
+            @if account.is_none() {
+                div { "Account" }
+            }
+            (sort_heading(account, search, Sort::Payee, "Payee"))
+            (sort_heading(account, search, Sort::Category, "Category"))
             div { "Memo" }
-            div { "Date" }
             div { "Cleared" }
-            div { "Inflow" }
-            div { "Outflow" }
-            @for transaction in transactions {
-                div { (transaction.id) }
-                div { (transaction.memo) }
-                div { (transaction.date) }
-                div { (transaction.cleared) }
-                div { (get_total_as_formatted_string(transaction.inflow)) }
-                div { (get_total_as_formatted_string(transaction.outflow)) }
+            (sort_heading(account, search, Sort::Amount, "Outflow"))
+            (sort_heading(account, search, Sort::Amount, "Inflow"))
+            @if account.is_some() {
+                div { "Balance" }
             }
+            (register_rows(account, options.accounts, &rows, attachments, search, locale))
         }
     }
 }
@@ -66,13 +388,29 @@ fn no_account() -> Markup {
     }
 }
 
-fn create_new_account() -> Markup {
+/// Options for every common currency, with `selected` chosen.
+fn currency_options(selected: &str) -> Markup {
+    html! {
+        @if !currency::COMMON.contains(&selected) {
+            option value=(selected) selected { (selected) }
+        }
+        @for code in currency::COMMON {
+            option value=(code) selected[code == selected] { (code) }
+        }
+    }
+}
+
+fn create_new_account(currency: &str) -> Markup {
     html! {
         button onclick="openAccountForm()" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 text-left" { "Add Account" }
         form id="new-account-form" class="hidden space-y-2" hx-post="/account/create" {
             input class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" type="text" name="name" placeholder="Nickname" {}
             select class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" type="text" name="type" placeholder="Type" {
                 option value="checking" { "Checking" }
+                option value="tracking" { "Tracking (off budget)" }
+            }
+            select class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" name="currency" {
+                (currency_options(currency))
             }
             input class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" type="text" name="starting_balance" placeholder="Starting balance" {}
             button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 text-left" { "Save" }
@@ -88,9 +426,9 @@ fn create_new_account() -> Markup {
     }
 }
 
-pub fn accounts_partial(accounts: Vec<Account>, budget_total: String) -> Markup {
+pub fn accounts_partial(accounts: Vec<Account>, budget_total: String, currency: &str, locale: &Locale) -> Markup {
     html! {
-        div hx-trigger="accountsUpdated" hx-get="/api/accounts" hx-swap="outerHTML" class="w-full space-y-2" {
+        div hx-trigger="accountsUpdated from:body, transactionsUpdated from:body" hx-get="/api/accounts" hx-swap="outerHTML" class="w-full space-y-2" {
             @if accounts.is_empty() {
                 (no_account())
             } @else {
@@ -98,33 +436,1470 @@ pub fn accounts_partial(accounts: Vec<Account>, budget_total: String) -> Markup
                     p class="tracking-wide uppercase" { "Budget" }
                     p class="tracking-wide uppercase text-sm" { (budget_total) }
                 }
-                @for acc in accounts {
-                    (account(acc.id, &acc.name, &acc.get_total_as_formatted_string()))
+                @for acc in accounts.iter().filter(|a| a.on_budget) {
+                    (account(acc.id, &acc.name, &acc.get_total_as_formatted_string(locale)))
+                }
+                @if accounts.iter().any(|a| !a.on_budget) {
+                    p class="tracking-wide uppercase py-1 px-3" { "Tracking" }
+                    @for acc in accounts.iter().filter(|a| !a.on_budget) {
+                        (account(acc.id, &acc.name, &acc.get_total_as_formatted_string(locale)))
+                    }
+                }
+            }
+            (create_new_account(currency))
+        }
+    }
+}
+
+/// Switches budget as soon as another is picked. Archived budgets are left out, other than the
+/// one being worked in when every budget is archived. Someone who isn't in any budget is asked to
+/// create one instead.
+fn budget_picker(budgets: &[Budget], current: Option<&Budget>) -> Markup {
+    let is_current = |budget: &Budget| current.is_some_and(|c| c.id == budget.id);
+    html! {
+        @if current.is_none() {
+            a class="w-full rounded block py-1 px-3 text-gray-400" hx-get="/budgets" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "Create a budget" }
+        } @else {
+            form hx-post="/budgets/switch" hx-trigger="change" class="py-1 px-3" {
+                select name="budget_id" class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                    @for budget in budgets.iter().filter(|b| !b.archived || is_current(b)) {
+                        option value=(budget.id) selected[is_current(budget)] { (budget.name) }
+                    }
                 }
             }
-            (create_new_account())
         }
     }
 }
 
-pub fn home(accounts: Vec<Account>, budget_total: String) -> Markup {
+/// The whole app, showing `content` if there is some, such as when a shared search is opened.
+/// Without a budget there are no accounts to show alongside it.
+pub fn home(budgets: &[Budget], budget: Option<&Budget>, accounts: Vec<Account>, budget_total: String, currency: &str, locale: &Locale, content: Option<Markup>) -> Markup {
     let title: &str = "Home";
     html! {
-        (header(&title))
+        (header(title))
         body class="w-full min-h-screen" {
             main class="grid grid-cols-5 bg-gray-950" {
                 nav class="col-span-1 bg-gray-950 text-white h-screen flex-col items-center text-left justify-center p-2" {
+                    (budget_picker(budgets, budget))
                     a class="w-full rounded block py-1 px-3 bg-blue-800" href="/" { "Home" }
-                    a class="w-full rounded block py-1 px-3" href="/" { "All Accounts" }
-                    (accounts_partial(accounts, budget_total))
+                    a class="w-full rounded block py-1 px-3" hx-get="/accounts" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "All Accounts" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/webhooks" hx-target="#content" hx-swap="innerHTML" { "Webhooks" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/import/ynab" hx-target="#content" hx-swap="innerHTML" { "Import from YNAB" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/backup" hx-target="#content" hx-swap="innerHTML" { "Backup" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/currencies" hx-target="#content" hx-swap="innerHTML" { "Currencies" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/categories" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "Categories" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/payees" hx-target="#content" hx-swap="innerHTML" { "Payees" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/rules" hx-target="#content" hx-swap="innerHTML" { "Rules" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/tags" hx-target="#content" hx-swap="innerHTML" { "Tags" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/reports" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "Reports" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/history" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "History" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/audit" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "Audit log" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/budget/members" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "Members" }
+                    a class="w-full rounded block py-1 px-3" hx-get="/budgets" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" { "Budgets" }
+                    div class="flex gap-2 py-1 px-3" {
+                        button class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 text-sm" hx-post="/undo" hx-target="#history-message" { "Undo" }
+                        button class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 text-sm" hx-post="/redo" hx-target="#history-message" { "Redo" }
+                    }
+                    p id="history-message" class="px-3 text-sm text-gray-400" {}
+                    @if budget.is_some() {
+                        (accounts_partial(accounts, budget_total, currency, locale))
+                    }
                 }
                 section id="content" class="col-span-4 bg-gray-900 text-white" {
-                    p { "Content" }
+                    @match content {
+                        Some(content) => (content),
+                        None => p { "Content" },
+                    }
+                }
+            }
+        }
+    }
+
+}
+
+pub fn import_upload(account: &Account) -> Markup {
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import into " (account.name) }
+            form hx-post=(format!("/accounts/{}/import", account.id)) hx-encoding="multipart/form-data" hx-target="#import" hx-swap="outerHTML" class="space-y-2" {
+                input type="file" name="file" accept=".csv,.ofx,.qfx,.qif,.xml,.sta,.mt940" class="block";
+                p class="text-sm text-gray-400" { "CSV, OFX, QFX, QIF, camt.053 and MT940 files are supported." }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Upload" }
+            }
+        }
+    }
+}
+
+fn column_select(name: &str, label: &str, headers: &[String], selected: Option<usize>, optional: bool) -> Markup {
+    html! {
+        label class="block text-sm" {
+            (label)
+            select name=(name) class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                @if optional {
+                    option value="" selected[selected.is_none()] { "None" }
+                }
+                @for (index, header) in headers.iter().enumerate() {
+                    option value=(index) selected[selected == Some(index)] { (header) }
+                }
+            }
+        }
+    }
+}
+
+fn import_errors(errors: &[ParseError]) -> Markup {
+    html! {
+        @if !errors.is_empty() {
+            div class="rounded bg-red-950 p-2 text-sm" {
+                p { (errors.len()) " rows could not be read and will be skipped:" }
+                ul {
+                    @for error in errors {
+                        li { "Line " (error.line) ": " (error.message) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn import_preview(transactions: &[ImportedTransaction], currency: &str, locale: &Locale) -> Markup {
+    html! {
+        div class="block w-full grid grid-cols-5 text-sm" {
+            div { "Date" }
+            div { "Payee" }
+            div { "Memo" }
+            div { "Inflow" }
+            div { "Outflow" }
+            @for transaction in transactions.iter().take(20) {
+                div { (transaction.date.format("%Y-%m-%d")) }
+                div { (transaction.payee.as_deref().unwrap_or_default()) }
+                div { (transaction.memo.as_deref().unwrap_or_default()) }
+                div { (format_money(transaction.amount.max(0), currency, locale)) }
+                div { (format_money((-transaction.amount).max(0), currency, locale)) }
+            }
+        }
+        @if transactions.len() > 20 {
+            p class="text-sm text-gray-400" { "And " (transactions.len() - 20) " more." }
+        }
+    }
+}
+
+pub fn import_mapping(account: &Account, content: &str, mapping: &CsvMapping, locale: &Locale) -> Markup {
+    let headers = import::csv::headers(content, mapping.delimiter, mapping.has_header);
+    let (transactions, errors) = import::csv::parse(content, mapping, currency::exponent(&account.currency));
+    let delimiter = match mapping.delimiter {
+        '\t' => "tab".to_string(),
+        d => d.to_string(),
+    };
+
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import into " (account.name) }
+            form hx-post=(format!("/accounts/{}/import/preview", account.id)) hx-trigger="change" hx-target="#import" hx-swap="outerHTML" class="grid grid-cols-4 gap-2" {
+                textarea name="content" class="hidden" { (content) }
+                label class="block text-sm" {
+                    "Delimiter"
+                    select name="delimiter" class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                        option value="," selected[delimiter == ","] { "Comma" }
+                        option value=";" selected[delimiter == ";"] { "Semicolon" }
+                        option value="tab" selected[delimiter == "tab"] { "Tab" }
+                    }
+                }
+                label class="block text-sm" {
+                    input type="checkbox" name="has_header" checked[mapping.has_header] class="mr-1";
+                    "First row is a header"
+                }
+                label class="block text-sm" {
+                    "Date format"
+                    select name="date_format" class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                        @for (format, example) in DATE_FORMATS {
+                            option value=(format) selected[mapping.date_format == format] { (example) }
+                        }
+                    }
+                }
+                label class="block text-sm" {
+                    "Decimal separator"
+                    select name="decimal_separator" class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                        option value="." selected[mapping.decimal_separator == '.'] { "1,234.56" }
+                        option value="," selected[mapping.decimal_separator == ','] { "1.234,56" }
+                    }
+                }
+                (column_select("date_column", "Date", &headers, Some(mapping.date_column), false))
+                (column_select("payee_column", "Payee", &headers, mapping.payee_column, true))
+                (column_select("memo_column", "Memo", &headers, mapping.memo_column, true))
+                (column_select("amount_column", "Amount", &headers, mapping.amount_column, true))
+                (column_select("inflow_column", "Inflow", &headers, mapping.inflow_column, true))
+                (column_select("outflow_column", "Outflow", &headers, mapping.outflow_column, true))
+                button type="button" hx-post=(format!("/accounts/{}/import/confirm", account.id)) hx-target="#import" hx-swap="outerHTML" class="col-span-4 rounded bg-blue-800 hover:bg-blue-700 transition-colors py-1 px-2" {
+                    "Import " (transactions.len()) " transactions"
+                }
+            }
+            (import_errors(&errors))
+            (import_preview(&transactions, &account.currency, locale))
+        }
+    }
+}
+
+pub fn import_statement(account: &Account, format: Format, content: &str, day_first: bool, locale: &Locale) -> Markup {
+    let decimals = currency::exponent(&account.currency);
+    let (transactions, errors) = format.parse(content, day_first, decimals);
+    let closing_balance = format.closing_balance(content, decimals);
+
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import " (format.as_str().to_uppercase()) " into " (account.name) }
+            form hx-post=(format!("/accounts/{}/import/statement/preview", account.id)) hx-trigger="change" hx-target="#import" hx-swap="outerHTML" class="space-y-2" {
+                textarea name="content" class="hidden" { (content) }
+                input type="hidden" name="format" value=(format.as_str());
+                @if format == Format::Qif {
+                    label class="block text-sm" {
+                        input type="checkbox" name="day_first" checked[day_first] class="mr-1";
+                        "Dates are written day first (31/01/2024)"
+                    }
                 }
+                @if let Some(balance) = closing_balance {
+                    p class="text-sm text-gray-400" {
+                        "Closing balance of " (format_money(balance.amount, &account.currency, locale)) " on " (balance.date.format("%Y-%m-%d"))
+                        " will be used as the reconciliation target."
+                    }
+                }
+                button type="button" hx-post=(format!("/accounts/{}/import/statement/confirm", account.id)) hx-target="#import" hx-swap="outerHTML" class="w-full rounded bg-blue-800 hover:bg-blue-700 transition-colors py-1 px-2" {
+                    "Import " (transactions.len()) " transactions"
+                }
+            }
+            (import_errors(&errors))
+            (import_preview(&transactions, &account.currency, locale))
+        }
+    }
+}
+
+pub fn import_result(account: &Account, saved: &Saved, errors: &[ParseError], closing_balance: Option<ClosingBalance>, locale: &Locale) -> Markup {
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import into " (account.name) }
+            p { "Imported " (saved.imported) " transactions." }
+            @if saved.duplicates > 0 {
+                p class="text-sm text-gray-400" { "Skipped " (saved.duplicates) " transactions that were already imported." }
+            }
+            @if let Some(balance) = closing_balance {
+                p class="text-sm text-gray-400" {
+                    "Reconciliation target set to " (format_money(balance.amount, &account.currency, locale)) " on " (balance.date.format("%Y-%m-%d")) "."
+                }
+            }
+            (import_errors(errors))
+            a hx-get=(format!("/accounts/{}", account.id)) hx-target="#content" hx-swap="innerHTML" class="text-blue-400" { "Back to account" }
+        }
+    }
+}
+
+pub fn ynab_upload() -> Markup {
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import from YNAB" }
+            form hx-post="/import/ynab" hx-encoding="multipart/form-data" hx-target="#import" hx-swap="outerHTML" class="space-y-2" {
+                input type="file" name="file" multiple accept=".csv,.tsv,.yfull" class="block";
+                p class="text-sm text-gray-400" {
+                    "Choose the Register and Budget files from a YNAB CSV export, or the Budget.yfull file from a YNAB 4 budget folder. "
+                    "Accounts and categories are matched to yours by name."
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Upload" }
+            }
+        }
+    }
+}
+
+fn ynab_summary(summary: &ynab::Summary) -> Markup {
+    html! {
+        ul class="text-sm" {
+            li { (summary.accounts) " accounts" }
+            li { (summary.categories) " categories in " (summary.category_groups) " groups" }
+            li { "Assigned amounts for " (summary.months) " months" }
+            li { (summary.payees) " payees" }
+            li { (summary.transactions) " transactions, including " (summary.transfers) " transfers and " (summary.splits) " split transactions" }
+        }
+    }
+}
+
+/// A dry run of a YNAB import: what would be created, without writing anything.
+pub fn ynab_preview(files: &YnabFiles, decimals: u32) -> Markup {
+    let (budget, errors) = files.parse(decimals);
+
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import from YNAB" }
+            p { "This will create or add to:" }
+            (ynab_summary(&budget.summary()))
+            form hx-post="/import/ynab/confirm" hx-target="#import" hx-swap="outerHTML" class="space-y-2" {
+                @if let Some(register) = &files.register {
+                    textarea name="register" class="hidden" { (register) }
+                }
+                @if let Some(budget) = &files.budget {
+                    textarea name="budget" class="hidden" { (budget) }
+                }
+                @if let Some(yfull) = &files.yfull {
+                    textarea name="yfull" class="hidden" { (yfull) }
+                }
+                button type="submit" class="w-full rounded bg-blue-800 hover:bg-blue-700 transition-colors py-1 px-2" { "Import" }
+            }
+            (import_errors(&errors))
+        }
+    }
+}
+
+pub fn ynab_result(summary: &ynab::Summary, saved: &Saved, errors: &[ParseError]) -> Markup {
+    html! {
+        div id="import" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Import from YNAB" }
+            p { "Imported " (saved.imported) " transactions." }
+            @if saved.duplicates > 0 {
+                p class="text-sm text-gray-400" { "Skipped " (saved.duplicates) " transactions that were already imported." }
+            }
+            (ynab_summary(summary))
+            (import_errors(errors))
+        }
+    }
+}
+
+pub fn backup() -> Markup {
+    html! {
+        div id="backup" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Backup" }
+            div class="space-y-2" {
+                p class="text-sm" { "Download everything in your budget as a single file: accounts, transactions, categories, assigned amounts and settings." }
+                a href="/backup/export" download class="inline-block rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Export" }
+            }
+            div class="space-y-2" {
+                p class="text-sm" { "Or export your transactions as a plain-text accounting journal. Accounts become assets and categories become expenses." }
+                @for dialect in Dialect::ALL {
+                    a href=(format!("/backup/export/{}", dialect.as_str())) download class="inline-block rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 mr-2" { (dialect.name()) }
+                }
+            }
+            form hx-post="/backup/restore" hx-encoding="multipart/form-data" hx-target="#backup" hx-swap="outerHTML" class="space-y-2" {
+                p class="text-sm" { "Restore an export into this budget. This only works while the budget has no accounts or categories." }
+                input type="file" name="file" accept=".json" class="block";
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Restore" }
             }
         }
     }
+}
+
+pub fn restored(archive: &Archive) -> Markup {
+    html! {
+        div id="backup" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Backup" }
+            p {
+                "Restored " (archive.accounts.len()) " accounts, " (archive.categories.len()) " categories and "
+                (archive.transactions.len()) " transactions exported on " (archive.exported_at.format("%Y-%m-%d")) "."
+            }
+        }
+    }
+}
 
+pub fn currencies(budget_currency: &str, locale: &Locale, rates: &[ExchangeRate], message: Option<&str>) -> Markup {
+    html! {
+        div id="currencies" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Currencies" }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            form hx-post="/currencies" hx-target="#currencies" hx-swap="outerHTML" class="space-y-2" {
+                p class="text-sm" { "Budget totals are shown in this currency. Accounts in other currencies are converted at the latest exchange rate." }
+                select class="rounded bg-gray-800 border border-gray-700 py-1 px-2" name="currency" {
+                    (currency_options(budget_currency))
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 ml-2" { "Save" }
+            }
+            form hx-post="/locale" hx-target="#currencies" hx-swap="outerHTML" class="space-y-2" {
+                p class="text-sm" { "Amounts are shown and typed the way your language writes them, for example " (format_money(123456, budget_currency, locale)) "." }
+                select class="rounded bg-gray-800 border border-gray-700 py-1 px-2" name="locale" {
+                    @for l in &LOCALES {
+                        option value=(l.code) selected[l.code == locale.code] { (l.name) }
+                    }
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 ml-2" { "Save" }
+            }
+            form hx-post="/currencies/rates" hx-target="#currencies" hx-swap="outerHTML" class="flex space-x-2" {
+                input class="rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="date" required {}
+                input class="rounded bg-gray-800 border border-gray-700 py-1 px-2 w-20" type="text" name="base" placeholder="EUR" required {}
+                input class="rounded bg-gray-800 border border-gray-700 py-1 px-2 w-24" type="text" name="rate" placeholder="Rate" required {}
+                input class="rounded bg-gray-800 border border-gray-700 py-1 px-2 w-20" type="text" name="quote" placeholder=(budget_currency) required {}
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Add rate" }
+            }
+            form hx-post="/currencies/rates/import" hx-encoding="multipart/form-data" hx-target="#currencies" hx-swap="outerHTML" class="space-y-2" {
+                p class="text-sm" { "Or import rates from the European Central Bank's XML files, or a CSV file with date, base, quote and rate columns." }
+                input type="file" name="file" accept=".xml,.csv" class="block";
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Import" }
+            }
+            @if rates.is_empty() {
+                p class="text-sm" { "No exchange rates yet." }
+            } @else {
+                div class="block w-full grid grid-cols-2 text-sm" {
+                    div { "Date" }
+                    div { "Rate" }
+                    @for rate in rates {
+                        div { (rate.date.format("%Y-%m-%d")) }
+                        div { "1 " (rate.base) " = " (rate.rate) " " (rate.quote) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn payees(payees: &[Payee], categories: &[Category], message: Option<&str>) -> Markup {
+    html! {
+        div id="payees" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Payees" }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if payees.is_empty() {
+                p class="text-sm" { "No payees yet. They are added as you enter and import transactions." }
+            } @else {
+                p class="text-sm text-gray-400" { "New transactions with no category get their payee's default category." }
+            }
+            @for payee in payees {
+                div class="rounded bg-gray-800 p-2 flex justify-between items-center space-x-2" {
+                    form hx-post=(format!("/payees/{}", payee.id)) hx-target="#payees" hx-swap="outerHTML" class="flex space-x-2" {
+                        input class="rounded bg-gray-900 border border-gray-700 py-1 px-2" type="text" name="name" value=(payee.name) required {}
+                        select class="rounded bg-gray-900 border border-gray-700 py-1 px-2" name="default_category_id" {
+                            option value="" { "No default category" }
+                            @for category in categories {
+                                option value=(category.id) selected[payee.default_category_id == Some(category.id)] { (category.group_name) ": " (category.name) }
+                            }
+                        }
+                        button type="submit" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Save" }
+                    }
+                    p class="text-sm text-gray-400" { (payee.transactions) " transactions" }
+                    @if payees.len() > 1 {
+                        form hx-post=(format!("/payees/{}/merge", payee.id)) hx-target="#payees" hx-swap="outerHTML" hx-confirm=(format!("Move every transaction from {} to the chosen payee and delete {}?", payee.name, payee.name)) class="flex space-x-2" {
+                            select class="rounded bg-gray-900 border border-gray-700 py-1 px-2" name="into_id" {
+                                @for other in payees.iter().filter(|p| p.id != payee.id) {
+                                    option value=(other.id) { (other.name) }
+                                }
+                            }
+                            button type="submit" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Merge into" }
+                        }
+                    }
+                    @if payee.transactions == 0 {
+                        button hx-post=(format!("/payees/{}/delete", payee.id)) hx-target="#payees" hx-swap="outerHTML" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Delete" }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// What is assigned to each category for a month and what is left in it, with forms to assign
+/// money and move it between categories.
+pub fn categories(balances: &[CategoryBalance], month: NaiveDate, currency: &str, locale: &Locale, message: Option<&str>) -> Markup {
+    let input = "rounded bg-gray-900 border border-gray-700 py-1 px-2";
+    let button = "rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2";
+    let link = |month: NaiveDate| format!("/categories?month={}", month.format("%Y-%m"));
+    let amount = |value: i64| if value == 0 { String::new() } else { format_decimal(value, currency) };
+    html! {
+        div id="categories" class="p-4 space-y-4" {
+            div class="flex items-center space-x-4" {
+                a hx-get=(link(month - Months::new(1))) hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="cursor-pointer" { "←" }
+                h2 class="text-xl" { (month.format("%B %Y")) }
+                a hx-get=(link(month + Months::new(1))) hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="cursor-pointer" { "→" }
+            }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if balances.is_empty() {
+                p class="text-sm" { "No categories yet. They are added when you import a budget from YNAB." }
+            } @else {
+                div class="grid grid-cols-4 gap-2 text-sm items-center" {
+                    div class="text-gray-400" { "Category" }
+                    div class="text-gray-400" { "Assigned" }
+                    div class="text-gray-400" { "Activity" }
+                    div class="text-gray-400" { "Available" }
+                    @for balance in balances {
+                        div { (balance.group_name) ": " (balance.name) }
+                        form hx-post=(format!("/categories/{}/assign", balance.id)) hx-target="#categories" hx-swap="outerHTML" {
+                            input type="hidden" name="month" value=(month.format("%Y-%m"));
+                            input class=(format!("{} w-32", input)) type="text" name="assigned" value=(amount(balance.assigned)) placeholder="0" {}
+                        }
+                        div { (format_money(balance.activity, currency, locale)) }
+                        div class=(if balance.available < 0 { "text-red-400" } else { "" }) { (format_money(balance.available, currency, locale)) }
+                    }
+                }
+                form hx-post="/categories/move" hx-target="#categories" hx-swap="outerHTML" class="flex flex-wrap gap-2 items-center text-sm" {
+                    input type="hidden" name="month" value=(month.format("%Y-%m"));
+                    span { "Move" }
+                    input class=(format!("{} w-32", input)) type="text" name="amount" placeholder="Amount" required {}
+                    span { "from" }
+                    select class=(input) name="from_id" {
+                        @for balance in balances {
+                            option value=(balance.id) { (balance.group_name) ": " (balance.name) }
+                        }
+                    }
+                    span { "to" }
+                    select class=(input) name="to_id" {
+                        @for balance in balances {
+                            option value=(balance.id) { (balance.group_name) ": " (balance.name) }
+                        }
+                    }
+                    button type="submit" class=(button) { "Move" }
+                }
+            }
+        }
+    }
+}
+
+/// A form for the parts of a transaction that are only for the user.
+pub fn transaction_details(transaction: &Transaction, attachments: &[Attachment], message: Option<&str>) -> Markup {
+    let flag = transaction.flag.as_deref().unwrap_or_default();
+    html! {
+        @if let Some(message) = message {
+            p class="text-sm px-2" { (message) }
+        }
+        form hx-post=(format!("/transactions/{}", transaction.id)) hx-target="#transaction-details" hx-swap="innerHTML" class="p-2 m-2 rounded bg-gray-800 space-y-2" {
+            p { (transaction.date.format("%Y-%m-%d")) " " (transaction.payee.as_deref().unwrap_or(&transaction.memo)) }
+            div class="flex space-x-2" {
+                select class="rounded bg-gray-900 border border-gray-700 py-1 px-2" name="flag" {
+                    option value="" { "No flag" }
+                    @for f in rules::FLAGS {
+                        option value=(f) selected[f == flag] { (f) }
+                    }
+                }
+                input class="rounded bg-gray-900 border border-gray-700 py-1 px-2 flex-1" type="text" name="tags" value=(transaction.tags().join(", ")) placeholder="Tags, separated by commas" {}
+            }
+            textarea class="w-full rounded bg-gray-900 border border-gray-700 py-1 px-2" name="notes" rows="3" placeholder="Notes" { (transaction.notes.as_deref().unwrap_or_default()) }
+            button type="submit" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Save" }
+        }
+        div class="p-2 m-2 rounded bg-gray-800 space-y-2" {
+            @for attachment in attachments {
+                div class="flex items-center space-x-2 text-sm" {
+                    a href=(format!("/attachments/{}", attachment.id)) target="_blank" class="text-blue-400" { (attachment.filename) }
+                    span class="text-gray-400" { (attachment.size / 1024) " KB" }
+                    button hx-post=(format!("/attachments/{}/delete", attachment.id)) hx-target="#transaction-details" hx-swap="innerHTML" hx-confirm="Delete this attachment?" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Delete" }
+                }
+            }
+            form hx-post=(format!("/transactions/{}/attachments", transaction.id)) hx-encoding="multipart/form-data" hx-target="#transaction-details" hx-swap="innerHTML" class="flex space-x-2 text-sm" {
+                input type="file" name="file" accept="image/jpeg,image/png,image/gif,image/webp,application/pdf" required;
+                button type="submit" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Attach receipt" }
+            }
+        }
+    }
+}
+
+/// Money in and out under each tag.
+pub fn tags(tags: &[Tag], locale: &Locale) -> Markup {
+    html! {
+        div class="p-4 space-y-4" {
+            h2 class="text-xl" { "Tags" }
+            @if tags.is_empty() {
+                p class="text-sm" { "No tagged transactions yet. Tag a transaction by clicking its id in the register." }
+            } @else {
+                div class="block w-full grid grid-cols-5 text-sm" {
+                    div { "Tag" }
+                    div { "Transactions" }
+                    div { "Inflow" }
+                    div { "Outflow" }
+                    div { "Net" }
+                    @for tag in tags {
+                        div { (tag.name) }
+                        div { (tag.transactions) }
+                        div { (format_money(tag.inflow, &tag.currency, locale)) }
+                        div { (format_money(tag.outflow, &tag.currency, locale)) }
+                        div { (format_money(tag.inflow - tag.outflow, &tag.currency, locale)) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Colours for the slices and bars of charts, reused in order when there are more.
+const CHART_COLOURS: [&str; 8] = ["#60a5fa", "#f87171", "#34d399", "#fbbf24", "#a78bfa", "#f472b6", "#2dd4bf", "#fb923c"];
+
+/// A slice of a pie chart or a bar of a bar chart. Clicking it loads `link` into `#report-lines`.
+pub struct ChartItem {
+    pub label: String,
+    pub value: i64,
+    /// Shown when hovering, such as the formatted amount.
+    pub title: String,
+    pub link: Option<String>,
+}
+
+/// A point on the edge of the pie chart's circle, `turns` of the way round from the top.
+fn pie_point(turns: f64) -> (f64, f64) {
+    let angle = turns * std::f64::consts::TAU - std::f64::consts::FRAC_PI_2;
+    (100.0 + 90.0 * angle.cos(), 100.0 + 90.0 * angle.sin())
+}
+
+/// Each item's share of the whole as a slice of a pie, drawn as an SVG so no charting library is
+/// needed. Items that aren't positive are left out.
+pub fn pie_chart(items: &[ChartItem]) -> Markup {
+    let items: Vec<&ChartItem> = items.iter().filter(|i| i.value > 0).collect();
+    let total: i64 = items.iter().map(|i| i.value).sum();
+    let shares: Vec<f64> = items.iter().map(|i| i.value as f64 / total as f64).collect();
+    html! {
+        svg viewBox="0 0 200 200" class="w-64 h-64" {
+            @for (i, item) in items.iter().enumerate() {
+                @let start: f64 = shares[..i].iter().sum();
+                @let share = shares[i];
+                @let colour = CHART_COLOURS[i % CHART_COLOURS.len()];
+                @if items.len() == 1 {
+                    circle cx="100" cy="100" r="90" fill=(colour) hx-get=[&item.link] hx-target="#report-lines" class=[item.link.as_ref().map(|_| "cursor-pointer")] {
+                        title { (item.label) ": " (item.title) }
+                    }
+                } @else {
+                    @let (x1, y1) = pie_point(start);
+                    @let (x2, y2) = pie_point(start + share);
+                    @let large = if share > 0.5 { 1 } else { 0 };
+                    path d=(format!("M 100 100 L {:.2} {:.2} A 90 90 0 {} 1 {:.2} {:.2} Z", x1, y1, large, x2, y2)) fill=(colour) stroke="#111827" hx-get=[&item.link] hx-target="#report-lines" class=[item.link.as_ref().map(|_| "cursor-pointer")] {
+                        title { (item.label) ": " (item.title) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The items side by side as bars scaled to the largest, drawn as an SVG. Bars below zero are
+/// drawn empty.
+pub fn bar_chart(items: &[ChartItem]) -> Markup {
+    let max = items.iter().map(|i| i.value).max().unwrap_or_default().max(1);
+    let width = 400.0 / items.len().max(1) as f64;
+    html! {
+        svg viewBox="0 0 400 200" class="w-full max-w-xl h-52" {
+            @for (i, item) in items.iter().enumerate() {
+                @let height = 160.0 * item.value.max(0) as f64 / max as f64;
+                @let x = i as f64 * width;
+                rect x=(format!("{:.2}", x + width * 0.1)) y=(format!("{:.2}", 170.0 - height)) width=(format!("{:.2}", width * 0.8)) height=(format!("{:.2}", height)) fill=(CHART_COLOURS[0]) hx-get=[&item.link] hx-target="#report-lines" class=[item.link.as_ref().map(|_| "cursor-pointer")] {
+                    title { (item.label) ": " (item.title) }
+                }
+                text x=(format!("{:.2}", x + width / 2.0)) y="190" text-anchor="middle" font-size="11" fill="#9ca3af" { (item.label) }
+            }
+        }
+    }
+}
+
+/// Bars above the axis for money one way and below it for money the other, such as assets and
+/// liabilities, with a line through what the two come to. Labels are thinned out to about a dozen.
+pub fn two_way_chart(labels: &[String], up: &[i64], down: &[i64], titles: &[String]) -> Markup {
+    let net: Vec<i64> = up.iter().zip(down).map(|(up, down)| up - down).collect();
+    let max = up.iter().chain(down).chain(net.iter()).map(|v| v.abs()).max().unwrap_or_default().max(1);
+    let width = 400.0 / labels.len().max(1) as f64;
+    let step = labels.len().div_ceil(12).max(1);
+    let y = |value: i64| 100.0 - 90.0 * value as f64 / max as f64;
+    let line: Vec<String> = net.iter().enumerate().map(|(i, value)| format!("{:.2},{:.2}", (i as f64 + 0.5) * width, y(*value))).collect();
+    html! {
+        svg viewBox="0 0 400 220" class="w-full max-w-xl h-56" {
+            line x1="0" y1="100" x2="400" y2="100" stroke="#4b5563" {}
+            @for (i, label) in labels.iter().enumerate() {
+                @let x = i as f64 * width;
+                g {
+                    title { (label) ": " (titles[i]) }
+                    rect x=(format!("{:.2}", x + width * 0.1)) y=(format!("{:.2}", y(up[i].max(0)))) width=(format!("{:.2}", width * 0.8)) height=(format!("{:.2}", 100.0 - y(up[i].max(0)))) fill="#34d399" {}
+                    rect x=(format!("{:.2}", x + width * 0.1)) y="100" width=(format!("{:.2}", width * 0.8)) height=(format!("{:.2}", y(-down[i].max(0)) - 100.0)) fill="#f87171" {}
+                }
+                @if i % step == 0 {
+                    text x=(format!("{:.2}", x + width / 2.0)) y="215" text-anchor="middle" font-size="11" fill="#9ca3af" { (label) }
+                }
+            }
+            polyline points=(line.join(" ")) fill="none" stroke="#60a5fa" stroke-width="2" {}
+        }
+    }
+}
+
+/// Links between the reports, with the one being shown highlighted.
+fn report_tabs(current: &str) -> Markup {
+    html! {
+        div class="flex gap-2 text-sm" {
+            @for (path, name) in [("/reports", "Spending"), ("/reports/income-and-spending", "Income vs spending"), ("/reports/cash-flow", "Cash flow"), ("/reports/net-worth", "Net worth")] {
+                a hx-get=(path) hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class=(if path == current { "rounded bg-blue-800 py-1 px-2 cursor-pointer" } else { "rounded bg-gray-800 hover:bg-gray-700 py-1 px-2 cursor-pointer" }) { (name) }
+            }
+        }
+    }
+}
+
+/// The change from one month to the next, coloured by whether spending went up or down.
+fn month_change(before: i64, after: i64, currency: &str, locale: &Locale) -> Markup {
+    let change = after - before;
+    html! {
+        @if change > 0 {
+            span class="block text-xs text-red-400" { "+" (format_money(change, currency, locale)) }
+        } @else if change < 0 {
+            span class="block text-xs text-green-400" { (format_money(change, currency, locale)) }
+        }
+    }
+}
+
+/// Spending over a period as a pie chart of where it went, a bar chart of each month, and each
+/// row's spending month by month. Clicking a row lists the transactions behind it.
+pub fn spending_report(spending: &Spending, params: &ReportParams, period: Period, locale: &Locale, message: Option<&str>) -> Markup {
+    let breakdown = params.breakdown();
+    let currency = spending.currency.as_str();
+    let link = |key: &str| format!("/reports/lines?{}", params.drill_down(key).query_string());
+    let slices: Vec<ChartItem> = spending.rows.iter().map(|row| ChartItem {
+        label: row.name.clone(),
+        value: row.total,
+        title: format_money(row.total, currency, locale),
+        link: Some(link(&row.key)),
+    }).collect();
+    let bars: Vec<ChartItem> = spending.months.iter().zip(spending.month_totals()).map(|(month, total)| ChartItem {
+        label: month.format("%b %Y").to_string(),
+        value: total,
+        title: format_money(total, currency, locale),
+        link: None,
+    }).collect();
+    let columns = format!("grid-template-columns: 2fr repeat({}, 1fr)", spending.months.len() + 1);
+    html! {
+        div class="p-4 space-y-4" {
+            (report_tabs("/reports"))
+            h2 class="text-xl" { "Spending" }
+            form hx-get="/reports" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="flex flex-wrap gap-2 items-end text-sm" {
+                label class="block" {
+                    "From"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="from" value=(period.from);
+                }
+                label class="block" {
+                    "To"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="to" value=(period.to);
+                }
+                label class="block" {
+                    "By"
+                    select name="by" class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                        @for option in Breakdown::ALL {
+                            option value=(option.name()) selected[option == breakdown] { (option.label()) }
+                        }
+                    }
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Show" }
+            }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if !spending.missing.is_empty() {
+                p class="text-sm text-gray-400" {
+                    "Spending in " (spending.missing.iter().cloned().collect::<Vec<_>>().join(", ")) " is left out, as there is no exchange rate to " (currency) "."
+                }
+            }
+            @if spending.rows.is_empty() {
+                p class="text-sm" { "No spending in this period." }
+            } @else {
+                p { "Total " (format_money(spending.total(), currency, locale)) }
+                div class="flex flex-wrap gap-8 items-center" {
+                    (pie_chart(&slices))
+                    (bar_chart(&bars))
+                }
+                div class="block w-full grid gap-x-2 text-sm" style=(columns) {
+                    div { (breakdown.label()) }
+                    @for month in &spending.months {
+                        div { (month.format("%b %Y")) }
+                    }
+                    div { "Total" }
+                    @for (i, row) in spending.rows.iter().enumerate() {
+                        a hx-get=(link(&row.key)) hx-target="#report-lines" class="cursor-pointer flex items-center gap-2" {
+                            span class="inline-block w-3 h-3 rounded-sm" style=(format!("background-color: {}", CHART_COLOURS[i % CHART_COLOURS.len()])) {}
+                            (row.name)
+                        }
+                        @for (m, amount) in row.months.iter().enumerate() {
+                            div {
+                                (format_money(*amount, currency, locale))
+                                @if m > 0 {
+                                    (month_change(row.months[m - 1], *amount, currency, locale))
+                                }
+                            }
+                        }
+                        div { (format_money(row.total, currency, locale)) }
+                    }
+                }
+            }
+            div id="report-lines" {}
+        }
+    }
+}
+
+/// Assets, liabilities and net worth at the end of each month, and how each account changed over
+/// the period.
+pub fn net_worth_report(net_worth: &NetWorth, period: Period, locale: &Locale, message: Option<&str>) -> Markup {
+    let currency = net_worth.currency.as_str();
+    let totals = net_worth.net_worth();
+    let labels: Vec<String> = net_worth.months.iter().map(|m| m.format("%b %Y").to_string()).collect();
+    let titles: Vec<String> = totals.iter().map(|total| format_money(*total, currency, locale)).collect();
+    let params = ReportParams { from: period.from.to_string(), to: period.to.to_string(), ..ReportParams::default() };
+    html! {
+        div class="p-4 space-y-4" {
+            (report_tabs("/reports/net-worth"))
+            h2 class="text-xl" { "Net worth" }
+            form hx-get="/reports/net-worth" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="flex flex-wrap gap-2 items-end text-sm" {
+                label class="block" {
+                    "From"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="from" value=(period.from);
+                }
+                label class="block" {
+                    "To"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="to" value=(period.to);
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Show" }
+                a href=(format!("/reports/net-worth.csv?{}", params.query_string())) class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Export CSV" }
+            }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if !net_worth.missing.is_empty() {
+                p class="text-sm text-gray-400" {
+                    "Accounts in " (net_worth.missing.iter().cloned().collect::<Vec<_>>().join(", ")) " are left out of the totals, as there is no exchange rate to " (currency) "."
+                }
+            }
+            @if net_worth.accounts.is_empty() {
+                p class="text-sm" { "No accounts yet." }
+            } @else {
+                (two_way_chart(&labels, &net_worth.assets, &net_worth.liabilities, &titles))
+                div class="block w-full grid grid-cols-4 gap-x-2 text-sm" {
+                    div { "Month" }
+                    div { "Assets" }
+                    div { "Liabilities" }
+                    div { "Net worth" }
+                    @for (i, label) in labels.iter().enumerate().rev() {
+                        div { (label) }
+                        div { (format_money(net_worth.assets[i], currency, locale)) }
+                        div { (format_money(net_worth.liabilities[i], currency, locale)) }
+                        div { (format_money(totals[i], currency, locale)) }
+                    }
+                }
+                div class="block w-full grid grid-cols-4 gap-x-2 text-sm" {
+                    div { "Account" }
+                    div { (labels[0]) }
+                    div { (labels[labels.len() - 1]) }
+                    div { "Change" }
+                    @for account in &net_worth.accounts {
+                        @let (first, last) = (account.balances[0], account.balances[account.balances.len() - 1]);
+                        div {
+                            (account.name)
+                            @if !account.on_budget {
+                                span class="text-gray-400" { " (tracking)" }
+                            }
+                        }
+                        div { (format_money(first, &account.currency, locale)) }
+                        div { (format_money(last, &account.currency, locale)) }
+                        div { (format_money(last - first, &account.currency, locale)) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Income against spending in each month, with what was left over and the share of income saved.
+pub fn income_expense_report(report: &IncomeExpense, period: Period, locale: &Locale, message: Option<&str>) -> Markup {
+    let currency = report.currency.as_str();
+    let labels: Vec<String> = report.months.iter().map(|m| m.month.format("%b %Y").to_string()).collect();
+    let income: Vec<i64> = report.months.iter().map(|m| m.flow.income).collect();
+    let spending: Vec<i64> = report.months.iter().map(|m| m.flow.spending).collect();
+    let titles: Vec<String> = report.months.iter().map(|m| format_money(m.flow.net, currency, locale)).collect();
+    let params = ReportParams { from: period.from.to_string(), to: period.to.to_string(), ..ReportParams::default() };
+    html! {
+        div class="p-4 space-y-4" {
+            (report_tabs("/reports/income-and-spending"))
+            h2 class="text-xl" { "Income vs spending" }
+            form hx-get="/reports/income-and-spending" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="flex flex-wrap gap-2 items-end text-sm" {
+                label class="block" {
+                    "From"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="from" value=(period.from);
+                }
+                label class="block" {
+                    "To"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="to" value=(period.to);
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Show" }
+                a href=(format!("/reports/income-and-spending/csv?{}", params.query_string())) class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Export CSV" }
+                a href=(format!("/reports/income-and-spending/json?{}", params.query_string())) class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Export JSON" }
+            }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if !report.missing.is_empty() {
+                p class="text-sm text-gray-400" {
+                    "Money in " (report.missing.iter().cloned().collect::<Vec<_>>().join(", ")) " is left out, as there is no exchange rate to " (currency) "."
+                }
+            }
+            (two_way_chart(&labels, &income, &spending, &titles))
+            div class="block w-full grid grid-cols-5 gap-x-2 text-sm" {
+                div { "Month" }
+                div { "Income" }
+                div { "Spending" }
+                div { "Net" }
+                div { "Savings rate" }
+                @for (label, month) in labels.iter().zip(&report.months).rev() {
+                    div { (label) }
+                    div { (format_money(month.flow.income, currency, locale)) }
+                    div { (format_money(month.flow.spending, currency, locale)) }
+                    div class=(if month.flow.net < 0 { "text-red-400" } else { "" }) { (format_money(month.flow.net, currency, locale)) }
+                    div { (reports::format_savings_rate(month.flow.savings_rate)) }
+                }
+                div class="font-bold" { "Total" }
+                div class="font-bold" { (format_money(report.total.income, currency, locale)) }
+                div class="font-bold" { (format_money(report.total.spending, currency, locale)) }
+                div class="font-bold" { (format_money(report.total.net, currency, locale)) }
+                div class="font-bold" { (reports::format_savings_rate(report.total.savings_rate)) }
+            }
+        }
+    }
+}
+
+/// What came into and went out of each account over a period, with transfers between budget
+/// accounts shown apart from other money.
+pub fn cash_flow_report(cash_flow: &CashFlow, locale: &Locale, message: Option<&str>) -> Markup {
+    let params = ReportParams { from: cash_flow.from.to_string(), to: cash_flow.to.to_string(), ..ReportParams::default() };
+    html! {
+        div class="p-4 space-y-4" {
+            (report_tabs("/reports/cash-flow"))
+            h2 class="text-xl" { "Cash flow" }
+            form hx-get="/reports/cash-flow" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="flex flex-wrap gap-2 items-end text-sm" {
+                label class="block" {
+                    "From"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="from" value=(cash_flow.from);
+                }
+                label class="block" {
+                    "To"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="to" value=(cash_flow.to);
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Show" }
+                a href=(format!("/reports/cash-flow/csv?{}", params.query_string())) class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Export CSV" }
+                a href=(format!("/reports/cash-flow/json?{}", params.query_string())) class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Export JSON" }
+            }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if cash_flow.accounts.is_empty() {
+                p class="text-sm" { "No accounts yet." }
+            } @else {
+                div class="block w-full grid grid-cols-7 gap-x-2 text-sm" {
+                    div { "Account" }
+                    div { "Opening" }
+                    div { "In" }
+                    div { "Out" }
+                    div { "Transfers in" }
+                    div { "Transfers out" }
+                    div { "Closing" }
+                    @for account in &cash_flow.accounts {
+                        div {
+                            (account.name)
+                            @if !account.on_budget {
+                                span class="text-gray-400" { " (tracking)" }
+                            }
+                        }
+                        div { (format_money(account.opening, &account.currency, locale)) }
+                        div { (format_money(account.inflow, &account.currency, locale)) }
+                        div { (format_money(account.outflow, &account.currency, locale)) }
+                        div { (format_money(account.transfers_in, &account.currency, locale)) }
+                        div { (format_money(account.transfers_out, &account.currency, locale)) }
+                        div { (format_money(account.closing, &account.currency, locale)) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The transactions behind a row of a report, each in its account's currency.
+pub fn report_lines(lines: &[reports::Line], locale: &Locale) -> Markup {
+    html! {
+        div class="space-y-2" {
+            h3 class="text-lg" { "Transactions" }
+            @if lines.is_empty() {
+                p class="text-sm" { "No transactions." }
+            } @else {
+                div class="block w-full grid grid-cols-6 gap-x-2 text-sm" {
+                    div { "Date" }
+                    div { "Account" }
+                    div { "Payee" }
+                    div { "Category" }
+                    div { "Memo" }
+                    div { "Amount" }
+                    @for line in lines {
+                        div { (line.date.format("%Y-%m-%d")) }
+                        div { (line.account) }
+                        div { (line.payee.as_deref().unwrap_or_default()) }
+                        div { (line.category.as_deref().unwrap_or("Uncategorised")) }
+                        div { (line.memo) }
+                        div { (format_money(-line.amount, &line.currency, locale)) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Everything the user has changed, newest first. Refreshes itself when something is done, undone
+/// or redone.
+pub fn history(change_sets: &[ChangeSet]) -> Markup {
+    html! {
+        div id="history" class="p-4 space-y-4" hx-get="/history" hx-trigger="transactionsUpdated from:body, historyUpdated from:body" hx-select="#history" hx-swap="outerHTML" {
+            h2 class="text-xl" { "History" }
+            @if change_sets.is_empty() {
+                p class="text-sm" { "Nothing has been changed yet." }
+            } @else {
+                div class="block w-full grid grid-cols-4 text-sm" {
+                    div { "When" }
+                    div class="col-span-2" { "Change" }
+                    div { "Status" }
+                    @for change_set in change_sets {
+                        div { (change_set.created_at.format("%Y-%m-%d %H:%M")) }
+                        div class="col-span-2" { (change_set.description) }
+                        div {
+                            @match change_set.status.as_str() {
+                                "undone" => "Undone",
+                                "discarded" => "Undone, can't be redone",
+                                _ => "Done",
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Who changed what, newest first, with filters that are kept in the address so a view of the log
+/// can be linked to.
+pub fn audit_log(entries: &[Entry], entities: &[String], params: &AuditParams, message: Option<&str>) -> Markup {
+    html! {
+        div class="p-4 space-y-4" {
+            h2 class="text-xl" { "Audit log" }
+            form hx-get="/audit" hx-target="#content" hx-swap="innerHTML" hx-push-url="true" class="flex flex-wrap gap-2 items-end text-sm" {
+                label class="block" {
+                    "Kind"
+                    select name="entity" class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                        option value="" { "Everything" }
+                        @for entity in entities {
+                            option value=(entity) selected[*entity == params.entity] { (entity) }
+                        }
+                    }
+                }
+                label class="block" {
+                    "Action"
+                    select name="action" class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" {
+                        option value="" { "Any" }
+                        @for action in ["create", "update", "delete", "restore"] {
+                            option value=(action) selected[action == params.action] { (action) }
+                        }
+                    }
+                }
+                label class="block" {
+                    "From"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="from" value=(params.from);
+                }
+                label class="block" {
+                    "To"
+                    input class="block rounded bg-gray-800 border border-gray-700 py-1 px-2" type="date" name="to" value=(params.to);
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Filter" }
+            }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if entries.is_empty() {
+                p class="text-sm" { "No changes found." }
+            } @else {
+                @if entries.len() as i64 == ENTRY_LIMIT {
+                    p class="text-sm text-gray-400" { "Showing the latest " (ENTRY_LIMIT) " changes." }
+                }
+                div class="block w-full grid grid-cols-6 gap-x-2 text-sm" {
+                    div { "When" }
+                    div { "Who" }
+                    div { "Action" }
+                    div class="col-span-3" { "Change" }
+                    @for entry in entries {
+                        div { (entry.created_at.format("%Y-%m-%d %H:%M:%S")) }
+                        div {
+                            (entry.actor.as_deref().unwrap_or("Unknown"))
+                            @if let Some(ip) = &entry.ip {
+                                span class="block text-gray-400" { (ip) }
+                            }
+                        }
+                        div { (entry.action) " " (entry.entity) " " (entry.entity_id) }
+                        div class="col-span-3" {
+                            @for (column, before, after) in entry.changed_columns() {
+                                p class="break-all" {
+                                    span class="text-gray-400" { (column) ": " }
+                                    @if !before.is_empty() {
+                                        span class="line-through" { (before) }
+                                        " "
+                                    }
+                                    (after)
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A coloured dot for a flagged transaction.
+fn flag(flag: Option<&str>) -> Markup {
+    html! {
+        @if let Some(flag) = flag {
+            span class=(format!("inline-block w-2 h-2 rounded-full mr-1 bg-{}-500", flag)) title=(flag) {}
+        }
+    }
+}
+
+fn category_name(categories: &[Category], id: i64) -> String {
+    categories.iter().find(|c| c.id == id).map_or_else(|| "a deleted category".to_string(), |c| format!("{}: {}", c.group_name, c.name))
+}
+
+/// A rule's conditions and actions in words, such as "Payee contains tesco".
+fn describe_rule(rule: &Rule, accounts: &[Account], categories: &[Category], currency: &str, locale: &Locale) -> (Vec<String>, Vec<String>) {
+    let conditions = rule.conditions.iter().map(|condition| match condition {
+        Condition::Text { field, operator, value } => format!(
+            "{} {} \"{}\"",
+            match field { Field::Payee => "Payee", Field::Memo => "Memo" },
+            match operator { Operator::Contains => "contains", Operator::Regex => "matches" },
+            value
+        ),
+        Condition::Amount { min, max } => match (min, max) {
+            (Some(min), Some(max)) => format!("Amount is between {} and {}", format_money(*min, currency, locale), format_money(*max, currency, locale)),
+            (Some(min), None) => format!("Amount is at least {}", format_money(*min, currency, locale)),
+            (None, Some(max)) => format!("Amount is at most {}", format_money(*max, currency, locale)),
+            (None, None) => "Any amount".to_string(),
+        },
+        Condition::Account { account_id } => format!(
+            "Account is {}",
+            accounts.iter().find(|a| i64::from(a.id) == *account_id).map_or("a deleted account", |a| a.name.as_str())
+        ),
+    }).collect();
+    let actions = rule.actions.iter().map(|action| match action {
+        Action::RenamePayee { payee } => format!("Rename the payee to \"{}\"", payee),
+        Action::SetCategory { category_id } => format!("Categorise as {}", category_name(categories, *category_id)),
+        Action::SetMemo { memo } => format!("Set the memo to \"{}\"", memo),
+        Action::SetFlag { flag } => format!("Flag {}", flag),
+    }).collect();
+    (conditions, actions)
+}
+
+pub fn rules(rules: &[Rule], accounts: &[Account], categories: &[Category], currency: &str, locale: &Locale, message: Option<&str>) -> Markup {
+    let input = "rounded bg-gray-800 border border-gray-700 py-1 px-2";
+    html! {
+        div id="rules" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Rules" }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            @if rules.is_empty() {
+                p class="text-sm" { "No rules yet. Rules tidy up and categorise transactions as they are entered and imported." }
+            } @else {
+                p class="text-sm text-gray-400" { "Rules run in order, lowest priority first. Once a rule has set something, later rules leave it alone." }
+            }
+            @for rule in rules {
+                @let (conditions, actions) = describe_rule(rule, accounts, categories, currency, locale);
+                div class="rounded bg-gray-800 p-2 flex justify-between" {
+                    div {
+                        p { (rule.name) span class="text-sm text-gray-400 ml-2" { "Priority " (rule.priority) } }
+                        p class="text-sm text-gray-400" { "When " (conditions.join(" and ")) }
+                        p class="text-sm text-gray-400" { (actions.join(", ")) }
+                    }
+                    button hx-post=(format!("/rules/{}/delete", rule.id)) hx-target="#rules" hx-swap="outerHTML" hx-confirm="Delete this rule?" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2 h-fit" { "Delete" }
+                }
+            }
+            form hx-post="/rules" hx-target="#rules" hx-swap="outerHTML" class="space-y-2" {
+                div class="flex space-x-2" {
+                    input class=(input) type="text" name="name" placeholder="Name" required {}
+                    input class=(format!("{} w-24", input)) type="number" name="priority" placeholder="Priority" {}
+                }
+                p class="text-sm" { "When" }
+                div class="flex space-x-2" {
+                    span class="py-1 w-16" { "Payee" }
+                    select class=(input) name="payee_operator" {
+                        option value="contains" { "contains" }
+                        option value="regex" { "matches the pattern" }
+                    }
+                    input class=(input) type="text" name="payee" {}
+                }
+                div class="flex space-x-2" {
+                    span class="py-1 w-16" { "Memo" }
+                    select class=(input) name="memo_operator" {
+                        option value="contains" { "contains" }
+                        option value="regex" { "matches the pattern" }
+                    }
+                    input class=(input) type="text" name="memo" {}
+                }
+                div class="flex space-x-2" {
+                    span class="py-1 w-16" { "Amount" }
+                    input class=(format!("{} w-28", input)) type="text" name="min_amount" placeholder="From" {}
+                    input class=(format!("{} w-28", input)) type="text" name="max_amount" placeholder="To" {}
+                    span class="py-1 text-sm text-gray-400" { "in " (currency) ", outflows are negative" }
+                }
+                div class="flex space-x-2" {
+                    span class="py-1 w-16" { "Account" }
+                    select class=(input) name="account_id" {
+                        option value="" { "Any account" }
+                        @for account in accounts {
+                            option value=(account.id) { (account.name) }
+                        }
+                    }
+                }
+                p class="text-sm" { "Then" }
+                div class="flex space-x-2" {
+                    input class=(input) type="text" name="rename_payee" placeholder="Rename payee to" {}
+                    select class=(input) name="category_id" {
+                        option value="" { "Keep category" }
+                        @for category in categories {
+                            option value=(category.id) { (category.group_name) ": " (category.name) }
+                        }
+                    }
+                    input class=(input) type="text" name="set_memo" placeholder="Set memo to" {}
+                    select class=(input) name="flag" {
+                        option value="" { "No flag" }
+                        @for flag in rules::FLAGS {
+                            option value=(flag) { (flag) }
+                        }
+                    }
+                }
+                div class="flex space-x-2" {
+                    button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Save rule" }
+                    button type="button" hx-post="/rules/preview" hx-target="#rule-preview" hx-swap="innerHTML" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Test against history" }
+                }
+            }
+            div id="rule-preview" {}
+        }
+    }
+}
+
+/// The existing transactions a rule would match, with what it would change.
+pub fn rule_preview(matches: &[(Transaction, Subject)], accounts: &[Account], categories: &[Category], locale: &Locale) -> Markup {
+    const SHOWN: usize = 50;
+    let change = |before: &str, after: &str| if before == after { before.to_string() } else { format!("{} → {}", before, after) };
+    html! {
+        div class="space-y-2" {
+            @if matches.is_empty() {
+                p class="text-sm" { "This rule would not match any of your transactions." }
+            } @else {
+                p class="text-sm" { "This rule would match " (matches.len()) " of your transactions." }
+                @if matches.len() > SHOWN {
+                    p class="text-sm text-gray-400" { "Showing the newest " (SHOWN) "." }
+                }
+                div class="block w-full grid grid-cols-6 text-sm" {
+                    div { "Date" }
+                    div { "Account" }
+                    div { "Amount" }
+                    div { "Payee" }
+                    div { "Category" }
+                    div { "Memo" }
+                    @for (transaction, after) in matches.iter().take(SHOWN) {
+                        @let account = accounts.iter().find(|a| a.id == transaction.account_id);
+                        @let category = |id: Option<i64>| id.map(|id| category_name(categories, id)).unwrap_or_default();
+                        div { (transaction.date.format("%Y-%m-%d")) }
+                        div { (account.map_or("", |a| a.name.as_str())) }
+                        div { (format_money(transaction.inflow - transaction.outflow, account.map_or("GBP", |a| a.currency.as_str()), locale)) }
+                        div { (flag(after.flag.as_deref())) (change(transaction.payee.as_deref().unwrap_or_default(), after.payee.as_deref().unwrap_or_default())) }
+                        div { (change(&category(transaction.category_id), &category(after.category_id))) }
+                        div { (change(&transaction.memo, &after.memo)) }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn webhooks(webhooks: Vec<Webhook>) -> Markup {
+    html! {
+        div hx-trigger="webhooksUpdated" hx-get="/webhooks" hx-swap="outerHTML" class="p-4 space-y-4" {
+            div class="flex justify-between" {
+                h2 class="text-xl" { "Webhooks" }
+                a hx-get="/webhooks/deliveries" hx-target="#content" hx-swap="innerHTML" class="text-blue-400" { "Delivery log" }
+            }
+            @if webhooks.is_empty() {
+                p class="text-sm" { "No webhooks yet. Add one below to be notified when things happen in your budget." }
+            }
+            @for webhook in webhooks {
+                div class="rounded bg-gray-800 p-2 flex justify-between" {
+                    div {
+                        p { (webhook.url) }
+                        p class="text-sm text-gray-400" { (webhook.events.replace(',', ", ")) }
+                        p class="text-sm text-gray-400" { "Signing secret: " code { (webhook.secret) } }
+                    }
+                    button hx-post=(format!("/webhooks/{}/delete", webhook.id)) hx-confirm="Delete this webhook?" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2 h-fit" { "Delete" }
+                }
+            }
+            form hx-post="/webhooks" hx-target="#webhook-error" class="space-y-2" {
+                input class="w-full rounded bg-gray-800 border border-gray-700 py-1 px-2" type="url" name="url" placeholder="https://example.com/hook" {}
+                @for event in Event::ALL {
+                    label class="block text-sm" { input type="checkbox" name="event" value=(event.as_str()) class="mr-1"; (event.as_str()) }
+                }
+                div id="webhook-error" {}
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2 text-left" { "Add Webhook" }
+            }
+        }
+    }
+}
+
+pub fn webhook_deliveries(deliveries: Vec<WebhookDelivery>) -> Markup {
+    html! {
+        div class="p-4 space-y-4" {
+            div class="flex justify-between" {
+                h2 class="text-xl" { "Webhook deliveries" }
+                a hx-get="/webhooks" hx-target="#content" hx-swap="innerHTML" class="text-blue-400" { "Webhooks" }
+            }
+            div class="block w-full grid grid-cols-7 text-sm" {
+                div { "Created" }
+                div class="col-span-2" { "Url" }
+                div { "Event" }
+                div { "Status" }
+                div { "Attempts" }
+                div { "Response" }
+                @for delivery in deliveries {
+                    div { (delivery.created_at.format("%Y-%m-%d %H:%M:%S")) }
+                    div class="col-span-2 truncate" { (delivery.url) }
+                    div { (delivery.event) }
+                    div { (delivery.status) }
+                    div { (delivery.attempts) }
+                    div title=(delivery.last_error.clone().unwrap_or_default()) {
+                        @match delivery.response_status {
+                            Some(status) => (status),
+                            None => (delivery.last_error.as_deref().unwrap_or("-")),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Every budget the user is a member of, with forms to add one and, for owners, to rename and
+/// archive them.
+pub fn budgets(budgets: &[Budget], current: Option<&Budget>, message: Option<&str>) -> Markup {
+    html! {
+        div id="budgets" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Budgets" }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            p class="text-sm text-gray-400" { "Each budget has its own accounts, categories and currency. Archived budgets keep everything in them but are left out of the picker." }
+            @for budget in budgets {
+                div class="rounded bg-gray-800 p-2 flex justify-between items-center space-x-2" {
+                    @if budget.role == Role::Owner {
+                        form hx-post=(format!("/budgets/{}", budget.id)) hx-target="#budgets" hx-swap="outerHTML" class="flex space-x-2" {
+                            input class="rounded bg-gray-900 border border-gray-700 py-1 px-2" type="text" name="name" value=(budget.name) required {}
+                            button type="submit" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Rename" }
+                        }
+                    } @else {
+                        p { (budget.name) }
+                    }
+                    p class="text-sm text-gray-400" {
+                        (budget.currency) ", " (budget.role.as_str())
+                        @if budget.archived { ", archived" }
+                    }
+                    div class="flex space-x-2" {
+                        @if current.is_some_and(|c| c.id == budget.id) {
+                            p class="text-sm py-1 px-2" { "Current" }
+                        } @else if !budget.archived {
+                            button hx-post="/budgets/switch" hx-vals=(format!(r#"{{"budget_id": {}}}"#, budget.id)) class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Switch" }
+                        }
+                        @if budget.role == Role::Owner {
+                            @if budget.archived {
+                                button hx-post=(format!("/budgets/{}/archive", budget.id)) hx-vals=r#"{"archived": false}"# hx-target="#budgets" hx-swap="outerHTML" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Unarchive" }
+                            } @else {
+                                button hx-post=(format!("/budgets/{}/archive", budget.id)) hx-vals=r#"{"archived": true}"# hx-target="#budgets" hx-swap="outerHTML" hx-confirm=(format!("Archive {}? It can be brought back from here.", budget.name)) class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Archive" }
+                            }
+                        }
+                    }
+                }
+            }
+            form hx-post="/budgets" hx-target="#budgets" hx-swap="outerHTML" class="flex space-x-2" {
+                input class="rounded bg-gray-800 border border-gray-700 py-1 px-2" type="text" name="name" placeholder="Name" required {}
+                select class="rounded bg-gray-800 border border-gray-700 py-1 px-2" name="currency" {
+                    (currency_options(current.map_or("GBP", |c| &c.currency)))
+                }
+                button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Add budget" }
+            }
+        }
+    }
+}
+
+fn role_select(name: &str, selected: Role) -> Markup {
+    html! {
+        select name=(name) class="rounded bg-gray-900 border border-gray-700 py-1 px-2" {
+            @for role in Role::ALL {
+                option value=(role.as_str()) selected[role == selected] { (role.as_str()) }
+            }
+        }
+    }
+}
+
+/// Who the budget is shared with and who has been invited. Only owners can change either.
+pub fn members(budget: &Budget, members: &[Member], invitations: &[Invitation], message: Option<&str>) -> Markup {
+    let owner = budget.role == Role::Owner;
+    html! {
+        div id="members" class="p-4 space-y-4" {
+            h2 class="text-xl" { "Members of " (budget.name) }
+            @if let Some(message) = message {
+                p class="text-sm" { (message) }
+            }
+            p class="text-sm text-gray-400" { "Owners can do anything, editors can change the budget but not who it is shared with, and viewers can only look." }
+            @for member in members {
+                div class="rounded bg-gray-800 p-2 flex justify-between items-center space-x-2" {
+                    div {
+                        p { (member.name) }
+                        p class="text-sm text-gray-400" { (member.email) }
+                    }
+                    @if owner {
+                        div class="flex space-x-2" {
+                            form hx-post=(format!("/budget/members/{}", member.user_id)) hx-target="#members" hx-swap="outerHTML" class="flex space-x-2" {
+                                (role_select("role", member.role))
+                                button type="submit" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Save" }
+                            }
+                            button hx-post=(format!("/budget/members/{}", member.user_id)) hx-vals=r#"{"role": ""}"# hx-target="#members" hx-swap="outerHTML" hx-confirm=(format!("Remove {} from {}?", member.name, budget.name)) class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Remove" }
+                        }
+                    } @else {
+                        p class="text-sm" { (member.role.as_str()) }
+                    }
+                }
+            }
+            @if owner {
+                h3 class="text-lg" { "Invitations" }
+                @if invitations.is_empty() {
+                    p class="text-sm" { "No one is waiting to join. Invite someone below, then send them the link." }
+                }
+                @for invitation in invitations {
+                    div class="rounded bg-gray-800 p-2 flex justify-between items-center space-x-2" {
+                        div {
+                            p { (invitation.email) " as " (invitation.role.as_str()) }
+                            p class="text-sm text-gray-400" { "Link: " a class="text-blue-400" href=(invitation.path()) { (invitation.path()) } }
+                        }
+                        button hx-post=(format!("/budget/invitations/{}/cancel", invitation.id)) hx-target="#members" hx-swap="outerHTML" class="rounded bg-gray-700 hover:bg-gray-600 transition-colors py-1 px-2" { "Cancel" }
+                    }
+                }
+                form hx-post="/budget/members" hx-target="#members" hx-swap="outerHTML" class="flex space-x-2" {
+                    input class="rounded bg-gray-800 border border-gray-700 py-1 px-2" type="email" name="email" placeholder="Email address" required {}
+                    (role_select("role", Role::Editor))
+                    button type="submit" class="rounded bg-gray-800 hover:bg-gray-700 transition-colors py-1 px-2" { "Invite" }
+                }
+            }
+        }
+    }
+}
+
+/// Where someone who was invited to a budget accepts the invitation.
+pub fn invitation(invitation: &Invitation) -> Markup {
+    html! {
+        (header("Join a budget"))
+        body class="container mx-auto" {
+            div class="pt-12 container mx-auto w-3/5 text-center space-y-4" {
+                h2 class="text-indigo-600 text-4xl" { "Join " (invitation.budget_name) }
+                p {
+                    (invitation.invited_by.as_deref().unwrap_or("Someone"))
+                    " invited " (invitation.email) " to join as " (invitation.role.as_str()) "."
+                }
+                @if invitation.accepted_at.is_some() {
+                    p { "This invitation has already been accepted." }
+                } @else {
+                    form hx-post=(format!("{}/accept", invitation.path())) hx-target="#error" class="flex flex-col space-y-4" {
+                        div id="error" {}
+                        button type="submit" class="text-center text-white font-semibold rounded bg-indigo-600 p-3" { "Accept" }
+                    }
+                }
+            }
+            (footer())
+        }
+    }
 }
 
 pub fn error_message(error: &str) -> Markup {
@@ -136,7 +1911,7 @@ pub fn error_message(error: &str) -> Markup {
 pub fn signup() -> Markup {
     let title: &str = "Sign up";
     html! {
-        (header(&title))
+        (header(title))
         body hx-boost="true" class="container mx-auto" {
             h1 { (&title) }
             div class="grid grid-cols-2 gap-4 pt-12 container mx-auto w-3/5" {
@@ -163,7 +1938,7 @@ pub fn signup() -> Markup {
 pub fn login() -> Markup {
     let title: &str = "YMNAB";
     html! {
-        (header(&title))
+        (header(title))
         body hx-boost="true" class="container mx-auto" {
             h1 { (&title) }
             div class="grid grid-cols-2 gap-4 pt-12 container mx-auto w-3/5" {
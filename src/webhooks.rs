@@ -0,0 +1,332 @@
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDate};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::Sha256;
+use sqlx::{Pool, Sqlite};
+
+use crate::db::{self, Transaction};
+
+/// Deliveries that still fail after this many attempts are marked as `failed`.
+pub const MAX_ATTEMPTS: i64 = 8;
+
+/// How often the delivery worker checks the queue.
+const POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    AccountCreated,
+    TransactionCreated,
+    CategoryOverspent,
+}
+
+impl Event {
+    pub const ALL: [Event; 3] = [Event::AccountCreated, Event::TransactionCreated, Event::CategoryOverspent];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Event::AccountCreated => "account.created",
+            Event::TransactionCreated => "transaction.created",
+            Event::CategoryOverspent => "category.overspent",
+        }
+    }
+
+    pub fn from_str(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.as_str() == value)
+    }
+}
+
+pub fn generate_secret() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect()
+}
+
+/// Hex encoded HMAC-SHA256 of `body`, sent as `X-Ymnab-Signature: sha256=<signature>`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Time to wait before retrying a delivery that has failed `attempts` times: 30 seconds doubling
+/// each attempt, capped at six hours.
+pub fn backoff(attempts: i64) -> chrono::Duration {
+    let exponent = attempts.clamp(1, 16) - 1;
+    chrono::Duration::seconds(30 * 2_i64.pow(exponent as u32)).min(chrono::Duration::hours(6))
+}
+
+/// Queues a delivery of `event` to every active webhook of `budget_id` that subscribes to it.
+pub async fn dispatch(conn: &Pool<Sqlite>, budget_id: i32, event: Event, data: serde_json::Value) {
+    let webhooks = match db::get_webhooks_for_budget(conn, budget_id).await {
+        Some(w) => w,
+        None => return,
+    };
+
+    let payload = serde_json::json!({
+        "event": event.as_str(),
+        "created_at": chrono::Utc::now().to_rfc3339(),
+        "data": data,
+    }).to_string();
+
+    for webhook in webhooks.iter().filter(|w| w.active && w.subscribes_to(event.as_str())) {
+        if let Err(e) = db::queue_webhook_delivery(conn, webhook.id, event.as_str(), &payload).await {
+            println!("{}", e);
+        }
+    }
+}
+
+pub async fn transaction_created(conn: &Pool<Sqlite>, transaction: &Transaction) {
+    let budget: Result<(i32,), sqlx::Error> = sqlx::query_as("SELECT budget_id FROM accounts WHERE id = ?")
+        .bind(transaction.account_id)
+        .fetch_one(conn)
+        .await;
+
+    if let Ok((budget_id,)) = budget {
+        dispatch(conn, budget_id, Event::TransactionCreated, serde_json::json!(transaction)).await;
+        let month = transaction.date.date().with_day(1).expect("Every month has a first day");
+        for (category_id, change) in db::get_transaction_category_changes(conn, transaction.id.into()).await {
+            category_changed(conn, budget_id, category_id, month, change).await;
+        }
+    }
+}
+
+/// Queues `category.overspent` webhooks when `change` is what took the category's available amount
+/// for the month starting on `month` below zero.
+pub async fn category_changed(conn: &Pool<Sqlite>, budget_id: i32, category_id: i64, month: NaiveDate, change: i64) {
+    if change >= 0 {
+        return;
+    }
+    let balance = match db::get_category_balance(conn, budget_id, category_id, month).await {
+        Some(b) => b,
+        None => return,
+    };
+
+    if balance.available < 0 && balance.available - change >= 0 {
+        dispatch(conn, budget_id, Event::CategoryOverspent, serde_json::json!({
+            "month": month,
+            "category": balance,
+        })).await;
+    }
+}
+
+/// Attempts every delivery that is due, returning how many were attempted.
+pub async fn deliver_due(conn: &Pool<Sqlite>, client: &reqwest::Client) -> usize {
+    let now = chrono::Utc::now().naive_utc();
+    let deliveries = db::get_due_webhook_deliveries(conn, now, 50).await;
+
+    for delivery in deliveries.iter() {
+        let response = client
+            .post(&delivery.url)
+            .header("Content-Type", "application/json")
+            .header("X-Ymnab-Event", &delivery.event)
+            .header("X-Ymnab-Delivery", delivery.id.to_string())
+            .header("X-Ymnab-Signature", format!("sha256={}", sign(&delivery.secret, &delivery.payload)))
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+
+        let (response_status, error) = match response {
+            Ok(r) if r.status().is_success() => (Some(r.status().as_u16()), None),
+            Ok(r) => (Some(r.status().as_u16()), Some(format!("Unexpected status {}", r.status()))),
+            Err(e) => (None, Some(e.to_string())),
+        };
+
+        let attempts = delivery.attempts + 1;
+        let (status, next_attempt_at) = match error {
+            None => ("delivered", now),
+            Some(_) if attempts >= MAX_ATTEMPTS => ("failed", now),
+            Some(_) => ("pending", now + backoff(attempts)),
+        };
+
+        if let Err(e) = db::record_webhook_attempt(conn, delivery.id, status, next_attempt_at, response_status, error.as_deref()).await {
+            println!("{}", e);
+        }
+    }
+
+    deliveries.len()
+}
+
+/// Runs forever, delivering queued webhooks. Spawned from `main`.
+pub async fn run(pool: Pool<Sqlite>) {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("Could not build webhook client");
+
+    loop {
+        deliver_due(&pool, &client).await;
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use poem::{handler, http::StatusCode, listener::{Acceptor, TcpAcceptor}, post, web::Data, EndpointExt, Request, Route, Server};
+
+    use crate::{budgets, db::{create_account, create_transaction, create_webhook, get_webhook_deliveries_for_budget, insert_category, insert_category_group, move_assigned, set_category_assigned, NewTransaction}};
+
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(String, String, String)>>>;
+
+    #[handler]
+    async fn receive(req: &Request, body: String, received: Data<&Received>) -> StatusCode {
+        let header = |name: &str| req.header(name).unwrap_or_default().to_string();
+        received.lock().unwrap().push((header("X-Ymnab-Event"), header("X-Ymnab-Signature"), body));
+        StatusCode::OK
+    }
+
+    #[handler]
+    fn reject() -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    /// Starts a poem server on a random local port and returns its base url.
+    async fn serve(route: Route, received: Received) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let acceptor = TcpAcceptor::from_tokio(listener).unwrap();
+        let addr = acceptor.local_addr().remove(0).as_socket_addr().cloned().unwrap();
+        tokio::spawn(Server::new_with_acceptor(acceptor).run(route.data(received)));
+        format!("http://{}", addr)
+    }
+
+    /// Creates a user and returns the id of the budget they own.
+    async fn create_test_budget(pool: &Pool<Sqlite>) -> i32 {
+        let user_id = sqlx::query("INSERT INTO users (name, email, password, active) VALUES ('test', 'test@example.com', '', 1)")
+            .execute(pool)
+            .await
+            .unwrap()
+            .last_insert_rowid() as i32;
+        budgets::insert(&mut pool.acquire().await.unwrap(), user_id.into(), "Budget", "GBP").await.unwrap() as i32
+    }
+
+    #[test]
+    fn test_sign() {
+        assert_eq!(
+            sign("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(1), chrono::Duration::seconds(30));
+        assert_eq!(backoff(2), chrono::Duration::seconds(60));
+        assert_eq!(backoff(5), chrono::Duration::seconds(480));
+        assert_eq!(backoff(MAX_ATTEMPTS + 20), chrono::Duration::hours(6));
+    }
+
+    #[sqlx::test]
+    async fn test_delivers_signed_payloads(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let received = Received::default();
+        let url = serve(Route::new().at("/hook", post(receive)), received.clone()).await;
+        let budget_id = create_test_budget(&pool).await;
+        create_webhook(&pool, budget_id, &format!("{}/hook", url), "secret", &["transaction.created"]).await.unwrap();
+
+        // Act
+        create_account(&pool, budget_id, "Current", "GBP", 10000).await.unwrap();
+        let attempted = deliver_due(&pool, &reqwest::Client::new()).await;
+
+        // Assert
+        assert_eq!(attempted, 1, "Only the subscribed event should be queued");
+        let (event, signature, body) = received.lock().unwrap()[0].clone();
+        assert_eq!(event, "transaction.created");
+        assert_eq!(signature, format!("sha256={}", sign("secret", &body)));
+        assert!(body.contains("Starting balance"));
+
+        let deliveries = get_webhook_deliveries_for_budget(&pool, budget_id, 10).await.unwrap();
+        assert_eq!(deliveries[0].status, "delivered");
+        assert_eq!(deliveries[0].response_status, Some(200));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_failed_delivery_is_retried_later(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let url = serve(Route::new().at("/hook", post(reject)), Received::default()).await;
+        let budget_id = create_test_budget(&pool).await;
+        create_webhook(&pool, budget_id, &format!("{}/hook", url), "secret", &["account.created"]).await.unwrap();
+        create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+
+        // Act
+        let client = reqwest::Client::new();
+        let first = deliver_due(&pool, &client).await;
+        let second = deliver_due(&pool, &client).await;
+
+        // Assert
+        assert_eq!(first, 1);
+        assert_eq!(second, 0, "Retries should wait for the back-off");
+        let deliveries = get_webhook_deliveries_for_budget(&pool, budget_id, 10).await.unwrap();
+        assert_eq!(deliveries[0].status, "pending");
+        assert_eq!(deliveries[0].attempts, 1);
+        assert_eq!(deliveries[0].response_status, Some(500));
+        assert!(deliveries[0].next_attempt_at > deliveries[0].created_at);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_category_overspent(pool: Pool<Sqlite>) -> sqlx::Result<()> {
+        // Setup
+        let budget_id = create_test_budget(&pool).await;
+        let (user_id,): (i32,) = sqlx::query_as("SELECT id FROM users").fetch_one(&pool).await?;
+        create_webhook(&pool, budget_id, "http://localhost/hook", "secret", &["category.overspent"]).await.unwrap();
+        let group = insert_category_group(&mut *pool.acquire().await?, budget_id, "Everyday").await?;
+        let groceries = insert_category(&mut *pool.acquire().await?, group, "Groceries").await?;
+        let eating_out = insert_category(&mut *pool.acquire().await?, group, "Eating out").await?;
+        let account_id = create_account(&pool, budget_id, "Current", "GBP", 0).await.unwrap();
+        let april = NaiveDate::from_ymd_opt(2024, 4, 1).unwrap();
+        set_category_assigned(&pool, user_id, budget_id, groceries, april, 5000).await.unwrap();
+        let spend = |outflow: i64| NewTransaction {
+            account_id,
+            date: NaiveDate::from_ymd_opt(2024, 4, 12).unwrap().and_hms_opt(0, 0, 0).unwrap(),
+            value_date: None,
+            payee: Some("Tesco".to_string()),
+            memo: String::new(),
+            inflow: 0,
+            outflow,
+            cleared: false,
+            import_id: None,
+            imported: false,
+            category_id: Some(groceries),
+        };
+        let overspent = |pool: Pool<Sqlite>| async move {
+            get_webhook_deliveries_for_budget(&pool, budget_id, 10).await.unwrap().into_iter().filter(|d| d.event == "category.overspent").collect::<Vec<_>>()
+        };
+
+        // Act
+        create_transaction(&pool, &spend(3000)).await.unwrap();
+        let within_budget = overspent(pool.clone()).await.len();
+        create_transaction(&pool, &spend(3000)).await.unwrap();
+        let after_overspending = overspent(pool.clone()).await;
+        create_transaction(&pool, &spend(1000)).await.unwrap();
+        let still_overspent = overspent(pool.clone()).await.len();
+        set_category_assigned(&pool, user_id, budget_id, groceries, april, 8000).await.unwrap();
+        move_assigned(&pool, user_id, budget_id, groceries, eating_out, april, 2000).await.unwrap();
+        let after_moving = overspent(pool.clone()).await.len();
+
+        // Assert
+        assert_eq!(within_budget, 0);
+        assert_eq!(after_overspending.len(), 1);
+        let payload: serde_json::Value = serde_json::from_str(&after_overspending[0].payload).unwrap();
+        assert_eq!(payload["data"]["month"], "2024-04-01");
+        assert_eq!(payload["data"]["category"]["name"], "Groceries");
+        assert_eq!(payload["data"]["category"]["available"], -1000);
+        assert_eq!(still_overspent, 1, "Only the change that takes a category below zero is sent");
+        assert_eq!(after_moving, 2, "Moving money out of a category can overspend it too");
+
+        Ok(())
+    }
+}